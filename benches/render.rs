@@ -0,0 +1,59 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use opl3_rs::Opl3Chip;
+
+/// Return a chip playing a note on every 2-operator channel, with vibrato and tremolo enabled, so
+/// all of the synthesis path is exercised.
+fn playing_chip(sample_rate: u32) -> Opl3Chip {
+    let mut chip = Opl3Chip::new(sample_rate);
+    chip.write_register(0x105, 0x01);
+    chip.write_register(0xBD, 0xC0);
+    for bank in [0x000, 0x100] {
+        for slot in [0x00, 0x01, 0x02, 0x08, 0x09, 0x0A, 0x10, 0x11, 0x12] {
+            for operator in [slot, slot + 3] {
+                chip.write_register(bank | (0x20 + operator), 0xC1);
+                chip.write_register(bank | (0x40 + operator), 0x10);
+                chip.write_register(bank | (0x60 + operator), 0xF4);
+                chip.write_register(bank | (0x80 + operator), 0x44);
+            }
+        }
+        for channel in 0..9 {
+            chip.write_register(bank | (0xC0 + channel), 0x3E);
+            chip.write_register(bank | (0xA0 + channel), 0x44 + channel as u8 * 8);
+            chip.write_register(bank | (0xB0 + channel), 0x31);
+        }
+    }
+    chip
+}
+
+fn render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render_into");
+    for frames in [64usize, 1024, 16384] {
+        group.throughput(Throughput::Elements(frames as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(frames),
+            &frames,
+            |b, &frames| {
+                let mut chip = playing_chip(44100);
+                let mut buffer = Vec::new();
+                b.iter(|| chip.render_into(frames, &mut buffer));
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("generate_per_sample");
+    group.throughput(Throughput::Elements(1024));
+    group.bench_function("1024", |b| {
+        let mut chip = playing_chip(44100);
+        let mut sample = [0i16; 2];
+        b.iter(|| {
+            for _ in 0..1024 {
+                _ = chip.generate_resampled(&mut sample);
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
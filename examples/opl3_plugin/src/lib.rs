@@ -0,0 +1,337 @@
+//! The `opl3-rs` MIDI synth as a CLAP or VST3 instrument, built on `nih-plug`.
+//!
+//! `OplPlugin` implements `nih-plug`'s `Plugin`, `ClapPlugin` and `Vst3Plugin` traits. Notes,
+//! controllers, program changes and pitch bends from the host are played sample-accurately, and
+//! the output gain, soft panning and random detune are host automatable parameters.
+//!
+//! The plugin's state chunk, saved with the host's project, holds the parameters and the
+//! instruments being played, as a WOPL bank file. Banks are loaded with
+//! `OplPluginParams::set_bank`, and take effect the next time the host initializes the plugin,
+//! which it does after loading a state. Without a bank, every program plays a string patch.
+//!
+//! The chips are not saved with `opl3_rs::Opl3Device::save_state`. A savestate holds the
+//! registers and envelopes of whatever notes were sounding, but not the synth's own record of
+//! which voice plays which note, so a restored chip would hold notes that no note off could ever
+//! end. Hosts reset plugins after loading a project anyway, so the state chunk keeps only what a
+//! project needs to sound the same: the parameters and the bank.
+//!
+//! This crate builds a `cdylib` exporting both the CLAP and the VST3 entry points.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::bank::{Bank, BankSet};
+//! use opl3_rs::patch::{Operator, OperatorPair, Patch};
+//! use opl3_plugin::OplPlugin;
+//!
+//! let mut bank = Bank::new("Organ");
+//! bank.patches.push(Patch::new(OperatorPair {
+//!     modulator: Operator::new(0x31, 0x1E, 0xF2, 0x07, 0x00),
+//!     carrier: Operator::new(0x31, 0x00, 0xF2, 0x07, 0x00),
+//!     feedback_connection: 0x0E,
+//! }));
+//! let banks = BankSet { melodic: vec![bank], percussion: Vec::new() };
+//!
+//! let plugin = OplPlugin::default();
+//! assert!(plugin.params().bank().is_none());
+//! plugin.params().set_bank(&banks);
+//! let saved = plugin.params().bank().unwrap();
+//! assert_eq!(saved.melodic[0].patches[0], banks.melodic[0].patches[0]);
+//! ```
+
+use std::sync::{Arc, RwLock};
+
+use nih_plug::prelude::*;
+
+use opl3_rs::bank::{Bank, BankSet};
+use opl3_rs::midi_synth::OplMidiSynth;
+use opl3_rs::opl_core::OplCore;
+use opl3_rs::patch::{Operator, OperatorPair, Patch};
+
+/// The seed of the random detune. A fixed seed makes renders of a project repeatable.
+const DETUNE_SEED: u32 = 1;
+
+/// The `OplPluginParams` struct holds the host automatable parameters of an `OplPlugin`, and the
+/// bank persisted in its state.
+#[derive(Params)]
+pub struct OplPluginParams {
+    /// The output gain, as a linear factor.
+    #[id = "gain"]
+    pub gain: FloatParam,
+    /// Whether the pan controller pans smoothly, as set by `OplMidiSynth::set_soft_pan`.
+    #[id = "soft_pan"]
+    pub soft_pan: BoolParam,
+    /// The largest random detune of each voice, in cents, as set by
+    /// `OplMidiSynth::set_random_detune`.
+    #[id = "detune"]
+    pub detune: FloatParam,
+    /// The instruments, as a WOPL bank file, or empty for the built-in instrument.
+    #[persist = "bank"]
+    bank: RwLock<Vec<u8>>,
+}
+
+impl Default for OplPluginParams {
+    fn default() -> Self {
+        OplPluginParams {
+            gain: FloatParam::new(
+                "Gain",
+                util::db_to_gain(0.0),
+                FloatRange::Skewed {
+                    min: util::db_to_gain(-30.0),
+                    max: util::db_to_gain(12.0),
+                    factor: FloatRange::gain_skew_factor(-30.0, 12.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
+            .with_string_to_value(formatters::s2v_f32_gain_to_db()),
+            soft_pan: BoolParam::new("Soft pan", false),
+            detune: FloatParam::new(
+                "Random detune",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 50.0,
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" cents"),
+            bank: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+impl OplPluginParams {
+    /// Set the instruments to play, saved with the plugin's state. They take effect the next
+    /// time the plugin is initialized.
+    ///
+    /// # Arguments
+    ///
+    /// * `banks` - The instruments.
+    pub fn set_bank(&self, banks: &BankSet) {
+        if let Ok(mut bank) = self.bank.write() {
+            *bank = banks.to_wopl();
+        }
+    }
+
+    /// Return the instruments saved with the plugin's state, or `None` if there are none, or
+    /// they cannot be read.
+    pub fn bank(&self) -> Option<BankSet> {
+        let bank = self.bank.read().ok()?;
+        if bank.is_empty() {
+            return None;
+        }
+        BankSet::from_wopl(&bank).ok()
+    }
+}
+
+/// The `OplPlugin` struct is an instrument plugin playing MIDI on an `OplMidiSynth`.
+pub struct OplPlugin {
+    params: Arc<OplPluginParams>,
+    /// The synth, created when the host initializes the plugin with its sample rate.
+    synth: Option<OplMidiSynth>,
+    /// Interleaved 16-bit audio, rendered before conversion to the host's buffers.
+    scratch: Vec<i16>,
+    /// The soft pan and detune parameters last passed to the synth.
+    soft_pan: bool,
+    detune: f32,
+}
+
+impl Default for OplPlugin {
+    fn default() -> Self {
+        OplPlugin {
+            params: Arc::new(OplPluginParams::default()),
+            synth: None,
+            scratch: Vec::new(),
+            soft_pan: false,
+            detune: 0.0,
+        }
+    }
+}
+
+impl OplPlugin {
+    /// Return the parameters of the plugin.
+    pub fn params(&self) -> &OplPluginParams {
+        &self.params
+    }
+
+    /// Pass a note event from the host to the synth.
+    fn handle_event(synth: &mut OplMidiSynth, event: NoteEvent<()>) {
+        let midi = |value: f32, max: f32| (value * max).round().clamp(0.0, max);
+        match event {
+            NoteEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+                ..
+            } => synth.note_on(channel, note, (midi(velocity, 127.0) as u8).max(1)),
+            NoteEvent::NoteOff { channel, note, .. } => synth.note_off(channel, note),
+            NoteEvent::MidiCC {
+                channel, cc, value, ..
+            } => synth.control_change(channel, cc, midi(value, 127.0) as u8),
+            NoteEvent::MidiProgramChange {
+                channel, program, ..
+            } => synth.program_change(channel, program),
+            NoteEvent::MidiPitchBend { channel, value, .. } => {
+                synth.pitch_bend(channel, midi(value, 16383.0) as u16)
+            }
+            _ => {}
+        }
+    }
+
+    /// Pass the soft pan and detune parameters to the synth, if they have changed.
+    fn apply_params(&mut self) {
+        let Some(synth) = self.synth.as_mut() else {
+            return;
+        };
+        let soft_pan = self.params.soft_pan.value();
+        if soft_pan != self.soft_pan {
+            synth.set_soft_pan(soft_pan);
+            self.soft_pan = soft_pan;
+        }
+        let detune = self.params.detune.value();
+        if detune != self.detune {
+            synth.set_random_detune(detune as f64, DETUNE_SEED);
+            self.detune = detune;
+        }
+    }
+
+    /// Render audio from the synth into part of the host's buffers.
+    fn render(&mut self, outputs: &mut [&mut [f32]], start: usize, end: usize) {
+        let Some(synth) = self.synth.as_mut() else {
+            return;
+        };
+        self.scratch.resize((end - start) * 2, 0);
+        if synth.generate_stream(&mut self.scratch).is_err() {
+            self.scratch.fill(0);
+        }
+        for (i, frame) in self.scratch.chunks_exact(2).enumerate() {
+            let gain = self.params.gain.smoothed.next();
+            for (output, &sample) in outputs.iter_mut().zip(frame) {
+                output[start + i] = sample as f32 / 32768.0 * gain;
+            }
+        }
+    }
+}
+
+/// The default instruments: a string patch on every program.
+fn default_banks() -> BankSet {
+    let strings = Patch::new(OperatorPair {
+        modulator: Operator::new(0x21, 0x18, 0x71, 0x15, 0x00),
+        carrier: Operator::new(0x21, 0x00, 0x71, 0x15, 0x00),
+        feedback_connection: 0x0A,
+    });
+    let mut bank = Bank::new("Strings");
+    bank.patches = vec![strings; 128];
+    BankSet {
+        melodic: vec![bank],
+        percussion: Vec::new(),
+    }
+}
+
+impl Plugin for OplPlugin {
+    const NAME: &'static str = "OPL3 MIDI Synth";
+    const VENDOR: &'static str = "opl3-rs";
+    const URL: &'static str = "https://github.com/dbalsom/opl3-rs";
+    const EMAIL: &'static str = "";
+    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
+        main_input_channels: None,
+        main_output_channels: NonZeroU32::new(2),
+        ..AudioIOLayout::const_default()
+    }];
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
+    const SAMPLE_ACCURATE_AUTOMATION: bool = true;
+
+    type SysExMessage = ();
+    type BackgroundTask = ();
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn initialize(
+        &mut self,
+        _audio_io_layout: &AudioIOLayout,
+        buffer_config: &BufferConfig,
+        _context: &mut impl InitContext<Self>,
+    ) -> bool {
+        let banks = self.params.bank().unwrap_or_else(default_banks);
+        let mut synth = OplMidiSynth::new(buffer_config.sample_rate.round() as u32, banks);
+        synth.reserve(buffer_config.max_buffer_size as usize);
+        self.scratch = Vec::with_capacity(buffer_config.max_buffer_size as usize * 2);
+        self.synth = Some(synth);
+        // Pass the parameters on again, to the new synth.
+        self.soft_pan = false;
+        self.detune = 0.0;
+        self.apply_params();
+        true
+    }
+
+    fn reset(&mut self) {
+        if let Some(synth) = self.synth.as_mut() {
+            synth.all_sound_off();
+        }
+    }
+
+    fn process(
+        &mut self,
+        buffer: &mut Buffer,
+        _aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        let samples = buffer.samples();
+        let outputs = buffer.as_slice();
+        for output in outputs.iter_mut() {
+            output.fill(0.0);
+        }
+        self.apply_params();
+
+        // Render up to each event, then play it, so that notes start on the sample they are
+        // timed at.
+        let mut next_event = context.next_event();
+        let mut start = 0;
+        while start < samples {
+            while let Some(event) = next_event.take() {
+                if event.timing() as usize > start {
+                    next_event = Some(event);
+                    break;
+                }
+                if let Some(synth) = self.synth.as_mut() {
+                    Self::handle_event(synth, event);
+                }
+                next_event = context.next_event();
+            }
+            let end = next_event
+                .as_ref()
+                .map_or(samples, |event| event.timing() as usize)
+                .clamp(start + 1, samples);
+            self.render(outputs, start, end);
+            start = end;
+        }
+        ProcessStatus::Normal
+    }
+}
+
+impl ClapPlugin for OplPlugin {
+    const CLAP_ID: &'static str = "com.github.dbalsom.opl3-rs";
+    const CLAP_DESCRIPTION: Option<&'static str> = Some("MIDI synth on an emulated OPL3");
+    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::Instrument,
+        ClapFeature::Synthesizer,
+        ClapFeature::Stereo,
+    ];
+}
+
+impl Vst3Plugin for OplPlugin {
+    const VST3_CLASS_ID: [u8; 16] = *b"Opl3RsMidiSynth!";
+    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] =
+        &[Vst3SubCategory::Instrument, Vst3SubCategory::Synth];
+}
+
+nih_export_clap!(OplPlugin);
+nih_export_vst3!(OplPlugin);
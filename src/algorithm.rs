@@ -0,0 +1,126 @@
+//! Descriptions of the operator connection algorithms, for drawing routing diagrams.
+//!
+//! How the operators of a channel are connected is selected by the connection (CNT) bit of
+//! register 0xC0, or by the connection bits of both channels of a 4-operator pair. `Algorithm`
+//! describes the result: which operator modulates which, which operators are heard, and which has
+//! feedback. The descriptions follow the order in which Nuked-OPL3 chains the operators, so
+//! diagrams drawn from them match what the core plays.
+//!
+//! Operators are numbered as in `registers::reg_for_operator`: 0 and 1 are the channel's own
+//! modulator and carrier, and 2 and 3 are those of its 4-operator partner.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::algorithm::Algorithm;
+//!
+//! // FM-AM: operators 0 and 1 form one FM voice, and operators 2 and 3 another.
+//! let algorithm = Algorithm::four_op(false, true);
+//! assert_eq!(algorithm.modulator(1), Some(0));
+//! assert_eq!(algorithm.modulator(2), None);
+//! assert_eq!(algorithm.modulator(3), Some(2));
+//! assert_eq!(algorithm.carriers().collect::<Vec<_>>(), vec![1, 3]);
+//! ```
+
+/// The `Algorithm` struct describes how the operators of a channel are connected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Algorithm {
+    /// The number of operators, 2 or 4.
+    pub operators: u8,
+    /// The operator modulating each operator, if any.
+    pub modulators: [Option<u8>; 4],
+    /// Whether each operator's output is heard.
+    pub outputs: [bool; 4],
+    /// Whether operator 0 modulates itself with the feedback set in register 0xC0.
+    pub feedback: bool,
+}
+
+impl Algorithm {
+    /// Return the algorithm of a 2-operator channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The channel's connection bit: `false` for FM, where operator 0
+    ///                  modulates operator 1, or `true` for additive synthesis, where both are
+    ///                  heard.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::algorithm::Algorithm;
+    ///
+    /// let fm = Algorithm::two_op(false);
+    /// assert_eq!(fm.modulator(1), Some(0));
+    /// assert_eq!(fm.carriers().collect::<Vec<_>>(), vec![1]);
+    /// ```
+    pub fn two_op(connection: bool) -> Self {
+        Algorithm {
+            operators: 2,
+            modulators: [None, (!connection).then_some(0), None, None],
+            outputs: [connection, true, false, false],
+            feedback: true,
+        }
+    }
+
+    /// Return the algorithm of a 4-operator channel. The operators are chained from 0 to 3, and
+    /// each connection bit breaks the chain after one operator, which is then heard.
+    ///
+    /// # Arguments
+    ///
+    /// * `first`  - The connection bit of the first channel of the pair, which owns operators 0
+    ///              and 1.
+    /// * `second` - The connection bit of the second channel, which owns operators 2 and 3.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::algorithm::Algorithm;
+    ///
+    /// // AM-AM: operator 0 alone, 1 modulating 2, and 3 alone.
+    /// let algorithm = Algorithm::four_op(true, true);
+    /// assert_eq!(algorithm.carriers().collect::<Vec<_>>(), vec![0, 2, 3]);
+    /// assert_eq!(algorithm.modulator(2), Some(1));
+    /// ```
+    pub fn four_op(first: bool, second: bool) -> Self {
+        let (modulators, outputs) = match (first, second) {
+            (false, false) => (
+                [None, Some(0), Some(1), Some(2)],
+                [false, false, false, true],
+            ),
+            (false, true) => ([None, Some(0), None, Some(2)], [false, true, false, true]),
+            (true, false) => ([None, None, Some(1), Some(2)], [true, false, false, true]),
+            (true, true) => ([None, None, Some(1), None], [true, false, true, true]),
+        };
+        Algorithm {
+            operators: 4,
+            modulators,
+            outputs,
+            feedback: true,
+        }
+    }
+
+    /// Return the algorithm of the snare and hi-hat channel, or the tom-tom and cymbal channel,
+    /// in rhythm mode. Each operator is a separate drum, heard without modulation or feedback.
+    pub fn drums() -> Self {
+        Algorithm {
+            operators: 2,
+            modulators: [None; 4],
+            outputs: [true, true, false, false],
+            feedback: false,
+        }
+    }
+
+    /// Return the operator modulating an operator, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `operator` - The operator, from 0 to 3.
+    pub fn modulator(&self, operator: u8) -> Option<u8> {
+        self.modulators.get(operator as usize).copied().flatten()
+    }
+
+    /// Return the operators whose output is heard, in order.
+    pub fn carriers(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.operators).filter(|&operator| self.outputs[operator as usize])
+    }
+}
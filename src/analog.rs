@@ -0,0 +1,136 @@
+//! Low-pass filtering approximating the DAC and analog output path of real sound cards.
+//!
+//! The YAC512 and YM3014 DACs hold each sample until the next, which rolls off the top octave,
+//! and every card follows the DAC with its own analog filtering. Raw emulator output has neither,
+//! and sounds noticeably brighter than recordings of hardware. `AnalogFilter` is an `Effect`
+//! approximating both for a few typical cards, selected with an `AnalogPreset`. Unlike
+//! `speaker::SpeakerSimulation`, it only models the card, and is meant to be left on.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::analog::{AnalogFilter, AnalogPreset};
+//! use opl3_rs::effects::WithEffects;
+//! use opl3_rs::opl_core::OplCore;
+//!
+//! let filter = AnalogFilter::new(AnalogPreset::Sb16, 44100);
+//! let mut chip = WithEffects::new(Opl3Chip::new(44100), filter);
+//!
+//! chip.write_register(0xB0, 0x32);
+//! let mut buffer = [0i16; 1024 * 2];
+//! chip.generate_stream(&mut buffer).unwrap();
+//! ```
+
+use crate::effects::{Biquad, BiquadKind, Effect};
+
+/// The `AnalogPreset` enum lists the cards `AnalogFilter` can approximate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AnalogPreset {
+    /// The original AdLib, with a YM3014 DAC and simple RC filtering that leaves little above
+    /// 8kHz.
+    AdLib,
+    /// A Sound Blaster 16, whose mixer filters the YAC512 output gently, keeping most of the top
+    /// end.
+    Sb16,
+    /// A typical OPL3 clone card, with a single op-amp filter stage around 10kHz.
+    Clone,
+}
+
+impl AnalogPreset {
+    /// Return the filters making up the preset, in the order they are applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate of the audio to filter.
+    pub fn filters(self, sample_rate: u32) -> Vec<Biquad> {
+        use BiquadKind::*;
+        // The sample and hold droop of the DAC, about 3dB at the top of the audio band.
+        let mut filters = vec![Biquad::new(HighShelf(-3.0), sample_rate, 18000.0, 0.6)];
+        filters.extend(match self {
+            AnalogPreset::AdLib => vec![
+                Biquad::new(HighPass, sample_rate, 20.0, 0.707),
+                Biquad::new(LowPass, sample_rate, 7000.0, 0.6),
+                Biquad::new(LowPass, sample_rate, 12000.0, 0.5),
+            ],
+            AnalogPreset::Sb16 => vec![
+                Biquad::new(HighPass, sample_rate, 15.0, 0.707),
+                Biquad::new(LowPass, sample_rate, 16000.0, 0.707),
+            ],
+            AnalogPreset::Clone => vec![
+                Biquad::new(HighPass, sample_rate, 30.0, 0.707),
+                Biquad::new(LowPass, sample_rate, 10000.0, 0.707),
+            ],
+        });
+        filters
+    }
+}
+
+/// The `AnalogFilter` struct filters audio to approximate the output path of an `AnalogPreset`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalogFilter {
+    preset: AnalogPreset,
+    filters: Vec<Biquad>,
+}
+
+impl AnalogFilter {
+    /// Create a new `AnalogFilter`.
+    ///
+    /// # Arguments
+    ///
+    /// * `preset`      - The card to approximate.
+    /// * `sample_rate` - The sample rate of the audio to filter.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::analog::{AnalogFilter, AnalogPreset};
+    /// use opl3_rs::effects::Effect;
+    ///
+    /// fn peak(preset: AnalogPreset, frequency: f32) -> i16 {
+    ///     let mut filter = AnalogFilter::new(preset, 44100);
+    ///     let mut buffer: Vec<i16> = (0..44100)
+    ///         .map(|i| ((i / 2) as f32 * frequency * std::f32::consts::TAU / 44100.0).sin())
+    ///         .map(|sample| (sample * 10000.0) as i16)
+    ///         .collect();
+    ///     filter.process(&mut buffer);
+    ///     buffer[22050..].iter().map(|sample| sample.abs()).max().unwrap()
+    /// }
+    ///
+    /// // The midrange passes through, and the AdLib is the dullest of the cards.
+    /// assert!(peak(AnalogPreset::AdLib, 500.0) > 9000);
+    /// assert!(peak(AnalogPreset::AdLib, 12000.0) < peak(AnalogPreset::Clone, 12000.0));
+    /// assert!(peak(AnalogPreset::Clone, 12000.0) < peak(AnalogPreset::Sb16, 12000.0));
+    /// ```
+    pub fn new(preset: AnalogPreset, sample_rate: u32) -> Self {
+        AnalogFilter {
+            preset,
+            filters: preset.filters(sample_rate),
+        }
+    }
+
+    /// Return the card being approximated.
+    pub fn preset(&self) -> AnalogPreset {
+        self.preset
+    }
+}
+
+impl Effect for AnalogFilter {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for filter in self.filters.iter_mut() {
+            filter.process(buffer);
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            filter.reset();
+        }
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        for filter in self.filters.iter_mut() {
+            filter.set_dither(enabled);
+        }
+    }
+}
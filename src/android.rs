@@ -0,0 +1,226 @@
+//! Audio output on Android through Oboe, with handles that can be passed through JNI.
+//!
+//! `OplAndroidOutput` opens a low latency Oboe output stream, which uses AAudio where the device
+//! supports it and OpenSL ES elsewhere, and renders any `OplCore` into it from Oboe's audio
+//! callback. Register writes are sent to the callback without blocking, and are applied before
+//! the next block of audio is rendered. If the device's native sample rate differs from the
+//! core's, Oboe converts it.
+//!
+//! Java and Kotlin code cannot hold Rust values, so `into_handle` turns an output into a `long`
+//! for the app to keep, and `from_handle` and `free_handle` turn it back in the app's native
+//! methods. JNI passes a `long` as an `i64`, so no JNI crate is needed for the handle itself.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::ffi::c_void;
+//!
+//! use opl3_rs::android::OplAndroidOutput;
+//! use opl3_rs::Opl3Chip;
+//!
+//! // `external fun start(): Long`
+//! #[no_mangle]
+//! pub extern "system" fn Java_com_example_opl_Player_start(
+//!     _env: *mut c_void,
+//!     _class: *mut c_void,
+//! ) -> i64 {
+//!     match OplAndroidOutput::new(Box::new(Opl3Chip::new(48000))) {
+//!         Ok(output) => output.into_handle(),
+//!         Err(_) => 0,
+//!     }
+//! }
+//!
+//! // `external fun write(handle: Long, register: Int, value: Int)`
+//! #[no_mangle]
+//! pub extern "system" fn Java_com_example_opl_Player_write(
+//!     _env: *mut c_void,
+//!     _class: *mut c_void,
+//!     handle: i64,
+//!     register: i32,
+//!     value: i32,
+//! ) {
+//!     if let Some(output) = unsafe { OplAndroidOutput::from_handle(handle) } {
+//!         _ = output.write_register(register as u16, value as u8);
+//!     }
+//! }
+//!
+//! // `external fun stop(handle: Long)`
+//! #[no_mangle]
+//! pub extern "system" fn Java_com_example_opl_Player_stop(
+//!     _env: *mut c_void,
+//!     _class: *mut c_void,
+//!     handle: i64,
+//! ) {
+//!     unsafe { OplAndroidOutput::free_handle(handle) };
+//! }
+//! ```
+
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use oboe::{
+    AudioOutputCallback, AudioOutputStreamSafe, AudioStream, AudioStreamAsync, AudioStreamBase,
+    AudioStreamBuilder, DataCallbackResult, Output, PerformanceMode, SampleRateConversionQuality,
+    SharingMode, Stereo,
+};
+
+use crate::opl_core::OplCore;
+use crate::OplError;
+
+/// The number of frames rendered at a time in the audio callback.
+const BLOCK_FRAMES: usize = 256;
+
+/// Convert an Oboe error to an `OplError`.
+fn backend_error(error: oboe::Error) -> OplError {
+    OplError::Backend(error.to_string())
+}
+
+/// The audio callback, which owns the core.
+struct OplCallback {
+    core: Box<dyn OplCore + Send>,
+    rx: Receiver<(u16, u8)>,
+}
+
+impl AudioOutputCallback for OplCallback {
+    type FrameType = (f32, Stereo);
+
+    fn on_audio_ready(
+        &mut self,
+        _stream: &mut dyn AudioOutputStreamSafe,
+        frames: &mut [(f32, f32)],
+    ) -> DataCallbackResult {
+        while let Ok((register, value)) = self.rx.try_recv() {
+            self.core.write_register(register, value);
+        }
+        let mut block = [0i16; BLOCK_FRAMES * 2];
+        for frames in frames.chunks_mut(BLOCK_FRAMES) {
+            let samples = &mut block[..frames.len() * 2];
+            if self.core.generate_stream(samples).is_err() {
+                samples.fill(0);
+            }
+            for (frame, samples) in frames.iter_mut().zip(samples.chunks_exact(2)) {
+                *frame = (samples[0] as f32 / 32768.0, samples[1] as f32 / 32768.0);
+            }
+        }
+        DataCallbackResult::Continue
+    }
+}
+
+/// The `OplAndroidOutput` struct is an Oboe output stream playing an `OplCore`. Dropping it
+/// closes the stream.
+pub struct OplAndroidOutput {
+    stream: AudioStreamAsync<Output, OplCallback>,
+    tx: Sender<(u16, u8)>,
+}
+
+impl OplAndroidOutput {
+    /// Open an output stream playing a core, and start it.
+    ///
+    /// # Arguments
+    ///
+    /// * `core` - The core to play, at the sample rate to request from the device.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the new `OplAndroidOutput` on success or an `OplError` on
+    /// failure. The error is `OplError::Backend` if the stream cannot be opened or started.
+    pub fn new(core: Box<dyn OplCore + Send>) -> Result<Self, OplError> {
+        let sample_rate = core.sample_rate() as i32;
+        let (tx, rx) = mpsc::channel();
+        let mut stream = AudioStreamBuilder::default()
+            .set_performance_mode(PerformanceMode::LowLatency)
+            .set_sharing_mode(SharingMode::Shared)
+            .set_format::<f32>()
+            .set_channel_count::<Stereo>()
+            .set_sample_rate(sample_rate)
+            .set_sample_rate_conversion_quality(SampleRateConversionQuality::Medium)
+            .set_callback(OplCallback { core, rx })
+            .open_stream()
+            .map_err(backend_error)?;
+        stream.start().map_err(backend_error)?;
+        Ok(OplAndroidOutput { stream, tx })
+    }
+
+    /// Return the sample rate of the stream, which may differ from the core's if Oboe converts
+    /// it.
+    pub fn sample_rate(&self) -> u32 {
+        self.stream.get_sample_rate() as u32
+    }
+
+    /// Send a register write to the core, applied before the next block of audio is rendered.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The register to write, in the range 0x000..=0x1FF.
+    /// * `value`    - The value to write.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the stream has stopped
+    /// for good, such as when the audio device was disconnected.
+    pub fn write_register(&self, register: u16, value: u8) -> Result<(), OplError> {
+        self.tx
+            .send((register, value))
+            .map_err(|_| OplError::StreamClosed)
+    }
+
+    /// Pause the stream, such as when the app goes to the background.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure. The error is
+    /// `OplError::Backend` if Oboe cannot stop the stream.
+    pub fn pause(&mut self) -> Result<(), OplError> {
+        self.stream.stop().map_err(backend_error)
+    }
+
+    /// Start the stream again after `pause`.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure. The error is
+    /// `OplError::Backend` if Oboe cannot start the stream.
+    pub fn resume(&mut self) -> Result<(), OplError> {
+        self.stream.start().map_err(backend_error)
+    }
+
+    /// Turn the output into a handle, for Java or Kotlin code to hold as a `long`. The handle is
+    /// never 0, so 0 can stand for no output.
+    pub fn into_handle(self) -> i64 {
+        Box::into_raw(Box::new(self)) as i64
+    }
+
+    /// Return the output a handle stands for.
+    ///
+    /// # Arguments
+    ///
+    /// * `handle` - A handle returned by `into_handle`, or 0.
+    ///
+    /// # Returns
+    ///
+    /// The output, or `None` if the handle is 0.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be 0 or a handle returned by `into_handle` that has not been freed, and
+    /// the output must not be used from two threads at once.
+    pub unsafe fn from_handle<'a>(handle: i64) -> Option<&'a mut Self> {
+        (handle as *mut Self).as_mut()
+    }
+
+    /// Close the output a handle stands for, and free it. Freeing a handle of 0 does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `handle` - A handle returned by `into_handle`, or 0.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be 0 or a handle returned by `into_handle` that has not been freed, and
+    /// it must not be used after.
+    pub unsafe fn free_handle(handle: i64) {
+        let output = handle as *mut Self;
+        if !output.is_null() {
+            drop(Box::from_raw(output));
+        }
+    }
+}
@@ -0,0 +1,680 @@
+//! Reading and writing instrument banks in the common OPL formats.
+//!
+//! Patches and banks are converted to and from `patch::Patch`, so any supported format can be
+//! converted to any other by reading it and writing the result:
+//!
+//! * SBI, a single Sound Blaster instrument (`Patch::from_sbi`).
+//! * IBK, a bank of 128 SBI instruments (`Bank::from_ibk`).
+//! * BNK, an AdLib Visual Composer bank of named instruments (`Bank::from_bnk`).
+//! * OP2, the DMX `GENMIDI` lump, with a melodic and a percussion bank (`BankSet::from_op2`).
+//! * WOPL, the libADLMIDI bank format, with any number of banks of either kind
+//!   (`BankSet::from_wopl`).
+//!
+//! Not every format can hold everything a `Patch` can, and writing a patch to a format that
+//! cannot hold it is lossy:
+//!
+//! * SBI, IBK and BNK hold a single 2-operator voice. Of a 4-operator instrument, the second pair
+//!   is kept, as it holds the carrier that always reaches the output; the first pair is lost.
+//!   Of a double-voice instrument, the first voice is kept. Note offsets, detune and fixed notes
+//!   are lost.
+//! * OP2 has no 4-operator instruments, and writes them as double-voice instruments playing both
+//!   pairs as separate voices. This keeps the operators, but not how they were connected.
+//! * Names are truncated to the length each format allows: 8 bytes for IBK and BNK, 31
+//!   otherwise.
+//! * OP2 banks hold exactly 128 melodic instruments and the 47 percussion instruments for notes
+//!   35-81, and IBK banks exactly 128 instruments. Other instruments are dropped, and missing
+//!   ones are written as `Patch::default()`, which is silent.
+//!
+//! WOPL velocity offsets, key-on and key-off delays and rhythm mode flags are not read, and are
+//! written as zero. The padding bytes of SBI and IBK instruments, which some editors use for
+//! percussion settings, are ignored.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::bank::{Bank, BankSet};
+//! use opl3_rs::patch::{Operator, OperatorPair, Patch};
+//!
+//! let mut bank = Bank::new("Organs");
+//! bank.patches.push(Patch {
+//!     name: "Drawbar".to_string(),
+//!     ..Patch::new(OperatorPair {
+//!         modulator: Operator::new(0x01, 0x10, 0xF0, 0x77, 0x00),
+//!         carrier: Operator::new(0x01, 0x00, 0xF0, 0x77, 0x00),
+//!         feedback_connection: 0x00,
+//!     })
+//! });
+//!
+//! // Convert the bank from BNK to WOPL and back.
+//! let bnk = Bank::from_bnk(&bank.to_bnk()).unwrap();
+//! let set = BankSet { melodic: vec![bnk], percussion: Vec::new() };
+//! let wopl = BankSet::from_wopl(&set.to_wopl()).unwrap();
+//! assert_eq!(wopl.melodic[0].patches[0], bank.patches[0]);
+//! ```
+
+use crate::patch::{Operator, OperatorPair, Patch, PatchKind};
+use crate::OplError;
+
+const SBI_MAGIC: &[u8; 4] = b"SBI\x1A";
+const SBI_SIZE: usize = 52;
+const IBK_MAGIC: &[u8; 4] = b"IBK\x1A";
+const IBK_PATCHES: usize = 128;
+const IBK_NAME_SIZE: usize = 9;
+const BNK_SIGNATURE: &[u8; 6] = b"ADLIB-";
+const BNK_HEADER_SIZE: usize = 28;
+const BNK_NAME_RECORD_SIZE: usize = 12;
+const BNK_DATA_RECORD_SIZE: usize = 30;
+const OP2_MAGIC: &[u8; 8] = b"#OPL_II#";
+const OP2_MELODIC: usize = 128;
+const OP2_PERCUSSION: usize = 47;
+const OP2_FIRST_DRUM: usize = 35;
+const OP2_RECORD_SIZE: usize = 36;
+const OP2_NAME_SIZE: usize = 32;
+const WOPL_MAGIC: &[u8; 11] = b"WOPL3-BANK\0";
+const WOPL_VERSION: u16 = 3;
+const WOPL_HEADER_SIZE: usize = 19;
+const WOPL_BANK_META_SIZE: usize = 34;
+const WOPL_PATCHES: usize = 128;
+const NAME_SIZE: usize = 32;
+
+/// The `Bank` struct is a list of instruments, indexed by MIDI program for melodic banks and by
+/// note for percussion banks.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Bank {
+    /// The name of the bank, which may be empty.
+    pub name: String,
+    /// The bank select MSB (controller 0) that selects this bank.
+    pub msb: u8,
+    /// The bank select LSB (controller 32) that selects this bank.
+    pub lsb: u8,
+    /// The instruments in the bank.
+    pub patches: Vec<Patch>,
+}
+
+/// The `BankSet` struct holds the melodic and percussion banks of a bank file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BankSet {
+    /// The melodic banks, each indexed by MIDI program.
+    pub melodic: Vec<Bank>,
+    /// The percussion banks, each indexed by note.
+    pub percussion: Vec<Bank>,
+}
+
+impl Patch {
+    /// Read a patch from an SBI file.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of the SBI file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the patch on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::patch::Patch;
+    ///
+    /// let mut sbi = b"SBI\x1APiano".to_vec();
+    /// sbi.resize(36, 0);
+    /// sbi.extend_from_slice(&[0x01, 0x11, 0x4F, 0x00, 0xF1, 0xD2, 0x53, 0x74, 0x00, 0x00, 0x06]);
+    /// let patch = Patch::from_sbi(&sbi).unwrap();
+    /// assert_eq!(patch.name, "Piano");
+    /// assert_eq!(patch.pairs[0].carrier.attack_decay, 0xD2);
+    /// assert_eq!(Patch::from_sbi(&patch.to_sbi()).unwrap(), patch);
+    /// ```
+    pub fn from_sbi(data: &[u8]) -> Result<Patch, OplError> {
+        const ERR: OplError = OplError::InvalidFormat("SBI");
+        if data.get(0..4) != Some(SBI_MAGIC) {
+            return Err(ERR);
+        }
+        let registers = data.get(36..47).ok_or(ERR)?;
+        Ok(Patch {
+            name: read_name(&data[4..36]),
+            ..Patch::new(read_sbi_registers(registers))
+        })
+    }
+
+    /// Return the patch as an SBI file. See the module documentation for what is lost.
+    pub fn to_sbi(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(SBI_SIZE);
+        data.extend_from_slice(SBI_MAGIC);
+        write_name(&mut data, &self.name, NAME_SIZE);
+        data.extend_from_slice(&sbi_registers(&self.two_op_pair()));
+        data.resize(SBI_SIZE, 0);
+        data
+    }
+
+    /// Return the pair kept when the patch is written to a 2-operator format.
+    fn two_op_pair(&self) -> OperatorPair {
+        match self.kind {
+            PatchKind::FourOp => self.pairs[1],
+            PatchKind::TwoOp | PatchKind::DoubleVoice => self.pairs[0],
+        }
+    }
+}
+
+impl Bank {
+    /// Create a new, empty `Bank`, selected by bank 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the bank.
+    pub fn new(name: &str) -> Self {
+        Bank {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Read a bank from an IBK file.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of the IBK file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the bank of 128 instruments on success or an `OplError` on
+    /// failure.
+    pub fn from_ibk(data: &[u8]) -> Result<Bank, OplError> {
+        const ERR: OplError = OplError::InvalidFormat("IBK");
+        let names_start = 4 + IBK_PATCHES * 16;
+        if data.get(0..4) != Some(IBK_MAGIC)
+            || data.len() < names_start + IBK_PATCHES * IBK_NAME_SIZE
+        {
+            return Err(ERR);
+        }
+        let patches = (0..IBK_PATCHES)
+            .map(|i| {
+                let registers = &data[4 + i * 16..4 + i * 16 + 11];
+                let name = &data[names_start + i * IBK_NAME_SIZE..][..IBK_NAME_SIZE];
+                Patch {
+                    name: read_name(name),
+                    ..Patch::new(read_sbi_registers(registers))
+                }
+            })
+            .collect();
+        Ok(Bank {
+            patches,
+            ..Default::default()
+        })
+    }
+
+    /// Return the bank as an IBK file. See the module documentation for what is lost.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::Bank;
+    /// use opl3_rs::patch::Patch;
+    ///
+    /// let mut bank = Bank::new("");
+    /// bank.patches.push(Patch { name: "A long name".to_string(), ..Default::default() });
+    /// let ibk = Bank::from_ibk(&bank.to_ibk()).unwrap();
+    /// assert_eq!(ibk.patches.len(), 128);
+    /// assert_eq!(ibk.patches[0].name, "A long n");
+    /// ```
+    pub fn to_ibk(&self) -> Vec<u8> {
+        let mut data = IBK_MAGIC.to_vec();
+        let mut names = Vec::with_capacity(IBK_PATCHES * IBK_NAME_SIZE);
+        for i in 0..IBK_PATCHES {
+            let patch = self.patches.get(i).cloned().unwrap_or_default();
+            data.extend_from_slice(&sbi_registers(&patch.two_op_pair()));
+            data.extend_from_slice(&[0; 5]);
+            write_name(&mut names, &patch.name, IBK_NAME_SIZE);
+        }
+        data.extend_from_slice(&names);
+        data
+    }
+
+    /// Read a bank from an AdLib Visual Composer BNK file. Instruments are returned in the order
+    /// of their data records, which is usually the order they were added to the bank.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of the BNK file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the bank on success or an `OplError` on failure.
+    pub fn from_bnk(data: &[u8]) -> Result<Bank, OplError> {
+        const ERR: OplError = OplError::InvalidFormat("BNK");
+        if data.len() < BNK_HEADER_SIZE || &data[2..8] != BNK_SIGNATURE {
+            return Err(ERR);
+        }
+        let entries = u16::from_le_bytes([data[10], data[11]]) as usize;
+        let names_offset = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+        let data_offset = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+
+        let mut patches = Vec::with_capacity(entries);
+        for i in 0..entries {
+            let entry = data
+                .get(names_offset + i * BNK_NAME_RECORD_SIZE..)
+                .and_then(|entry| entry.get(..BNK_NAME_RECORD_SIZE))
+                .ok_or(ERR)?;
+            let index = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+            let record = data
+                .get(data_offset + index * BNK_DATA_RECORD_SIZE..)
+                .and_then(|record| record.get(..BNK_DATA_RECORD_SIZE))
+                .ok_or(ERR)?;
+            let modulator = read_bnk_operator(&record[2..15], record[28]);
+            let carrier = read_bnk_operator(&record[15..28], record[29]);
+            // The modulator holds the channel's feedback and connection.
+            let feedback_connection = (record[4] & 0x07) << 1 | (record[14] & 0x01) ^ 0x01;
+            let patch = Patch {
+                name: read_name(&entry[3..]),
+                ..Patch::new(OperatorPair {
+                    modulator,
+                    carrier,
+                    feedback_connection,
+                })
+            };
+            patches.push((index, patch));
+        }
+        patches.sort_by_key(|&(index, _)| index);
+        Ok(Bank {
+            patches: patches.into_iter().map(|(_, patch)| patch).collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Return the bank as an AdLib Visual Composer BNK file. See the module documentation for
+    /// what is lost.
+    pub fn to_bnk(&self) -> Vec<u8> {
+        let count = self.patches.len().min(u16::MAX as usize);
+        let names_offset = BNK_HEADER_SIZE;
+        let data_offset = names_offset + count * BNK_NAME_RECORD_SIZE;
+
+        let mut data = vec![1, 0];
+        data.extend_from_slice(BNK_SIGNATURE);
+        data.extend_from_slice(&(count as u16).to_le_bytes());
+        data.extend_from_slice(&(count as u16).to_le_bytes());
+        data.extend_from_slice(&(names_offset as u32).to_le_bytes());
+        data.extend_from_slice(&(data_offset as u32).to_le_bytes());
+        data.resize(BNK_HEADER_SIZE, 0);
+        for (i, patch) in self.patches.iter().take(count).enumerate() {
+            data.extend_from_slice(&(i as u16).to_le_bytes());
+            data.push(1);
+            write_name(&mut data, &patch.name, 9);
+        }
+        for patch in self.patches.iter().take(count) {
+            let pair = patch.two_op_pair();
+            // Melodic, with no rhythm voice.
+            data.extend_from_slice(&[0, 0]);
+            data.extend_from_slice(&bnk_operator(&pair.modulator, pair.feedback_connection));
+            data.extend_from_slice(&bnk_operator(&pair.carrier, pair.feedback_connection));
+            data.push(pair.modulator.waveform);
+            data.push(pair.carrier.waveform);
+        }
+        data
+    }
+}
+
+impl BankSet {
+    /// Read the banks of a DMX OP2 (`GENMIDI`) file: one melodic bank of 128 instruments, and
+    /// one percussion bank of 128, in which notes 35-81 hold instruments.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of the OP2 file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the banks on success or an `OplError` on failure.
+    pub fn from_op2(data: &[u8]) -> Result<BankSet, OplError> {
+        const ERR: OplError = OplError::InvalidFormat("OP2");
+        let count = OP2_MELODIC + OP2_PERCUSSION;
+        let names_start = 8 + count * OP2_RECORD_SIZE;
+        if data.get(0..8) != Some(OP2_MAGIC) || data.len() < names_start + count * OP2_NAME_SIZE {
+            return Err(ERR);
+        }
+        let mut patches = (0..count).map(|i| {
+            let record = &data[8 + i * OP2_RECORD_SIZE..][..OP2_RECORD_SIZE];
+            let mut patch = read_op2_record(record);
+            patch.name = read_name(&data[names_start + i * OP2_NAME_SIZE..][..OP2_NAME_SIZE]);
+            patch
+        });
+
+        let melodic = Bank {
+            patches: patches.by_ref().take(OP2_MELODIC).collect(),
+            ..Default::default()
+        };
+        let mut percussion = Bank {
+            patches: vec![Patch::default(); OP2_FIRST_DRUM],
+            ..Default::default()
+        };
+        percussion.patches.extend(patches);
+        percussion.patches.resize(128, Patch::default());
+        Ok(BankSet {
+            melodic: vec![melodic],
+            percussion: vec![percussion],
+        })
+    }
+
+    /// Return the first melodic and percussion banks as a DMX OP2 file. See the module
+    /// documentation for what is lost.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::patch::{Patch, PatchKind};
+    ///
+    /// let mut drums = Bank::new("Drums");
+    /// drums.patches.resize(36, Patch::default());
+    /// drums.patches[35] = Patch {
+    ///     name: "Kick".to_string(),
+    ///     kind: PatchKind::DoubleVoice,
+    ///     fixed_note: Some(24),
+    ///     detune: -4,
+    ///     ..Default::default()
+    /// };
+    /// let set = BankSet { melodic: Vec::new(), percussion: vec![drums] };
+    /// let op2 = BankSet::from_op2(&set.to_op2()).unwrap();
+    /// assert_eq!(op2.percussion[0].patches[35], set.percussion[0].patches[35]);
+    /// ```
+    pub fn to_op2(&self) -> Vec<u8> {
+        let patch = |banks: &[Bank], index: usize| {
+            banks
+                .first()
+                .and_then(|bank| bank.patches.get(index))
+                .cloned()
+                .unwrap_or_default()
+        };
+        let patches: Vec<Patch> = (0..OP2_MELODIC)
+            .map(|i| patch(&self.melodic, i))
+            .chain((0..OP2_PERCUSSION).map(|i| patch(&self.percussion, OP2_FIRST_DRUM + i)))
+            .collect();
+
+        let mut data = OP2_MAGIC.to_vec();
+        for patch in &patches {
+            write_op2_record(&mut data, patch);
+        }
+        for patch in &patches {
+            write_name(&mut data, &patch.name, OP2_NAME_SIZE);
+        }
+        data
+    }
+
+    /// Read the banks of a WOPL file, of any version from 1 to 3.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of the WOPL file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the banks on success or an `OplError` on failure.
+    pub fn from_wopl(data: &[u8]) -> Result<BankSet, OplError> {
+        const ERR: OplError = OplError::InvalidFormat("WOPL");
+        if data.len() < WOPL_HEADER_SIZE || &data[0..11] != WOPL_MAGIC {
+            return Err(ERR);
+        }
+        let version = u16::from_le_bytes([data[11], data[12]]);
+        if !(1..=WOPL_VERSION).contains(&version) {
+            return Err(ERR);
+        }
+        let melodic_count = u16::from_be_bytes([data[13], data[14]]) as usize;
+        let percussion_count = u16::from_be_bytes([data[15], data[16]]) as usize;
+        let record_size = if version >= 3 { 66 } else { 62 };
+
+        let mut offset = WOPL_HEADER_SIZE;
+        let mut take = |len: usize| -> Result<&[u8], OplError> {
+            let slice = data.get(offset..offset + len).ok_or(ERR)?;
+            offset += len;
+            Ok(slice)
+        };
+        let mut banks: Vec<Bank> = Vec::with_capacity(melodic_count + percussion_count);
+        for _ in 0..melodic_count + percussion_count {
+            let mut bank = Bank::default();
+            if version >= 2 {
+                let meta = take(WOPL_BANK_META_SIZE)?;
+                bank.name = read_name(&meta[..NAME_SIZE]);
+                bank.lsb = meta[32];
+                bank.msb = meta[33];
+            }
+            banks.push(bank);
+        }
+        for bank in banks.iter_mut() {
+            for _ in 0..WOPL_PATCHES {
+                bank.patches.push(read_wopl_record(take(record_size)?));
+            }
+        }
+        let percussion = banks.split_off(melodic_count);
+        Ok(BankSet {
+            melodic: banks,
+            percussion,
+        })
+    }
+
+    /// Return the banks as a WOPL version 3 file. See the module documentation for what is lost.
+    pub fn to_wopl(&self) -> Vec<u8> {
+        let mut data = WOPL_MAGIC.to_vec();
+        data.extend_from_slice(&WOPL_VERSION.to_le_bytes());
+        data.extend_from_slice(&(self.melodic.len() as u16).to_be_bytes());
+        data.extend_from_slice(&(self.percussion.len() as u16).to_be_bytes());
+        // No deep tremolo or vibrato, and the generic volume model.
+        data.extend_from_slice(&[0, 0]);
+        let banks = || self.melodic.iter().chain(&self.percussion);
+        for bank in banks() {
+            write_name(&mut data, &bank.name, NAME_SIZE);
+            data.push(bank.lsb);
+            data.push(bank.msb);
+        }
+        for bank in banks() {
+            for i in 0..WOPL_PATCHES {
+                write_wopl_record(&mut data, &bank.patches.get(i).cloned().unwrap_or_default());
+            }
+        }
+        data
+    }
+}
+
+/// Read a NUL-terminated name.
+fn read_name(data: &[u8]) -> String {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
+
+/// Write a name into a NUL-padded field of `size` bytes, truncating it to leave room for the
+/// terminator.
+fn write_name(data: &mut Vec<u8>, name: &str, size: usize) {
+    let mut len = name.len().min(size - 1);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    data.extend_from_slice(&name.as_bytes()[..len]);
+    data.resize(data.len() + size - len, 0);
+}
+
+/// Read the 11 register bytes of an SBI or IBK instrument.
+fn read_sbi_registers(r: &[u8]) -> OperatorPair {
+    OperatorPair {
+        modulator: Operator::new(r[0], r[2], r[4], r[6], r[8]),
+        carrier: Operator::new(r[1], r[3], r[5], r[7], r[9]),
+        feedback_connection: r[10] & 0x0F,
+    }
+}
+
+/// Return the 11 register bytes of an SBI or IBK instrument.
+fn sbi_registers(pair: &OperatorPair) -> [u8; 11] {
+    let (m, c) = (&pair.modulator, &pair.carrier);
+    [
+        m.characteristics,
+        c.characteristics,
+        m.level,
+        c.level,
+        m.attack_decay,
+        c.attack_decay,
+        m.sustain_release,
+        c.sustain_release,
+        m.waveform,
+        c.waveform,
+        pair.feedback_connection & 0x0F,
+    ]
+}
+
+/// Read a 13-byte BNK operator, which stores each register field in its own byte.
+fn read_bnk_operator(r: &[u8], waveform: u8) -> Operator {
+    let bit = |index: usize, shift: u8| (r[index] & 0x01) << shift;
+    Operator {
+        characteristics: bit(9, 7) | bit(10, 6) | bit(5, 5) | bit(11, 4) | r[1] & 0x0F,
+        level: (r[0] & 0x03) << 6 | r[8] & 0x3F,
+        attack_decay: (r[3] & 0x0F) << 4 | r[6] & 0x0F,
+        sustain_release: (r[4] & 0x0F) << 4 | r[7] & 0x0F,
+        waveform,
+    }
+}
+
+/// Return a 13-byte BNK operator.
+fn bnk_operator(op: &Operator, feedback_connection: u8) -> [u8; 13] {
+    let c = op.characteristics;
+    [
+        op.level >> 6,
+        c & 0x0F,
+        (feedback_connection >> 1) & 0x07,
+        op.attack_decay >> 4,
+        op.sustain_release >> 4,
+        (c >> 5) & 0x01,
+        op.attack_decay & 0x0F,
+        op.sustain_release & 0x0F,
+        op.level & 0x3F,
+        c >> 7,
+        (c >> 6) & 0x01,
+        (c >> 4) & 0x01,
+        // BNK stores 1 for FM, the opposite of the connection bit.
+        (feedback_connection & 0x01) ^ 0x01,
+    ]
+}
+
+/// Read the 36-byte record of an OP2 instrument.
+fn read_op2_record(record: &[u8]) -> Patch {
+    let flags = u16::from_le_bytes([record[0], record[1]]);
+    let voice = |v: &[u8]| {
+        let operator = |o: &[u8]| Operator::new(o[0], o[4] & 0xC0 | o[5] & 0x3F, o[1], o[2], o[3]);
+        let pair = OperatorPair {
+            modulator: operator(&v[0..6]),
+            carrier: operator(&v[7..13]),
+            feedback_connection: v[6] & 0x0F,
+        };
+        let offset = i16::from_le_bytes([v[14], v[15]]).clamp(i8::MIN as i16, i8::MAX as i16);
+        (pair, offset as i8)
+    };
+    let (first, first_offset) = voice(&record[4..20]);
+    let (second, second_offset) = voice(&record[20..36]);
+    Patch {
+        name: String::new(),
+        kind: if flags & 0x0004 != 0 {
+            PatchKind::DoubleVoice
+        } else {
+            PatchKind::TwoOp
+        },
+        pairs: [first, second],
+        note_offsets: [first_offset, second_offset],
+        detune: (record[2] as i16 - 128) as i8,
+        fixed_note: (flags & 0x0001 != 0).then_some(record[3]),
+    }
+}
+
+/// Append the 36-byte record of an OP2 instrument.
+fn write_op2_record(data: &mut Vec<u8>, patch: &Patch) {
+    let flags =
+        (patch.fixed_note.is_some() as u16) | ((patch.kind != PatchKind::TwoOp) as u16) << 2;
+    data.extend_from_slice(&flags.to_le_bytes());
+    data.push((patch.detune as i16 + 128) as u8);
+    data.push(patch.fixed_note.unwrap_or(0));
+    for (pair, &offset) in patch.pairs.iter().zip(&patch.note_offsets) {
+        for (i, op) in [&pair.modulator, &pair.carrier].into_iter().enumerate() {
+            data.extend_from_slice(&[
+                op.characteristics,
+                op.attack_decay,
+                op.sustain_release,
+                op.waveform,
+                op.level & 0xC0,
+                op.level & 0x3F,
+            ]);
+            // The feedback byte follows the modulator, and an unused byte the carrier.
+            data.push(if i == 0 {
+                pair.feedback_connection & 0x0F
+            } else {
+                0
+            });
+        }
+        data.extend_from_slice(&(offset as i16).to_le_bytes());
+    }
+}
+
+/// Read a WOPL instrument record. Operators are stored carrier first within each pair.
+fn read_wopl_record(record: &[u8]) -> Patch {
+    let flags = record[39];
+    if flags & 0x04 != 0 {
+        return Patch::default();
+    }
+    let operator = |i: usize| {
+        let o = &record[42 + i * 5..];
+        Operator::new(o[0], o[1], o[2], o[3], o[4])
+    };
+    let note_offset = |i: usize| {
+        i16::from_be_bytes([record[i], record[i + 1]]).clamp(i8::MIN as i16, i8::MAX as i16) as i8
+    };
+    Patch {
+        name: read_name(&record[..NAME_SIZE]),
+        kind: match flags & 0x03 {
+            0 => PatchKind::TwoOp,
+            0x01 => PatchKind::FourOp,
+            _ => PatchKind::DoubleVoice,
+        },
+        pairs: [
+            OperatorPair {
+                modulator: operator(1),
+                carrier: operator(0),
+                feedback_connection: record[40] & 0x0F,
+            },
+            OperatorPair {
+                modulator: operator(3),
+                carrier: operator(2),
+                feedback_connection: record[41] & 0x0F,
+            },
+        ],
+        note_offsets: [note_offset(32), note_offset(34)],
+        detune: record[37] as i8,
+        fixed_note: (record[38] != 0).then_some(record[38]),
+    }
+}
+
+/// Append a WOPL version 3 instrument record.
+fn write_wopl_record(data: &mut Vec<u8>, patch: &Patch) {
+    write_name(data, &patch.name, NAME_SIZE);
+    for offset in patch.note_offsets {
+        data.extend_from_slice(&(offset as i16).to_be_bytes());
+    }
+    let flags = match patch.kind {
+        _ if *patch == Patch::default() => 0x04,
+        PatchKind::TwoOp => 0x00,
+        PatchKind::FourOp => 0x01,
+        PatchKind::DoubleVoice => 0x02,
+    };
+    data.extend_from_slice(&[
+        0,
+        patch.detune as u8,
+        patch.fixed_note.unwrap_or(0),
+        flags,
+        patch.pairs[0].feedback_connection & 0x0F,
+        patch.pairs[1].feedback_connection & 0x0F,
+    ]);
+    for pair in &patch.pairs {
+        for op in [&pair.carrier, &pair.modulator] {
+            data.extend_from_slice(&[
+                op.characteristics,
+                op.level,
+                op.attack_decay,
+                op.sustain_release,
+                op.waveform,
+            ]);
+        }
+    }
+    // No key-on or key-off delays.
+    data.extend_from_slice(&[0; 4]);
+}
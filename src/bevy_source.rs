@@ -0,0 +1,394 @@
+//! Streaming OPL audio sources for the Bevy game engine.
+//!
+//! `OplAudio` is a Bevy asset that plays either a bare `Opl3Chip` or an `OplMidiSynth`, rendered
+//! as it streams, so the music and sound effects of a game can be driven live rather than
+//! prerecorded. Adding `OplAudioPlugin` registers it as an audio source, and it is played like
+//! any other, with an `AudioPlayer` holding its handle.
+//!
+//! Creating an `OplAudio` also returns an `OplCommands` component, which sends register writes
+//! and MIDI messages to every playback of the source. Spawning it on the same entity as the
+//! `AudioPlayer` lets systems find it with a query. Commands are applied at the start of the next
+//! block of 256 frames rendered, about 6ms at 44.1kHz. Each playback starts from a freshly reset
+//! chip or synth. Commands sent while nothing is playing, such as before Bevy has started the
+//! source, are kept for the next playback to start.
+//!
+//! ```ignore
+//! use bevy::prelude::*;
+//! use opl3_rs::bevy_source::{OplAudio, OplAudioPlugin, OplCommands};
+//!
+//! fn setup(mut commands: Commands, mut sources: ResMut<Assets<OplAudio>>) {
+//!     let (audio, controller) = OplAudio::synth(44100, load_banks());
+//!     commands.spawn((AudioPlayer(sources.add(audio)), controller));
+//! }
+//!
+//! fn play_jingle(keys: Res<ButtonInput<KeyCode>>, query: Query<&OplCommands>) {
+//!     if keys.just_pressed(KeyCode::Space) {
+//!         for controller in &query {
+//!             _ = controller.note_on(0, 72, 100);
+//!         }
+//!     }
+//! }
+//!
+//! App::new()
+//!     .add_plugins((DefaultPlugins, OplAudioPlugin))
+//!     .add_systems(Startup, setup)
+//!     .add_systems(Update, play_jingle)
+//!     .run();
+//! ```
+//!
+//! # Example
+//!
+//! ```
+//! use bevy::audio::{Decodable, Source};
+//! use opl3_rs::bevy_source::OplAudio;
+//!
+//! let (audio, controller) = OplAudio::chip(44100);
+//! let mut decoder = audio.decoder();
+//! let mut other = audio.decoder();
+//! assert_eq!((decoder.channels(), decoder.sample_rate()), (2, 44100));
+//! // An endless stream whose format never changes.
+//! assert_eq!(decoder.current_frame_len(), None);
+//!
+//! // Every playback hears every command.
+//! for (register, value) in [(0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+//!     controller.write_register(register, value).unwrap();
+//! }
+//! let samples: Vec<i16> = decoder.by_ref().take(4096).collect();
+//! assert!(samples.iter().any(|&sample| sample != 0));
+//! assert!(other.by_ref().take(4096).eq(samples));
+//! ```
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bevy::audio::{AddAudioSource, Decodable, Source};
+use bevy::prelude::*;
+
+use crate::bank::BankSet;
+use crate::midi_synth::OplMidiSynth;
+use crate::opl_core::OplCore;
+use crate::{Opl3Chip, OplError};
+
+/// The number of frames rendered at a time. Commands are applied between blocks.
+const BLOCK_FRAMES: usize = 256;
+
+/// The `OplCommand` enum is something sent to the playbacks of an `OplAudio`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OplCommand {
+    /// Write a register directly. For a synth, the write may be overwritten by the synth.
+    Write {
+        /// The register to write, in the range 0x000..=0x1FF.
+        register: u16,
+        /// The value to write.
+        value: u8,
+    },
+    /// Handle a MIDI channel message, as `OplMidiSynth::process_message` does. Data bytes a
+    /// message does not use are ignored, and a bare chip ignores MIDI.
+    Midi([u8; 3]),
+    /// Reset the chip or synth to its initial state.
+    Reset,
+}
+
+/// The `OplCommands` component sends commands to the playbacks of an `OplAudio`. It never blocks,
+/// and can be cloned to send from several places.
+#[derive(Component, Clone, Debug)]
+pub struct OplCommands {
+    playbacks: Weak<Mutex<Playbacks>>,
+}
+
+impl OplCommands {
+    /// Send a command to every playback, applied at the start of the next block each renders. If
+    /// nothing is playing, the command is kept for the next playback to start.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the `OplAudio` has been
+    /// dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bevy::audio::Decodable;
+    /// use opl3_rs::bevy_source::{OplAudio, OplCommand};
+    /// use opl3_rs::OplError;
+    ///
+    /// let (audio, controller) = OplAudio::chip(44100);
+    /// // Nothing is playing yet, so the writes wait for the first playback.
+    /// for (register, value) in [(0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+    ///     controller.send(OplCommand::Write { register, value }).unwrap();
+    /// }
+    /// assert!(audio.decoder().take(4096).any(|sample| sample != 0));
+    ///
+    /// drop(audio);
+    /// assert!(matches!(controller.send(OplCommand::Reset), Err(OplError::StreamClosed)));
+    /// ```
+    pub fn send(&self, command: OplCommand) -> Result<(), OplError> {
+        let playbacks = self.playbacks.upgrade().ok_or(OplError::StreamClosed)?;
+        let mut playbacks = playbacks.lock().unwrap_or_else(|e| e.into_inner());
+        // Playbacks that have ended have dropped their receivers, and are forgotten.
+        playbacks.senders.retain(|tx| tx.send(command).is_ok());
+        if playbacks.senders.is_empty() {
+            playbacks.pending.push(command);
+        }
+        Ok(())
+    }
+
+    /// Send a direct register write.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The register to write, in the range 0x000..=0x1FF.
+    /// * `value`    - The value to write.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the `OplAudio` has been
+    /// dropped.
+    pub fn write_register(&self, register: u16, value: u8) -> Result<(), OplError> {
+        self.send(OplCommand::Write { register, value })
+    }
+
+    /// Send a note on to a synth.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel`  - The MIDI channel, from 0 to 15.
+    /// * `note`     - The note, from 0 to 127.
+    /// * `velocity` - The velocity, from 0 to 127.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the `OplAudio` has been
+    /// dropped.
+    pub fn note_on(&self, channel: u8, note: u8, velocity: u8) -> Result<(), OplError> {
+        self.send(OplCommand::Midi([0x90 | channel & 0x0F, note, velocity]))
+    }
+
+    /// Send a note off to a synth.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The MIDI channel, from 0 to 15.
+    /// * `note`    - The note, from 0 to 127.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the `OplAudio` has been
+    /// dropped.
+    pub fn note_off(&self, channel: u8, note: u8) -> Result<(), OplError> {
+        self.send(OplCommand::Midi([0x80 | channel & 0x0F, note, 0]))
+    }
+
+    /// Send a program change to a synth.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The MIDI channel, from 0 to 15.
+    /// * `program` - The program, from 0 to 127.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the `OplAudio` has been
+    /// dropped.
+    pub fn program_change(&self, channel: u8, program: u8) -> Result<(), OplError> {
+        self.send(OplCommand::Midi([0xC0 | channel & 0x0F, program, 0]))
+    }
+}
+
+/// What an `OplAudio` plays.
+#[derive(Clone, Debug)]
+enum Instrument {
+    Chip,
+    Synth(BankSet),
+}
+
+/// The playbacks of an `OplAudio` that commands are sent to.
+#[derive(Default)]
+struct Playbacks {
+    /// A sender to each playback started.
+    senders: Vec<Sender<OplCommand>>,
+    /// The commands sent while nothing was playing, for the next playback to start.
+    pending: Vec<OplCommand>,
+}
+
+/// The `OplAudio` struct is a Bevy audio source asset that renders a chip or synth as it plays.
+#[derive(Asset, TypePath)]
+pub struct OplAudio {
+    sample_rate: u32,
+    instrument: Instrument,
+    playbacks: Arc<Mutex<Playbacks>>,
+}
+
+impl OplAudio {
+    /// Create a source playing a bare chip, driven by register writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to render at.
+    ///
+    /// # Returns
+    ///
+    /// The source, and the component to send it commands with.
+    pub fn chip(sample_rate: u32) -> (Self, OplCommands) {
+        Self::new(sample_rate, Instrument::Chip)
+    }
+
+    /// Create a source playing a MIDI synth.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to render at.
+    /// * `banks`       - The instruments to play.
+    ///
+    /// # Returns
+    ///
+    /// The source, and the component to send it commands with.
+    pub fn synth(sample_rate: u32, banks: BankSet) -> (Self, OplCommands) {
+        Self::new(sample_rate, Instrument::Synth(banks))
+    }
+
+    fn new(sample_rate: u32, instrument: Instrument) -> (Self, OplCommands) {
+        let playbacks = Arc::new(Mutex::new(Playbacks::default()));
+        let commands = OplCommands {
+            playbacks: Arc::downgrade(&playbacks),
+        };
+        let audio = OplAudio {
+            sample_rate,
+            instrument,
+            playbacks,
+        };
+        (audio, commands)
+    }
+}
+
+impl Decodable for OplAudio {
+    type DecoderItem = i16;
+    type Decoder = OplDecoder;
+
+    fn decoder(&self) -> OplDecoder {
+        let core = match &self.instrument {
+            Instrument::Chip => DecoderCore::Chip(Opl3Chip::new(self.sample_rate)),
+            Instrument::Synth(banks) => {
+                DecoderCore::Synth(Box::new(OplMidiSynth::new(self.sample_rate, banks.clone())))
+            }
+        };
+        let (tx, commands) = mpsc::channel();
+        // A poisoned lock only means a sender panicked, and the playbacks are still usable.
+        let mut playbacks = self.playbacks.lock().unwrap_or_else(|e| e.into_inner());
+        for command in playbacks.pending.drain(..) {
+            _ = tx.send(command);
+        }
+        playbacks.senders.push(tx);
+        OplDecoder {
+            core,
+            sample_rate: self.sample_rate,
+            commands,
+            block: vec![0; BLOCK_FRAMES * 2],
+            position: BLOCK_FRAMES * 2,
+        }
+    }
+}
+
+/// The core a decoder renders.
+enum DecoderCore {
+    Chip(Opl3Chip),
+    Synth(Box<OplMidiSynth>),
+}
+
+impl DecoderCore {
+    fn core(&mut self) -> &mut dyn OplCore {
+        match self {
+            DecoderCore::Chip(chip) => chip,
+            DecoderCore::Synth(synth) => synth.as_mut(),
+        }
+    }
+
+    fn apply(&mut self, command: OplCommand) {
+        match (self, command) {
+            (core, OplCommand::Write { register, value }) => {
+                core.core().write_register(register, value)
+            }
+            (DecoderCore::Synth(synth), OplCommand::Midi(message)) => {
+                synth.process_message(&message)
+            }
+            (DecoderCore::Chip(_), OplCommand::Midi(_)) => {}
+            (core, OplCommand::Reset) => {
+                let core = core.core();
+                let sample_rate = core.sample_rate();
+                core.reset(sample_rate);
+            }
+        }
+    }
+}
+
+/// The `OplDecoder` struct is the stream of interleaved stereo samples of one playback of an
+/// `OplAudio`. It never ends.
+pub struct OplDecoder {
+    core: DecoderCore,
+    sample_rate: u32,
+    commands: Receiver<OplCommand>,
+    block: Vec<i16>,
+    /// The index of the next sample of `block` to return.
+    position: usize,
+}
+
+impl OplDecoder {
+    /// Apply the commands received since the last block, and render the next.
+    fn refill(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            self.core.apply(command);
+        }
+        if self.core.core().generate_stream(&mut self.block).is_err() {
+            self.block.fill(0);
+        }
+        self.position = 0;
+    }
+}
+
+impl Iterator for OplDecoder {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.position == self.block.len() {
+            self.refill();
+        }
+        let sample = self.block[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+/// The channels and sample rate never change, so the stream is one endless frame.
+impl Source for OplDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The `OplAudioPlugin` struct is a Bevy plugin registering `OplAudio` as an audio source. It
+/// must be added after Bevy's `AudioPlugin`, which `DefaultPlugins` includes.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OplAudioPlugin;
+
+impl Plugin for OplAudioPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_audio_source::<OplAudio>();
+    }
+}
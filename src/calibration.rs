@@ -0,0 +1,141 @@
+//! Test tones and pitch measurement, for checking an audio path end to end.
+//!
+//! An application that integrates the chip has several places to get wrong: the sample rate the
+//! chip is created with, the resampler, the device's buffer format, and the mapping of the chip's
+//! left and right outputs to speakers. `test_tone` renders a pure tone of a known frequency, and
+//! `measure_frequency` measures the frequency of a recording. Playing a test tone through the
+//! application and measuring what comes out the other end quickly shows whether the pitch and
+//! channels survive the trip.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::calibration::{measure_frequency, test_tone};
+//!
+//! let tone = test_tone(48000, 0, 1000.0, 0.25);
+//! assert_eq!(tone.len(), 12000 * 2);
+//! let left = measure_frequency(&tone, 48000, 0).unwrap();
+//! assert!((left - 1000.0).abs() < 1.0);
+//! let right = measure_frequency(&tone, 48000, 1).unwrap();
+//! assert!((right - 1000.0).abs() < 1.0);
+//! ```
+
+use crate::patch::hz_to_fnum;
+use crate::registers::MODE_REGISTER;
+use crate::Opl3Chip;
+
+/// The share of a recording's peak level that it must fall below between two rising zero
+/// crossings, so that noise around zero is not counted as extra cycles.
+const HYSTERESIS: f64 = 0.1;
+
+/// Render a test tone: one operator of a new chip, playing a waveform at a constant level on
+/// both outputs. The tone starts at full level, with no attack, and does not decay.
+///
+/// # Arguments
+///
+/// * `sample_rate` - The sample rate to render at.
+/// * `waveform`    - The operator waveform, from 0 to 7, as written to registers 0xE0 to 0xF5.
+///                   0 is a sine.
+/// * `hz`          - The frequency of the tone, up to about 6.2kHz.
+/// * `seconds`     - The length of the tone.
+///
+/// # Returns
+///
+/// The tone, as 2 channel interleaved samples.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::calibration::test_tone;
+///
+/// // A square wave at A4, for half a second.
+/// let tone = test_tone(44100, 6, 440.0, 0.5);
+/// assert!(tone.iter().any(|&sample| sample > 2000));
+/// ```
+pub fn test_tone(sample_rate: u32, waveform: u8, hz: f64, seconds: f64) -> Vec<i16> {
+    let mut chip = Opl3Chip::new(sample_rate);
+    let (fnum, block) = hz_to_fnum(hz);
+    // OPL3 mode enables all eight waveforms. Channel 0 is in additive mode with its modulator
+    // silent, so the carrier is heard alone.
+    chip.write_register(MODE_REGISTER, 0x01);
+    chip.write_register(0x20, 0x01);
+    chip.write_register(0x40, 0x3F);
+    chip.write_register(0x23, 0x21);
+    chip.write_register(0x43, 0x00);
+    chip.write_register(0x63, 0xF0);
+    chip.write_register(0x83, 0x00);
+    chip.write_register(0xE3, waveform & 0x07);
+    chip.write_register(0xC0, 0x31);
+    chip.write_register(0xA0, fnum as u8);
+    chip.write_register(0xB0, 0x20 | block << 2 | (fnum >> 8) as u8);
+    chip.render((sample_rate as f64 * seconds.max(0.0)).round() as usize)
+}
+
+/// Measure the frequency of a periodic signal in one channel of a recording, from the times at
+/// which it crosses its mean level going up. This suits tones that cross their mean once per
+/// cycle, such as the sine and square waveforms 0 and 6, and the output of a chip playing one note
+/// with little modulation.
+///
+/// # Arguments
+///
+/// * `audio`       - The recording, as 2 channel interleaved samples.
+/// * `sample_rate` - The sample rate of the recording.
+/// * `channel`     - The channel to measure: 0 for left, 1 for right.
+///
+/// # Returns
+///
+/// The frequency in Hz, or `None` if the channel is silent or shorter than one cycle.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::calibration::{measure_frequency, test_tone};
+///
+/// let tone = test_tone(44100, 0, 220.0, 0.5);
+/// assert!((measure_frequency(&tone, 44100, 0).unwrap() - 220.0).abs() < 0.5);
+/// assert_eq!(measure_frequency(&[0; 1000], 44100, 0), None);
+/// ```
+pub fn measure_frequency(audio: &[i16], sample_rate: u32, channel: usize) -> Option<f64> {
+    let samples = || {
+        audio
+            .iter()
+            .skip(channel)
+            .step_by(2)
+            .map(|&sample| sample as f64)
+    };
+    let count = samples().count();
+    if count == 0 {
+        return None;
+    }
+    let mean = samples().sum::<f64>() / count as f64;
+    let peak = samples()
+        .map(|sample| (sample - mean).abs())
+        .fold(0.0, f64::max);
+    if peak == 0.0 {
+        return None;
+    }
+
+    let threshold = peak * HYSTERESIS;
+    let mut armed = false;
+    let mut previous = 0.0;
+    let mut first = None;
+    let mut last = 0.0;
+    let mut cycles = 0;
+    for (index, sample) in samples().map(|sample| sample - mean).enumerate() {
+        if sample < -threshold {
+            armed = true;
+        } else if armed && sample >= 0.0 && previous < 0.0 {
+            // Interpolate the time of the crossing between the two samples.
+            let time = index as f64 - sample / (sample - previous);
+            match first {
+                None => first = Some(time),
+                Some(_) => cycles += 1,
+            }
+            last = time;
+            armed = false;
+        }
+        previous = sample;
+    }
+    let first = first?;
+    (cycles > 0).then(|| cycles as f64 * sample_rate as f64 / (last - first))
+}
@@ -0,0 +1,287 @@
+//! Choosing chip channels for new notes, independent of MIDI.
+//!
+//! An OPL3 has 18 channels, of which six pairs can be joined into 4-operator channels. Anything
+//! playing more notes than that, such as a tracker engine or a game's sound effects, has to pick
+//! a channel for every new note, and steal one when they are all busy. `ChannelAllocator` keeps
+//! track of which channels are free, which hold notes, and which hold notes that have been
+//! released and are fading out, and picks channels the way `midi_synth::OplMidiSynth` does.
+//!
+//! Free channels are picked first, then released ones, then held ones with the lowest priority,
+//! oldest first. 4-operator notes take the first channel of a pair, and stop whatever was playing
+//! on either channel of the pair.
+//!
+//! The allocator only does the bookkeeping; it never writes to a chip. When a claim steals a
+//! channel, the channels of the notes it stopped are returned, so that the caller can key them
+//! off, and register 0x104 should be written with `four_op_mask` after claims and frees.
+//! Channels can span several chips: channel `n` is channel `n % 18` of chip `n / 18`.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::channel_allocator::{ChannelAllocator, ChannelState};
+//!
+//! let mut allocator = ChannelAllocator::new(1);
+//! let lead = allocator.claim(true, 10).unwrap();
+//! assert_eq!(lead.channel, 0);
+//! assert_eq!(allocator.four_op_mask(0), 0x01);
+//!
+//! // Fill the other 16 channels with low priority notes and release one of them.
+//! let notes: Vec<usize> = (0..16).map(|_| allocator.claim(false, 1).unwrap().channel).collect();
+//! allocator.release(notes[5]);
+//!
+//! // The released channel is stolen first, then the oldest note with the lowest priority. The
+//! // 4-operator lead is never stolen by a lower priority note.
+//! assert_eq!(allocator.claim(false, 1).unwrap().evicted, [Some(notes[5]), None]);
+//! assert_eq!(allocator.claim(false, 1).unwrap().evicted, [Some(notes[0]), None]);
+//! assert_eq!(allocator.state(lead.channel), ChannelState::Held(10));
+//! ```
+
+#![cfg_attr(feature = "fixed-point", deny(clippy::float_arithmetic))]
+
+/// The number of channels of each chip.
+pub const CHANNELS_PER_CHIP: usize = 18;
+
+/// The channels that can be the first of a 4-operator pair, in the order of the bits of register
+/// 0x104. The second channel of each pair is three channels later.
+pub const FOUR_OP_CHANNELS: [usize; 6] = [0, 1, 2, 9, 10, 11];
+
+/// The `ChannelState` enum is the state of a channel, as far as stealing it is concerned.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChannelState {
+    /// The channel plays nothing.
+    #[default]
+    Free,
+    /// The channel's note has been released, and may still be fading out.
+    Released,
+    /// The channel holds a note, with a priority. Notes with a higher priority are stolen last.
+    Held(u8),
+}
+
+impl ChannelState {
+    /// Return the order in which channels in this state are stolen, lowest first.
+    fn rank(self) -> u16 {
+        match self {
+            ChannelState::Free => 0,
+            ChannelState::Released => 1,
+            ChannelState::Held(priority) => 2 + priority as u16,
+        }
+    }
+}
+
+/// The `Allocation` struct is the result of a claim.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// The channel claimed. For 4-operator notes, this is the first channel of the pair.
+    pub channel: usize,
+    /// The channels of the notes that were stopped to make room, each the first channel of its
+    /// note. Released notes are included, as they may still be sounding.
+    pub evicted: [Option<usize>; 2],
+}
+
+/// The bookkeeping for one channel.
+#[derive(Copy, Clone, Debug, Default)]
+struct Slot {
+    state: ChannelState,
+    /// For the second channel of a 4-operator pair, the first channel.
+    partner_of: Option<usize>,
+    four_op: bool,
+    /// The time of the last claim or release, counted in notes.
+    age: u64,
+}
+
+/// The `ChannelAllocator` struct picks channels for new notes on one or more chips, stealing
+/// channels when none are free.
+#[derive(Clone, Debug)]
+pub struct ChannelAllocator {
+    slots: Vec<Slot>,
+    clock: u64,
+}
+
+impl ChannelAllocator {
+    /// Create a new `ChannelAllocator` with every channel free.
+    ///
+    /// # Arguments
+    ///
+    /// * `chips` - The number of chips, with `CHANNELS_PER_CHIP` channels each.
+    pub fn new(chips: usize) -> Self {
+        ChannelAllocator {
+            slots: vec![Slot::default(); chips * CHANNELS_PER_CHIP],
+            clock: 0,
+        }
+    }
+
+    /// Return the number of channels, on all chips.
+    pub fn channels(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Free every channel.
+    pub fn reset(&mut self) {
+        self.slots.fill(Slot::default());
+        self.clock = 0;
+    }
+
+    /// Claim a channel for a new note, stealing one if none are free.
+    ///
+    /// # Arguments
+    ///
+    /// * `four_op`  - Whether the note needs a 4-operator channel.
+    /// * `priority` - The priority of the note. Held notes with a lower priority are stolen first.
+    ///
+    /// # Returns
+    ///
+    /// The channel claimed and the notes stopped to make room, or `None` if there are no
+    /// channels.
+    pub fn claim(&mut self, four_op: bool, priority: u8) -> Option<Allocation> {
+        let channel = self.candidate(four_op)?;
+        let mut evicted = [None; 2];
+        evicted[0] = self.evict(channel);
+        if four_op {
+            evicted[1] = self.evict(channel + 3);
+            self.slots[channel + 3] = Slot {
+                partner_of: Some(channel),
+                ..Slot::default()
+            };
+        }
+        self.slots[channel] = Slot {
+            state: ChannelState::Held(priority),
+            partner_of: None,
+            four_op,
+            age: self.clock,
+        };
+        self.clock += 1;
+        Some(Allocation { channel, evicted })
+    }
+
+    /// Return the channel `claim` would pick for a new note, without claiming it.
+    ///
+    /// # Arguments
+    ///
+    /// * `four_op` - Whether the note needs a 4-operator channel.
+    pub fn candidate(&self, four_op: bool) -> Option<usize> {
+        if four_op {
+            (0..self.slots.len() / CHANNELS_PER_CHIP)
+                .flat_map(|chip| FOUR_OP_CHANNELS.map(|channel| chip * CHANNELS_PER_CHIP + channel))
+                .min_by_key(|&first| self.cost(first).max(self.cost(first + 3)))
+        } else {
+            (0..self.slots.len()).min_by_key(|&index| self.cost(index))
+        }
+    }
+
+    /// Mark a channel's note as released, so that it is stolen before held notes. Releasing a
+    /// channel that is not held does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, or either channel of a 4-operator pair.
+    pub fn release(&mut self, channel: usize) {
+        let Some(owner) = self.owner(channel) else {
+            return;
+        };
+        let slot = &mut self.slots[owner];
+        if let ChannelState::Held(_) = slot.state {
+            slot.state = ChannelState::Released;
+            slot.age = self.clock;
+            self.clock += 1;
+        }
+    }
+
+    /// Change the priority of a held note, such as when a note released under a sustain pedal
+    /// should be stolen before notes still held. Its age is kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel`  - The channel, or either channel of a 4-operator pair.
+    /// * `priority` - The new priority.
+    pub fn set_priority(&mut self, channel: usize, priority: u8) {
+        let Some(owner) = self.owner(channel) else {
+            return;
+        };
+        let slot = &mut self.slots[owner];
+        if let ChannelState::Held(_) = slot.state {
+            slot.state = ChannelState::Held(priority);
+        }
+    }
+
+    /// Free a channel once its note has stopped, including the rest of its 4-operator pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, or either channel of a 4-operator pair.
+    pub fn free(&mut self, channel: usize) {
+        if let Some(owner) = self.owner(channel) {
+            self.evict(owner);
+        }
+    }
+
+    /// Return the state of a channel. Both channels of a 4-operator pair share the state of the
+    /// note, and channels out of range are free.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel.
+    pub fn state(&self, channel: usize) -> ChannelState {
+        self.owner(channel)
+            .map_or(ChannelState::Free, |owner| self.slots[owner].state)
+    }
+
+    /// Return the first channel of the note playing on a channel: the channel itself, unless it
+    /// is the second channel of a 4-operator pair. Returns `None` for channels out of range.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel.
+    pub fn owner(&self, channel: usize) -> Option<usize> {
+        let slot = self.slots.get(channel)?;
+        Some(slot.partner_of.unwrap_or(channel))
+    }
+
+    /// Return `true` if a channel is part of a claimed 4-operator pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, or either channel of a 4-operator pair.
+    pub fn is_four_op(&self, channel: usize) -> bool {
+        self.owner(channel)
+            .is_some_and(|owner| self.slots[owner].four_op)
+    }
+
+    /// Return the value of register 0x104 for a chip, enabling 4-operator mode for the pairs
+    /// claimed by 4-operator notes.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip` - The chip.
+    pub fn four_op_mask(&self, chip: usize) -> u8 {
+        FOUR_OP_CHANNELS
+            .iter()
+            .enumerate()
+            .filter(|&(_, &channel)| {
+                self.slots
+                    .get(chip * CHANNELS_PER_CHIP + channel)
+                    .is_some_and(|slot| slot.four_op)
+            })
+            .fold(0, |mask, (bit, _)| mask | 1 << bit)
+    }
+
+    /// Return the order in which a channel is stolen, lowest first.
+    fn cost(&self, channel: usize) -> (u16, u64) {
+        let slot = &self.slots[channel];
+        let owner = &self.slots[slot.partner_of.unwrap_or(channel)];
+        match owner.state {
+            ChannelState::Free => (0, 0),
+            state => (state.rank(), owner.age),
+        }
+    }
+
+    /// Free the note playing on a channel, returning its first channel if there was one.
+    fn evict(&mut self, channel: usize) -> Option<usize> {
+        let owner = self.owner(channel)?;
+        let slot = self.slots[owner];
+        if slot.four_op {
+            self.slots[owner + 3] = Slot::default();
+        }
+        self.slots[owner] = Slot::default();
+        (slot.state != ChannelState::Free).then_some(owner)
+    }
+}
@@ -0,0 +1,187 @@
+//! Converting host timestamps to sample positions, for scheduling live events.
+//!
+//! Live MIDI input and emulated CPU writes arrive stamped with the host's clock, but take effect
+//! when the renderer reaches a position in the audio stream. `Clock` maps one onto the other: the
+//! audio thread calls `Clock::sync` once per block with the number of frames rendered so far and
+//! the current host time, and `Clock::sample_at` then converts any host timestamp to a frame
+//! position. Those positions can be used directly as the times of a `queue::CommandQueue`, with
+//! the renderer calling `Consumer::apply_until` with its own frame count.
+//!
+//! The sound card's clock is never exactly the nominal sample rate, and drifts against the
+//! host's clock, so `Clock` measures the actual rate from the times of the syncs. It also smooths
+//! out the jitter in when blocks are rendered, so that events keep their spacing. A latency is
+//! added to every position, so that an event stamped while a block is rendering lands in a later
+//! block rather than being applied late.
+//!
+//! `Clock` is `Copy`, so the audio thread can hand copies to an input thread, such as through a
+//! `triple_buffer::Publisher`. It reads the host time from a `time_source::WallClock`, or from
+//! any other `TimeSource` it is created with, such as a `time_source::ManualTime` in tests.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::clock::Clock;
+//!
+//! let mut clock = Clock::new(48000);
+//! clock.set_latency(0);
+//! // The sound card runs 0.1% fast: every host second, 48048 frames are rendered.
+//! for second in 0..=120u64 {
+//!     clock.sync(second * 48048, second * 1_000_000);
+//! }
+//! assert!((clock.measured_rate() - 48048.0).abs() < 1.0);
+//! // Half a second after the last sync.
+//! let position = clock.sample_at(120_500_000);
+//! assert!(position.abs_diff(120 * 48048 + 24024) < 10);
+//! ```
+
+use std::time::Instant;
+
+use crate::time_source::{TimeSource, WallClock};
+
+/// The share of each timing error corrected in the position of the latest sync.
+const POSITION_GAIN: f64 = 0.1;
+/// The share of each timing error, per frame since the previous sync, corrected in the rate.
+const RATE_GAIN: f64 = 0.05;
+/// The largest difference between the measured and nominal rates, as a fraction of the rate.
+const MAX_DRIFT: f64 = 0.01;
+/// The timing error, in microseconds, at which the clock gives up smoothing and jumps to the
+/// measured time, such as after the audio stream was stalled.
+const RESYNC_USEC: f64 = 100_000.0;
+
+/// The `Clock` struct converts host timestamps, in microseconds or as `Instant`s, to frame
+/// positions in a rendered audio stream.
+#[derive(Copy, Clone, Debug)]
+pub struct Clock<T = WallClock> {
+    sample_rate: u32,
+    /// The source of the host time read by `sync_now`.
+    time: T,
+    /// The frame position of the latest sync, or `None` before the first.
+    anchor_frames: Option<u64>,
+    /// The smoothed host time of the latest sync, in microseconds.
+    anchor_usec: f64,
+    /// The measured length of a frame, in microseconds.
+    frame_usec: f64,
+    latency: u64,
+}
+
+impl Clock {
+    /// Create a new `Clock` with no syncs yet. Host time 0 is the moment the clock is created.
+    /// The latency defaults to 10ms.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The nominal sample rate of the audio stream.
+    pub fn new(sample_rate: u32) -> Self {
+        Clock::with_time_source(sample_rate, WallClock::new())
+    }
+
+    /// Return the host time of an `Instant`, in microseconds since the clock was created.
+    /// Instants before that are at time 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `instant` - The instant to convert.
+    pub fn host_usec(&self, instant: Instant) -> u64 {
+        instant
+            .saturating_duration_since(self.time.origin())
+            .as_micros() as u64
+    }
+}
+
+impl<T: TimeSource> Clock<T> {
+    /// Create a new `Clock` with no syncs yet, reading the host time from a time source. Host
+    /// times are the times of the source, in microseconds. The latency defaults to 10ms.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The nominal sample rate of the audio stream.
+    /// * `time`        - The source of the host time read by `sync_now`.
+    pub fn with_time_source(sample_rate: u32, time: T) -> Self {
+        let sample_rate = sample_rate.max(1);
+        Clock {
+            sample_rate,
+            time,
+            anchor_frames: None,
+            anchor_usec: 0.0,
+            frame_usec: 1_000_000.0 / sample_rate as f64,
+            latency: sample_rate as u64 / 100,
+        }
+    }
+
+    /// Return the nominal sample rate.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Return the sample rate measured against the host clock, in Hz.
+    pub fn measured_rate(&self) -> f64 {
+        1_000_000.0 / self.frame_usec
+    }
+
+    /// Set the latency added to every position returned by `sample_at`. It should be at least
+    /// the length of one block, so that events are never scheduled in a block already rendered.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The latency, in frames.
+    pub fn set_latency(&mut self, frames: u64) {
+        self.latency = frames;
+    }
+
+    /// Return the latency added to every position, in frames.
+    pub fn latency(&self) -> u64 {
+        self.latency
+    }
+
+    /// Record that the renderer has reached a frame position at a host time. Call this from the
+    /// audio thread once per block, with the same point of each block, such as its start.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames`    - The number of frames rendered so far.
+    /// * `host_usec` - The host time, in microseconds.
+    pub fn sync(&mut self, frames: u64, host_usec: u64) {
+        let host_usec = host_usec as f64;
+        let Some(anchor_frames) = self.anchor_frames else {
+            self.anchor_frames = Some(frames);
+            self.anchor_usec = host_usec;
+            return;
+        };
+        let elapsed = frames.saturating_sub(anchor_frames) as f64;
+        let predicted = self.anchor_usec + elapsed * self.frame_usec;
+        let error = host_usec - predicted;
+        if error.abs() > RESYNC_USEC || frames < anchor_frames {
+            self.anchor_usec = host_usec;
+        } else {
+            self.anchor_usec = predicted + error * POSITION_GAIN;
+            if elapsed > 0.0 {
+                let nominal = 1_000_000.0 / self.sample_rate as f64;
+                self.frame_usec = (self.frame_usec + error / elapsed * RATE_GAIN)
+                    .clamp(nominal * (1.0 - MAX_DRIFT), nominal * (1.0 + MAX_DRIFT));
+            }
+        }
+        self.anchor_frames = Some(frames);
+    }
+
+    /// Record that the renderer has reached a frame position now, as read from the time source.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The number of frames rendered so far.
+    pub fn sync_now(&mut self, frames: u64) {
+        let host_usec = self.time.now().as_micros() as u64;
+        self.sync(frames, host_usec);
+    }
+
+    /// Return the frame position at which an event stamped at a host time should take effect,
+    /// including the latency. Before the first sync, host time 0 is taken to be frame 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `host_usec` - The host time of the event, in microseconds.
+    pub fn sample_at(&self, host_usec: u64) -> u64 {
+        let anchor_frames = self.anchor_frames.unwrap_or(0) as f64;
+        let frames = anchor_frames + (host_usec as f64 - self.anchor_usec) / self.frame_usec;
+        frames.round().max(0.0) as u64 + self.latency
+    }
+}
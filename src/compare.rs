@@ -0,0 +1,114 @@
+//! Rendering the same register script on two cores and measuring how their output differs.
+//!
+//! This is used to validate new backends against Nuked-OPL3, and to document how far an
+//! approximate core strays from it. Both cores must generate audio at the same sample rate.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::compare::compare_cores;
+//! use opl3_rs::event_log::{EventLog, OplEvent};
+//!
+//! let mut log = EventLog::new(49716);
+//! for (register, value) in [(0x23, 0x01), (0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+//!     log.push(OplEvent { time: 0, register, value });
+//! }
+//!
+//! // Two identical cores never diverge.
+//! let same = compare_cores(&mut Opl3Chip::new(44100), &mut Opl3Chip::new(44100), &log, 100)
+//!     .unwrap();
+//! assert_eq!(same.first_divergence, None);
+//!
+//! // A chip on a faster clock drifts out of tune.
+//! let mut fast = Opl3Chip::new(44100);
+//! fast.set_master_clock(14_500_000);
+//! let report = compare_cores(&mut Opl3Chip::new(44100), &mut fast, &log, 100).unwrap();
+//! assert!(report.first_divergence.is_some());
+//! assert!(report.max_error > 0);
+//! ```
+
+use crate::event_log::EventLog;
+use crate::export::render_event_log_with;
+use crate::opl_core::OplCore;
+use crate::OplError;
+
+/// The `CoreComparison` struct reports how the output of two cores differs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CoreComparison {
+    /// The number of interleaved samples compared.
+    pub samples: usize,
+    /// The index of the first interleaved sample that differs, or `None` if the outputs are
+    /// identical.
+    pub first_divergence: Option<usize>,
+    /// The largest absolute difference between two samples.
+    pub max_error: u16,
+    /// The index of the interleaved sample with the largest difference.
+    pub max_error_index: usize,
+    /// The root mean square of the differences.
+    pub rms_error: f64,
+}
+
+impl CoreComparison {
+    /// Compare two buffers of interleaved samples. Samples beyond the end of the shorter buffer
+    /// are not compared.
+    ///
+    /// # Arguments
+    ///
+    /// * `a` - The reference output.
+    /// * `b` - The output to compare against the reference.
+    pub fn of(a: &[i16], b: &[i16]) -> Self {
+        let mut report = CoreComparison {
+            samples: a.len().min(b.len()),
+            first_divergence: None,
+            max_error: 0,
+            max_error_index: 0,
+            rms_error: 0.0,
+        };
+        let mut squares = 0.0;
+        for (index, (&a, &b)) in a.iter().zip(b).enumerate() {
+            let error = a.abs_diff(b);
+            if error == 0 {
+                continue;
+            }
+            report.first_divergence.get_or_insert(index);
+            if error > report.max_error {
+                report.max_error = error;
+                report.max_error_index = index;
+            }
+            squares += error as f64 * error as f64;
+        }
+        if report.samples > 0 {
+            report.rms_error = (squares / report.samples as f64).sqrt();
+        }
+        report
+    }
+}
+
+/// Render an event log on two cores from their current state, and compare the output.
+///
+/// # Arguments
+///
+/// * `a`       - The reference core.
+/// * `b`       - The core to compare against the reference.
+/// * `log`     - The register script to render.
+/// * `tail_ms` - The number of milliseconds to render after the last event.
+///
+/// # Returns
+///
+/// A Result containing either the comparison on success, or an `OplError` on failure. The error
+/// is `OplError::BufferMismatch` if the cores generate audio at different sample rates.
+pub fn compare_cores(
+    a: &mut dyn OplCore,
+    b: &mut dyn OplCore,
+    log: &EventLog,
+    tail_ms: u32,
+) -> Result<CoreComparison, OplError> {
+    if a.sample_rate() != b.sample_rate() {
+        return Err(OplError::BufferMismatch);
+    }
+    let (mut out_a, mut out_b) = (Vec::new(), Vec::new());
+    render_event_log_with(a, log, tail_ms, &mut out_a)?;
+    render_event_log_with(b, log, tail_ms, &mut out_b)?;
+    Ok(CoreComparison::of(&out_a, &out_b))
+}
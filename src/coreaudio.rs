@@ -0,0 +1,259 @@
+//! A pull-model render callback shaped for CoreAudio and AUv3 hosts on iOS and macOS.
+//!
+//! CoreAudio pulls audio from an Audio Unit by calling its render block with a timestamp, a
+//! frame count that can change from one call to the next, and the buffers to fill, which are
+//! usually one per channel. Events for the render cycle, such as parameter changes, are timed by
+//! absolute sample time, or relative to `AUEventSampleTimeImmediate` for events to play as soon
+//! as possible. `OplRenderCallback` follows the same contract around any `OplCore`, so the render
+//! block of an `AUAudioUnit` subclass only has to translate its arguments and call `render`.
+//!
+//! Rendering never allocates or locks, so it is safe on the real-time render thread. Events are
+//! played before the sample they are timed at, and the timeline is tracked from one render call
+//! to the next, so that jumps in the host's sample time, such as when a transport loops or the
+//! render graph is restarted, are counted by `discontinuities`.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::coreaudio::{
+//!     OplRenderCallback, RenderEvent, RenderOutput, RenderTimestamp, SAMPLE_TIME_IMMEDIATE,
+//! };
+//! use opl3_rs::Opl3Chip;
+//!
+//! let mut callback = OplRenderCallback::new(Opl3Chip::new(48000));
+//! let (mut left, mut right) = (vec![0.0f32; 1024], vec![0.0f32; 1024]);
+//!
+//! // The first cycle sets up a note, and the second keys it on at sample time 700.
+//! let setup = [(0x63, 0xF0), (0xA0, 0x98)].map(|(register, value)| RenderEvent {
+//!     sample_time: SAMPLE_TIME_IMMEDIATE,
+//!     register,
+//!     value,
+//! });
+//! let timestamp = RenderTimestamp { sample_time: 0.0, host_time: 0 };
+//! let output = RenderOutput::Planar(&mut left, &mut right);
+//! callback.render(&timestamp, 512, output, &setup).unwrap();
+//!
+//! let key_on = [RenderEvent { sample_time: 700, register: 0xB0, value: 0x31 }];
+//! let timestamp = RenderTimestamp { sample_time: 512.0, host_time: 1000 };
+//! let output = RenderOutput::Planar(&mut left, &mut right);
+//! callback.render(&timestamp, 300, output, &key_on).unwrap();
+//! assert!(left[..188].iter().all(|&sample| sample == 0.0));
+//! assert!(left[188..300].iter().any(|&sample| sample != 0.0));
+//! assert_eq!(callback.next_sample_time(), Some(812.0));
+//! assert_eq!(callback.discontinuities(), 0);
+//!
+//! // The host loops back to the start.
+//! let timestamp = RenderTimestamp { sample_time: 0.0, host_time: 2000 };
+//! let mut interleaved = vec![0.0f32; 256 * 2];
+//! callback.render(&timestamp, 256, RenderOutput::Interleaved(&mut interleaved), &[]).unwrap();
+//! assert_eq!(callback.discontinuities(), 1);
+//! ```
+
+use crate::opl_core::OplCore;
+use crate::OplError;
+
+/// The sample time of events to play at the start of the next render cycle, as
+/// `AUEventSampleTimeImmediate`. Adding a number of frames to it plays the event that far into
+/// the cycle.
+pub const SAMPLE_TIME_IMMEDIATE: i64 = -0x1_0000_0000;
+
+/// The number of frames rendered at a time, into a buffer on the stack.
+const BLOCK_FRAMES: usize = 256;
+
+/// The `RenderTimestamp` struct holds the fields of a CoreAudio `AudioTimeStamp` a render call
+/// uses.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RenderTimestamp {
+    /// The sample time of the first frame of the cycle, as `mSampleTime`.
+    pub sample_time: f64,
+    /// The host time of the first frame of the cycle, as `mHostTime`.
+    pub host_time: u64,
+}
+
+/// The `RenderEvent` struct is a register write timed within a render cycle.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RenderEvent {
+    /// The sample time the write plays at, as an `AUEventSampleTime`: either an absolute sample
+    /// time, or `SAMPLE_TIME_IMMEDIATE` plus an offset into the cycle.
+    pub sample_time: i64,
+    /// The register to write, in the range 0x000..=0x1FF.
+    pub register: u16,
+    /// The value to write.
+    pub value: u8,
+}
+
+/// The `RenderOutput` enum is the buffers a render call fills, in the range -1.0 to 1.0. The
+/// buffers may be longer than the cycle; only its frames are written.
+#[derive(Debug)]
+pub enum RenderOutput<'a> {
+    /// A single buffer of interleaved stereo frames.
+    Interleaved(&'a mut [f32]),
+    /// A buffer for each channel, left then right, as in a non-interleaved `AudioBufferList`.
+    Planar(&'a mut [f32], &'a mut [f32]),
+}
+
+impl RenderOutput<'_> {
+    /// Return the number of frames the buffers hold.
+    fn frames(&self) -> usize {
+        match self {
+            RenderOutput::Interleaved(buffer) => buffer.len() / 2,
+            RenderOutput::Planar(left, right) => left.len().min(right.len()),
+        }
+    }
+
+    /// Write interleaved 16-bit samples to the buffers, starting at a frame.
+    fn write(&mut self, start: usize, samples: &[i16]) {
+        let convert = |sample: i16| sample as f32 / 32768.0;
+        match self {
+            RenderOutput::Interleaved(buffer) => {
+                for (out, &sample) in buffer[start * 2..].iter_mut().zip(samples) {
+                    *out = convert(sample);
+                }
+            }
+            RenderOutput::Planar(left, right) => {
+                let frames = left[start..].iter_mut().zip(right[start..].iter_mut());
+                for ((left, right), frame) in frames.zip(samples.chunks_exact(2)) {
+                    *left = convert(frame[0]);
+                    *right = convert(frame[1]);
+                }
+            }
+        }
+    }
+}
+
+/// The `OplRenderCallback` struct renders a core on demand, for a CoreAudio render callback or
+/// AUv3 render block.
+#[derive(Clone, Debug)]
+pub struct OplRenderCallback<C> {
+    core: C,
+    /// The sample time the next render call is expected at, if there has been one.
+    next_sample_time: Option<f64>,
+    host_time: u64,
+    discontinuities: u64,
+}
+
+impl<C: OplCore> OplRenderCallback<C> {
+    /// Create a new `OplRenderCallback` rendering a core.
+    ///
+    /// # Arguments
+    ///
+    /// * `core` - The core to render, at the sample rate of the host's output format.
+    pub fn new(core: C) -> Self {
+        OplRenderCallback {
+            core,
+            next_sample_time: None,
+            host_time: 0,
+            discontinuities: 0,
+        }
+    }
+
+    /// Return a reference to the core.
+    pub fn core(&self) -> &C {
+        &self.core
+    }
+
+    /// Return a mutable reference to the core.
+    pub fn core_mut(&mut self) -> &mut C {
+        &mut self.core
+    }
+
+    /// Consume the callback, returning the core.
+    pub fn into_inner(self) -> C {
+        self.core
+    }
+
+    /// Return the sample time the next render call is expected at, following on from the last,
+    /// or `None` before the first render call.
+    pub fn next_sample_time(&self) -> Option<f64> {
+        self.next_sample_time
+    }
+
+    /// Return the host time of the last render call.
+    pub fn host_time(&self) -> u64 {
+        self.host_time
+    }
+
+    /// Return the number of render calls whose sample time did not follow on from the call
+    /// before.
+    pub fn discontinuities(&self) -> u64 {
+        self.discontinuities
+    }
+
+    /// Forget the timeline, so that the next render call is not counted as a discontinuity. Call
+    /// this when the host allocates render resources, after which the sample time may restart.
+    pub fn reset_timeline(&mut self) {
+        self.next_sample_time = None;
+    }
+
+    /// Render a cycle of audio, playing the events timed within it. Events should be in order of
+    /// their sample times: events timed before the cycle play at its first frame, events out of
+    /// order at the frame of the event before them, and events after the cycle after its last
+    /// frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - The timestamp of the first frame of the cycle.
+    /// * `frames`    - The number of frames to render, which can change between calls.
+    /// * `output`    - The buffers to fill, holding at least `frames` frames.
+    /// * `events`    - The register writes timed within the cycle.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames rendered on success or an `OplError` on
+    /// failure. The error is `OplError::BufferUndersized` if the buffers hold fewer than
+    /// `frames` frames, in which case nothing is rendered.
+    pub fn render(
+        &mut self,
+        timestamp: &RenderTimestamp,
+        frames: u32,
+        mut output: RenderOutput<'_>,
+        events: &[RenderEvent],
+    ) -> Result<usize, OplError> {
+        let frames = frames as usize;
+        if output.frames() < frames {
+            return Err(OplError::BufferUndersized);
+        }
+        if self
+            .next_sample_time
+            .is_some_and(|expected| expected != timestamp.sample_time)
+        {
+            self.discontinuities += 1;
+        }
+        self.next_sample_time = Some(timestamp.sample_time + frames as f64);
+        self.host_time = timestamp.host_time;
+
+        let start_time = timestamp.sample_time.floor() as i64;
+        let mut done = 0;
+        for event in events {
+            let offset = if (SAMPLE_TIME_IMMEDIATE..0).contains(&event.sample_time) {
+                event.sample_time - SAMPLE_TIME_IMMEDIATE
+            } else {
+                event.sample_time - start_time
+            };
+            let offset = offset.clamp(done as i64, frames as i64) as usize;
+            self.render_frames(&mut output, done, offset)?;
+            self.core.write_register(event.register, event.value);
+            done = offset;
+        }
+        self.render_frames(&mut output, done, frames)?;
+        Ok(frames)
+    }
+
+    /// Render the frames from `start` up to `end` into the buffers.
+    fn render_frames(
+        &mut self,
+        output: &mut RenderOutput<'_>,
+        start: usize,
+        end: usize,
+    ) -> Result<(), OplError> {
+        let mut block = [0i16; BLOCK_FRAMES * 2];
+        let mut frame = start;
+        while frame < end {
+            let run = (end - frame).min(BLOCK_FRAMES);
+            self.core.generate_stream(&mut block[..run * 2])?;
+            output.write(frame, &block[..run * 2]);
+            frame += run;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! A self test that runs the canonical AdLib and OPL3 detection sequence against `Opl3Device`.
+//!
+//! Nearly every DOS program that supports AdLib or Sound Blaster music detects the card by
+//! starting timer 1 and checking that the status register reports it as expired. Programs that
+//! support OPL3 then check that the low bits of the status register are clear, as they read as
+//! set on an OPL2. An emulator whose timer emulation is broken will find its games falling back
+//! to PC speaker music, so this is a quick check that the device is wired up correctly.
+
+use crate::{Opl3Device, OplRegisterFile};
+
+const STATUS_TIMER_BITS: u8 = 0xE0;
+const STATUS_TIMER_1_EXPIRED: u8 = 0xC0;
+const STATUS_OPL2_BITS: u8 = 0x06;
+
+/// The `DetectionReport` struct contains the results of `Opl3Device::self_test_detection`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DetectionReport {
+    /// The status register read after resetting the timers. Detection expects the timer bits
+    /// (0xE0) to be clear.
+    pub status_before: u8,
+    /// The status register read after timer 1 was started and allowed to expire. Detection
+    /// expects the timer bits to read 0xC0.
+    pub status_after: u8,
+}
+
+impl DetectionReport {
+    /// Return `true` if a program would detect an AdLib compatible card.
+    pub fn adlib_detected(&self) -> bool {
+        self.status_before & STATUS_TIMER_BITS == 0
+            && self.status_after & STATUS_TIMER_BITS == STATUS_TIMER_1_EXPIRED
+    }
+
+    /// Return `true` if a program would detect an OPL3, rather than an OPL2.
+    pub fn opl3_detected(&self) -> bool {
+        self.adlib_detected() && self.status_before & STATUS_OPL2_BITS == 0
+    }
+}
+
+impl Opl3Device {
+    /// Run the canonical AdLib detection sequence of timer writes and status reads against this
+    /// device, and report whether detection would pass. The device's state, including its timers
+    /// and statistics, is restored afterwards.
+    ///
+    /// # Returns
+    ///
+    /// A `DetectionReport` containing the status register values read during detection.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Device;
+    ///
+    /// let mut device = Opl3Device::new(44100);
+    /// let report = device.self_test_detection();
+    /// assert!(report.adlib_detected());
+    /// assert!(report.opl3_detected());
+    /// ```
+    pub fn self_test_detection(&mut self) -> DetectionReport {
+        let state = self.snapshot();
+        let stats = self.stats;
+        let warnings = self.warnings.len();
+
+        // Mask and stop both timers, then reset the IRQ flag.
+        self.write_register(0x04, 0x60, OplRegisterFile::Primary, false);
+        self.write_register(0x04, 0x80, OplRegisterFile::Primary, false);
+        let status_before = self.read_status();
+
+        // Load timer 1 so that it expires on its next tick, and start it with timer 2 masked.
+        self.write_register(0x02, 0xFF, OplRegisterFile::Primary, false);
+        self.write_register(0x04, 0x21, OplRegisterFile::Primary, false);
+        // Programs wait at least 80 microseconds, usually by reading the status port repeatedly.
+        self.run(100.0);
+        let status_after = self.read_status();
+
+        self.restore(&state);
+        self.stats = stats;
+        self.warnings.truncate(warnings);
+        DetectionReport {
+            status_before,
+            status_after,
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Human-readable differences between two register states.
+//!
+//! `RegisterDiff` decodes the register files of two `Opl3DeviceState` snapshots into the fields
+//! they control and lists the fields that differ, such as "ch3 op1 attack 12→15, ch3 key-on".
+//! This is useful in tests, and in debuggers, to explain why the output changed between two
+//! points in time.
+
+use std::fmt;
+
+use crate::Opl3DeviceState;
+
+/// The part of the chip that a `FieldChange` applies to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiffScope {
+    /// A global setting, such as the rhythm mode or the OPL3 enable bit.
+    Global,
+    /// A channel setting. Channels 0-8 are in the primary register file and 9-17 in the secondary
+    /// register file.
+    Channel(u8),
+    /// An operator setting. Operator 1 is the modulator of a 2-operator channel, and operator 2
+    /// the carrier.
+    Operator {
+        /// The channel the operator belongs to, from 0 to 17.
+        channel: u8,
+        /// The operator within the channel, 1 or 2.
+        operator: u8,
+    },
+}
+
+impl fmt::Display for DiffScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffScope::Global => Ok(()),
+            DiffScope::Channel(channel) => write!(f, "ch{}", channel),
+            DiffScope::Operator { channel, operator } => write!(f, "ch{} op{}", channel, operator),
+        }
+    }
+}
+
+/// A single field that differs between two register states.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FieldChange {
+    /// The part of the chip the field belongs to.
+    pub scope: DiffScope,
+    /// The name of the field, such as "attack" or "fnum".
+    pub field: &'static str,
+    /// The value of the field in the earlier state.
+    pub before: u16,
+    /// The value of the field in the later state.
+    pub after: u16,
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.scope != DiffScope::Global {
+            write!(f, "{} ", self.scope)?;
+        }
+        if self.field == KEY_ON {
+            f.write_str(if self.after != 0 { "key-on" } else { "key-off" })
+        } else {
+            write!(f, "{} {}→{}", self.field, self.before, self.after)
+        }
+    }
+}
+
+/// The `RegisterDiff` struct lists the register fields that differ between two states.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::{Opl3Device, OplRegisterFile};
+/// use opl3_rs::diff::RegisterDiff;
+///
+/// let mut device = Opl3Device::new(44100);
+/// device.write_register(0x60, 0xC4, OplRegisterFile::Primary, false);
+/// let before = device.snapshot();
+/// device.write_register(0x60, 0xF4, OplRegisterFile::Primary, false);
+/// device.write_register(0xB0, 0x20, OplRegisterFile::Primary, false);
+/// let after = device.snapshot();
+///
+/// let diff = RegisterDiff::between(&before, &after);
+/// assert_eq!(diff.to_string(), "ch0 op1 attack 12→15, ch0 key-on");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegisterDiff {
+    changes: Vec<FieldChange>,
+}
+
+const KEY_ON: &str = "key-on";
+
+/// A bit field within a single register.
+struct Field {
+    name: &'static str,
+    shift: u8,
+    mask: u8,
+}
+
+const fn field(name: &'static str, shift: u8, mask: u8) -> Field {
+    Field { name, shift, mask }
+}
+
+const OPERATOR_FIELDS: [(u8, &[Field]); 5] = [
+    (
+        0x20,
+        &[
+            field("tremolo", 7, 0x01),
+            field("vibrato", 6, 0x01),
+            field("sustain", 5, 0x01),
+            field("ksr", 4, 0x01),
+            field("multiplier", 0, 0x0F),
+        ],
+    ),
+    (0x40, &[field("ksl", 6, 0x03), field("level", 0, 0x3F)]),
+    (0x60, &[field("attack", 4, 0x0F), field("decay", 0, 0x0F)]),
+    (
+        0x80,
+        &[field("sustain level", 4, 0x0F), field("release", 0, 0x0F)],
+    ),
+    (0xE0, &[field("waveform", 0, 0x07)]),
+];
+
+const CHANNEL_FIELDS: [Field; 3] = [
+    field("feedback", 1, 0x07),
+    field("connection", 0, 0x01),
+    field("outputs", 4, 0x0F),
+];
+
+const GLOBAL_FIELDS: [(u16, &[Field]); 7] = [
+    (0x001, &[field("wave select", 5, 0x01)]),
+    (0x002, &[field("timer 1", 0, 0xFF)]),
+    (0x003, &[field("timer 2", 0, 0xFF)]),
+    (
+        0x008,
+        &[field("csm", 7, 0x01), field("note select", 6, 0x01)],
+    ),
+    (
+        0x0BD,
+        &[
+            field("tremolo depth", 7, 0x01),
+            field("vibrato depth", 6, 0x01),
+            field("rhythm", 5, 0x01),
+            field("bass drum", 4, 0x01),
+            field("snare drum", 3, 0x01),
+            field("tom-tom", 2, 0x01),
+            field("cymbal", 1, 0x01),
+            field("hi-hat", 0, 0x01),
+        ],
+    ),
+    (0x104, &[field("4-op", 0, 0x3F)]),
+    (0x105, &[field("opl3 mode", 0, 0x01)]),
+];
+
+impl RegisterDiff {
+    /// Compare the register files of two device states.
+    ///
+    /// # Arguments
+    ///
+    /// * `before` - The earlier state.
+    /// * `after`  - The later state.
+    pub fn between(before: &Opl3DeviceState, after: &Opl3DeviceState) -> Self {
+        Self::between_registers(before.registers(), after.registers())
+    }
+
+    /// Compare two sets of register files, primary first, as returned by
+    /// `Opl3DeviceState::registers`.
+    ///
+    /// # Arguments
+    ///
+    /// * `before` - The earlier register files.
+    /// * `after`  - The later register files.
+    pub fn between_registers(before: &[[u8; 256]; 2], after: &[[u8; 256]; 2]) -> Self {
+        let mut diff = RegisterDiff::default();
+        let compare = |diff: &mut RegisterDiff, scope: DiffScope, fields: &[Field], reg: u16| {
+            let (old, new) = (read(before, reg), read(after, reg));
+            for f in fields {
+                diff.push(
+                    scope,
+                    f.name,
+                    (old >> f.shift) & f.mask,
+                    (new >> f.shift) & f.mask,
+                );
+            }
+        };
+
+        for (reg, fields) in GLOBAL_FIELDS {
+            compare(&mut diff, DiffScope::Global, fields, reg);
+        }
+        for channel in 0..18u8 {
+            let bank = (channel as u16 / 9) << 8;
+            let index = (channel % 9) as u16;
+            let slot = index % 3 + index / 3 * 8;
+            for operator in 1..=2u8 {
+                let scope = DiffScope::Operator { channel, operator };
+                let offset = slot + (operator as u16 - 1) * 3;
+                for (base, fields) in OPERATOR_FIELDS {
+                    compare(&mut diff, scope, fields, bank | (base as u16 + offset));
+                }
+            }
+
+            let scope = DiffScope::Channel(channel);
+            let (a0, b0) = (bank | (0xA0 + index), bank | (0xB0 + index));
+            let fnum = |files| read(files, a0) as u16 | ((read(files, b0) as u16 & 0x03) << 8);
+            diff.push(scope, "fnum", fnum(before), fnum(after));
+            compare(&mut diff, scope, &[field("block", 2, 0x07)], b0);
+            compare(&mut diff, scope, &CHANNEL_FIELDS, bank | (0xC0 + index));
+            compare(&mut diff, scope, &[field(KEY_ON, 5, 0x01)], b0);
+        }
+        diff
+    }
+
+    /// Return the fields that differ. Global fields come first, followed by the operator and
+    /// channel fields of each channel in turn, with key-on changes last for each channel.
+    pub fn changes(&self) -> &[FieldChange] {
+        &self.changes
+    }
+
+    /// Return `true` if no fields differ.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    fn push(
+        &mut self,
+        scope: DiffScope,
+        field: &'static str,
+        before: impl Into<u16>,
+        after: impl Into<u16>,
+    ) {
+        let (before, after) = (before.into(), after.into());
+        if before != after {
+            self.changes.push(FieldChange {
+                scope,
+                field,
+                before,
+                after,
+            });
+        }
+    }
+}
+
+fn read(files: &[[u8; 256]; 2], reg: u16) -> u8 {
+    files[(reg >> 8) as usize & 1][reg as usize & 0xFF]
+}
+
+impl fmt::Display for RegisterDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! A double buffer for streaming audio to DMA-driven outputs such as I2S peripherals.
+//!
+//! While the DMA controller plays one block, the other is rendered. When the DMA transfer
+//! completes, the interrupt handler calls `DoubleBuffer::swap`, which hands the freshly rendered
+//! block to the DMA controller and then renders the next block into the one that just finished
+//! playing. Rendering must complete within the playing time of one block.
+//!
+//! The DMA controller reads the blocks directly, so the `DoubleBuffer` must not be moved while a
+//! transfer is in progress. Keep it in a `static` or otherwise pinned location.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::double_buffer::DoubleBuffer;
+//!
+//! let mut chip = Opl3Chip::new(48000);
+//! let mut buffer: DoubleBuffer<256> = DoubleBuffer::new(&mut chip);
+//! // start_dma(buffer.front());
+//!
+//! // In the DMA-complete interrupt handler:
+//! buffer.swap(&mut chip, |block| {
+//!     // start_dma(block);
+//!     assert_eq!(block.len(), 256);
+//! });
+//! ```
+
+#![cfg_attr(feature = "fixed-point", deny(clippy::float_arithmetic))]
+
+use crate::Opl3Chip;
+
+/// The `DoubleBuffer` struct holds two blocks of `N` interleaved stereo samples, one being played
+/// and one ready to play next.
+pub struct DoubleBuffer<const N: usize> {
+    blocks: [[i16; N]; 2],
+    front: usize,
+}
+
+impl<const N: usize> DoubleBuffer<N> {
+    /// Create a new `DoubleBuffer`, rendering both blocks in advance.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip` - The chip to render from. `N` must be a non-zero even number, as each frame is
+    ///            two samples.
+    pub fn new(chip: &mut Opl3Chip) -> Self {
+        let mut buffer = DoubleBuffer {
+            blocks: [[0; N]; 2],
+            front: 0,
+        };
+        for block in buffer.blocks.iter_mut() {
+            chip.generate_into(block);
+        }
+        buffer
+    }
+
+    /// Return the block that is currently being played.
+    pub fn front(&self) -> &[i16; N] {
+        &self.blocks[self.front]
+    }
+
+    /// Return the block that will be played next.
+    pub fn back(&self) -> &[i16; N] {
+        &self.blocks[self.front ^ 1]
+    }
+
+    /// Swap the blocks, to be called when the DMA transfer of the front block completes.
+    ///
+    /// `start_dma` is called with the new front block before anything is rendered, so that the
+    /// next transfer can be started with as little delay as possible. The block that has just
+    /// finished playing is then rendered with the next `N` samples from `chip`.
+    ///
+    /// With a circular DMA transfer over both blocks, the controller moves on to the next block
+    /// by itself and `start_dma` can do nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip`      - The chip to render from.
+    /// * `start_dma` - A closure that starts the DMA transfer of the new front block.
+    pub fn swap<F: FnOnce(&[i16; N])>(&mut self, chip: &mut Opl3Chip, start_dma: F) {
+        let finished = self.front;
+        self.front ^= 1;
+        start_dma(&self.blocks[self.front]);
+        chip.generate_into(&mut self.blocks[finished]);
+    }
+}
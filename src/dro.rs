@@ -0,0 +1,201 @@
+//! Conversion between event logs and DOSBox Raw OPL (DRO) captures.
+//!
+//! Both DRO v0.1 (written by DOSBox 0.61 - 0.72) and DRO v2.0 (DOSBox 0.73 and later) can be
+//! read. DRO v2.0 is written.
+
+use crate::event_log::{EventLog, OplEvent, DEFAULT_TICK_RATE};
+use crate::OplError;
+
+const DRO_MAGIC: &[u8; 8] = b"DBRAWOPL";
+const DRO_V2_HEADER_SIZE: usize = 26;
+/// The largest codemap a DRO v2 file can have while leaving room for the two delay codes.
+const DRO_MAX_CODEMAP: usize = 126;
+
+const DRO_HARDWARE_OPL2: u8 = 0;
+const DRO_HARDWARE_OPL3: u8 = 2;
+
+impl EventLog {
+    /// Convert a DRO capture into an event log. The event log uses the default tick rate of
+    /// 49716 ticks per second.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of a DRO v0.1 or v2.0 file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the converted `EventLog` or an `OplError` on failure.
+    pub fn from_dro(data: &[u8]) -> Result<EventLog, OplError> {
+        const ERR: OplError = OplError::InvalidFormat("DRO");
+        if data.len() < 16 || &data[0..8] != DRO_MAGIC {
+            return Err(ERR);
+        }
+        let mut log = EventLog::new(DEFAULT_TICK_RATE);
+        let mut ms: u64 = 0;
+        // Times are rounded up, so that converting back to milliseconds is lossless.
+        let push = |log: &mut EventLog, ms: u64, register: u16, value: u8| {
+            log.push(OplEvent {
+                time: (ms * DEFAULT_TICK_RATE as u64).div_ceil(1000),
+                register,
+                value,
+            })
+        };
+
+        match u32::from_le_bytes([data[8], data[9], data[10], data[11]]) {
+            // Version 2.0, stored as major 2, minor 0.
+            0x0000_0002 => {
+                if data.len() < DRO_V2_HEADER_SIZE {
+                    return Err(ERR);
+                }
+                let pairs = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+                if data[21] != 0 || data[22] != 0 {
+                    // Only the interleaved, uncompressed format exists in the wild.
+                    return Err(ERR);
+                }
+                let short_delay = data[23];
+                let long_delay = data[24];
+                let codemap_len = data[25] as usize;
+                let codemap = data
+                    .get(DRO_V2_HEADER_SIZE..DRO_V2_HEADER_SIZE + codemap_len)
+                    .ok_or(ERR)?;
+                let commands = &data[DRO_V2_HEADER_SIZE + codemap_len..];
+
+                for pair in commands.chunks_exact(2).take(pairs) {
+                    let (code, value) = (pair[0], pair[1]);
+                    if code == short_delay {
+                        ms += value as u64 + 1;
+                    } else if code == long_delay {
+                        ms += (value as u64 + 1) << 8;
+                    } else {
+                        let reg = *codemap.get((code & 0x7F) as usize).ok_or(ERR)?;
+                        let bank = ((code >> 7) as u16) << 8;
+                        push(&mut log, ms, bank | reg as u16, value);
+                    }
+                }
+            }
+            // Version 0.1, stored as major 0, minor 1.
+            0x0001_0000 => {
+                if data.len() < 0x18 {
+                    return Err(ERR);
+                }
+                // The hardware type field was a single byte in early versions of DOSBox, and a
+                // 32-bit value later. Early files have command data in the padding bytes.
+                let start = if data[0x15..0x18] == [0, 0, 0] {
+                    0x18
+                } else {
+                    0x15
+                };
+                let mut bank = 0u16;
+                let mut i = start;
+                while i < data.len() {
+                    let code = data[i];
+                    match code {
+                        0x00 => {
+                            ms += *data.get(i + 1).ok_or(ERR)? as u64 + 1;
+                            i += 2;
+                        }
+                        0x01 => {
+                            let delay = data.get(i + 1..i + 3).ok_or(ERR)?;
+                            ms += u16::from_le_bytes([delay[0], delay[1]]) as u64 + 1;
+                            i += 3;
+                        }
+                        0x02 | 0x03 => {
+                            bank = ((code - 0x02) as u16) << 8;
+                            i += 1;
+                        }
+                        0x04 => {
+                            let pair = data.get(i + 1..i + 3).ok_or(ERR)?;
+                            push(&mut log, ms, bank | pair[0] as u16, pair[1]);
+                            i += 3;
+                        }
+                        _ => {
+                            let value = *data.get(i + 1).ok_or(ERR)?;
+                            push(&mut log, ms, bank | code as u16, value);
+                            i += 2;
+                        }
+                    }
+                }
+            }
+            _ => return Err(ERR),
+        }
+        Ok(log)
+    }
+
+    /// Convert the event log into a DRO v2.0 capture. Event times are rounded down to the
+    /// millisecond resolution of the DRO format. The hardware type is set to OPL3 if any event
+    /// writes to the secondary register file, and OPL2 otherwise.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the contents of the DRO file or an `OplError` if the log writes
+    /// to more distinct registers than a DRO codemap can hold.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::event_log::{EventLog, OplEvent};
+    ///
+    /// let mut log = EventLog::new(1000);
+    /// log.push(OplEvent { time: 0, register: 0x20, value: 0x01 });
+    /// log.push(OplEvent { time: 500, register: 0xB0, value: 0x31 });
+    ///
+    /// let dro = log.to_dro().unwrap();
+    /// let events: Vec<OplEvent> = EventLog::from_dro(&dro).unwrap().iter().collect();
+    /// assert_eq!(events[1].register, 0xB0);
+    /// ```
+    pub fn to_dro(&self) -> Result<Vec<u8>, OplError> {
+        let mut codemap: Vec<u8> = Vec::new();
+        let mut hardware = DRO_HARDWARE_OPL2;
+        for event in self.iter() {
+            let reg = event.register as u8;
+            if !codemap.contains(&reg) {
+                codemap.push(reg);
+            }
+            if event.register & 0x100 != 0 {
+                hardware = DRO_HARDWARE_OPL3;
+            }
+        }
+        if codemap.len() > DRO_MAX_CODEMAP {
+            return Err(OplError::InvalidFormat("DRO"));
+        }
+        let short_delay = codemap.len() as u8;
+        let long_delay = short_delay + 1;
+
+        let mut commands: Vec<u8> = Vec::with_capacity(self.len() * 2);
+        let mut pairs = 0u32;
+        let mut last_ms = 0u64;
+        for event in self.iter() {
+            let ms = event.time * 1000 / self.tick_rate() as u64;
+            let mut delay = ms - last_ms;
+            last_ms = ms;
+            while delay > 256 {
+                let chunks = (delay >> 8).min(256);
+                commands.extend_from_slice(&[long_delay, (chunks - 1) as u8]);
+                delay -= chunks << 8;
+                pairs += 1;
+            }
+            if delay > 0 {
+                commands.extend_from_slice(&[short_delay, (delay - 1) as u8]);
+                pairs += 1;
+            }
+            let index = codemap
+                .iter()
+                .position(|&r| r == event.register as u8)
+                .unwrap_or(0);
+            let bank = ((event.register >> 1) & 0x80) as u8;
+            commands.extend_from_slice(&[bank | index as u8, event.value]);
+            pairs += 1;
+        }
+
+        let mut dro = Vec::with_capacity(DRO_V2_HEADER_SIZE + codemap.len() + commands.len());
+        dro.extend_from_slice(DRO_MAGIC);
+        dro.extend_from_slice(&2u16.to_le_bytes());
+        dro.extend_from_slice(&0u16.to_le_bytes());
+        dro.extend_from_slice(&pairs.to_le_bytes());
+        dro.extend_from_slice(&(last_ms.min(u32::MAX as u64) as u32).to_le_bytes());
+        dro.extend_from_slice(&[hardware, 0, 0, short_delay, long_delay, codemap.len() as u8]);
+        dro.extend_from_slice(&codemap);
+        dro.extend_from_slice(&commands);
+        Ok(dro)
+    }
+}
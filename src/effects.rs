@@ -0,0 +1,559 @@
+//! Simple post-processing of rendered audio.
+//!
+//! An `Effect` processes 2 channel, interleaved samples in place. Effects can be combined into an
+//! `EffectChain`, which applies them in order, and a chain can be attached to any `OplCore` with
+//! `WithEffects`, so that everything the core generates is processed. Built-in effects cover the
+//! basics a frontend needs: `Gain`, `DcBlock`, `Limiter`, `Echo` and `Biquad`, a general purpose
+//! filter for building equalisers such as those in `speaker`.
+//!
+//! Effects work in floating point and round their result back to 16 bits. On quiet passages,
+//! that rounding is audible as distortion, so every built-in effect can instead add TPDF dither
+//! with `Effect::set_dither`. Enabling it on an `EffectChain` enables it for every effect in the
+//! chain.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::effects::{DcBlock, Echo, EffectChain, Gain, Limiter, WithEffects};
+//! use opl3_rs::opl_core::OplCore;
+//!
+//! let chain = EffectChain::new()
+//!     .with(DcBlock::new())
+//!     .with(Echo::new(44100 / 4, 0.3, 0.25))
+//!     .with(Gain::new(2.0))
+//!     .with(Limiter::new(30000));
+//! let mut chip = WithEffects::new(Opl3Chip::new(44100), chain);
+//!
+//! chip.write_register(0xB0, 0x32);
+//! let mut buffer = [0i16; 1024 * 2];
+//! chip.generate_stream(&mut buffer).unwrap();
+//! ```
+
+use crate::opl_core::OplCore;
+use crate::sample::{Dither, Sample};
+use crate::OplError;
+
+/// The `Effect` trait is implemented by processors of 2 channel, interleaved audio.
+pub trait Effect {
+    /// Process samples in place. Buffers hold interleaved left and right samples, and a trailing
+    /// odd sample is left unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The samples to process.
+    fn process(&mut self, buffer: &mut [i16]);
+
+    /// Clear any internal state, such as delay lines, as if no audio had been processed.
+    fn reset(&mut self) {}
+
+    /// Enable or disable dithering where the effect rounds its output back to 16 bits. Dither
+    /// adds up to one least significant bit of noise to every sample, even where the effect
+    /// leaves a sample unchanged. Effects that do not round their output ignore this. Dithering
+    /// is disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to dither the output.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::effects::{Effect, EffectChain, Gain};
+    ///
+    /// // Halving a constant level of 3 rounds every sample to 2, while dither spreads them
+    /// // between the levels around 1.5.
+    /// let mut chain = EffectChain::new().with(Gain::new(0.5));
+    /// let mut buffer = [3i16; 1024];
+    /// chain.process(&mut buffer);
+    /// assert!(buffer.iter().all(|&sample| sample == 2));
+    ///
+    /// chain.set_dither(true);
+    /// let mut buffer = [3i16; 1024];
+    /// chain.process(&mut buffer);
+    /// assert!(buffer.contains(&1) && buffer.contains(&2));
+    /// ```
+    fn set_dither(&mut self, _enabled: bool) {}
+}
+
+/// The `EffectChain` struct applies a sequence of effects in order.
+#[derive(Default)]
+pub struct EffectChain {
+    effects: Vec<Box<dyn Effect>>,
+}
+
+impl EffectChain {
+    /// Create a new, empty `EffectChain`, which leaves audio unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an effect to the end of the chain, returning the chain.
+    ///
+    /// # Arguments
+    ///
+    /// * `effect` - The effect to append.
+    pub fn with<E: Effect + 'static>(mut self, effect: E) -> Self {
+        self.push(effect);
+        self
+    }
+
+    /// Append an effect to the end of the chain.
+    ///
+    /// # Arguments
+    ///
+    /// * `effect` - The effect to append.
+    pub fn push<E: Effect + 'static>(&mut self, effect: E) {
+        self.effects.push(Box::new(effect));
+    }
+
+    /// Return the number of effects in the chain.
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    /// Return `true` if the chain has no effects.
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+}
+
+impl Effect for EffectChain {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for effect in self.effects.iter_mut() {
+            effect.process(buffer);
+        }
+    }
+
+    fn reset(&mut self) {
+        for effect in self.effects.iter_mut() {
+            effect.reset();
+        }
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        for effect in self.effects.iter_mut() {
+            effect.set_dither(enabled);
+        }
+    }
+}
+
+/// The `WithEffects` struct wraps an `OplCore`, processing everything it generates with an
+/// effect.
+pub struct WithEffects<C: OplCore, E: Effect = EffectChain> {
+    core: C,
+    effect: E,
+}
+
+impl<C: OplCore, E: Effect> WithEffects<C, E> {
+    /// Attach an effect to a core.
+    ///
+    /// # Arguments
+    ///
+    /// * `core`   - The core to wrap.
+    /// * `effect` - The effect to apply to its output, often an `EffectChain`.
+    pub fn new(core: C, effect: E) -> Self {
+        WithEffects { core, effect }
+    }
+
+    /// Return the wrapped core.
+    pub fn core(&self) -> &C {
+        &self.core
+    }
+
+    /// Return the wrapped core, for calling methods outside of `OplCore`.
+    pub fn core_mut(&mut self) -> &mut C {
+        &mut self.core
+    }
+
+    /// Return the effect.
+    pub fn effect_mut(&mut self) -> &mut E {
+        &mut self.effect
+    }
+
+    /// Detach the effect, returning the core and the effect.
+    pub fn into_inner(self) -> (C, E) {
+        (self.core, self.effect)
+    }
+}
+
+/// Resetting the core also resets the effect.
+impl<C: OplCore, E: Effect> OplCore for WithEffects<C, E> {
+    fn sample_rate(&self) -> u32 {
+        self.core.sample_rate()
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        self.core.reset(sample_rate);
+        self.effect.reset();
+    }
+
+    fn write_register(&mut self, reg: u16, value: u8) {
+        self.core.write_register(reg, value);
+    }
+
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let frames = self.core.generate_stream(buffer)?;
+        self.effect.process(buffer);
+        Ok(frames)
+    }
+}
+
+/// The `Gain` struct scales audio by a constant gain, clipping to the i16 range.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Gain {
+    gain: f32,
+    output: Quantizer,
+}
+
+impl Gain {
+    /// Create a new `Gain`.
+    ///
+    /// # Arguments
+    ///
+    /// * `gain` - The linear gain, where 1.0 leaves audio unchanged.
+    pub fn new(gain: f32) -> Self {
+        Gain {
+            gain,
+            output: Quantizer::default(),
+        }
+    }
+
+    /// Create a new `Gain` from a gain in decibels.
+    ///
+    /// # Arguments
+    ///
+    /// * `db` - The gain in decibels, where 0.0 leaves audio unchanged.
+    pub fn from_db(db: f32) -> Self {
+        Gain::new(10f32.powf(db / 20.0))
+    }
+}
+
+impl Effect for Gain {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for sample in buffer {
+            *sample = self.output.quantize(*sample as f32 * self.gain);
+        }
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        self.output.set_dither(enabled);
+    }
+}
+
+/// The `DcBlock` struct removes any DC offset with a first order high-pass filter at about 10Hz.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DcBlock {
+    previous_in: [f32; 2],
+    previous_out: [f32; 2],
+    output: Quantizer,
+}
+
+impl DcBlock {
+    /// The pole of the filter. Closer to 1.0 lowers the cutoff frequency.
+    const POLE: f32 = 0.9985;
+
+    /// Create a new `DcBlock`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Effect for DcBlock {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for frame in buffer.chunks_exact_mut(2) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let input = *sample as f32;
+                let output =
+                    input - self.previous_in[channel] + Self::POLE * self.previous_out[channel];
+                self.previous_in[channel] = input;
+                self.previous_out[channel] = output;
+                *sample = self.output.quantize(output);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.previous_in = [0.0; 2];
+        self.previous_out = [0.0; 2];
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        self.output.set_dither(enabled);
+    }
+}
+
+/// The `Limiter` struct keeps peaks below a threshold, reducing the gain of both channels at once
+/// as soon as a sample would exceed it and recovering it gradually afterwards.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Limiter {
+    threshold: f32,
+    gain: f32,
+    release: f32,
+    output: Quantizer,
+}
+
+impl Limiter {
+    /// Create a new `Limiter` with a release time of about 50ms at 44100Hz.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - The largest absolute sample value to let through.
+    pub fn new(threshold: i16) -> Self {
+        Limiter {
+            threshold: threshold.unsigned_abs().max(1) as f32,
+            gain: 1.0,
+            release: 1.0 / 2205.0,
+            output: Quantizer::default(),
+        }
+    }
+}
+
+impl Effect for Limiter {
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::effects::{Effect, Limiter};
+    ///
+    /// let mut limiter = Limiter::new(10000);
+    /// let mut buffer = [20000i16, -20000, 5000, 5000];
+    /// limiter.process(&mut buffer);
+    /// assert!(buffer.iter().all(|sample| sample.abs() <= 10000));
+    /// ```
+    fn process(&mut self, buffer: &mut [i16]) {
+        for frame in buffer.chunks_exact_mut(2) {
+            let peak = frame[0].unsigned_abs().max(frame[1].unsigned_abs()) as f32;
+            if peak * self.gain > self.threshold {
+                self.gain = self.threshold / peak;
+            }
+            for sample in frame.iter_mut() {
+                *sample = self.output.quantize(*sample as f32 * self.gain);
+            }
+            self.gain = (self.gain + self.release).min(1.0);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.gain = 1.0;
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        self.output.set_dither(enabled);
+    }
+}
+
+/// The `Echo` struct mixes delayed copies of the audio back in, for a simple echo or, with a
+/// short delay and high feedback, a rough room reverb.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Echo {
+    delay: Vec<[f32; 2]>,
+    position: usize,
+    feedback: f32,
+    mix: f32,
+    output: Quantizer,
+}
+
+impl Echo {
+    /// Create a new `Echo`.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay_frames` - The delay between echoes, in frames.
+    /// * `feedback`     - The fraction of each echo fed back into the next, from 0.0 to below 1.0.
+    /// * `mix`          - The level of the echoes mixed into the output, from 0.0 to 1.0.
+    pub fn new(delay_frames: usize, feedback: f32, mix: f32) -> Self {
+        Echo {
+            delay: vec![[0.0; 2]; delay_frames.max(1)],
+            position: 0,
+            feedback: feedback.clamp(0.0, 0.99),
+            mix: mix.clamp(0.0, 1.0),
+            output: Quantizer::default(),
+        }
+    }
+}
+
+impl Effect for Echo {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for frame in buffer.chunks_exact_mut(2) {
+            let delayed = self.delay[self.position];
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let input = *sample as f32;
+                self.delay[self.position][channel] = input + delayed[channel] * self.feedback;
+                *sample = self.output.quantize(input + delayed[channel] * self.mix);
+            }
+            self.position = (self.position + 1) % self.delay.len();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay.fill([0.0; 2]);
+        self.position = 0;
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        self.output.set_dither(enabled);
+    }
+}
+
+/// The `BiquadKind` enum lists the filter shapes a `Biquad` can take.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BiquadKind {
+    /// Pass frequencies below the corner frequency.
+    LowPass,
+    /// Pass frequencies above the corner frequency.
+    HighPass,
+    /// Boost or cut a band around the centre frequency by a gain in decibels.
+    Peaking(f32),
+    /// Boost or cut frequencies below the corner frequency by a gain in decibels.
+    LowShelf(f32),
+    /// Boost or cut frequencies above the corner frequency by a gain in decibels.
+    HighShelf(f32),
+}
+
+/// The `Biquad` struct is a second order IIR filter, with coefficients from the well known
+/// "Audio EQ Cookbook" formulas. Both channels are filtered with the same response.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [[f64; 2]; 2],
+    y: [[f64; 2]; 2],
+    output: Quantizer,
+}
+
+impl Biquad {
+    /// Create a new `Biquad`.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind`        - The shape of the filter.
+    /// * `sample_rate` - The sample rate of the audio to filter.
+    /// * `frequency`   - The corner or centre frequency in Hz, limited to just below Nyquist.
+    /// * `q`           - The quality factor, where 0.707 gives a flat pass band.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::effects::{Biquad, BiquadKind, Effect};
+    ///
+    /// // A 1kHz low-pass filter at 44100Hz removes a tone at 11025Hz.
+    /// let mut filter = Biquad::new(BiquadKind::LowPass, 44100, 1000.0, 0.707);
+    /// let mut buffer: Vec<i16> = [10000, 10000, -10000, -10000].repeat(500);
+    /// filter.process(&mut buffer);
+    /// assert!(buffer[900..].iter().all(|sample| sample.abs() < 200));
+    /// ```
+    pub fn new(kind: BiquadKind, sample_rate: u32, frequency: f32, q: f32) -> Self {
+        let sample_rate = sample_rate.max(1) as f64;
+        let frequency = (frequency as f64).clamp(1.0, sample_rate * 0.49);
+        let w0 = 2.0 * std::f64::consts::PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * (q as f64).max(0.01));
+        let shelf = |gain: f32| {
+            let a = 10f64.powf(gain as f64 / 40.0);
+            (a, 2.0 * a.sqrt() * alpha)
+        };
+        let (b, a0, a) = match kind {
+            BiquadKind::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                1.0 + alpha,
+                [-2.0 * cos, 1.0 - alpha],
+            ),
+            BiquadKind::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                1.0 + alpha,
+                [-2.0 * cos, 1.0 - alpha],
+            ),
+            BiquadKind::Peaking(gain) => {
+                let a = 10f64.powf(gain as f64 / 40.0);
+                (
+                    [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                    1.0 + alpha / a,
+                    [-2.0 * cos, 1.0 - alpha / a],
+                )
+            }
+            BiquadKind::LowShelf(gain) => {
+                let (a, beta) = shelf(gain);
+                (
+                    [
+                        a * ((a + 1.0) - (a - 1.0) * cos + beta),
+                        2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                        a * ((a + 1.0) - (a - 1.0) * cos - beta),
+                    ],
+                    (a + 1.0) + (a - 1.0) * cos + beta,
+                    [
+                        -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                        (a + 1.0) + (a - 1.0) * cos - beta,
+                    ],
+                )
+            }
+            BiquadKind::HighShelf(gain) => {
+                let (a, beta) = shelf(gain);
+                (
+                    [
+                        a * ((a + 1.0) + (a - 1.0) * cos + beta),
+                        -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                        a * ((a + 1.0) + (a - 1.0) * cos - beta),
+                    ],
+                    (a + 1.0) - (a - 1.0) * cos + beta,
+                    [
+                        2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                        (a + 1.0) - (a - 1.0) * cos - beta,
+                    ],
+                )
+            }
+        };
+        Biquad {
+            b: b.map(|b| b / a0),
+            a: a.map(|a| a / a0),
+            x: [[0.0; 2]; 2],
+            y: [[0.0; 2]; 2],
+            output: Quantizer::default(),
+        }
+    }
+}
+
+impl Effect for Biquad {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for frame in buffer.chunks_exact_mut(2) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let input = *sample as f64;
+                let [x1, x2] = self.x[channel];
+                let [y1, y2] = self.y[channel];
+                let output = self.b[0] * input + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[0] * y1
+                    - self.a[1] * y2;
+                self.x[channel] = [input, x1];
+                self.y[channel] = [output, y1];
+                *sample = self.output.quantize(output as f32);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.x = [[0.0; 2]; 2];
+        self.y = [[0.0; 2]; 2];
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        self.output.set_dither(enabled);
+    }
+}
+
+/// Rounds the floating point output of an effect back to 16 bits, optionally with dither.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct Quantizer {
+    dither: Option<Dither>,
+}
+
+impl Quantizer {
+    fn set_dither(&mut self, enabled: bool) {
+        self.dither = enabled.then(|| self.dither.unwrap_or_default());
+    }
+
+    fn quantize(&mut self, sample: f32) -> i16 {
+        match &mut self.dither {
+            Some(dither) => dither.quantize(sample / 32768.0),
+            None => i16::from_f64(sample as f64 / 32768.0),
+        }
+    }
+}
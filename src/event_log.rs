@@ -0,0 +1,396 @@
+//! A compact binary format for capturing OPL register writes.
+//!
+//! An event log is a short header followed by a stream of register writes. Each write is encoded
+//! as a variable-length time delta (with the register file folded into the lowest bit), followed
+//! by the register and value bytes. Typical writes therefore take three bytes, which makes it
+//! cheap for emulators to keep hours of capture in memory.
+//!
+//! Header layout:
+//!
+//! | Offset | Size | Description                                   |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | Magic, `b"OPLE"`                              |
+//! | 4      | 1    | Format version, currently 1                   |
+//! | 5      | 4    | Tick rate in ticks per second, little-endian  |
+//!
+//! Each event is encoded as a LEB128 varint of `(delta_ticks << 1) | file`, where `file` is 1 for
+//! the secondary register file, followed by the low 8 bits of the register and the value.
+//!
+//! Event logs can be converted to and from DOSBox Raw OPL (DRO) and VGM files.
+
+use std::io::{self, Read, Write};
+
+use crate::{state, Opl3Chip, OplError};
+
+/// The magic bytes that begin every event log.
+pub const EVENT_LOG_MAGIC: [u8; 4] = *b"OPLE";
+/// The event log format version written by this library.
+pub const EVENT_LOG_VERSION: u8 = 1;
+/// The default tick rate, equal to the native sample rate of the OPL3.
+pub const DEFAULT_TICK_RATE: u32 = 49716;
+
+const HEADER_SIZE: usize = 9;
+
+/// A single register write in an event log.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OplEvent {
+    /// The absolute time of the write, in ticks since the start of the log.
+    pub time: u64,
+    /// The register written, in the range 0x000..=0x1FF. Addresses 0x100 and above are in the
+    /// secondary register file.
+    pub register: u16,
+    /// The value written.
+    pub value: u8,
+}
+
+/// The `EventLogWriter` struct encodes register writes into an event log on any `Write`.
+pub struct EventLogWriter<W: Write> {
+    writer: W,
+    last_time: u64,
+}
+
+impl<W: Write> EventLogWriter<W> {
+    /// Create a new `EventLogWriter`, writing the event log header immediately.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`    - The destination for the encoded event log.
+    /// * `tick_rate` - The number of ticks per second used for event times.
+    pub fn new(mut writer: W, tick_rate: u32) -> io::Result<Self> {
+        writer.write_all(&EVENT_LOG_MAGIC)?;
+        writer.write_all(&[EVENT_LOG_VERSION])?;
+        writer.write_all(&tick_rate.to_le_bytes())?;
+        Ok(EventLogWriter {
+            writer,
+            last_time: 0,
+        })
+    }
+
+    /// Append a register write to the event log.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The register write. Event times must not decrease.
+    pub fn write_event(&mut self, event: &OplEvent) -> io::Result<()> {
+        let mut buf = [0u8; 12];
+        let len = encode_event(&mut buf, self.last_time, event)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "event out of order"))?;
+        self.writer.write_all(&buf[..len])?;
+        self.last_time = event.time;
+        Ok(())
+    }
+
+    /// Consume the `EventLogWriter`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// The `EventLogReader` struct decodes an event log from any `Read`. It is an iterator over the
+/// events in the log.
+///
+/// Malformed events, including deltas that take the time past `u64::MAX`, end the iteration with
+/// an error of kind `InvalidData`.
+///
+/// # Example
+///
+/// ```
+/// use std::io::ErrorKind;
+/// use opl3_rs::event_log::{EventLogReader, EventLogWriter};
+///
+/// let mut data = EventLogWriter::new(Vec::new(), 1000).unwrap().into_inner();
+/// // Three events, each with the largest delta a varint can hold.
+/// for _ in 0..3 {
+///     data.extend_from_slice(&[0xFF; 9]);
+///     data.extend_from_slice(&[0x01, 0x20, 0x01]);
+/// }
+/// let mut reader = EventLogReader::new(&data[..]).unwrap();
+/// assert_eq!(reader.next().unwrap().unwrap().time, u64::MAX >> 1);
+/// assert_eq!(reader.next().unwrap().unwrap().time, u64::MAX - 1);
+/// assert_eq!(reader.next().unwrap().unwrap_err().kind(), ErrorKind::InvalidData);
+/// ```
+pub struct EventLogReader<R: Read> {
+    reader: R,
+    tick_rate: u32,
+    time: u64,
+}
+
+impl<R: Read> EventLogReader<R> {
+    /// Create a new `EventLogReader`, reading and validating the event log header immediately.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The source of the encoded event log.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let tick_rate = parse_header(&header)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an OPL event log"))?;
+        Ok(EventLogReader {
+            reader,
+            tick_rate,
+            time: 0,
+        })
+    }
+
+    /// Return the number of ticks per second used for event times.
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    fn read_event(&mut self) -> io::Result<Option<OplEvent>> {
+        let mut varint = 0u64;
+        let mut shift = 0;
+        loop {
+            let mut byte = [0u8];
+            if self.reader.read(&mut byte)? == 0 {
+                return if shift == 0 {
+                    Ok(None)
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                };
+            }
+            if shift > 63 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad delta"));
+            }
+            varint |= ((byte[0] & 0x7F) as u64) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut reg_value = [0u8; 2];
+        self.reader.read_exact(&mut reg_value)?;
+        self.time = self
+            .time
+            .checked_add(varint >> 1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "time overflow"))?;
+        Ok(Some(OplEvent {
+            time: self.time,
+            register: ((varint & 1) as u16) << 8 | reg_value[0] as u16,
+            value: reg_value[1],
+        }))
+    }
+}
+
+impl<R: Read> Iterator for EventLogReader<R> {
+    type Item = io::Result<OplEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_event().transpose()
+    }
+}
+
+/// The `EventLog` struct is an in-memory event log, stored in its compact encoded form.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::event_log::{EventLog, OplEvent};
+///
+/// let mut log = EventLog::new(49716);
+/// log.push(OplEvent { time: 0, register: 0x20, value: 0x01 });
+/// log.push(OplEvent { time: 100, register: 0x1B0, value: 0x31 });
+///
+/// let events: Vec<OplEvent> = log.iter().collect();
+/// assert_eq!(events.len(), 2);
+/// assert_eq!(events[1].register, 0x1B0);
+/// assert_eq!(log.duration_ticks(), 100);
+/// ```
+#[derive(Clone, Debug)]
+pub struct EventLog {
+    tick_rate: u32,
+    data: Vec<u8>,
+    last_time: u64,
+    len: usize,
+}
+
+impl EventLog {
+    /// Create a new, empty `EventLog`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tick_rate` - The number of ticks per second used for event times.
+    pub fn new(tick_rate: u32) -> Self {
+        let mut data = Vec::with_capacity(HEADER_SIZE);
+        data.extend_from_slice(&EVENT_LOG_MAGIC);
+        data.push(EVENT_LOG_VERSION);
+        data.extend_from_slice(&tick_rate.to_le_bytes());
+        EventLog {
+            tick_rate,
+            data,
+            last_time: 0,
+            len: 0,
+        }
+    }
+
+    /// Create an `EventLog` from its encoded form, validating every event.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The encoded event log, as produced by `as_bytes` or `EventLogWriter`.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, OplError> {
+        let reader =
+            EventLogReader::new(&data[..]).map_err(|_| OplError::InvalidFormat("event log"))?;
+        let tick_rate = reader.tick_rate();
+        let mut last_time = 0;
+        let mut len = 0;
+        for event in reader {
+            last_time = event
+                .map_err(|_| OplError::InvalidFormat("event log"))?
+                .time;
+            len += 1;
+        }
+        Ok(EventLog {
+            tick_rate,
+            data,
+            last_time,
+            len,
+        })
+    }
+
+    /// Return the number of ticks per second used for event times.
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    /// Return the number of events in the log.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return `true` if the log contains no events.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the time of the last event in the log, in ticks.
+    pub fn duration_ticks(&self) -> u64 {
+        self.last_time
+    }
+
+    /// Return the encoded form of the log.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Append a register write to the log. Events that would occur before the last event in the
+    /// log are moved forward to the time of the last event, so that the log stays in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The register write.
+    pub fn push(&mut self, mut event: OplEvent) {
+        event.time = event.time.max(self.last_time);
+        let mut buf = [0u8; 12];
+        if let Some(len) = encode_event(&mut buf, self.last_time, &event) {
+            self.data.extend_from_slice(&buf[..len]);
+            self.last_time = event.time;
+            self.len += 1;
+        }
+    }
+
+    /// Return an iterator over the events in the log.
+    pub fn iter(&self) -> impl Iterator<Item = OplEvent> + '_ {
+        // The log is validated on construction, so decoding can't fail.
+        EventLogReader {
+            reader: &self.data[HEADER_SIZE..],
+            tick_rate: self.tick_rate,
+            time: 0,
+        }
+        .map_while(Result::ok)
+    }
+
+    /// Convert a time in ticks to microseconds.
+    pub fn ticks_to_usec(&self, ticks: u64) -> f64 {
+        ticks as f64 * 1_000_000.0 / self.tick_rate as f64
+    }
+}
+
+impl Opl3Chip {
+    /// Create a chip in the state an event log leaves it in at a point in time, by replaying the
+    /// log's writes without generating any audio. This is much cheaper than rendering the log up to
+    /// that point, and unlike a savestate needs nothing but the log, which suits deterministic
+    /// emulators seeking through a recording.
+    ///
+    /// Only the final value of each register is written, in an order that brings the chip to the
+    /// same register state as the whole log: the mode registers first, then the instruments, then
+    /// the key-on and rhythm registers. Time is not simulated, so notes keyed on by the log start
+    /// from the beginning of their attack, and phases and the LFOs start from zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `log`         - The event log to replay.
+    /// * `up_to_time`  - The time to reconstruct the chip at, in the log's ticks. Events at this
+    ///                   time are included.
+    /// * `sample_rate` - The sample rate of the new chip.
+    ///
+    /// # Returns
+    ///
+    /// The reconstructed chip.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::event_log::{EventLog, OplEvent};
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut log = EventLog::new(49716);
+    /// log.push(OplEvent { time: 0, register: 0xA0, value: 0x98 });
+    /// log.push(OplEvent { time: 100, register: 0xB0, value: 0x31 });
+    /// log.push(OplEvent { time: 200, register: 0xB0, value: 0x11 });
+    ///
+    /// let chip = Opl3Chip::from_event_log(&log, 150, 44100);
+    /// assert_eq!(chip.status().channels[0].f_number, 0x198);
+    /// assert!(chip.status().channels[0].operators[1].keyed);
+    /// let chip = Opl3Chip::from_event_log(&log, 200, 44100);
+    /// assert!(!chip.status().channels[0].operators[1].keyed);
+    /// ```
+    pub fn from_event_log(log: &EventLog, up_to_time: u64, sample_rate: u32) -> Self {
+        let mut registers = [None; 0x200];
+        for event in log.iter().take_while(|event| event.time <= up_to_time) {
+            registers[event.register as usize & 0x1FF] = Some(event.value);
+        }
+        let mut chip = Opl3Chip::new(sample_rate);
+        for reg in state::replay_order() {
+            if let Some(value) = registers[reg as usize] {
+                chip.write_register(reg, value);
+            }
+        }
+        chip
+    }
+}
+
+fn parse_header(header: &[u8]) -> Option<u32> {
+    if header.len() < HEADER_SIZE
+        || header[0..4] != EVENT_LOG_MAGIC
+        || header[4] != EVENT_LOG_VERSION
+    {
+        return None;
+    }
+    let tick_rate = u32::from_le_bytes([header[5], header[6], header[7], header[8]]);
+    (tick_rate != 0).then_some(tick_rate)
+}
+
+/// Encode an event into `buf`, returning the encoded length, or `None` if the event occurs
+/// before `last_time`.
+fn encode_event(buf: &mut [u8; 12], last_time: u64, event: &OplEvent) -> Option<usize> {
+    let delta = event.time.checked_sub(last_time)?;
+    // Deltas that don't fit in 63 bits are clamped. That's over 5 million years at 49716Hz.
+    let mut varint = (delta.min(u64::MAX >> 1) << 1) | ((event.register >> 8) & 1) as u64;
+    let mut len = 0;
+    loop {
+        let byte = (varint & 0x7F) as u8;
+        varint >>= 7;
+        if varint == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    buf[len] = event.register as u8;
+    buf[len + 1] = event.value;
+    Some(len + 2)
+}
@@ -0,0 +1,367 @@
+//! Offline rendering of event logs to audio files.
+//!
+//! Rendered audio is written to an `AudioSink`. A WAV sink is always available. A FLAC sink is
+//! available with the `flac` feature, and an Ogg Vorbis sink with the `vorbis` feature. For live
+//! streaming, a low latency Opus sink is available with the `opus` feature.
+//!
+//! # Example
+//!
+//! ```
+//! use std::io::Cursor;
+//! use opl3_rs::event_log::{EventLog, OplEvent};
+//! use opl3_rs::export::{render_event_log, AudioSink, WavSink};
+//!
+//! let mut log = EventLog::new(49716);
+//! log.push(OplEvent { time: 0, register: 0xB0, value: 0x32 });
+//!
+//! let mut sink = WavSink::new(Cursor::new(Vec::new()), 44100, 2).unwrap();
+//! render_event_log(&log, 44100, 100, &mut sink).unwrap();
+//! sink.finish().unwrap();
+//! ```
+
+use std::io::{Seek, SeekFrom, Write};
+
+#[cfg(feature = "flac")]
+pub use crate::flac::FlacSink;
+
+use crate::event_log::EventLog;
+use crate::opl_core::OplCore;
+#[cfg(feature = "vorbis")]
+use crate::sample::Sample;
+use crate::{Opl3Chip, OplError};
+
+/// Samples are rendered in blocks of at most this many frames between register writes.
+const RENDER_BLOCK_FRAMES: usize = 4096;
+
+/// The `AudioSink` trait is implemented by destinations for rendered audio.
+pub trait AudioSink {
+    /// Write interleaved samples to the sink. The number of samples must be a multiple of the
+    /// sink's channel count.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - The interleaved samples to write.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError>;
+
+    /// Finish writing, flushing any buffered audio and finalizing the file. No samples may be
+    /// written after the sink is finished.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    fn finish(&mut self) -> Result<(), OplError>;
+}
+
+/// Rendering into a `Vec<i16>` appends the samples to it.
+impl AudioSink for Vec<i16> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        self.extend_from_slice(samples);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), OplError> {
+        Ok(())
+    }
+}
+
+/// Render an event log as stereo audio into a sink.
+///
+/// # Arguments
+///
+/// * `log`         - The event log to render.
+/// * `sample_rate` - The sample rate to render at. This should match the sample rate the sink was
+///                   created with.
+/// * `tail_ms`     - The number of milliseconds of audio to render after the last event, so that
+///                   released notes can decay.
+/// * `sink`        - The sink to write to. It must have been created with 2 channels. The sink is
+///                   not finished, so that several logs can be rendered into one file.
+///
+/// # Returns
+///
+/// A Result containing either `()` on success or an `OplError` on failure.
+pub fn render_event_log(
+    log: &EventLog,
+    sample_rate: u32,
+    tail_ms: u32,
+    sink: &mut dyn AudioSink,
+) -> Result<(), OplError> {
+    render_event_log_with(&mut Opl3Chip::new(sample_rate), log, tail_ms, sink)
+}
+
+/// Render an event log as stereo audio into a sink, on any chip implementing `OplCore`. The
+/// chip is rendered from its current state, at its own sample rate.
+///
+/// # Arguments
+///
+/// * `chip`    - The chip to render with.
+/// * `log`     - The event log to render.
+/// * `tail_ms` - The number of milliseconds of audio to render after the last event.
+/// * `sink`    - The sink to write to. It must have been created with 2 channels.
+///
+/// # Returns
+///
+/// A Result containing either `()` on success or an `OplError` on failure.
+pub fn render_event_log_with(
+    chip: &mut dyn OplCore,
+    log: &EventLog,
+    tail_ms: u32,
+    sink: &mut dyn AudioSink,
+) -> Result<(), OplError> {
+    let sample_rate = chip.sample_rate();
+    let mut buffer = vec![0i16; RENDER_BLOCK_FRAMES * 2];
+    let mut position = 0u64;
+    let mut render = |chip: &mut dyn OplCore, mut frames: u64| -> Result<(), OplError> {
+        while frames > 0 {
+            let block = frames.min(RENDER_BLOCK_FRAMES as u64) as usize;
+            chip.generate_stream(&mut buffer[..block * 2])?;
+            sink.write_samples(&buffer[..block * 2])?;
+            frames -= block as u64;
+        }
+        Ok(())
+    };
+
+    for event in log.iter() {
+        let due = event.time * sample_rate as u64 / log.tick_rate() as u64;
+        render(chip, due - position.min(due))?;
+        position = position.max(due);
+        chip.write_register(event.register, event.value);
+    }
+    render(chip, tail_ms as u64 * sample_rate as u64 / 1000)
+}
+
+/// The `WavSink` struct writes 16-bit PCM WAV files.
+pub struct WavSink<W: Write + Seek> {
+    writer: W,
+    channels: u16,
+    data_bytes: u32,
+    finished: bool,
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    /// Create a new `WavSink`, writing the WAV header immediately. The header is completed when
+    /// the sink is finished.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`      - The destination for the WAV file.
+    /// * `sample_rate` - The sample rate of the audio.
+    /// * `channels`    - The number of interleaved channels, usually 2, or 4 for `Frame4` output.
+    pub fn new(mut writer: W, sample_rate: u32, channels: u16) -> Result<Self, OplError> {
+        let channels = channels.max(1);
+        let block_align = channels * 2;
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(WavSink {
+            writer,
+            channels,
+            data_bytes: 0,
+            finished: false,
+        })
+    }
+
+    /// Return the number of interleaved channels.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Consume the sink, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.writer.write_all(&bytes)?;
+        self.data_bytes = self.data_bytes.saturating_add(bytes.len() as u32);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), OplError> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(36u32.saturating_add(self.data_bytes)).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The `VorbisSink` struct encodes audio to Ogg Vorbis, using libvorbis through the `vorbis_rs`
+/// crate.
+#[cfg(feature = "vorbis")]
+pub struct VorbisSink<W: Write> {
+    encoder: Option<vorbis_rs::VorbisEncoder<W>>,
+    channels: usize,
+    planar: Vec<Vec<f32>>,
+}
+
+#[cfg(feature = "vorbis")]
+impl<W: Write> VorbisSink<W> {
+    /// Create a new `VorbisSink` with libvorbis's default quality setting.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`      - The destination for the Ogg Vorbis stream.
+    /// * `sample_rate` - The sample rate of the audio.
+    /// * `channels`    - The number of interleaved channels.
+    pub fn new(writer: W, sample_rate: u32, channels: u8) -> Result<Self, OplError> {
+        let sample_rate =
+            std::num::NonZeroU32::new(sample_rate).ok_or(OplError::InvalidFormat("sample rate"))?;
+        let channel_count =
+            std::num::NonZeroU8::new(channels).ok_or(OplError::InvalidFormat("channel count"))?;
+        let encoder = vorbis_rs::VorbisEncoderBuilder::new(sample_rate, channel_count, writer)
+            .and_then(|mut builder| builder.build())
+            .map_err(|e| OplError::Encoder(e.to_string()))?;
+        Ok(VorbisSink {
+            encoder: Some(encoder),
+            channels: channels as usize,
+            planar: vec![Vec::new(); channels as usize],
+        })
+    }
+}
+
+#[cfg(feature = "vorbis")]
+impl<W: Write> AudioSink for VorbisSink<W> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        let encoder = self.encoder.as_mut().ok_or(OplError::StreamClosed)?;
+        for channel in self.planar.iter_mut() {
+            channel.clear();
+        }
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, &sample) in self.planar.iter_mut().zip(frame) {
+                channel.push(f32::from_i16(sample));
+            }
+        }
+        encoder
+            .encode_audio_block(&self.planar)
+            .map_err(|e| OplError::Encoder(e.to_string()))
+    }
+
+    fn finish(&mut self) -> Result<(), OplError> {
+        if let Some(encoder) = self.encoder.take() {
+            encoder
+                .finish()
+                .map_err(|e| OplError::Encoder(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// The duration of each packet produced by `OpusSink`, in milliseconds. Opus supports frames of
+/// 2.5 to 60ms; 10ms keeps latency low without costing too much bandwidth.
+#[cfg(feature = "opus")]
+pub const OPUS_FRAME_MS: u32 = 10;
+
+/// The `OpusSink` struct encodes audio to raw Opus packets for low latency streaming, and passes
+/// each packet to a callback as soon as it is encoded. Packets are not framed in a container, so
+/// they can be sent directly over a datagram socket or WebSocket.
+///
+/// Opus only supports sample rates of 8000, 12000, 16000, 24000 and 48000Hz, so the chip should
+/// be created at one of those rates, usually 48000Hz.
+#[cfg(feature = "opus")]
+pub struct OpusSink<F: FnMut(&[u8])> {
+    encoder: opus::Encoder,
+    frame_samples: usize,
+    pending: Vec<i16>,
+    packet: Vec<u8>,
+    on_packet: F,
+}
+
+#[cfg(feature = "opus")]
+impl<F: FnMut(&[u8])> OpusSink<F> {
+    /// Create a new `OpusSink`, using the Opus low delay mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate of the audio. It must be a rate supported by Opus.
+    /// * `channels`    - The number of interleaved channels, either 1 or 2.
+    /// * `bitrate`     - The target bitrate, in bits per second.
+    /// * `on_packet`   - A callback that receives each encoded packet.
+    pub fn new(
+        sample_rate: u32,
+        channels: u8,
+        bitrate: i32,
+        on_packet: F,
+    ) -> Result<Self, OplError> {
+        let opus_channels = match channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => return Err(OplError::InvalidFormat("channel count")),
+        };
+        let mut encoder =
+            opus::Encoder::new(sample_rate, opus_channels, opus::Application::LowDelay)
+                .map_err(|e| OplError::Encoder(e.to_string()))?;
+        encoder
+            .set_bitrate(opus::Bitrate::Bits(bitrate))
+            .map_err(|e| OplError::Encoder(e.to_string()))?;
+        let frame_samples = (sample_rate * OPUS_FRAME_MS / 1000) as usize * channels as usize;
+        Ok(OpusSink {
+            encoder,
+            frame_samples,
+            pending: Vec::with_capacity(frame_samples),
+            // The largest packet Opus will produce.
+            packet: vec![0; 1276 * 3],
+            on_packet,
+        })
+    }
+
+    fn encode_pending(&mut self) -> Result<(), OplError> {
+        let len = self
+            .encoder
+            .encode(&self.pending, &mut self.packet)
+            .map_err(|e| OplError::Encoder(e.to_string()))?;
+        (self.on_packet)(&self.packet[..len]);
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "opus")]
+impl<F: FnMut(&[u8])> AudioSink for OpusSink<F> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        let mut samples = samples;
+        while !samples.is_empty() {
+            let take = (self.frame_samples - self.pending.len()).min(samples.len());
+            self.pending.extend_from_slice(&samples[..take]);
+            samples = &samples[take..];
+            if self.pending.len() == self.frame_samples {
+                self.encode_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), OplError> {
+        // Pad the last partial frame with silence, as Opus only encodes whole frames.
+        if !self.pending.is_empty() {
+            self.pending.resize(self.frame_samples, 0);
+            self.encode_pending()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+//! A C interface to `midi_synth::OplMidiSynth`, shaped for audio plugin wrappers.
+//!
+//! Plugin formats such as LV2 and LADSPA hand an instrument its sample rate when it is created,
+//! then ask it for blocks of audio along with the MIDI events timed within each block. The
+//! functions here do just that, so a wrapper only has to translate its host's structures:
+//! create a synth with `opl3_synth_new`, load instruments with `opl3_synth_load_bank`, and call
+//! `opl3_synth_process` from the host's run callback.
+//!
+//! The functions are exported with the `ffi` feature. To link them from C, build the crate as a
+//! C library, such as with `cargo rustc --release --features ffi --crate-type cdylib`, and
+//! declare them as follows:
+//!
+//! ```c
+//! #include <stddef.h>
+//! #include <stdint.h>
+//!
+//! typedef struct OplMidiSynth OplMidiSynth;
+//!
+//! typedef struct {
+//!     uint32_t frame;       /* The frame of the block the event plays at. */
+//!     uint32_t size;        /* The length of the message, in bytes. */
+//!     const uint8_t *data;  /* The MIDI message, starting with its status byte. */
+//! } OplMidiEvent;
+//!
+//! OplMidiSynth *opl3_synth_new(uint32_t sample_rate);
+//! void opl3_synth_free(OplMidiSynth *synth);
+//! int32_t opl3_synth_load_bank(OplMidiSynth *synth, const uint8_t *data, size_t len);
+//! void opl3_synth_all_sound_off(OplMidiSynth *synth);
+//! void opl3_synth_process(OplMidiSynth *synth, const OplMidiEvent *events, size_t event_count,
+//!                         float *left, float *right, uint32_t frames);
+//! ```
+//!
+//! None of the functions allocate or lock except `opl3_synth_new` and `opl3_synth_load_bank`, so
+//! `opl3_synth_process` and `opl3_synth_all_sound_off` are safe to call from a real-time audio
+//! thread. Functions passed a null synth do nothing.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::bank::{Bank, BankSet};
+//! use opl3_rs::ffi::*;
+//! use opl3_rs::patch::{Operator, OperatorPair, Patch};
+//!
+//! let mut bank = Bank::new("Strings");
+//! bank.patches.push(Patch::new(OperatorPair {
+//!     modulator: Operator::new(0x21, 0x18, 0x71, 0x15, 0x00),
+//!     carrier: Operator::new(0x21, 0x00, 0x71, 0x15, 0x00),
+//!     feedback_connection: 0x0A,
+//! }));
+//! let wopl = BankSet { melodic: vec![bank], percussion: Vec::new() }.to_wopl();
+//!
+//! let note_on = [0x90, 60, 100];
+//! let events = [OplMidiEvent { frame: 256, size: 3, data: note_on.as_ptr() }];
+//! let (mut left, mut right) = (vec![0.0f32; 1024], vec![0.0f32; 1024]);
+//! unsafe {
+//!     let synth = opl3_synth_new(44100);
+//!     assert_eq!(opl3_synth_load_bank(synth, wopl.as_ptr(), wopl.len()), 0);
+//!     opl3_synth_process(synth, events.as_ptr(), 1, left.as_mut_ptr(), right.as_mut_ptr(), 1024);
+//!     opl3_synth_free(synth);
+//! }
+//! // The note starts at the frame it was timed at.
+//! assert!(left[..256].iter().all(|&sample| sample == 0.0));
+//! assert!(left[256..].iter().any(|&sample| sample != 0.0));
+//!
+//! // Sample rates too low to render at are refused.
+//! assert!(opl3_synth_new(48).is_null());
+//! ```
+
+use std::slice;
+
+use crate::bank::BankSet;
+use crate::midi_synth::OplMidiSynth;
+use crate::Opl3Chip;
+
+/// The `OplMidiEvent` struct is a MIDI message timed within a block passed to
+/// `opl3_synth_process`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct OplMidiEvent {
+    /// The frame of the block the event plays at, before the sample at that frame is rendered.
+    pub frame: u32,
+    /// The length of the message, in bytes.
+    pub size: u32,
+    /// The MIDI message, starting with its status byte, as handled by
+    /// `OplMidiSynth::process_message`.
+    pub data: *const u8,
+}
+
+/// Create a synth with no instruments.
+///
+/// # Arguments
+///
+/// * `sample_rate` - The sample rate of the audio to render.
+///
+/// # Returns
+///
+/// The synth, to be freed with `opl3_synth_free`, or null if the sample rate is outside
+/// `Opl3Chip::MIN_SAMPLE_RATE` to `Opl3Chip::MAX_SAMPLE_RATE`.
+#[no_mangle]
+pub extern "C" fn opl3_synth_new(sample_rate: u32) -> *mut OplMidiSynth {
+    if Opl3Chip::check_sample_rate(sample_rate).is_err() {
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(OplMidiSynth::new(sample_rate, BankSet::default())))
+}
+
+/// Free a synth created by `opl3_synth_new`.
+///
+/// # Safety
+///
+/// `synth` must be null or a synth returned by `opl3_synth_new`, and not used after.
+#[no_mangle]
+pub unsafe extern "C" fn opl3_synth_free(synth: *mut OplMidiSynth) {
+    if !synth.is_null() {
+        drop(Box::from_raw(synth));
+    }
+}
+
+/// Replace the instruments of a synth with those of a WOPL or DMX OP2 bank file. Every sounding
+/// note is stopped.
+///
+/// # Arguments
+///
+/// * `synth` - The synth.
+/// * `data`  - The contents of the bank file.
+/// * `len`   - The length of the bank file, in bytes.
+///
+/// # Returns
+///
+/// 0 on success, or -1 if the bank file cannot be read, in which case the instruments are
+/// unchanged.
+///
+/// # Safety
+///
+/// `synth` must be null or a live synth returned by `opl3_synth_new`, and `data` must point to
+/// `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn opl3_synth_load_bank(
+    synth: *mut OplMidiSynth,
+    data: *const u8,
+    len: usize,
+) -> i32 {
+    let Some(synth) = synth.as_mut() else {
+        return -1;
+    };
+    if data.is_null() {
+        return -1;
+    }
+    let data = slice::from_raw_parts(data, len);
+    match BankSet::from_wopl(data).or_else(|_| BankSet::from_op2(data)) {
+        Ok(banks) => {
+            synth.set_banks(banks);
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Stop every note of a synth immediately, such as when the host deactivates the plugin.
+///
+/// # Safety
+///
+/// `synth` must be null or a live synth returned by `opl3_synth_new`.
+#[no_mangle]
+pub unsafe extern "C" fn opl3_synth_all_sound_off(synth: *mut OplMidiSynth) {
+    if let Some(synth) = synth.as_mut() {
+        synth.all_sound_off();
+    }
+}
+
+/// Render a block of audio, playing the MIDI events timed within it. Each event plays before the
+/// sample at its frame is rendered, so notes start exactly where the host placed them. Events
+/// should be in order of their frames; an event earlier than the one before it is played at the
+/// frame of that one, and events past the end of the block are played after its last sample.
+///
+/// # Arguments
+///
+/// * `synth`       - The synth.
+/// * `events`      - The MIDI events, or null if there are none.
+/// * `event_count` - The number of events.
+/// * `left`        - The buffer for the left channel, in the range -1.0 to 1.0.
+/// * `right`       - The buffer for the right channel.
+/// * `frames`      - The length of the block, and of each buffer, in frames.
+///
+/// # Safety
+///
+/// `synth` must be null or a live synth returned by `opl3_synth_new`, `events` must point to
+/// `event_count` events whose data points to `size` readable bytes each, and `left` and `right`
+/// must each point to `frames` writable floats, without overlapping.
+#[no_mangle]
+pub unsafe extern "C" fn opl3_synth_process(
+    synth: *mut OplMidiSynth,
+    events: *const OplMidiEvent,
+    event_count: usize,
+    left: *mut f32,
+    right: *mut f32,
+    frames: u32,
+) {
+    let Some(synth) = synth.as_mut() else {
+        return;
+    };
+    if left.is_null() || right.is_null() {
+        return;
+    }
+    let frames = frames as usize;
+    let left = slice::from_raw_parts_mut(left, frames);
+    let right = slice::from_raw_parts_mut(right, frames);
+    let events = if events.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(events, event_count)
+    };
+
+    let messages = events
+        .iter()
+        .filter(|event| !event.data.is_null())
+        .map(|event| {
+            let message = slice::from_raw_parts(event.data, event.size as usize);
+            (event.frame as usize, message)
+        });
+    synth.render_planar_timed(messages, left, right);
+}
@@ -0,0 +1,177 @@
+//! Integer-only gain and mixing, for targets without a floating point unit.
+//!
+//! The synthesis and resampling code in Nuked-OPL3 uses integer arithmetic only, so every
+//! `Opl3Chip` generate function, and `DoubleBuffer`, produce audio without touching floating
+//! point. The `fixed-point` feature adds this module, which provides the gain and stereo fold
+//! helpers of the output path in fixed point, and compiles it and the other output path modules
+//! with floating point arithmetic denied under clippy, so that the guarantee is checked.
+//!
+//! `Opl3Device::run` takes its time in microseconds as an `f64` and is not part of the output
+//! path. Embedded users can drive an `Opl3Chip` directly instead.
+
+#![deny(clippy::float_arithmetic)]
+
+use crate::{Frame4, OplError};
+
+const GAIN_FRACTION_BITS: u32 = 16;
+
+/// The `Gain` struct is a signed fixed point gain with 16 fractional bits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Gain(i32);
+
+impl Gain {
+    /// A gain of 1, leaving samples unchanged.
+    pub const UNITY: Gain = Gain(1 << GAIN_FRACTION_BITS);
+    /// A gain of 0, silencing samples.
+    pub const MUTE: Gain = Gain(0);
+    /// A gain of 1/√2, or -3dB.
+    pub const MINUS_3DB: Gain = Gain(46341);
+
+    /// Create a `Gain` from its raw fixed point representation, where 65536 is unity.
+    pub const fn from_raw(raw: i32) -> Gain {
+        Gain(raw)
+    }
+
+    /// Create a `Gain` from a ratio, such as 1/2 for -6dB.
+    ///
+    /// # Arguments
+    ///
+    /// * `numerator`   - The numerator of the ratio.
+    /// * `denominator` - The denominator of the ratio, which must not be 0.
+    pub const fn from_ratio(numerator: i32, denominator: i32) -> Gain {
+        Gain(((numerator as i64) << GAIN_FRACTION_BITS) as i32 / denominator)
+    }
+
+    /// Return the raw fixed point representation of the gain, where 65536 is unity.
+    pub const fn raw(&self) -> i32 {
+        self.0
+    }
+
+    /// Apply the gain to a sample, rounding to nearest and saturating to the i16 range.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::fixed::Gain;
+    ///
+    /// assert_eq!(Gain::from_ratio(1, 2).apply(1001), 501);
+    /// assert_eq!(Gain::from_ratio(4, 1).apply(i16::MAX), i16::MAX);
+    /// ```
+    pub fn apply(&self, sample: i16) -> i16 {
+        clip(self.scale(sample))
+    }
+
+    /// Apply the gain to every sample in a buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to the samples to scale in place.
+    pub fn apply_slice(&self, buffer: &mut [i16]) {
+        for sample in buffer {
+            *sample = self.apply(*sample);
+        }
+    }
+
+    /// Scale a sample, returning the result with the fractional bits still present.
+    fn scale(&self, sample: i16) -> i64 {
+        sample as i64 * self.0 as i64
+    }
+}
+
+/// The `FixedStereoFold` struct is the fixed point equivalent of `mix::StereoFold`. It folds all
+/// four DAC outputs down to a stereo pair, with a configurable (left, right) gain for each output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FixedStereoFold {
+    /// The (left, right) gain applied to output A.
+    pub a: (Gain, Gain),
+    /// The (left, right) gain applied to output B.
+    pub b: (Gain, Gain),
+    /// The (left, right) gain applied to output C.
+    pub c: (Gain, Gain),
+    /// The (left, right) gain applied to output D.
+    pub d: (Gain, Gain),
+}
+
+impl Default for FixedStereoFold {
+    /// The default fold keeps A and B as left and right at unity gain, and mixes C and D into left
+    /// and right at -3dB.
+    fn default() -> Self {
+        FixedStereoFold {
+            a: (Gain::UNITY, Gain::MUTE),
+            b: (Gain::MUTE, Gain::UNITY),
+            c: (Gain::MINUS_3DB, Gain::MUTE),
+            d: (Gain::MUTE, Gain::MINUS_3DB),
+        }
+    }
+}
+
+impl FixedStereoFold {
+    /// Fold a single 4-channel frame down to stereo. The result is clipped to the i16 range.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame to fold.
+    ///
+    /// # Returns
+    ///
+    /// The left and right samples.
+    pub fn fold_frame(&self, frame: Frame4) -> [i16; 2] {
+        let inputs = [
+            (frame.a, self.a),
+            (frame.b, self.b),
+            (frame.c, self.c),
+            (frame.d, self.d),
+        ];
+        let (mut left, mut right) = (0i64, 0i64);
+        for (sample, (gain_l, gain_r)) in inputs {
+            left += gain_l.scale(sample);
+            right += gain_r.scale(sample);
+        }
+        [clip(left), clip(right)]
+    }
+
+    /// Fold a slice of 4-channel frames down to interleaved stereo.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The 4-channel frames to fold.
+    /// * `out`    - A mutable reference to a buffer that will receive the interleaved stereo
+    ///              output. It must hold at least `frames.len() * 2` samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Frame4;
+    /// use opl3_rs::fixed::{FixedStereoFold, Gain};
+    ///
+    /// let fold = FixedStereoFold {
+    ///     c: (Gain::UNITY, Gain::MUTE),
+    ///     d: (Gain::MUTE, Gain::UNITY),
+    ///     ..Default::default()
+    /// };
+    /// let frames = [Frame4 { a: 100, b: 200, c: 10, d: 20 }];
+    /// let mut out = [0i16; 2];
+    /// fold.fold(&frames, &mut out).unwrap();
+    /// assert_eq!(out, [110, 220]);
+    /// ```
+    pub fn fold(&self, frames: &[Frame4], out: &mut [i16]) -> Result<(), OplError> {
+        if out.len() < frames.len() * 2 {
+            return Err(OplError::BufferUndersized);
+        }
+        for (frame, out) in frames.iter().zip(out.chunks_exact_mut(2)) {
+            out.copy_from_slice(&self.fold_frame(*frame));
+        }
+        Ok(())
+    }
+}
+
+/// Round a scaled sample to nearest, drop its fractional bits and clip it to the i16 range.
+#[inline]
+fn clip(scaled: i64) -> i16 {
+    let rounded = (scaled + (1 << (GAIN_FRACTION_BITS - 1))) >> GAIN_FRACTION_BITS;
+    rounded.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+}
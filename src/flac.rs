@@ -0,0 +1,353 @@
+//! A small FLAC encoder, enabled with the `flac` feature.
+//!
+//! Each block of audio is encoded with whichever fixed linear predictor gives the smallest
+//! residual, followed by Rice coding of the residual. Silent blocks, which are very common in
+//! AdLib music, are encoded as constant subframes. This falls short of the compression of the
+//! reference encoder, but is simple, fast and has no dependencies.
+
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::export::AudioSink;
+use crate::OplError;
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_RICE_PARAMETER: u32 = 14;
+/// The offset of the STREAMINFO metadata block, just after the `fLaC` marker.
+const STREAMINFO_OFFSET: u64 = 4;
+
+/// The `FlacSink` struct encodes 16-bit audio to a FLAC file.
+pub struct FlacSink<W: Write + Seek> {
+    writer: W,
+    sample_rate: u32,
+    channels: usize,
+    pending: Vec<i16>,
+    frame_number: u32,
+    total_frames: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+    finished: bool,
+}
+
+impl<W: Write + Seek> FlacSink<W> {
+    /// Create a new `FlacSink`, writing the FLAC header immediately. The header is completed when
+    /// the sink is finished.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`      - The destination for the FLAC file.
+    /// * `sample_rate` - The sample rate of the audio.
+    /// * `channels`    - The number of interleaved channels, from 1 to 8.
+    pub fn new(writer: W, sample_rate: u32, channels: u16) -> Result<Self, OplError> {
+        if !(1..=8).contains(&channels) || sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(OplError::InvalidFormat("FLAC stream parameter"));
+        }
+        let mut sink = FlacSink {
+            writer,
+            sample_rate,
+            channels: channels as usize,
+            pending: Vec::with_capacity(BLOCK_SIZE * channels as usize),
+            frame_number: 0,
+            total_frames: 0,
+            min_frame_size: u32::MAX,
+            max_frame_size: 0,
+            finished: false,
+        };
+        sink.writer.write_all(b"fLaC")?;
+        sink.write_streaminfo()?;
+        Ok(sink)
+    }
+
+    /// Consume the sink, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_streaminfo(&mut self) -> Result<(), OplError> {
+        let mut bits = BitWriter::default();
+        // Metadata block header: last block, type 0 (STREAMINFO), 34 bytes.
+        bits.write(1, 1);
+        bits.write(0, 7);
+        bits.write(34, 24);
+        bits.write(BLOCK_SIZE as u64, 16);
+        bits.write(BLOCK_SIZE as u64, 16);
+        let min_frame_size = if self.min_frame_size == u32::MAX {
+            0
+        } else {
+            self.min_frame_size
+        };
+        bits.write(min_frame_size as u64, 24);
+        bits.write(self.max_frame_size as u64, 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.channels as u64 - 1, 3);
+        bits.write(BITS_PER_SAMPLE as u64 - 1, 5);
+        bits.write(self.total_frames.min((1 << 36) - 1), 36);
+        // An MD5 signature of zero means that the signature is unknown.
+        for _ in 0..4 {
+            bits.write(0, 32);
+        }
+        self.writer.write_all(&bits.into_bytes())?;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        let block_size = samples.len() / self.channels;
+        let mut bits = BitWriter::default();
+
+        // Frame header: sync code, fixed block size, 16-bit block size and sample rate from
+        // STREAMINFO, independent channels, 16 bits per sample.
+        bits.write(0b1111_1111_1111_1000, 16);
+        bits.write(0b0111, 4);
+        bits.write(0b0000, 4);
+        bits.write(self.channels as u64 - 1, 4);
+        bits.write(0b100, 3);
+        bits.write(0, 1);
+        write_utf8(&mut bits, self.frame_number);
+        bits.write(block_size as u64 - 1, 16);
+        let crc = crc8(bits.bytes());
+        bits.write(crc as u64, 8);
+
+        let mut channel = Vec::with_capacity(block_size);
+        for c in 0..self.channels {
+            channel.clear();
+            channel.extend(
+                samples
+                    .iter()
+                    .skip(c)
+                    .step_by(self.channels)
+                    .map(|&s| s as i32),
+            );
+            write_subframe(&mut bits, &channel);
+        }
+        bits.align();
+        let crc = crc16(bits.bytes());
+        bits.write(crc as u64, 16);
+
+        let frame = bits.into_bytes();
+        self.writer.write_all(&frame)?;
+        self.min_frame_size = self.min_frame_size.min(frame.len() as u32);
+        self.max_frame_size = self.max_frame_size.max(frame.len() as u32);
+        self.frame_number = self.frame_number.wrapping_add(1);
+        self.total_frames += block_size as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> AudioSink for FlacSink<W> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        let block_samples = BLOCK_SIZE * self.channels;
+        let mut samples = samples;
+        while !samples.is_empty() {
+            let take = (block_samples - self.pending.len()).min(samples.len());
+            self.pending.extend_from_slice(&samples[..take]);
+            samples = &samples[take..];
+            if self.pending.len() == block_samples {
+                let block = std::mem::take(&mut self.pending);
+                self.write_frame(&block)?;
+                self.pending = block;
+                self.pending.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), OplError> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let whole_frames = self.pending.len() / self.channels * self.channels;
+        if whole_frames > 0 {
+            let block = std::mem::take(&mut self.pending);
+            self.write_frame(&block[..whole_frames])?;
+        }
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.write_streaminfo()?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+fn write_subframe(bits: &mut BitWriter, samples: &[i32]) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        // Constant subframe.
+        bits.write(0x00, 8);
+        bits.write_signed(samples[0], BITS_PER_SAMPLE);
+        return;
+    }
+
+    // Pick the fixed predictor order with the smallest total residual. Orders are limited by
+    // the block size, as each order needs that many warm-up samples.
+    let max_order = 4.min(samples.len() - 1);
+    let (order, residual) = (0..=max_order)
+        .map(|order| (order, fixed_residual(samples, order)))
+        .min_by_key(|(_, residual)| {
+            residual
+                .iter()
+                .map(|r| r.unsigned_abs() as u64)
+                .sum::<u64>()
+        })
+        .unwrap_or_default();
+    let (parameter, rice_bits) = best_rice_parameter(&residual);
+
+    // The subframe type, warm-up samples, coding method and Rice parameter take some space too.
+    let fixed_bits = 8 + (order as u64 * BITS_PER_SAMPLE as u64) + 10 + rice_bits;
+    if fixed_bits >= 8 + samples.len() as u64 * BITS_PER_SAMPLE as u64 {
+        // Verbatim subframe.
+        bits.write(0x02, 8);
+        for &sample in samples {
+            bits.write_signed(sample, BITS_PER_SAMPLE);
+        }
+        return;
+    }
+
+    // Fixed subframe, followed by a Rice coded residual with a single partition.
+    bits.write(0, 1);
+    bits.write(0b001000 | order as u64, 6);
+    bits.write(0, 1);
+    for &sample in &samples[..order] {
+        bits.write_signed(sample, BITS_PER_SAMPLE);
+    }
+    bits.write(0b00, 2);
+    bits.write(0, 4);
+    bits.write(parameter as u64, 4);
+    for &r in &residual {
+        let folded = zigzag(r);
+        let quotient = folded >> parameter;
+        bits.write_unary(quotient);
+        bits.write((folded & ((1 << parameter) - 1)) as u64, parameter);
+    }
+}
+
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i32> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |n: usize| samples[i - n];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// Return the Rice parameter that codes the residual in the fewest bits, and that bit count.
+fn best_rice_parameter(residual: &[i32]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAMETER)
+        .map(|k| {
+            let bits: u64 = residual
+                .iter()
+                .map(|&r| (zigzag(r) >> k) as u64 + 1 + k as u64)
+                .sum();
+            (k, bits)
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+#[inline]
+fn zigzag(r: i32) -> u32 {
+    ((r << 1) ^ (r >> 31)) as u32
+}
+
+/// Write a frame number using the extended UTF-8 style coding from the FLAC specification.
+fn write_utf8(bits: &mut BitWriter, value: u32) {
+    if value < 0x80 {
+        bits.write(value as u64, 8);
+        return;
+    }
+    let mut continuation = 1;
+    while value >= 1 << (5 * continuation + 6) {
+        continuation += 1;
+    }
+    let lead_mask = !(0xFFu32 >> (continuation + 1)) & 0xFF;
+    let lead = lead_mask | (value >> (6 * continuation));
+    bits.write(lead as u64, 8);
+    for i in (0..continuation).rev() {
+        bits.write((0x80 | ((value >> (6 * i)) & 0x3F)) as u64, 8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A big-endian bit writer.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            self.accumulator = (self.accumulator << 1) | ((value >> i) & 1);
+            self.bit_count += 1;
+            if self.bit_count == 8 {
+                self.bytes.push(self.accumulator as u8);
+                self.accumulator = 0;
+                self.bit_count = 0;
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        self.write(value as u64 & ((1 << bits) - 1), bits);
+    }
+
+    fn write_unary(&mut self, zeros: u32) {
+        for _ in 0..zeros {
+            self.write(0, 1);
+        }
+        self.write(1, 1);
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.write(0, 8 - self.bit_count);
+        }
+    }
+
+    /// Return the complete bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
@@ -0,0 +1,444 @@
+//! Read-only views of internal chip state, for visualizers, debuggers and tests.
+
+use std::fmt;
+
+use crate::algorithm::Algorithm;
+use crate::tuning::TuningTable;
+use crate::{bindings, Opl3Chip, Opl3Device};
+
+/// The first operator slot of each channel, as wired up by Nuked-OPL3. The second operator is
+/// always three slots later.
+const CHANNEL_SLOTS: [usize; 18] = [
+    0, 1, 2, 6, 7, 8, 12, 13, 14, 18, 19, 20, 24, 25, 26, 30, 31, 32,
+];
+
+/// The `LfoState` struct holds the state of the OPL3's global low frequency oscillators, which
+/// drive tremolo (amplitude modulation) and vibrato (phase modulation) for every operator that
+/// has them enabled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LfoState {
+    /// The chip's global sample counter, which clocks both LFOs.
+    pub timer: u16,
+    /// The position of the tremolo LFO within its 210 step triangle wave.
+    pub tremolo_position: u8,
+    /// The attenuation currently added by tremolo, in envelope steps of 0.1875dB.
+    pub tremolo: u8,
+    /// Whether the deep tremolo depth of 4.8dB is selected (register 0xBD bit 7), rather than
+    /// 1dB.
+    pub tremolo_deep: bool,
+    /// The position of the vibrato LFO within its 8 step cycle.
+    pub vibrato_position: u8,
+    /// Whether the deep vibrato depth of 14 cents is selected (register 0xBD bit 6), rather than
+    /// 7 cents.
+    pub vibrato_deep: bool,
+}
+
+/// The `EnvelopeStage` enum is the stage of an operator's envelope generator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EnvelopeStage {
+    /// The level is rising at the attack rate after key-on.
+    Attack,
+    /// The level is falling at the decay rate towards the sustain level.
+    Decay,
+    /// The level is held at the sustain level, for sustaining operators.
+    Sustain,
+    /// The level is falling at the release rate after key-off, or for non-sustaining operators
+    /// after the decay.
+    Release,
+}
+
+/// The `OperatorStatus` struct holds the state of one operator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OperatorStatus {
+    /// Whether the operator is keyed on, by its channel or by rhythm mode.
+    pub keyed: bool,
+    /// The stage of the envelope generator.
+    pub stage: EnvelopeStage,
+    /// The attenuation of the envelope, including the total level, key scaling and tremolo, in
+    /// steps of 0.1875dB from 0 (loudest) to 511 (silent).
+    pub attenuation: u16,
+    /// The output of the operator for the most recently generated chip sample.
+    pub output: i16,
+}
+
+/// The `ChannelStatus` struct holds the state of one channel and its two operators.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChannelStatus {
+    /// The 10-bit frequency number.
+    pub f_number: u16,
+    /// The block, or octave, from 0 to 7.
+    pub block: u8,
+    /// Whether the channel is sent to each of the outputs A, B, C and D.
+    pub outputs: [bool; 4],
+    /// The modulator and carrier operators. In 4-operator mode, the second channel of a pair
+    /// holds the third and fourth operators.
+    pub operators: [OperatorStatus; 2],
+}
+
+/// The `ChipStatus` struct is a snapshot of the synthesis state of the whole chip, for meters and
+/// visualizers. It holds no references, so it can be sent to another thread, such as through a
+/// `triple_buffer::Publisher`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChipStatus {
+    /// Whether OPL3 mode is enabled.
+    pub opl3_mode: bool,
+    /// Whether rhythm mode is enabled.
+    pub rhythm: bool,
+    /// The state of the tremolo and vibrato LFOs.
+    pub lfo: LfoState,
+    /// The 18 channels.
+    pub channels: [ChannelStatus; 18],
+}
+
+impl Opl3Chip {
+    /// Return the current state of the tremolo and vibrato LFOs.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let mut buffer = [0i16; 4096];
+    /// chip.generate_stream(&mut buffer).unwrap();
+    ///
+    /// // LFO phase carries over when a snapshot is restored into another chip.
+    /// let state = chip.snapshot();
+    /// let mut other = Opl3Chip::new(44100);
+    /// other.restore(&state);
+    /// assert_eq!(other.lfo_state(), chip.lfo_state());
+    /// ```
+    pub fn lfo_state(&self) -> LfoState {
+        let chip = self.raw();
+        LfoState {
+            timer: chip.timer,
+            tremolo_position: chip.tremolopos,
+            tremolo: chip.tremolo,
+            // Nuked-OPL3 stores the depths as shifts applied to the LFO output.
+            tremolo_deep: chip.tremoloshift == 2,
+            vibrato_position: chip.vibpos,
+            vibrato_deep: chip.vibshift == 0,
+        }
+    }
+
+    /// Return the state of the 23-bit noise generator LFSR, which drives the snare drum, hi-hat
+    /// and cymbal in rhythm mode. The generator is clocked every sample, whether or not rhythm
+    /// mode is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// chip.write_register(0xBD, 0x29);
+    /// let state = chip.snapshot();
+    /// assert_eq!(state.noise(), chip.noise_state());
+    ///
+    /// let mut first = [0i16; 1024];
+    /// chip.generate_stream(&mut first).unwrap();
+    /// assert_ne!(state.noise(), chip.noise_state());
+    ///
+    /// // Percussion is bit-exact after a restore.
+    /// chip.restore(&state);
+    /// let mut second = [0i16; 1024];
+    /// chip.generate_stream(&mut second).unwrap();
+    /// assert_eq!(first, second);
+    /// ```
+    pub fn noise_state(&self) -> u32 {
+        self.raw().noise
+    }
+
+    /// Return `true` if the chip is silent and will stay silent until a register is written: every
+    /// operator is keyed off with its envelope fully attenuated, rhythm mode is off and no
+    /// buffered writes are pending.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// assert!(chip.is_idle());
+    /// chip.write_register(0x63, 0xF0);
+    /// chip.write_register(0xB0, 0x20);
+    /// assert!(!chip.is_idle());
+    /// ```
+    pub fn is_idle(&self) -> bool {
+        let chip = self.raw();
+        // Buffered writes are pending while the next entry still has its pending flag set.
+        let pending = chip.writebuf[chip.writebuf_cur as usize % chip.writebuf.len()].reg & 0x200;
+        chip.rhy & 0x20 == 0
+            && pending == 0
+            && chip
+                .slot
+                .iter()
+                .all(|slot| slot.key == 0 && slot.eg_rout == 0x1FF)
+    }
+
+    /// Return `true` if OPL3 mode is enabled, as seen by the synthesis core. This follows the NEW
+    /// bit of register 0x105 only; see `registers::ModeFlags` for how the other bits are handled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// assert!(!chip.opl3_mode());
+    /// // NEW2 alone does not enable OPL3 mode.
+    /// chip.write_register(0x105, 0x02);
+    /// assert!(!chip.opl3_mode());
+    /// chip.write_register(0x105, 0x03);
+    /// assert!(chip.opl3_mode());
+    /// ```
+    pub fn opl3_mode(&self) -> bool {
+        self.raw().newm != 0
+    }
+
+    /// Return the channels with at least one operator keyed on, from 0 to 17. In rhythm mode,
+    /// channels 6 to 8 are listed while any of their drums is playing.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// chip.write_register(0xB2, 0x20);
+    /// chip.write_register(0x1B0, 0x20);
+    /// assert_eq!(chip.keyed_channels(), vec![2, 9]);
+    /// ```
+    pub fn keyed_channels(&self) -> Vec<u8> {
+        let chip = self.raw();
+        (0..18u8)
+            .filter(|&channel| {
+                let slot = CHANNEL_SLOTS[channel as usize];
+                chip.slot[slot].key != 0 || chip.slot[slot + 3].key != 0
+            })
+            .collect()
+    }
+
+    /// Return the pitch a channel is playing, in Hz, from its block and frequency number and the
+    /// frequency multipliers of its carriers. In FM mode the second operator is the carrier; in
+    /// additive mode both are heard, and the lower multiplier sets the pitch. Operators that are
+    /// keyed on take precedence over ones that are not, so that a tuner follows the sounding note.
+    /// The value does not include vibrato.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, from 0 to 17.
+    ///
+    /// # Returns
+    ///
+    /// The pitch in Hz, or 0 if the channel is out of range.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// // A4: F-Num 580 in block 4, with a multiplier of 1.
+    /// chip.write_register(0x23, 0x01);
+    /// chip.write_register(0xA0, 0x44);
+    /// chip.write_register(0xB0, 0x32);
+    /// assert!((chip.channel_frequency_hz(0) - 440.0).abs() < 0.5);
+    /// // A multiplier of 2 on the carrier plays an octave up.
+    /// chip.write_register(0x23, 0x02);
+    /// assert!((chip.channel_frequency_hz(0) - 880.0).abs() < 1.0);
+    /// ```
+    pub fn channel_frequency_hz(&self, channel: usize) -> f64 {
+        /// The frequency multiplier selected by each value of the MULT bits, times 2.
+        const MULTIPLIERS: [u8; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+        let Some(&slot) = CHANNEL_SLOTS.get(channel) else {
+            return 0.0;
+        };
+        let chip = self.raw();
+        let state = &chip.channel[channel];
+        let carriers: &[usize] = if state.con != 0 {
+            &[slot, slot + 3]
+        } else {
+            &[slot + 3]
+        };
+        let multiplier = |keyed_only: bool| {
+            carriers
+                .iter()
+                .map(|&slot| &chip.slot[slot])
+                .filter(|slot| !keyed_only || slot.key != 0)
+                .map(|slot| MULTIPLIERS[slot.reg_mult as usize & 0x0F])
+                .min()
+        };
+        let multiplier = multiplier(true).or_else(|| multiplier(false)).unwrap_or(2);
+        let base = TuningTable::new(self.master_clock())
+            .hz(state.block.min(7), state.f_num & 0x3FF)
+            .unwrap_or(0.0);
+        base * multiplier as f64 / 2.0
+    }
+
+    /// Return how a channel's operators are connected, as the core has set them up from the
+    /// connection bits, the 4-operator enables of register 0x104 and rhythm mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, from 0 to 17.
+    ///
+    /// # Returns
+    ///
+    /// The algorithm, or `None` if the channel is out of range or is the second channel of a
+    /// 4-operator pair, whose operators are part of the first channel's algorithm.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::algorithm::Algorithm;
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// chip.write_register(0xC0, 0x01);
+    /// assert_eq!(chip.channel_algorithm(0), Some(Algorithm::two_op(true)));
+    /// // Pair channels 0 and 3 into a 4-operator channel.
+    /// chip.write_register(0x105, 0x01);
+    /// chip.write_register(0x104, 0x01);
+    /// assert_eq!(chip.channel_algorithm(0), Some(Algorithm::four_op(true, false)));
+    /// assert_eq!(chip.channel_algorithm(3), None);
+    /// ```
+    pub fn channel_algorithm(&self, channel: usize) -> Option<Algorithm> {
+        /// The values of `chtype` in Nuked-OPL3.
+        const CH_4OP: u8 = 1;
+        const CH_4OP2: u8 = 2;
+        const CH_DRUM: u8 = 3;
+        let chip = self.raw();
+        let state = chip.channel.get(channel)?;
+        let four_op = chip.newm != 0;
+        match state.chtype {
+            CH_4OP if four_op => Some(Algorithm::four_op(
+                state.con != 0,
+                chip.channel[channel + 3].con != 0,
+            )),
+            CH_4OP2 if four_op => None,
+            CH_DRUM if state.ch_num != 6 => Some(Algorithm::drums()),
+            _ => Some(Algorithm::two_op(state.con != 0)),
+        }
+    }
+
+    /// Return a snapshot of the state of every channel and operator. This does not allocate, so
+    /// it can be called from the audio thread.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    /// use opl3_rs::inspect::EnvelopeStage;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// chip.write_register(0x63, 0x20);
+    /// chip.write_register(0xA0, 0x98);
+    /// chip.write_register(0xB0, 0x31);
+    /// chip.render(64);
+    /// let status = chip.status();
+    /// assert_eq!(status.channels[0].f_number, 0x198);
+    /// assert_eq!(status.channels[0].block, 4);
+    /// assert!(status.channels[0].operators[1].keyed);
+    /// assert_eq!(status.channels[0].operators[1].stage, EnvelopeStage::Attack);
+    /// ```
+    pub fn status(&self) -> ChipStatus {
+        let chip = self.raw();
+        let operator = |slot: usize| {
+            let slot = &chip.slot[slot];
+            OperatorStatus {
+                keyed: slot.key != 0,
+                stage: match slot.eg_gen {
+                    0 => EnvelopeStage::Attack,
+                    1 => EnvelopeStage::Decay,
+                    2 => EnvelopeStage::Sustain,
+                    _ => EnvelopeStage::Release,
+                },
+                attenuation: slot.eg_out.min(0x1FF),
+                output: slot.out,
+            }
+        };
+        let channels = std::array::from_fn(|index| {
+            let channel = &chip.channel[index];
+            let slot = CHANNEL_SLOTS[index];
+            ChannelStatus {
+                f_number: channel.f_num,
+                block: channel.block,
+                outputs: [channel.cha, channel.chb, channel.chc, channel.chd].map(|mask| mask != 0),
+                operators: [operator(slot), operator(slot + 3)],
+            }
+        });
+        ChipStatus {
+            opl3_mode: self.opl3_mode(),
+            rhythm: chip.rhy & 0x20 != 0,
+            lfo: self.lfo_state(),
+            channels,
+        }
+    }
+
+    fn raw(&self) -> &bindings::Opl3Chip {
+        // Safety: the chip pointer is valid for the lifetime of `self`.
+        unsafe { &*self.chip }
+    }
+}
+
+/// A summary of the chip's configuration and synthesis state, rather than its raw registers.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::Opl3Chip;
+///
+/// let mut chip = Opl3Chip::new(44100);
+/// chip.write_register(0xB0, 0x20);
+/// let summary = format!("{:?}", chip);
+/// assert!(summary.contains("mode: Opl2"));
+/// assert!(summary.contains("keyed_channels: [0]"));
+/// ```
+impl fmt::Debug for Opl3Chip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Opl3Chip")
+            .field("sample_rate", &self.sample_rate)
+            .field("master_clock", &self.master_clock)
+            .field("mode", &ChipMode(self.opl3_mode()))
+            .field("rhythm", &(self.raw().rhy & 0x20 != 0))
+            .field("keyed_channels", &self.keyed_channels())
+            .field("idle", &self.is_idle())
+            .finish()
+    }
+}
+
+/// Formats as `Opl2` or `Opl3`.
+struct ChipMode(bool);
+
+impl fmt::Debug for ChipMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0 { "Opl3" } else { "Opl2" })
+    }
+}
+
+/// A summary of the device's timers and status register alongside that of its chip. Formatting
+/// does not count as a status read.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::Opl3Device;
+///
+/// let device = Opl3Device::new(44100);
+/// let summary = format!("{:?}", device);
+/// assert!(summary.contains("status: 0"));
+/// assert!(summary.contains("Opl3Chip"));
+/// ```
+impl fmt::Debug for Opl3Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Opl3Device")
+            .field("sample_rate", &self.sample_rate)
+            .field("address", &self.addr_reg)
+            .field("status", &self.status())
+            .field("timers", &self.timers)
+            .field("stats", &self.stats)
+            .field("chip", &self.inner_chip)
+            .finish()
+    }
+}
@@ -0,0 +1,220 @@
+//! Sound cards on an emulated PC's I/O bus.
+//!
+//! PC emulators usually route port accesses through a bus that looks up the device owning each
+//! port. The `IoDevice` trait is the small interface such a bus needs: the ports a device
+//! decodes, reads and writes of those ports, and the passing of time. `AdLib` and `SoundBlaster`
+//! implement it for the two common ways an OPL chip was wired into a PC, so hooking one into an
+//! emulator is a matter of forwarding these calls.
+//!
+//! Both cards wrap an `Opl3Device`, which can be reached with `device_mut` to generate audio.
+//! `IoDevice::tick` returns the number of samples due for the time that passed, as
+//! `Opl3Device::run` does.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::io_device::{AdLib, IoDevice, SoundBlaster};
+//!
+//! let mut devices: Vec<Box<dyn IoDevice>> = vec![
+//!     Box::new(AdLib::new(44100)),
+//!     Box::new(SoundBlaster::new(44100, 0x220)),
+//! ];
+//! let bus_write = |devices: &mut Vec<Box<dyn IoDevice>>, port, value| {
+//!     for device in devices.iter_mut() {
+//!         if device.port_list().contains(&port) {
+//!             device.port_write(port, value);
+//!         }
+//!     }
+//! };
+//!
+//! // The AdLib detection sequence, run against the Sound Blaster's own ports: reset both
+//! // timers, then start timer 1 and wait for it to expire.
+//! for (reg, value) in [(0x04, 0x60), (0x04, 0x80), (0x02, 0xFF), (0x04, 0x21)] {
+//!     bus_write(&mut devices, 0x228, reg);
+//!     bus_write(&mut devices, 0x229, value);
+//! }
+//! devices[1].tick(100.0);
+//! assert_eq!(devices[1].port_read(0x228) & 0xE0, 0xC0);
+//! // The AdLib's timers were not touched.
+//! assert_eq!(devices[0].port_read(0x388) & 0xE0, 0x00);
+//! ```
+
+use crate::Opl3Device;
+
+/// The ports of an AdLib card, and of the AdLib compatible ports of other cards.
+pub const ADLIB_PORT: u16 = 0x388;
+
+/// The status register bits that read as set on an OPL2, and clear on an OPL3.
+const OPL2_STATUS_BITS: u8 = 0x06;
+
+/// The `IoDevice` trait is implemented by devices that sit on an emulated PC's I/O bus.
+pub trait IoDevice {
+    /// Return the ports the device decodes. The bus should only pass accesses to these ports.
+    fn port_list(&self) -> Vec<u16>;
+
+    /// Read a port, as the CPU would with an `IN` instruction.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The port to read.
+    fn port_read(&mut self, port: u16) -> u8;
+
+    /// Write a port, as the CPU would with an `OUT` instruction.
+    ///
+    /// # Arguments
+    ///
+    /// * `port`  - The port to write.
+    /// * `value` - The value to write.
+    fn port_write(&mut self, port: u16, value: u8);
+
+    /// Advance the device's timers.
+    ///
+    /// # Arguments
+    ///
+    /// * `usec` - The number of microseconds that have passed since the last call.
+    ///
+    /// # Returns
+    ///
+    /// The number of samples of audio due for the time that passed.
+    fn tick(&mut self, usec: f64) -> usize;
+}
+
+/// The `AdLib` struct is an AdLib Music Synthesizer Card, with an OPL2 at ports 0x388 and 0x389.
+/// Only the primary register file can be reached, so the chip always runs in OPL2 mode, and the
+/// status register reads as that of an OPL2.
+#[derive(Debug)]
+pub struct AdLib {
+    device: Opl3Device,
+}
+
+impl AdLib {
+    /// Create a new `AdLib`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn new(sample_rate: u32) -> Self {
+        AdLib {
+            device: Opl3Device::new(sample_rate),
+        }
+    }
+
+    /// Return the card's chip.
+    pub fn device(&self) -> &Opl3Device {
+        &self.device
+    }
+
+    /// Return the card's chip, for generating audio.
+    pub fn device_mut(&mut self) -> &mut Opl3Device {
+        &mut self.device
+    }
+
+    /// Remove the card, returning its chip.
+    pub fn into_inner(self) -> Opl3Device {
+        self.device
+    }
+}
+
+impl IoDevice for AdLib {
+    fn port_list(&self) -> Vec<u16> {
+        vec![ADLIB_PORT, ADLIB_PORT + 1]
+    }
+
+    fn port_read(&mut self, port: u16) -> u8 {
+        match port & 1 {
+            0 => self.device.read_port(0) | OPL2_STATUS_BITS,
+            _ => 0xFF,
+        }
+    }
+
+    fn port_write(&mut self, port: u16, value: u8) {
+        self.device.write_port(port & 1, value);
+    }
+
+    fn tick(&mut self, usec: f64) -> usize {
+        self.device.run(usec)
+    }
+}
+
+/// The `SoundBlaster` struct is the FM part of a Sound Blaster Pro 2 or Sound Blaster 16, with an
+/// OPL3 at the card's base port. Offsets 0 to 3 from the base are the address and data ports of
+/// the primary and secondary register files, offsets 8 and 9 are the primary file again, and
+/// ports 0x388 to 0x38B mirror offsets 0 to 3 for AdLib compatible software.
+#[derive(Debug)]
+pub struct SoundBlaster {
+    device: Opl3Device,
+    base: u16,
+}
+
+impl SoundBlaster {
+    /// Create a new `SoundBlaster`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    /// * `base`        - The card's base port, usually 0x220 or 0x240.
+    pub fn new(sample_rate: u32, base: u16) -> Self {
+        SoundBlaster {
+            device: Opl3Device::new(sample_rate),
+            base,
+        }
+    }
+
+    /// Return the card's base port.
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Return the card's chip.
+    pub fn device(&self) -> &Opl3Device {
+        &self.device
+    }
+
+    /// Return the card's chip, for generating audio.
+    pub fn device_mut(&mut self) -> &mut Opl3Device {
+        &mut self.device
+    }
+
+    /// Remove the card, returning its chip.
+    pub fn into_inner(self) -> Opl3Device {
+        self.device
+    }
+
+    /// Return the chip port offset, from 0 to 3, that a bus port maps to.
+    fn offset(&self, port: u16) -> Option<u16> {
+        match port.wrapping_sub(self.base) {
+            offset @ 0..=3 => Some(offset),
+            offset @ 8..=9 => Some(offset - 8),
+            _ => match port.wrapping_sub(ADLIB_PORT) {
+                offset @ 0..=3 => Some(offset),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl IoDevice for SoundBlaster {
+    fn port_list(&self) -> Vec<u16> {
+        let base = self.base;
+        let mut ports = vec![base, base + 1, base + 2, base + 3, base + 8, base + 9];
+        ports.extend(ADLIB_PORT..ADLIB_PORT + 4);
+        ports
+    }
+
+    fn port_read(&mut self, port: u16) -> u8 {
+        match self.offset(port) {
+            Some(offset) => self.device.read_port(offset),
+            None => 0xFF,
+        }
+    }
+
+    fn port_write(&mut self, port: u16, value: u8) {
+        if let Some(offset) = self.offset(port) {
+            self.device.write_port(offset, value);
+        }
+    }
+
+    fn tick(&mut self, usec: f64) -> usize {
+        self.device.run(usec)
+    }
+}
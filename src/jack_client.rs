@@ -0,0 +1,154 @@
+//! A ready-to-run JACK instrument playing `midi_synth::OplMidiSynth`.
+//!
+//! `OplJackClient` registers a JACK client with a MIDI input port and a pair of audio output
+//! ports, and plays the MIDI it receives on a synth running at the JACK server's sample rate.
+//! Events are played at the frames JACK timestamped them with, and the process callback neither
+//! allocates nor locks. Once the client is running, its ports can be connected with any patchbay,
+//! or the outputs to the system's playback ports with `connect_playback`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use opl3_rs::bank::BankSet;
+//! use opl3_rs::jack_client::OplJackClient;
+//!
+//! let banks = BankSet::from_op2(&std::fs::read("GENMIDI.op2").unwrap()).unwrap();
+//! let client = OplJackClient::new("opl3", banks).unwrap();
+//! client.connect_playback().unwrap();
+//! std::thread::park();
+//! ```
+
+use jack::{
+    AsyncClient, AudioOut, Client, ClientOptions, Control, MidiIn, Port, PortFlags, ProcessHandler,
+    ProcessScope,
+};
+
+use crate::bank::BankSet;
+use crate::midi_synth::OplMidiSynth;
+use crate::OplError;
+
+/// Convert a JACK error to an `OplError`.
+fn backend_error(error: jack::Error) -> OplError {
+    OplError::Backend(error.to_string())
+}
+
+/// The process callback, which owns the synth and its ports.
+struct OplJackProcess {
+    synth: OplMidiSynth,
+    midi: Port<MidiIn>,
+    left: Port<AudioOut>,
+    right: Port<AudioOut>,
+}
+
+impl ProcessHandler for OplJackProcess {
+    fn process(&mut self, _client: &Client, scope: &ProcessScope) -> Control {
+        let messages = self
+            .midi
+            .iter(scope)
+            .map(|event| (event.time as usize, event.bytes));
+        self.synth.render_planar_timed(
+            messages,
+            self.left.as_mut_slice(scope),
+            self.right.as_mut_slice(scope),
+        );
+        Control::Continue
+    }
+}
+
+/// The `OplJackClient` struct is an active JACK client playing the MIDI from its input port on
+/// an `OplMidiSynth`. Dropping it closes the client.
+pub struct OplJackClient {
+    client: AsyncClient<(), OplJackProcess>,
+    ports: [String; 3],
+}
+
+impl OplJackClient {
+    /// Register a new JACK client and start playing. The JACK server must already be running.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`  - The name of the client, which prefixes the names of its ports.
+    /// * `banks` - The instruments to play.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the new `OplJackClient` on success or an `OplError` on
+    /// failure. The error is `OplError::Backend` if the client or its ports cannot be registered
+    /// or activated.
+    pub fn new(name: &str, banks: BankSet) -> Result<Self, OplError> {
+        let (client, _status) =
+            Client::new(name, ClientOptions::NO_START_SERVER).map_err(backend_error)?;
+        let midi = client
+            .register_port("midi_in", MidiIn)
+            .map_err(backend_error)?;
+        let left = client
+            .register_port("out_left", AudioOut)
+            .map_err(backend_error)?;
+        let right = client
+            .register_port("out_right", AudioOut)
+            .map_err(backend_error)?;
+        let ports = [
+            midi.name().map_err(backend_error)?,
+            left.name().map_err(backend_error)?,
+            right.name().map_err(backend_error)?,
+        ];
+
+        let mut synth = OplMidiSynth::new(client.sample_rate() as u32, banks);
+        synth.reserve(client.buffer_size() as usize);
+        let process = OplJackProcess {
+            synth,
+            midi,
+            left,
+            right,
+        };
+        let client = client.activate_async((), process).map_err(backend_error)?;
+        Ok(OplJackClient { client, ports })
+    }
+
+    /// Return the underlying JACK client, to query the server or connect ports.
+    pub fn client(&self) -> &Client {
+        self.client.as_client()
+    }
+
+    /// Return the full name of the MIDI input port.
+    pub fn midi_port(&self) -> &str {
+        &self.ports[0]
+    }
+
+    /// Return the full names of the left and right audio output ports.
+    pub fn output_ports(&self) -> [&str; 2] {
+        [&self.ports[1], &self.ports[2]]
+    }
+
+    /// Connect the audio outputs to the first two physical playback ports of the system.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure. The error is
+    /// `OplError::Backend` if a connection fails.
+    pub fn connect_playback(&self) -> Result<(), OplError> {
+        let client = self.client();
+        let playback = client.ports(
+            None,
+            Some("32 bit float mono audio"),
+            PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL,
+        );
+        for (output, playback) in self.output_ports().iter().zip(&playback) {
+            client
+                .connect_ports_by_name(output, playback)
+                .map_err(backend_error)?;
+        }
+        Ok(())
+    }
+
+    /// Stop playing and close the client, returning the synth.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the synth on success or an `OplError` on failure. The error
+    /// is `OplError::Backend` if the client cannot be deactivated.
+    pub fn close(self) -> Result<OplMidiSynth, OplError> {
+        let (_client, (), process) = self.client.deactivate().map_err(backend_error)?;
+        Ok(process.synth)
+    }
+}
@@ -34,7 +34,90 @@
 
 use thiserror::Error;
 
+pub mod algorithm;
+pub mod analog;
+#[cfg(feature = "android")]
+pub mod android;
+pub mod bank;
+#[cfg(feature = "bevy")]
+pub mod bevy_source;
 mod bindings;
+pub mod calibration;
+pub mod channel_allocator;
+pub mod clock;
+pub mod compare;
+pub mod coreaudio;
+pub mod detect;
+pub mod diff;
+pub mod double_buffer;
+mod dro;
+pub mod effects;
+pub mod envelope;
+pub mod event_log;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "fixed-point")]
+pub mod fixed;
+#[cfg(feature = "flac")]
+mod flac;
+pub mod inspect;
+pub mod io_device;
+pub mod isolate;
+#[cfg(feature = "jack")]
+pub mod jack_client;
+pub mod loudness;
+pub mod midi_out;
+pub mod midi_synth;
+pub mod mix;
+pub mod multirate;
+#[cfg(feature = "opl4")]
+pub mod opl4;
+pub mod opl_core;
+#[cfg(feature = "opll")]
+pub mod opll;
+pub mod pacing;
+pub mod patch;
+pub mod pcm;
+pub mod playlist;
+pub mod queue;
+pub mod realtime;
+pub mod register_cache;
+pub mod registers;
+pub mod renderer;
+pub mod rewind;
+pub mod sample;
+#[cfg(feature = "sdl2")]
+pub mod sdl;
+pub mod sequence;
+pub mod sequencer;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod session;
+pub mod sfx;
+pub mod speaker;
+pub mod spectrum;
+mod state;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(feature = "symphonia")]
+pub mod symphonia;
+#[cfg(feature = "taps")]
+pub mod taps;
+#[cfg(feature = "test-vectors")]
+pub mod test_vectors;
+pub mod time_source;
+pub mod triple_buffer;
+pub mod tuning;
+pub mod turbo;
+pub mod validate;
+mod vgm;
+pub mod vgm_player;
+pub mod waveform;
+
+pub use state::{Opl3ChipState, Opl3DeviceState, StateRestore};
+
+use validate::{RegisterWarning, MAX_REGISTER_WARNINGS};
 
 unsafe impl Send for Opl3Chip {}
 
@@ -53,6 +136,18 @@ const OPL_TICK_RATE: f64 = 80.0; // Perform a timer tick every 80us.
 const OPL_TIMER_1_RATE: u32 = 80; // Timer 1 tick rate is every 80us.
 const OPL_TIMER_2_RATE: u32 = 320; // Timer 2 tick rate is every 320us.
 
+const OPL_MAX_REGISTER: u16 = 0x1FF;
+
+/// The number of writes the buffered write queue holds, `OPL_WRITEBUF_SIZE` in Nuked-OPL3.
+pub const BUFFERED_WRITE_CAPACITY: usize = 1024;
+/// The number of native samples between buffered writes, `OPL_WRITEBUF_DELAY` in Nuked-OPL3.
+pub const BUFFERED_WRITE_DELAY: u64 = 2;
+
+const OPL_BUSY_FLAG: u8 = 0b0000_0001;
+// The duration of one status port read. Drivers commonly delay by reading the status port 6 times
+// after an address write and 35 times after a data write, which matches the OPL2 write delays.
+const OPL_STATUS_READ_USEC: f64 = 0.66;
+
 #[derive(Error, Debug)]
 /// The `OplError` enum represents errors that can occur when using the `opl3-rs` library.
 pub enum OplError {
@@ -68,9 +163,43 @@ pub enum OplError {
     #[error("Failed to lock mutex")]
     /// Failed to lock the mutex for the OPL3 device.
     MutexLockFailed,
+    #[error("State data is invalid or corrupt")]
+    /// The state data provided could not be restored.
+    InvalidState,
+    #[error("Requested rewind point is not available")]
+    /// The requested rewind point is not retained by the rewind buffer.
+    RewindUnavailable,
+    #[error("Invalid or unsupported {0} data")]
+    /// Data passed to a parser or converter was not in the expected format.
+    InvalidFormat(&'static str),
+    #[error("Audio stream has been dropped")]
+    /// The audio stream that a command was sent to no longer exists.
+    StreamClosed,
+    #[error("I/O error: {0}")]
+    /// An I/O error occurred while reading or writing a file.
+    Io(#[from] std::io::Error),
+    #[error("Encoder error: {0}")]
+    /// An audio encoder reported an error.
+    Encoder(String),
+    #[error("Unsupported sample rate {0}Hz")]
+    /// The sample rate is outside `Opl3Chip::MIN_SAMPLE_RATE` to `Opl3Chip::MAX_SAMPLE_RATE`, the
+    /// range Nuked-OPL3 can resample to.
+    InvalidSampleRate(u32),
+    #[error("Audio backend error: {0}")]
+    /// An audio output backend reported an error.
+    Backend(String),
+    #[error("Timed writes are not in order")]
+    /// The offsets of timed register writes decrease.
+    UnorderedWrites,
+    #[error("Duration of {0}ms is too long")]
+    /// The duration, in milliseconds, is longer than the function accepts.
+    DurationTooLong(u32),
 }
 
-#[derive(Debug)]
+/// An alias of `OplError`, the error type returned by every fallible function in this crate.
+pub type Opl3Error = OplError;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 /// The `Opl3RegisterFile` enum represents the two register files available on the OPL3 chip.
 /// If in OPL2 mode, only the primary register file is available.
 pub enum OplRegisterFile {
@@ -80,9 +209,52 @@ pub enum OplRegisterFile {
     Secondary,
 }
 
+/// The `BusyTiming` enum selects how `Opl3Device` models the time the chip needs to accept each
+/// address and data write. While the chip is busy, status reads made through `read_port` have
+/// bit 0 set.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BusyTiming {
+    /// Writes are accepted instantly and the busy flag is never set.
+    #[default]
+    Instant,
+    /// Model the write delays of the YM3812 (OPL2), about 3.4us after an address write and 23.5us
+    /// after a data write.
+    Opl2,
+    /// Model the write delays of the YMF262 (OPL3), about 2.2us after each write.
+    Opl3,
+}
+
+/// The `BufferedWriteDelay` enum selects how far apart `Opl3Chip::write_register_buffered`
+/// spaces the writes it queues, in samples at the native rate of 49716Hz.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BufferedWriteDelay {
+    /// Apply every queued write at the end of the next native sample, with no spacing.
+    None,
+    /// Space writes 2 samples (about 40us) apart, as Nuked-OPL3 does.
+    #[default]
+    Opl3,
+    /// Space writes 4 samples (about 80us) apart, approximating the slower pacing of drivers
+    /// written for the YM3812 (OPL2) on early PCs.
+    Opl2,
+    /// Space writes a custom number of samples apart.
+    Custom(u32),
+}
+
+impl BufferedWriteDelay {
+    /// Return the number of native samples between writes.
+    pub fn samples(self) -> u64 {
+        match self {
+            BufferedWriteDelay::None => 0,
+            BufferedWriteDelay::Opl3 => BUFFERED_WRITE_DELAY,
+            BufferedWriteDelay::Opl2 => 4,
+            BufferedWriteDelay::Custom(samples) => samples as u64,
+        }
+    }
+}
+
 /// The `Opl3DeviceStats` struct contains statistics about the OPL3 device.
 /// It can be retrieved via the `get_stats` function on `Opl3Device`.
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct Opl3DeviceStats {
     /// The number of writes to the OPL3 data register since reset.
     pub data_writes: usize,
@@ -95,8 +267,110 @@ pub struct Opl3DeviceStats {
     pub samples_generated: usize,
 }
 
+/// The `Frame4` struct holds one sample of all four of the OPL3's DAC outputs.
+///
+/// In OPL3 mode, the output bits of registers 0xC0-0xC8 (bits 4-7) select which of the outputs
+/// A, B, C and D each channel is mixed into. Most cards connect A and B as the left and right
+/// stereo outputs and leave C and D unconnected. In OPL2 mode, every channel is mixed into A and
+/// B, and C and D are silent.
+///
+/// `Frame4` is `repr(C)` and matches the layout of the 4-element sample buffers used by
+/// Nuked-OPL3, so slices of frames can be rendered into directly.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Frame4 {
+    /// Output A, conventionally the left channel.
+    pub a: i16,
+    /// Output B, conventionally the right channel.
+    pub b: i16,
+    /// Output C.
+    pub c: i16,
+    /// Output D.
+    pub d: i16,
+}
+
+/// The `OutputMap` struct chooses which of the chip's outputs each channel of generated audio
+/// carries, for targets that expect the channels in another order. Entries may repeat, so that
+/// one output can be sent to several channels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutputMap {
+    /// For each channel of 2 channel audio, the output it carries: 0 for left and 1 for right.
+    pub stereo: [u8; 2],
+    /// For each channel of 4 channel audio, the DAC output it carries: 0 to 3 for A to D.
+    pub quad: [u8; 4],
+}
+
+impl OutputMap {
+    /// The outputs in their usual order.
+    pub const IDENTITY: OutputMap = OutputMap {
+        stereo: [0, 1],
+        quad: [0, 1, 2, 3],
+    };
+
+    /// Left and right swapped, and likewise outputs A and B, and C and D.
+    pub const SWAPPED: OutputMap = OutputMap {
+        stereo: [1, 0],
+        quad: [1, 0, 3, 2],
+    };
+
+    /// Reorder interleaved 2 channel frames in place.
+    fn map_stereo(&self, buffer: &mut [i16]) {
+        if *self == Self::IDENTITY {
+            return;
+        }
+        let [left, right] = self.stereo.map(|output| (output & 1) as usize);
+        for frame in buffer.chunks_exact_mut(2) {
+            let outputs = [frame[0], frame[1]];
+            frame[0] = outputs[left];
+            frame[1] = outputs[right];
+        }
+    }
+
+    /// Reorder the outputs of a 4 channel frame.
+    fn map_frame4(&self, frame: Frame4) -> Frame4 {
+        if *self == Self::IDENTITY {
+            return frame;
+        }
+        let outputs = [frame.a, frame.b, frame.c, frame.d];
+        let [a, b, c, d] = self.quad.map(|output| outputs[(output & 3) as usize]);
+        Frame4 { a, b, c, d }
+    }
+
+    /// Reorder a 4 channel frame held in a slice of 4 samples in place.
+    fn map_quad(&self, sample: &mut [i16]) {
+        if let [a, b, c, d, ..] = sample {
+            let frame = self.map_frame4(Frame4 {
+                a: *a,
+                b: *b,
+                c: *c,
+                d: *d,
+            });
+            (*a, *b, *c, *d) = (frame.a, frame.b, frame.c, frame.d);
+        }
+    }
+}
+
+impl Default for OutputMap {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// The `TimedWrite` struct describes a register write that occurs at a specific sample offset
+/// within a frame of audio. It is used with `Opl3Device::generate_frame`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimedWrite {
+    /// The offset, in output samples from the start of the frame, at which the write occurs.
+    pub offset: usize,
+    /// The register to write, in the range 0x000..=0x1FF. Addresses 0x100 and above are in the
+    /// secondary register file.
+    pub register: u16,
+    /// The value to write.
+    pub value: u8,
+}
+
 /// The `Opl3Device` maintains two internal timers.
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 struct OplTimer {
     enabled: bool,
     masked: bool,
@@ -179,6 +453,14 @@ pub struct Opl3Device {
     inner_chip: Opl3Chip,
     samples_fpart: f64,
     usec_accumulator: f64,
+    strict: bool,
+    waveform_masking: bool,
+    opl2_truncation: bool,
+    warnings: Vec<RegisterWarning>,
+    warnings_dropped: usize,
+    frame_buffer: Vec<i16>,
+    busy_timing: BusyTiming,
+    busy_usec: f64,
 }
 
 impl Opl3Device {
@@ -199,9 +481,51 @@ impl Opl3Device {
             inner_chip: Opl3Chip::new(sample_rate),
             samples_fpart: 0.0,
             usec_accumulator: 0.0,
+            strict: false,
+            waveform_masking: false,
+            opl2_truncation: false,
+            warnings: Vec::new(),
+            warnings_dropped: 0,
+            frame_buffer: Vec::new(),
+            busy_timing: BusyTiming::Instant,
+            busy_usec: 0.0,
         }
     }
 
+    /// Create a new OPL3 device instance with its buffers allocated up front, so that
+    /// `generate_frame` and strict mode never allocate. See the `realtime` module.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate`       - The sample rate to generate audio at.
+    /// * `samples_per_frame` - The largest number of samples that will be passed to
+    ///                         `generate_frame`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::alloc::System;
+    ///
+    /// use opl3_rs::realtime::{assert_no_alloc, CheckedAllocator};
+    /// use opl3_rs::{Opl3Device, TimedWrite};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: CheckedAllocator<System> = CheckedAllocator::new(System);
+    ///
+    /// fn main() {
+    ///     let mut device = Opl3Device::with_capacity(44100, 735);
+    ///     let writes = [TimedWrite { offset: 0, register: 0xA0, value: 0x41 }];
+    ///     let samples = assert_no_alloc(|| device.generate_frame(735, &writes).len());
+    ///     assert_eq!(samples, 735 * 2);
+    /// }
+    /// ```
+    pub fn with_capacity(sample_rate: u32, samples_per_frame: usize) -> Self {
+        let mut device = Self::new(sample_rate);
+        device.frame_buffer = Vec::with_capacity(samples_per_frame * 2);
+        device.warnings = Vec::with_capacity(MAX_REGISTER_WARNINGS);
+        device
+    }
+
     /// Retrieve the statistics for the OPL3 device in the form of an `Opl3DeviceStats` struct.
     ///
     /// # Returns
@@ -210,6 +534,190 @@ impl Opl3Device {
         self.stats
     }
 
+    /// Enable or disable strict mode. In strict mode, writes to registers that have no function
+    /// on the YMF262, that set unused or reserved bits, that target the secondary register file
+    /// while OPL3 mode is disabled, or that key channels 6 to 8 on while rhythm mode owns their
+    /// operators, are recorded as warnings which can be retrieved via `warnings`. Writes are still
+    /// passed to the chip as usual.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict` - Whether to enable strict mode.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Device, OplRegisterFile};
+    ///
+    /// let mut device = Opl3Device::new(44100);
+    /// device.set_strict_mode(true);
+    /// // Register 0x06 has no function.
+    /// device.write_register(0x06, 0xFF, OplRegisterFile::Primary, false);
+    /// assert_eq!(device.warnings().len(), 1);
+    /// // OPL3 mode has not been enabled through register 0x105.
+    /// device.write_register(0x20, 0x01, OplRegisterFile::Secondary, false);
+    /// assert_eq!(device.warnings().len(), 2);
+    /// ```
+    pub fn set_strict_mode(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Enable or disable OPL2 waveform masking. While OPL3 mode is disabled, the chip then plays
+    /// waveforms as an OPL2 does: only waveforms 0 to 3 can be selected, and only while the
+    /// waveform select enable (WSE) bit of register 0x01 is set; otherwise every operator plays a
+    /// sine. The masking follows later changes to registers 0x01 and 0x105, so clearing WSE or
+    /// leaving OPL3 mode changes the waveforms of operators that are already playing. Drivers that
+    /// select waveforms without setting WSE sound as they did on an AdLib.
+    ///
+    /// Without masking, the core only masks waveform values to 0 to 3 as they are written while
+    /// OPL3 mode is disabled, and ignores WSE. The register values read back by `read_register`
+    /// are the values written either way.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to enable waveform masking.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Device, OplRegisterFile};
+    ///
+    /// let render = |waveform: u8, wse: u8| {
+    ///     let mut device = Opl3Device::new(44100);
+    ///     device.set_waveform_masking(true);
+    ///     for (reg, value) in [(0x01, wse), (0xE3, waveform), (0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+    ///         device.write_register(reg, value, OplRegisterFile::Primary, false);
+    ///     }
+    ///     let mut buffer = vec![0i16; 512 * 2];
+    ///     device.generate_samples(&mut buffer).unwrap();
+    ///     buffer
+    /// };
+    /// // Without WSE, the half sine plays as a sine.
+    /// assert_eq!(render(0x01, 0x00), render(0x00, 0x00));
+    /// assert_ne!(render(0x01, 0x20), render(0x00, 0x20));
+    /// // Only waveforms 0 to 3 exist on an OPL2.
+    /// assert_eq!(render(0x05, 0x20), render(0x01, 0x20));
+    /// ```
+    pub fn set_waveform_masking(&mut self, enabled: bool) {
+        self.waveform_masking = enabled;
+        self.write_waveforms(false);
+    }
+
+    /// Return `true` if OPL2 waveform masking is enabled.
+    pub fn waveform_masking(&self) -> bool {
+        self.waveform_masking
+    }
+
+    /// Enable or disable OPL2 truncation. While OPL3 mode is disabled, writes to the secondary
+    /// register file, other than to register 0x105, are then dropped, as if the chip were an OPL2
+    /// with only the primary file. Dropped writes are not kept for `read_register`, and are
+    /// flagged as usual in strict mode.
+    ///
+    /// Without truncation, the core behaves as follows while OPL3 mode is disabled:
+    ///
+    /// * Waveform select values are masked to 0 to 3 as they are written, so waveforms 4 to 7
+    ///   play as 0 to 3. The mask is not applied again when the mode changes: a waveform written
+    ///   in OPL3 mode keeps playing after leaving it, and one masked in OPL2 mode stays masked
+    ///   after entering OPL3 mode, until it is written again. See `set_waveform_masking` for
+    ///   masking that follows the mode.
+    /// * The secondary register file is written as usual, so channels 9 to 17 play, 18 channels
+    ///   in all.
+    /// * The output bits of registers 0xC0 to 0xC8 are ignored as they are written, and the
+    ///   channel plays on both outputs.
+    /// * The 4-operator bits of register 0x104 are stored, but every channel plays as a
+    ///   2-operator channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to enable OPL2 truncation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Device, OplRegisterFile};
+    ///
+    /// let render = |writes: &[(u16, u8)], truncate: bool| {
+    ///     let mut device = Opl3Device::new(44100);
+    ///     device.set_opl2_truncation(truncate);
+    ///     for &(reg, value) in writes {
+    ///         let file = match reg >> 8 {
+    ///             0 => OplRegisterFile::Primary,
+    ///             _ => OplRegisterFile::Secondary,
+    ///         };
+    ///         device.write_register(reg as u8, value, file, false);
+    ///     }
+    ///     let mut buffer = vec![0i16; 512 * 2];
+    ///     device.generate_samples(&mut buffer).unwrap();
+    ///     buffer
+    /// };
+    /// let note = |base: u16| [(base + 0x63, 0xF0), (base + 0xA0, 0x98), (base + 0xB0, 0x31)];
+    ///
+    /// // Channel 9 plays in OPL2 mode, with its output bits never set, unless truncated.
+    /// assert!(render(&note(0x100), false).iter().any(|sample| sample.abs() > 100));
+    /// assert!(render(&note(0x100), true).iter().all(|&sample| sample == 0));
+    ///
+    /// // Waveform 6 written in OPL2 mode is masked to 2, but not if written in OPL3 mode.
+    /// let square = render(&[&[(0xE3, 0x06)][..], &note(0)].concat(), false);
+    /// let half_sine = render(&[&[(0xE3, 0x02)][..], &note(0)].concat(), false);
+    /// assert_eq!(square, half_sine);
+    /// let writes = [&[(0x105, 0x01), (0xE3, 0x06), (0x105, 0x00)][..], &note(0)].concat();
+    /// assert_ne!(render(&writes, false), half_sine);
+    /// ```
+    pub fn set_opl2_truncation(&mut self, enabled: bool) {
+        self.opl2_truncation = enabled;
+    }
+
+    /// Return `true` if OPL2 truncation is enabled.
+    pub fn opl2_truncation(&self) -> bool {
+        self.opl2_truncation
+    }
+
+    /// Return the waveform the chip should play for a waveform select register, applying OPL2
+    /// waveform masking if it is enabled.
+    fn effective_waveform(&self, file: usize, reg: u8) -> u8 {
+        let value = self.registers[file][reg as usize];
+        if !self.waveform_masking || self.registers[1][0x05] & 0x01 != 0 {
+            value
+        } else if self.registers[0][0x01] & 0x20 != 0 {
+            value & 0x03
+        } else {
+            0
+        }
+    }
+
+    /// Write the effective value of every waveform select register to the chip, after a change to
+    /// the registers or setting that the masking depends on.
+    fn write_waveforms(&mut self, buffered: bool) {
+        for file in 0..2 {
+            for reg in 0xE0..=0xF5 {
+                let reg16 = (file as u16) << 8 | reg as u16;
+                let value = self.effective_waveform(file, reg);
+                if buffered {
+                    self.inner_chip.write_register_buffered(reg16, value);
+                } else {
+                    self.inner_chip.write_register(reg16, value);
+                }
+            }
+        }
+    }
+
+    /// Return the register warnings recorded in strict mode since the last call to
+    /// `clear_warnings` or `reset`. At most `MAX_REGISTER_WARNINGS` warnings are retained.
+    pub fn warnings(&self) -> &[RegisterWarning] {
+        &self.warnings
+    }
+
+    /// Return the number of warnings that were discarded because the warning list was full.
+    pub fn warnings_dropped(&self) -> usize {
+        self.warnings_dropped
+    }
+
+    /// Clear the list of recorded register warnings.
+    pub fn clear_warnings(&mut self) {
+        self.warnings.clear();
+        self.warnings_dropped = 0;
+    }
+
     /// Update the `Opl3Device` instance. This function should be called periodically to update the
     /// state of the OPL3 timers.
     /// # Arguments
@@ -221,14 +729,18 @@ impl Opl3Device {
     /// The Opl3Device maintains a fractional accumulator, so you can use this returned value to
     /// determine how many samples to generate.
     pub fn run(&mut self, usec: f64) -> usize {
-        self.usec_accumulator += usec;
+        self.busy_usec = (self.busy_usec - usec).max(0.0);
+        // The timers count chip clocks, so they run fast or slow with the master clock.
+        self.usec_accumulator +=
+            usec * self.inner_chip.master_clock() as f64 / pacing::YMF262_CLOCK as f64;
         while self.usec_accumulator >= OPL_TICK_RATE {
             self.usec_accumulator -= OPL_TICK_RATE;
             self.timers[0].tick(OPL_TICK_RATE);
             self.timers[1].tick(OPL_TICK_RATE);
         }
 
-        let samples_f = (usec / 1_000_000.0 * self.sample_rate as f64) + self.samples_fpart;
+        let sample_rate = self.sample_rate as f64 * self.inner_chip.rate_ratio();
+        let samples_f = (usec / 1_000_000.0 * sample_rate) + self.samples_fpart;
 
         let samples = samples_f as usize;
         self.samples_fpart = samples_f - samples_f.floor();
@@ -242,7 +754,11 @@ impl Opl3Device {
     /// emulate this timer state, it is necessary to call run() on the OPL3 device periodically.
     pub fn read_status(&mut self) -> u8 {
         self.stats.status_reads = self.stats.status_reads.saturating_add(1);
+        self.status()
+    }
 
+    /// Return the value of the Status register without counting it as a read.
+    fn status(&self) -> u8 {
         let mut status_reg = 0;
 
         status_reg |= if self.timers[0].is_elapsed() {
@@ -266,6 +782,138 @@ impl Opl3Device {
         status_reg
     }
 
+    /// Enable or disable idle skipping, which saves CPU time by not running the emulation while
+    /// the chip is silent. Timers keep running. See `Opl3Chip::set_idle_skip`.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to skip generation while the chip is idle.
+    pub fn set_idle_skip(&mut self, enabled: bool) {
+        self.inner_chip.set_idle_skip(enabled);
+    }
+
+    /// Set which of the chip's outputs each channel of generated audio carries. See
+    /// `Opl3Chip::set_output_map`.
+    ///
+    /// # Arguments
+    ///
+    /// * `map` - The output mapping.
+    pub fn set_output_map(&mut self, map: OutputMap) {
+        self.inner_chip.set_output_map(map);
+    }
+
+    /// Scale the output sample rate by `ratio`, for dynamic rate control. `run` returns sample
+    /// counts at the adjusted rate. See `Opl3Chip::set_rate_ratio`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ratio` - The output rate as a multiple of the nominal sample rate.
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.inner_chip.set_rate_ratio(ratio);
+    }
+
+    /// Set the frequency of the clock driving the chip. This scales the pitch and envelope speed
+    /// of the generated audio, and the rate at which the timers count. See
+    /// `Opl3Chip::set_master_clock`.
+    ///
+    /// # Arguments
+    ///
+    /// * `hz` - The master clock frequency, in Hz.
+    pub fn set_master_clock(&mut self, hz: u32) {
+        self.inner_chip.set_master_clock(hz);
+    }
+
+    /// Set how far apart writes made with `buffered` set are spaced. See
+    /// `Opl3Chip::set_buffered_write_delay`.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The spacing between buffered writes.
+    pub fn set_buffered_write_delay(&mut self, delay: BufferedWriteDelay) {
+        self.inner_chip.set_buffered_write_delay(delay);
+    }
+
+    /// Set how the time the chip needs to accept each write is modeled. See `BusyTiming`.
+    ///
+    /// # Arguments
+    ///
+    /// * `timing` - The busy timing model to use.
+    pub fn set_busy_timing(&mut self, timing: BusyTiming) {
+        self.busy_timing = timing;
+        self.busy_usec = 0.0;
+    }
+
+    /// Return `true` if the chip is still busy accepting the last address or data write.
+    pub fn is_busy(&self) -> bool {
+        self.busy_usec > 0.0
+    }
+
+    /// Read from one of the OPL3's I/O ports, as an emulated CPU would. Even port offsets read the
+    /// status register, with bit 0 set while the chip is busy accepting a write. Odd port offsets
+    /// are not readable and return 0xFF.
+    ///
+    /// Drivers often wait for the chip by polling the status port in a tight loop. Each status
+    /// read is counted as taking about 0.66us, so that such loops take a realistic number of reads
+    /// to complete even if `run` is not called between them.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The port to read. Only the low two bits are used, so both 0x388 and 0x220 style
+    ///            addresses can be passed directly.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{BusyTiming, Opl3Device};
+    ///
+    /// let mut device = Opl3Device::new(44100);
+    /// device.set_busy_timing(BusyTiming::Opl2);
+    /// device.write_port(0x388, 0x20);
+    /// // A typical driver delay loop after an address write.
+    /// let mut busy_reads = 0;
+    /// while device.read_port(0x388) & 0x01 != 0 {
+    ///     busy_reads += 1;
+    /// }
+    /// assert_eq!(busy_reads, 6);
+    /// ```
+    pub fn read_port(&mut self, port: u16) -> u8 {
+        if port & 1 != 0 {
+            return 0xFF;
+        }
+        let busy = if self.is_busy() { OPL_BUSY_FLAG } else { 0 };
+        self.busy_usec = (self.busy_usec - OPL_STATUS_READ_USEC).max(0.0);
+        self.read_status() | busy
+    }
+
+    /// Write to one of the OPL3's I/O ports, as an emulated CPU would. Port offsets 0 and 1 are
+    /// the address and data ports of the primary register file, and offsets 2 and 3 are those of
+    /// the secondary register file. Writes are never buffered.
+    ///
+    /// # Arguments
+    ///
+    /// * `port`  - The port to write. Only the low two bits are used.
+    /// * `value` - The value to write.
+    pub fn write_port(&mut self, port: u16, value: u8) {
+        let file = match port & 2 {
+            0 => OplRegisterFile::Primary,
+            _ => OplRegisterFile::Secondary,
+        };
+        // Neither write can fail.
+        if port & 1 == 0 {
+            _ = self.write_address(value, file);
+        } else {
+            _ = self.write_data(value, file, false);
+        }
+    }
+
+    fn set_busy(&mut self, delay: fn(&pacing::ChipType) -> f64) {
+        self.busy_usec = match self.busy_timing {
+            BusyTiming::Instant => 0.0,
+            BusyTiming::Opl2 => delay(&pacing::ChipType::Opl2),
+            BusyTiming::Opl3 => delay(&pacing::ChipType::Opl3),
+        };
+    }
+
     /// Write a byte to the OPL3 device's Address register.
     /// This function, along with write_data, is likely the primary interface for an emulator
     /// implementing an OPL device.
@@ -280,6 +928,7 @@ impl Opl3Device {
             OplRegisterFile::Primary => self.addr_reg[0] = addr,
             OplRegisterFile::Secondary => self.addr_reg[1] = addr,
         }
+        self.set_busy(pacing::ChipType::address_delay_usec);
         Ok(())
     }
 
@@ -311,6 +960,7 @@ impl Opl3Device {
             OplRegisterFile::Secondary => self.addr_reg[1],
         };
         self.write_register(addr, data, file, buffered);
+        self.set_busy(pacing::ChipType::data_delay_usec);
         Ok(())
     }
 
@@ -350,17 +1000,46 @@ impl Opl3Device {
     /// * `file` - The register file to write to. OPL3 devices have two register files, the Primary
     ///            and Secondary files. OPL2 devices only have the Primary register file
     pub fn write_register(&mut self, reg: u8, value: u8, file: OplRegisterFile, buffered: bool) {
+        let previous_primary_value = self.registers[0][reg as usize];
+        let truncated = self.opl2_truncation
+            && file == OplRegisterFile::Secondary
+            && reg != 0x05
+            && self.registers[1][0x05] & 0x01 == 0;
         let reg16 = match file {
             OplRegisterFile::Primary => {
                 self.registers[0][reg as usize] = value;
                 reg as u16
             }
             OplRegisterFile::Secondary => {
-                self.registers[1][reg as usize] = value;
+                if !truncated {
+                    self.registers[1][reg as usize] = value;
+                }
                 reg as u16 | 0x100
             }
         };
 
+        if self.strict {
+            let opl3_enabled = self.registers[1][0x05] & 0x01 != 0;
+            let mut previous_primary = self.registers[0];
+            if reg16 < 0x100 {
+                previous_primary[reg as usize] = previous_primary_value;
+            }
+            if let Some(kind) = validate::check_register_write(reg16, value)
+                .or_else(|| validate::check_opl3_mode(reg16, opl3_enabled))
+                .or_else(|| validate::check_rhythm_mode(reg16, value, &previous_primary))
+            {
+                if self.warnings.len() < MAX_REGISTER_WARNINGS {
+                    self.warnings.push(RegisterWarning {
+                        register: reg16,
+                        value,
+                        kind,
+                    });
+                } else {
+                    self.warnings_dropped = self.warnings_dropped.saturating_add(1);
+                }
+            }
+        }
+
         // We need to intercept certain register addresses that Nuked-OPL3 doesn't emulate, namely
         // the timer registers.
         if let OplRegisterFile::Primary = file {
@@ -390,11 +1069,22 @@ impl Opl3Device {
         }
 
         self.stats.data_writes = self.stats.data_writes.saturating_add(1);
+        if truncated {
+            return;
+        }
+        let value = if (0xE0..=0xF5).contains(&reg) {
+            self.effective_waveform((reg16 >> 8) as usize, reg)
+        } else {
+            value
+        };
         if buffered {
             self.inner_chip.write_register_buffered(reg16, value);
         } else {
             self.inner_chip.write_register(reg16, value);
         }
+        if self.waveform_masking && (reg16 == 0x01 || reg16 == 0x105) {
+            self.write_waveforms(buffered);
+        }
     }
 
     /// Reset the Opl3Device.
@@ -418,6 +1108,8 @@ impl Opl3Device {
             }
         }
         self.stats = Opl3DeviceStats::default();
+        self.clear_warnings();
+        self.busy_usec = 0.0;
         Ok(())
     }
 
@@ -445,15 +1137,111 @@ impl Opl3Device {
     ///
     /// # Returns
     ///
-    /// A Result containing either `()` on success or an `OplError` on failure.
-    pub fn generate_samples(&mut self, buffer: &mut [i16]) -> Result<(), OplError> {
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure. See `Opl3Chip::generate_stream` for how buffers of odd length are handled.
+    pub fn generate_samples(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
         self.inner_chip.generate_stream(buffer)
     }
+
+    /// Generate a single 4-channel audio sample as a `Frame4`.
+    ///
+    /// # Returns
+    ///
+    /// A `Frame4` containing the sample for each of the four DAC outputs.
+    pub fn generate_frame4(&mut self) -> Frame4 {
+        self.inner_chip.generate_frame4()
+    }
+
+    /// Generate a stream of 4-channel audio samples as `Frame4`s.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - A mutable reference to a slice of `Frame4` that will be filled with audio
+    ///              samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure.
+    pub fn generate_frame4_samples(&mut self, frames: &mut [Frame4]) -> Result<usize, OplError> {
+        self.inner_chip.generate_frame4_stream(frames)
+    }
+
+    /// Generate one video frame's worth of audio, applying a frame's worth of register writes at
+    /// their sample offsets. This matches the structure of a typical emulator main loop, where
+    /// the writes made by the emulated CPU during a frame are collected with timestamps and the
+    /// audio for the frame is rendered all at once.
+    ///
+    /// Writes are applied in slice order. A write whose offset is earlier than the offset of the
+    /// previous write is applied immediately after it, and writes with offsets at or past the end
+    /// of the frame are applied after the frame has been rendered. The OPL3 timers are not
+    /// advanced; continue to call `run` for the elapsed time.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples_per_frame` - The number of stereo samples in one frame.
+    /// * `writes`            - The register writes that occurred during the frame.
+    ///
+    /// # Returns
+    ///
+    /// A slice of `samples_per_frame` interleaved stereo samples, which is valid until the next
+    /// call to `generate_frame`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Device, TimedWrite};
+    ///
+    /// let mut device = Opl3Device::new(44100);
+    /// // 44100Hz at 60 frames per second.
+    /// let writes = [
+    ///     TimedWrite { offset: 0, register: 0xA0, value: 0x41 },
+    ///     TimedWrite { offset: 400, register: 0xB0, value: 0x32 },
+    /// ];
+    /// let audio = device.generate_frame(735, &writes);
+    /// assert_eq!(audio.len(), 735 * 2);
+    /// ```
+    pub fn generate_frame(&mut self, samples_per_frame: usize, writes: &[TimedWrite]) -> &[i16] {
+        let mut buffer = std::mem::take(&mut self.frame_buffer);
+        buffer.clear();
+        buffer.resize(samples_per_frame * 2, 0);
+
+        let mut position = 0;
+        for write in writes {
+            let offset = write.offset.clamp(position, samples_per_frame);
+            if offset > position {
+                _ = self
+                    .inner_chip
+                    .generate_stream(&mut buffer[position * 2..offset * 2]);
+                position = offset;
+            }
+            let file = match write.register & 0x100 {
+                0 => OplRegisterFile::Primary,
+                _ => OplRegisterFile::Secondary,
+            };
+            self.write_register(write.register as u8, write.value, file, false);
+        }
+        if samples_per_frame > position {
+            _ = self.inner_chip.generate_stream(&mut buffer[position * 2..]);
+        }
+
+        self.frame_buffer = buffer;
+        &self.frame_buffer
+    }
 }
 
 /// The `Opl3Chip` struct provides a safe interface for interacting with the Nuked-OPL3 library.
 pub struct Opl3Chip {
     chip: *mut bindings::Opl3Chip,
+    sample_rate: u32,
+    master_clock: u32,
+    rate_ratio: f64,
+    fast_forward_fpart: u64,
+    idle_skip: bool,
+    half_frame: Option<i16>,
+    half_frame_4ch: Option<[i16; 2]>,
+    buffered_write_delay: BufferedWriteDelay,
+    output_map: OutputMap,
 }
 
 impl Drop for Opl3Chip {
@@ -467,9 +1255,26 @@ impl Drop for Opl3Chip {
 }
 
 impl Opl3Chip {
+    /// The lowest sample rate Nuked-OPL3 can resample to. Below it, the resampling step rounds
+    /// down to zero and generating a sample would never complete, so the chip generates audio at
+    /// this rate instead.
+    pub const MIN_SAMPLE_RATE: u32 = 49;
+
+    /// The highest sample rate Nuked-OPL3 can resample to. Above it, the resampling step is too
+    /// large for the core's interpolation, which overflows, and from 4194304Hz it wraps around to
+    /// zero, so the chip generates audio at this rate instead.
+    pub const MAX_SAMPLE_RATE: u32 = 3_181_823;
+
+    /// The largest resampling step, in 1/1024ths of a native sample, the core interpolates
+    /// without overflowing. It is the step at `MAX_SAMPLE_RATE`.
+    const MAX_RATE_RATIO: u64 = u16::MAX as u64;
+
     /// Creates a new OPL3 chip instance. The chip is initialized with the given sample rate.
     /// The internal chip device is Pinned to ensure that it is not moved in memory. The Nuked-OPL3
     /// instance contains many self-referencing pointers, which would be invalidated if moved.
+    /// Sample rates outside `MIN_SAMPLE_RATE` to `MAX_SAMPLE_RATE` are clamped to that range by
+    /// the resampler, so audio plays at the wrong speed; use `try_new` to check a sample rate
+    /// from an untrusted source.
     ///
     /// # Arguments
     ///
@@ -491,8 +1296,60 @@ impl Opl3Chip {
             let layout = std::alloc::Layout::new::<bindings::Opl3Chip>();
             let chip = std::alloc::alloc(layout) as *mut bindings::Opl3Chip;
             bindings::Opl3Reset(chip, sample_rate);
-            Opl3Chip { chip }
+            let mut opl3 = Opl3Chip {
+                chip,
+                sample_rate,
+                master_clock: pacing::YMF262_CLOCK,
+                rate_ratio: 1.0,
+                fast_forward_fpart: 0,
+                idle_skip: false,
+                half_frame: None,
+                half_frame_4ch: None,
+                buffered_write_delay: BufferedWriteDelay::Opl3,
+                output_map: OutputMap::IDENTITY,
+            };
+            opl3.apply_resampling_ratio();
+            opl3
+        }
+    }
+
+    /// Creates a new OPL3 chip instance, as `new` does, after checking that audio can be generated
+    /// at the sample rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to initialize the OPL3 chip with.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the new Opl3Chip instance on success or an `OplError` on
+    /// failure. The error is `OplError::InvalidSampleRate` if the sample rate is outside
+    /// `MIN_SAMPLE_RATE` to `MAX_SAMPLE_RATE`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Chip, OplError};
+    ///
+    /// assert!(Opl3Chip::try_new(44100).is_ok());
+    /// assert!(matches!(Opl3Chip::try_new(0), Err(OplError::InvalidSampleRate(0))));
+    /// assert!(matches!(
+    ///     Opl3Chip::try_new(4_194_304),
+    ///     Err(OplError::InvalidSampleRate(4_194_304))
+    /// ));
+    /// ```
+    pub fn try_new(sample_rate: u32) -> Result<Self, OplError> {
+        Self::check_sample_rate(sample_rate)?;
+        Ok(Self::new(sample_rate))
+    }
+
+    /// Return `OplError::InvalidSampleRate` if the sample rate is outside `MIN_SAMPLE_RATE` to
+    /// `MAX_SAMPLE_RATE`.
+    pub(crate) fn check_sample_rate(sample_rate: u32) -> Result<(), OplError> {
+        if !(Self::MIN_SAMPLE_RATE..=Self::MAX_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(OplError::InvalidSampleRate(sample_rate));
         }
+        Ok(())
     }
 
     /// Reinitialize the OPL3 chip instance.
@@ -515,19 +1372,20 @@ impl Opl3Chip {
         unsafe {
             bindings::Opl3Reset(&mut *self.chip, sample_rate);
         }
+        self.sample_rate = sample_rate;
+        self.half_frame = None;
+        self.half_frame_4ch = None;
+        self.apply_resampling_ratio();
     }
 
-    /// Generate an audio sample.
-    ///
-    /// Internally, this calls Opl3Generate4Ch and returns samples for the first 2 channels.
+    /// Set the frequency of the clock driving the chip. Some clone cards clock the OPL3 slightly
+    /// off its nominal 14.31818MHz. The chip generates one sample every 288 clocks, so a different
+    /// clock scales the pitch of every note and the speed of every envelope and LFO, as it does on
+    /// real hardware. The clock is kept across calls to `reset`.
     ///
     /// # Arguments
     ///
-    /// * `sample` - A mutable slice of 2 elements that will receive the sample.
-    ///
-    /// # Returns
-    ///
-    /// A Result containing either `()` on success or an `OplError` on failure.
+    /// * `hz` - The master clock frequency, in Hz.
     ///
     /// # Example
     ///
@@ -535,28 +1393,43 @@ impl Opl3Chip {
     /// use opl3_rs::Opl3Chip;
     ///
     /// let mut chip = Opl3Chip::new(44100);
+    /// // A clone card running 1% fast.
+    /// chip.set_master_clock(14_461_362);
+    /// assert_eq!(chip.master_clock(), 14_461_362);
+    ///
+    /// // Even an absurd clock still generates audio.
+    /// let mut chip = Opl3Chip::new(8000);
+    /// chip.set_master_clock(u32::MAX);
     /// let mut buffer = [0i16; 2];
-    /// _ = chip.generate(&mut buffer);
+    /// chip.generate(&mut buffer).unwrap();
     /// ```
-    pub fn generate(&mut self, sample: &mut [i16]) -> Result<(), OplError> {
-        if sample.len() < 2 {
-            return Err(OplError::BufferUndersized);
-        }
-        unsafe {
-            bindings::Opl3Generate(&mut *self.chip, sample.as_mut_ptr());
-        }
-        Ok(())
+    pub fn set_master_clock(&mut self, hz: u32) {
+        self.master_clock = hz.max(1);
+        self.apply_resampling_ratio();
     }
 
-    /// Generate a resampled audio sample.
-    ///
-    /// # Arguments
+    /// Return the frequency of the clock driving the chip, in Hz.
+    pub fn master_clock(&self) -> u32 {
+        self.master_clock
+    }
+
+    /// Return the sample rate the chip generates audio at, as passed to `new` or `reset`.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Scale the output sample rate by `ratio`, for dynamic rate control. With a ratio above 1.0,
+    /// the chip produces slightly more output samples per second of emulated time, and slightly
+    /// fewer below 1.0. Emulator frontends can use this to hold their audio buffer at a steady
+    /// fill level, adjusting the ratio by fractions of a percent.
     ///
-    /// * `sample` - A mutable slice of 2 elements that will receive the sample.
+    /// Nuked-OPL3's resampler steps in units of about 0.1% at common sample rates, so the ratio is
+    /// rounded to the nearest step. For finer control, render at the native rate and use
+    /// `multirate::Resampler::set_rate_ratio`. The ratio is kept across calls to `reset`.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// A Result containing either `()` on success or an `OplError` on failure.
+    /// * `ratio` - The output rate as a multiple of the nominal sample rate.
     ///
     /// # Example
     ///
@@ -564,16 +1437,159 @@ impl Opl3Chip {
     /// use opl3_rs::Opl3Chip;
     ///
     /// let mut chip = Opl3Chip::new(44100);
-    /// let mut buffer = [0i16; 2];
-    /// _ = chip.generate_resampled(&mut buffer);
+    /// // The audio buffer is running low, so produce samples 0.5% faster.
+    /// chip.set_rate_ratio(1.005);
     /// ```
-    pub fn generate_resampled(&mut self, sample: &mut [i16]) -> Result<(), OplError> {
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.rate_ratio = ratio;
+        self.apply_resampling_ratio();
+    }
+
+    /// Return the ratio set by `set_rate_ratio`.
+    pub fn rate_ratio(&self) -> f64 {
+        self.rate_ratio
+    }
+
+    /// Recompute Nuked-OPL3's resampling ratio for the master clock and rate ratio. At the nominal
+    /// clock and rate, the ratio is the same as computed by Opl3Reset, so output stays bit-exact
+    /// with upstream.
+    fn apply_resampling_ratio(&mut self) {
+        let sample_rate = (self.sample_rate as u64) << 10;
+        let nominal = if self.master_clock == pacing::YMF262_CLOCK {
+            sample_rate / multirate::NATIVE_SAMPLE_RATE as u64
+        } else {
+            sample_rate * 288 / self.master_clock as u64
+        };
+        // A ratio of 0 would never advance the resampler, and one above MAX_RATE_RATIO would
+        // overflow its interpolation, so extreme rates and clocks are clamped.
+        let nominal = nominal.clamp(1, Self::MAX_RATE_RATIO);
+        let ratio = if self.rate_ratio == 1.0 {
+            nominal as i32
+        } else {
+            ((nominal as f64 * self.rate_ratio).round() as i32)
+                .clamp(1, Self::MAX_RATE_RATIO as i32)
+        };
+        unsafe {
+            (*self.chip).rateratio = ratio;
+        }
+    }
+
+    /// Generate an audio sample.
+    ///
+    /// Internally, this calls Opl3Generate4Ch and returns samples for the first 2 channels.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - A mutable slice of 2 elements that will receive the sample.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let mut buffer = [0i16; 2];
+    /// _ = chip.generate(&mut buffer);
+    /// ```
+    pub fn generate(&mut self, sample: &mut [i16]) -> Result<(), OplError> {
+        if sample.len() < 2 {
+            return Err(OplError::BufferUndersized);
+        }
+        unsafe {
+            bindings::Opl3Generate(&mut *self.chip, sample.as_mut_ptr());
+        }
+        self.output_map.map_stereo(&mut sample[..2]);
+        Ok(())
+    }
+
+    /// Return the rate at which the chip generates samples before resampling, in Hz. This is the
+    /// master clock divided by 288, or 49716Hz for a YMF262 at its nominal clock.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// assert_eq!(chip.native_sample_rate(), 49716);
+    /// chip.set_master_clock(14_400_000);
+    /// assert_eq!(chip.native_sample_rate(), 50000);
+    /// ```
+    pub fn native_sample_rate(&self) -> u32 {
+        if self.master_clock == pacing::YMF262_CLOCK {
+            multirate::NATIVE_SAMPLE_RATE
+        } else {
+            (self.master_clock + 144) / 288
+        }
+    }
+
+    /// Generate a stream of 2 channel, interleaved audio samples at the native sample rate,
+    /// bypassing the built-in resampler entirely. The sample rate passed to `new` and `reset` has
+    /// no effect on the output, so this suits applications that do their own resampling.
+    ///
+    /// One frame is generated for every two samples in the buffer. If the buffer has an odd
+    /// length, its last sample is left unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with native rate
+    ///              audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// // 10ms of audio at the native rate.
+    /// let mut buffer = vec![0i16; chip.native_sample_rate() as usize / 100 * 2];
+    /// assert_eq!(chip.generate_native(&mut buffer).unwrap(), 497);
+    /// ```
+    pub fn generate_native(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let mut frames = 0;
+        for frame in buffer.chunks_exact_mut(2) {
+            self.generate(frame)?;
+            frames += 1;
+        }
+        Ok(frames)
+    }
+
+    /// Generate a resampled audio sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - A mutable slice of 2 elements that will receive the sample.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let mut buffer = [0i16; 2];
+    /// _ = chip.generate_resampled(&mut buffer);
+    /// ```
+    pub fn generate_resampled(&mut self, sample: &mut [i16]) -> Result<(), OplError> {
         if sample.len() < 2 {
             return Err(OplError::BufferUndersized);
         }
         unsafe {
             bindings::Opl3GenerateResampled(&mut *self.chip, sample.as_mut_ptr());
         }
+        self.output_map.map_stereo(&mut sample[..2]);
         Ok(())
     }
 
@@ -581,7 +1597,8 @@ impl Opl3Chip {
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register to write to.
+    /// * `reg` - The register to write to, in the range 0x000..=0x1FF. Writes to registers
+    ///           outside this range are ignored; use `try_write_register` to detect them.
     /// * `value` - The value to write to the register.
     ///
     /// # Example
@@ -593,19 +1610,157 @@ impl Opl3Chip {
     /// chip.write_register(0x20, 0x01);
     /// ```
     pub fn write_register(&mut self, reg: u16, value: u8) {
+        _ = self.try_write_register(reg, value);
+    }
+
+    /// Writes a value to an OPL register, failing if the register is out of range.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register to write to, in the range 0x000..=0x1FF.
+    /// * `value` - The value to write to the register.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or `OplError::RegisterOutOfRange` if `reg` is
+    /// above 0x1FF.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Chip, OplError};
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// assert!(chip.try_write_register(0x120, 0x01).is_ok());
+    /// assert!(matches!(chip.try_write_register(0x220, 0x01), Err(OplError::RegisterOutOfRange)));
+    /// ```
+    pub fn try_write_register(&mut self, reg: u16, value: u8) -> Result<(), OplError> {
+        if reg > OPL_MAX_REGISTER {
+            return Err(OplError::RegisterOutOfRange);
+        }
         unsafe {
             bindings::Opl3WriteReg(&mut *self.chip, reg, value);
         }
+        Ok(())
+    }
+
+    /// Write many registers at once, such as a whole instrument or bank. Every register is
+    /// checked before any is written, and the writes are then made in order without further
+    /// checks, which is cheaper than calling `write_register` for each.
+    ///
+    /// # Arguments
+    ///
+    /// * `writes` - The registers to write, in the range 0x000..=0x1FF, with their values.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or `OplError::RegisterOutOfRange` if any
+    /// register is above 0x1FF, in which case nothing is written.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let patch = [(0x20, 0x01), (0x40, 0x10), (0x60, 0xF0), (0x80, 0x77), (0x23, 0x01)];
+    /// chip.write_registers(&patch).unwrap();
+    /// assert!(chip.write_registers(&[(0x20, 0x02), (0x200, 0x00)]).is_err());
+    /// ```
+    pub fn write_registers(&mut self, writes: &[(u16, u8)]) -> Result<(), OplError> {
+        if writes.iter().any(|&(reg, _)| reg > OPL_MAX_REGISTER) {
+            return Err(OplError::RegisterOutOfRange);
+        }
+        unsafe {
+            let chip = &mut *self.chip;
+            for &(reg, value) in writes {
+                bindings::Opl3WriteReg(chip, reg, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate a stream of 2 channel, interleaved audio samples, writing registers at given
+    /// points within it. Each write is made once the frames before its offset have been
+    /// generated, so that a whole block of writes and audio takes a single call.
+    ///
+    /// # Arguments
+    ///
+    /// * `writes` - The writes to make, as a frame offset from the start of `buffer`, a register
+    ///              in the range 0x000..=0x1FF and a value. Offsets must not decrease.
+    /// * `buffer` - A mutable reference to a slice that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure. The error is `OplError::RegisterOutOfRange` if any register is above 0x1FF,
+    /// `OplError::BufferUndersized` if an offset is past the end of `buffer`, and
+    /// `OplError::UnorderedWrites` if the offsets decrease. Nothing is written or generated on
+    /// error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Chip, OplError};
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let writes = [(0, 0x23, 0x01), (0, 0x63, 0xF0), (0, 0xA0, 0x98), (256, 0xB0, 0x31)];
+    /// let mut buffer = [0i16; 512 * 2];
+    /// chip.write_registers_timed(&writes, &mut buffer).unwrap();
+    /// // The note starts halfway through the buffer.
+    /// assert!(buffer[..512].iter().all(|sample| sample.abs() <= 1));
+    /// assert!(buffer[512..].iter().any(|sample| sample.abs() > 1));
+    ///
+    /// // Writes must be in order.
+    /// let writes = [(256, 0xB0, 0x11), (0, 0xB0, 0x31)];
+    /// assert!(matches!(
+    ///     chip.write_registers_timed(&writes, &mut buffer),
+    ///     Err(OplError::UnorderedWrites)
+    /// ));
+    /// ```
+    pub fn write_registers_timed(
+        &mut self,
+        writes: &[(usize, u16, u8)],
+        buffer: &mut [i16],
+    ) -> Result<usize, OplError> {
+        if writes.iter().any(|&(_, reg, _)| reg > OPL_MAX_REGISTER) {
+            return Err(OplError::RegisterOutOfRange);
+        }
+        if writes
+            .iter()
+            .any(|&(offset, _, _)| offset * 2 > buffer.len())
+        {
+            return Err(OplError::BufferUndersized);
+        }
+        if writes.windows(2).any(|pair| pair[1].0 < pair[0].0) {
+            return Err(OplError::UnorderedWrites);
+        }
+
+        let mut frames = 0;
+        let mut position = 0;
+        for &(offset, reg, value) in writes {
+            if offset > position {
+                frames += self.generate_stream(&mut buffer[position * 2..offset * 2])?;
+                position = offset;
+            }
+            unsafe {
+                bindings::Opl3WriteReg(&mut *self.chip, reg, value);
+            }
+        }
+        frames += self.generate_stream(&mut buffer[position * 2..])?;
+        Ok(frames)
     }
 
     /// Write a value to an OPL register, in buffered mode.
     ///
     /// The OPL3 normally requires a delay between register writes. This function
-    /// will queue the write operation and execute it after any necessary delay.
+    /// will queue the write operation and execute it after any necessary delay. See
+    /// `buffered_writes_pending` for how quickly the queue drains.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register to write to.
+    /// * `reg` - The register to write to, in the range 0x000..=0x1FF. Writes to registers
+    ///           outside this range are ignored.
     /// * `value` - The value to write to the register.
     ///
     /// # Example
@@ -617,14 +1772,149 @@ impl Opl3Chip {
     /// chip.write_register_buffered(0x20, 0x01);
     /// ```
     pub fn write_register_buffered(&mut self, reg: u16, value: u8) {
-        unsafe {
-            bindings::Opl3WriteRegBuffered(&mut *self.chip, reg, value);
+        if reg > OPL_MAX_REGISTER {
+            return;
+        }
+        if self.buffered_write_delay == BufferedWriteDelay::Opl3 {
+            unsafe {
+                bindings::Opl3WriteRegBuffered(&mut *self.chip, reg, value);
+            }
+            return;
+        }
+
+        // The same as Opl3WriteRegBuffered, with the delay replaced.
+        // Safety: the chip pointer is valid for the lifetime of `self`.
+        let chip = unsafe { &mut *self.chip };
+        let last = chip.writebuf_last as usize;
+        if chip.writebuf[last].reg & 0x200 != 0 {
+            // The queue is full, so apply the oldest write now.
+            let (oldest_reg, oldest_value) =
+                (chip.writebuf[last].reg & 0x1FF, chip.writebuf[last].data);
+            unsafe {
+                bindings::Opl3WriteReg(chip, oldest_reg, oldest_value);
+            }
+            chip.writebuf_cur = ((last + 1) % BUFFERED_WRITE_CAPACITY) as u32;
+            chip.writebuf_samplecnt = chip.writebuf[last].time;
         }
+        let time = (chip.writebuf_lasttime + self.buffered_write_delay.samples())
+            .max(chip.writebuf_samplecnt);
+        let entry = &mut chip.writebuf[last];
+        entry.reg = reg | 0x200;
+        entry.data = value;
+        entry.time = time;
+        chip.writebuf_lasttime = time;
+        chip.writebuf_last = ((last + 1) % BUFFERED_WRITE_CAPACITY) as u32;
+    }
+
+    /// Set how far apart buffered writes are spaced. Writes that are already queued keep their
+    /// timing. The delay is kept across calls to `reset`.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The spacing between buffered writes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{BufferedWriteDelay, Opl3Chip};
+    ///
+    /// let mut chip = Opl3Chip::new(49716);
+    /// chip.set_buffered_write_delay(BufferedWriteDelay::Opl2);
+    /// for channel in 0..4 {
+    ///     chip.write_register_buffered(0xA0 + channel, 0x44);
+    /// }
+    /// // Spaced 4 native samples apart, half of the writes are still queued after 10 samples.
+    /// let mut buffer = [0i16; 10 * 2];
+    /// chip.generate_stream(&mut buffer).unwrap();
+    /// assert_eq!(chip.buffered_writes_pending(), 2);
+    /// ```
+    pub fn set_buffered_write_delay(&mut self, delay: BufferedWriteDelay) {
+        self.buffered_write_delay = delay;
+    }
+
+    /// Return the spacing between buffered writes, as set by `set_buffered_write_delay`.
+    pub fn buffered_write_delay(&self) -> BufferedWriteDelay {
+        self.buffered_write_delay
+    }
+
+    /// Return the number of buffered writes that have not been applied to the chip yet.
+    ///
+    /// Buffered writes are applied in order, by default at most one every `BUFFERED_WRITE_DELAY`
+    /// samples at the native rate of 49716Hz, or about every 40us. The spacing can be changed
+    /// with `set_buffered_write_delay`. A write made while the queue is empty is
+    /// applied at the end of the next native sample, and a write queued behind n others reaches
+    /// the chip about n * 40us after it was made. The queue holds up to `BUFFERED_WRITE_CAPACITY`
+    /// writes; once it is full, each new write applies the oldest pending write immediately.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// for channel in 0..9 {
+    ///     chip.write_register_buffered(0xA0 + channel, 0x44);
+    /// }
+    /// assert_eq!(chip.buffered_writes_pending(), 9);
+    ///
+    /// // Nine writes take 18 native samples, just over 16 output frames at 44100Hz, to drain.
+    /// let mut buffer = [0i16; 8 * 2];
+    /// chip.generate_stream(&mut buffer).unwrap();
+    /// assert!(chip.buffered_writes_pending() > 0);
+    /// let mut buffer = [0i16; 10 * 2];
+    /// chip.generate_stream(&mut buffer).unwrap();
+    /// assert_eq!(chip.buffered_writes_pending(), 0);
+    /// ```
+    pub fn buffered_writes_pending(&self) -> usize {
+        // Safety: the chip pointer is valid for the lifetime of `self`.
+        let chip = unsafe { &*self.chip };
+        let cur = chip.writebuf_cur as usize;
+        (0..BUFFERED_WRITE_CAPACITY)
+            .take_while(|i| chip.writebuf[(cur + i) % BUFFERED_WRITE_CAPACITY].reg & 0x200 != 0)
+            .count()
+    }
+
+    /// Apply every pending buffered write to the chip immediately, in order. Buffered writes made
+    /// afterwards are delayed from the current sample rather than behind the flushed writes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// chip.write_register_buffered(0x63, 0xF0);
+    /// chip.write_register_buffered(0xB0, 0x20);
+    /// chip.flush_buffered_writes();
+    /// assert_eq!(chip.buffered_writes_pending(), 0);
+    /// assert_eq!(chip.keyed_channels(), vec![0]);
+    /// ```
+    pub fn flush_buffered_writes(&mut self) {
+        // Safety: the chip pointer is valid for the lifetime of `self`.
+        let chip = unsafe { &mut *self.chip };
+        loop {
+            let cur = chip.writebuf_cur as usize;
+            let entry = &mut chip.writebuf[cur];
+            if entry.reg & 0x200 == 0 {
+                break;
+            }
+            entry.reg &= 0x1FF;
+            let (reg, data) = (entry.reg, entry.data);
+            unsafe {
+                bindings::Opl3WriteReg(chip, reg, data);
+            }
+            chip.writebuf_cur = ((cur + 1) % BUFFERED_WRITE_CAPACITY) as u32;
+        }
+        chip.writebuf_lasttime = chip.writebuf_samplecnt;
     }
 
     /// Generates a stream of resampled audio samples.
     ///
-    /// The number of samples generated is determined by the size of the buffer provided.
+    /// The number of samples generated is determined by the size of the buffer provided, which
+    /// may have any length. Samples are interleaved left and right. If the buffer ends halfway
+    /// through a frame, the left sample of that frame is written and the right sample is kept and
+    /// written at the start of the next call, so consecutive calls always produce a correctly
+    /// interleaved stream.
     ///
     /// # Arguments
     ///
@@ -633,7 +1923,8 @@ impl Opl3Chip {
     ///
     /// # Returns
     ///
-    /// A Result containing either `()` on success or an `OplError` on failure.
+    /// A Result containing either the number of frames generated by the chip on success, or an
+    /// `OplError` on failure. A frame that was started by the previous call is not counted again.
     ///
     /// # Example
     ///
@@ -642,20 +1933,317 @@ impl Opl3Chip {
     ///
     /// let mut chip = Opl3Chip::new(44100);
     /// let mut buffer = [0i16; 1024 * 2];
-    /// _ = chip.generate_stream(&mut buffer);
+    /// assert_eq!(chip.generate_stream(&mut buffer).unwrap(), 1024);
+    ///
+    /// // Two buffers of 3 samples hold 3 frames between them.
+    /// let mut odd = [0i16; 3];
+    /// assert_eq!(chip.generate_stream(&mut odd).unwrap(), 2);
+    /// assert_eq!(chip.generate_stream(&mut odd).unwrap(), 1);
     /// ```
-    pub fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<(), OplError> {
-        if buffer.len() < 2 {
-            return Err(OplError::BufferUndersized);
+    pub fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let mut buffer = buffer;
+        if let Some(right) = self.half_frame {
+            let Some((first, rest)) = buffer.split_first_mut() else {
+                return Ok(0);
+            };
+            *first = right;
+            self.half_frame = None;
+            buffer = rest;
+        }
+
+        let frames = buffer.len() / 2;
+        let (whole, tail) = buffer.split_at_mut(frames * 2);
+        if !whole.is_empty() && !self.skip_if_idle(whole) {
+            unsafe {
+                bindings::Opl3GenerateStream(&mut *self.chip, whole.as_mut_ptr(), frames as u32);
+            }
+            self.output_map.map_stereo(whole);
+        }
+        if let [left] = tail {
+            let mut frame = [0i16; 2];
+            if !self.skip_if_idle(&mut frame) {
+                self.generate_resampled(&mut frame)?;
+            }
+            *left = frame[0];
+            self.half_frame = Some(frame[1]);
+            return Ok(frames + 1);
+        }
+        Ok(frames)
+    }
+
+    /// Generate resampled audio straight into separate left and right buffers of `f32` samples,
+    /// from -1.0 to 1.0, as DAW-style hosts provide them. Samples are converted and
+    /// deinterleaved in small blocks on the stack, so no intermediate buffer is needed, and
+    /// nothing is allocated. Idle skipping and the output mapping apply as for `generate_stream`.
+    ///
+    /// Only whole frames are generated. If a previous call to `generate_stream` ended halfway
+    /// through a frame, the rest of that frame is discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `left`  - A mutable reference to a slice of f32 that will be filled with the left
+    ///             channel.
+    /// * `right` - A mutable reference to a slice of f32 that will be filled with the right
+    ///             channel. It must be as long as `left`.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure. The error is `OplError::BufferMismatch` if the buffers differ in length.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let note = |chip: &mut Opl3Chip| {
+    ///     for (reg, value) in [(0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+    ///         chip.write_register(reg, value);
+    ///     }
+    /// };
+    /// let mut chip = Opl3Chip::new(44100);
+    /// note(&mut chip);
+    /// let (mut left, mut right) = (vec![0.0f32; 1000], vec![0.0f32; 1000]);
+    /// assert_eq!(chip.generate_stream_into_planar_f32(&mut left, &mut right).unwrap(), 1000);
+    ///
+    /// let mut interleaved = Opl3Chip::new(44100);
+    /// note(&mut interleaved);
+    /// let audio = interleaved.render(1000);
+    /// for (i, frame) in audio.chunks(2).enumerate() {
+    ///     assert_eq!(left[i], frame[0] as f32 / 32768.0);
+    ///     assert_eq!(right[i], frame[1] as f32 / 32768.0);
+    /// }
+    /// ```
+    pub fn generate_stream_into_planar_f32(
+        &mut self,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) -> Result<usize, OplError> {
+        const BLOCK_FRAMES: usize = 256;
+        if left.len() != right.len() {
+            return Err(OplError::BufferMismatch);
+        }
+        self.half_frame = None;
+        let mut block = [0i16; BLOCK_FRAMES * 2];
+        for (left, right) in left
+            .chunks_mut(BLOCK_FRAMES)
+            .zip(right.chunks_mut(BLOCK_FRAMES))
+        {
+            let samples = &mut block[..left.len() * 2];
+            if !self.skip_if_idle(samples) {
+                unsafe {
+                    bindings::Opl3GenerateStream(
+                        &mut *self.chip,
+                        samples.as_mut_ptr(),
+                        left.len() as u32,
+                    );
+                }
+                self.output_map.map_stereo(samples);
+            }
+            for ((frame, l), r) in samples.chunks_exact(2).zip(left).zip(right) {
+                *l = frame[0] as f32 / 32768.0;
+                *r = frame[1] as f32 / 32768.0;
+            }
+        }
+        Ok(left.len())
+    }
+
+    /// Enable or disable idle skipping. With idle skipping enabled, `generate_stream` and
+    /// `generate_into` fill the buffer with silence instead of running the emulation when
+    /// `is_idle` reports that the chip is silent, which greatly reduces CPU use for emulated
+    /// machines that are not playing music.
+    ///
+    /// A silent chip still produces output within a few steps of zero, and its LFOs, noise
+    /// generator and envelope clock keep running. Skipping stops them, so output is no longer
+    /// bit-exact with Nuked-OPL3 once the chip plays again. Idle skipping is disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to skip generation while the chip is idle.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// chip.set_idle_skip(true);
+    /// let mut buffer = [1i16; 1024 * 2];
+    /// chip.generate_stream(&mut buffer).unwrap();
+    /// assert!(buffer.iter().all(|&s| s == 0));
+    /// ```
+    pub fn set_idle_skip(&mut self, enabled: bool) {
+        self.idle_skip = enabled;
+    }
+
+    /// Set which of the chip's outputs each channel of generated audio carries, for targets
+    /// that expect right-left order, or 4 channel setups whose DAC outputs are wired in another
+    /// order. The mapping applies to every `generate` method, 2 and 4 channel, and is kept
+    /// across resets. The default is `OutputMap::IDENTITY`.
+    ///
+    /// # Arguments
+    ///
+    /// * `map` - The output mapping.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Chip, OutputMap};
+    ///
+    /// let render = |map: OutputMap| {
+    ///     let mut chip = Opl3Chip::new(44100);
+    ///     chip.set_output_map(map);
+    ///     // A note on the left output only.
+    ///     for (reg, value) in [(0x105, 0x01), (0xC0, 0x11), (0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+    ///         chip.write_register(reg, value);
+    ///     }
+    ///     chip.render(256)
+    /// };
+    /// let normal = render(OutputMap::IDENTITY);
+    /// let swapped = render(OutputMap::SWAPPED);
+    /// assert!(normal.iter().step_by(2).any(|&left| left != 0));
+    /// for (a, b) in normal.chunks(2).zip(swapped.chunks(2)) {
+    ///     assert_eq!((a[0], a[1]), (b[1], b[0]));
+    /// }
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// chip.set_output_map(OutputMap { stereo: [0, 1], quad: [2, 3, 0, 1] });
+    /// for (reg, value) in [(0x105, 0x01), (0xC0, 0x41), (0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+    ///     chip.write_register(reg, value);
+    /// }
+    /// // Output C, where the note plays, now comes first.
+    /// let frames: Vec<_> = (0..64).map(|_| chip.generate_frame4_resampled()).collect();
+    /// assert!(frames.iter().any(|frame| frame.a != 0));
+    /// assert!(frames.iter().all(|frame| frame.c == 0));
+    /// ```
+    pub fn set_output_map(&mut self, map: OutputMap) {
+        self.output_map = map;
+    }
+
+    /// Return the output mapping.
+    pub fn output_map(&self) -> OutputMap {
+        self.output_map
+    }
+
+    /// Zero-fill `buffer` and return `true` if idle skipping is enabled and the chip is idle.
+    fn skip_if_idle(&mut self, buffer: &mut [i16]) -> bool {
+        if self.idle_skip && self.is_idle() {
+            buffer.fill(0);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Fill a fixed-size block with resampled, 2 channel interleaved audio samples.
+    ///
+    /// The block size is checked at compile time, so unlike `generate_stream` this cannot fail.
+    /// This suits rendering directly into fixed DMA blocks on embedded targets.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - A mutable reference to an array of `N` samples, where `N` is a non-zero even
+    ///             number.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let mut block = [0i16; 128 * 2];
+    /// chip.generate_into(&mut block);
+    /// ```
+    ///
+    /// A block holding a partial frame is rejected at compile time:
+    ///
+    /// ```compile_fail
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let mut block = [0i16; 3];
+    /// chip.generate_into(&mut block);
+    /// ```
+    pub fn generate_into<const N: usize>(&mut self, block: &mut [i16; N]) {
+        const {
+            assert!(
+                N >= 2 && N % 2 == 0,
+                "block must hold a whole number of frames"
+            )
+        };
+        if self.skip_if_idle(block) {
+            return;
         }
         unsafe {
-            bindings::Opl3GenerateStream(
-                &mut *self.chip,
-                buffer.as_mut_ptr(),
-                buffer.len() as u32 / 2,
-            );
+            bindings::Opl3GenerateStream(&mut *self.chip, block.as_mut_ptr(), N as u32 / 2);
         }
-        Ok(())
+        self.output_map.map_stereo(block);
+    }
+
+    /// Generate a fixed-size block of resampled, 2 channel interleaved audio samples.
+    ///
+    /// This is a by-value variant of `generate_into`. `N` is the number of samples, which is twice
+    /// the number of frames.
+    ///
+    /// # Returns
+    ///
+    /// An array of `N` interleaved samples.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let block: [i16; 64 * 2] = chip.generate_exact();
+    /// ```
+    pub fn generate_exact<const N: usize>(&mut self) -> [i16; N] {
+        let mut block = [0; N];
+        self.generate_into(&mut block);
+        block
+    }
+
+    /// Render a number of frames of resampled, 2 channel interleaved audio into a new vector.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The number of stereo frames to render.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `frames * 2` interleaved samples.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let audio = chip.render(4410);
+    /// assert_eq!(audio.len(), 4410 * 2);
+    /// ```
+    pub fn render(&mut self, frames: usize) -> Vec<i16> {
+        let mut buffer = Vec::new();
+        self.render_into(frames, &mut buffer);
+        buffer
+    }
+
+    /// Render a number of frames of resampled, 2 channel interleaved audio into a reusable
+    /// vector. The vector is cleared first, and its allocation is reused, so rendering repeatedly
+    /// into the same scratch vector does not allocate once it has grown to size. The whole
+    /// request is rendered with a single call into Nuked-OPL3.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The number of stereo frames to render.
+    /// * `buffer` - The vector to render into. On return it holds `frames * 2` samples.
+    pub fn render_into(&mut self, frames: usize, buffer: &mut Vec<i16>) {
+        buffer.clear();
+        if frames == 0 {
+            return;
+        }
+        buffer.resize(frames * 2, 0);
+        _ = self.generate_stream(buffer);
     }
 
     /// Generate a 4 channel audio sample.
@@ -684,6 +2272,7 @@ impl Opl3Chip {
         unsafe {
             bindings::Opl3Generate4Ch(&mut *self.chip, sample.as_mut_ptr());
         }
+        self.output_map.map_quad(sample);
         Ok(())
     }
 
@@ -713,6 +2302,7 @@ impl Opl3Chip {
         unsafe {
             bindings::Opl3Generate4ChResampled(&mut *self.chip, sample.as_mut_ptr());
         }
+        self.output_map.map_quad(sample);
         Ok(())
     }
 
@@ -729,11 +2319,15 @@ impl Opl3Chip {
     ///               audio samples, interleaved between left and right channels.
     /// * `buffer2` - A mutable reference to a slice that will be filled with audio samples for the
     ///               channels 2 and 3.
-    ///               The length of buffer1 should equal the length of buffer2.
+    ///               The length of buffer1 must equal the length of buffer2.
+    ///
+    /// The buffers may have any length. As with `generate_stream`, a frame that is cut off at the
+    /// end of the buffers is completed at the start of the next call.
     ///
     /// # Returns
     ///
-    /// A Result containing either `()` on success or an `OplError` on failure.
+    /// A Result containing either the number of frames generated by the chip on success, or an
+    /// `OplError` on failure. A frame that was started by the previous call is not counted again.
     ///
     /// # Example
     ///
@@ -749,22 +2343,138 @@ impl Opl3Chip {
         &mut self,
         buffer1: &mut [i16],
         buffer2: &mut [i16],
-    ) -> Result<(), OplError> {
+    ) -> Result<usize, OplError> {
         if buffer1.len() != buffer2.len() {
             return Err(OplError::BufferMismatch);
         }
-        if buffer1.len() < 4 || buffer2.len() < 4 {
-            return Err(OplError::BufferUndersized);
+        let (mut buffer1, mut buffer2) = (buffer1, buffer2);
+        if let Some([right1, right2]) = self.half_frame_4ch {
+            let (Some((first1, rest1)), Some((first2, rest2))) =
+                (buffer1.split_first_mut(), buffer2.split_first_mut())
+            else {
+                return Ok(0);
+            };
+            (*first1, *first2) = (right1, right2);
+            self.half_frame_4ch = None;
+            (buffer1, buffer2) = (rest1, rest2);
+        }
+
+        let frames = buffer1.len() / 2;
+        let (whole1, tail1) = buffer1.split_at_mut(frames * 2);
+        let (whole2, tail2) = buffer2.split_at_mut(frames * 2);
+        if frames > 0 {
+            unsafe {
+                bindings::Opl3Generate4ChStream(
+                    &mut *self.chip,
+                    whole1.as_mut_ptr(),
+                    whole2.as_mut_ptr(),
+                    frames as u32,
+                );
+            }
+            if self.output_map != OutputMap::IDENTITY {
+                for (pair1, pair2) in whole1.chunks_exact_mut(2).zip(whole2.chunks_exact_mut(2)) {
+                    let mut sample = [pair1[0], pair1[1], pair2[0], pair2[1]];
+                    self.output_map.map_quad(&mut sample);
+                    pair1.copy_from_slice(&sample[..2]);
+                    pair2.copy_from_slice(&sample[2..]);
+                }
+            }
         }
+        if let ([left1], [left2]) = (tail1, tail2) {
+            let frame = self.generate_frame4_resampled();
+            (*left1, *left2) = (frame.a, frame.c);
+            self.half_frame_4ch = Some([frame.b, frame.d]);
+            return Ok(frames + 1);
+        }
+        Ok(frames)
+    }
+
+    /// Generate a 4-channel audio sample as a `Frame4`.
+    ///
+    /// Internally, this calls Opl3Generate4Ch.
+    ///
+    /// # Returns
+    ///
+    /// A `Frame4` containing the sample for each of the four DAC outputs.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let frame = chip.generate_frame4();
+    /// assert_eq!(frame.c, 0);
+    /// ```
+    pub fn generate_frame4(&mut self) -> Frame4 {
+        let mut frame = Frame4::default();
         unsafe {
-            bindings::Opl3Generate4ChStream(
+            bindings::Opl3Generate4Ch(&mut *self.chip, &mut frame as *mut Frame4 as *mut i16);
+        }
+        self.output_map.map_frame4(frame)
+    }
+
+    /// Generate a resampled 4-channel audio sample as a `Frame4`.
+    ///
+    /// # Returns
+    ///
+    /// A `Frame4` containing the sample for each of the four DAC outputs.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let frame = chip.generate_frame4_resampled();
+    /// ```
+    pub fn generate_frame4_resampled(&mut self) -> Frame4 {
+        let mut frame = Frame4::default();
+        unsafe {
+            bindings::Opl3Generate4ChResampled(
                 &mut *self.chip,
-                buffer1.as_mut_ptr(),
-                buffer2.as_mut_ptr(),
-                buffer1.len() as u32 / 2,
+                &mut frame as *mut Frame4 as *mut i16,
             );
         }
-        Ok(())
+        self.output_map.map_frame4(frame)
+    }
+
+    /// Generates a stream of 4-channel audio samples as `Frame4`s, resampled to the configured
+    /// sample rate. Unlike `generate_4ch_stream`, all four outputs are kept together in each
+    /// frame, so no assumptions about buffer ordering are required.
+    ///
+    /// The number of samples is determined by the length of the slice.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - A mutable reference to a slice of `Frame4` that will be filled with audio
+    ///              samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success, which is the length
+    /// of the slice, or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Frame4, Opl3Chip};
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let mut frames = [Frame4::default(); 1024];
+    /// _ = chip.generate_frame4_stream(&mut frames);
+    /// ```
+    pub fn generate_frame4_stream(&mut self, frames: &mut [Frame4]) -> Result<usize, OplError> {
+        for frame in frames.iter_mut() {
+            unsafe {
+                bindings::Opl3Generate4ChResampled(
+                    &mut *self.chip,
+                    frame as *mut Frame4 as *mut i16,
+                );
+            }
+            *frame = self.output_map.map_frame4(*frame);
+        }
+        Ok(frames.len())
     }
 }
 
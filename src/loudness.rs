@@ -0,0 +1,282 @@
+//! Loudness measurement by EBU R128 and ReplayGain 2.0, for normalizing rendered tracks.
+//!
+//! AdLib music was mixed by ear on whatever sound card the composer had, so one track can be
+//! several times louder than the next. `LoudnessMeter` measures the integrated loudness of
+//! stereo audio as defined by ITU-R BS.1770-4, which EBU R128 and ReplayGain 2.0 both build on:
+//! the audio is K-weighted, split into overlapping 400ms blocks, and the blocks left after an
+//! absolute gate at -70 LUFS and a relative gate 10 LU below their mean are averaged. The
+//! ReplayGain of a track is the gain that brings it to the reference loudness of -18 LUFS.
+//!
+//! Audio can be measured once it has been rendered, with `LoudnessMeter::process`, or while it
+//! is rendered, with a `MeteredSink` wrapped around the sink it is written to. The meter only
+//! keeps one value per 100ms of audio, so whole albums can be measured in one pass.
+//!
+//! # Example
+//!
+//! ```
+//! use std::io::Cursor;
+//!
+//! use opl3_rs::event_log::{EventLog, OplEvent};
+//! use opl3_rs::export::{render_event_log, AudioSink, WavSink};
+//! use opl3_rs::loudness::MeteredSink;
+//!
+//! let mut log = EventLog::new(44100);
+//! for (register, value) in [(0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+//!     log.push(OplEvent { time: 0, register, value });
+//! }
+//! log.push(OplEvent { time: 88200, register: 0xB0, value: 0x11 });
+//!
+//! let wav = WavSink::new(Cursor::new(Vec::new()), 44100, 2).unwrap();
+//! let mut sink = MeteredSink::new(wav, 44100);
+//! render_event_log(&log, 44100, 500, &mut sink).unwrap();
+//! sink.finish().unwrap();
+//!
+//! let (_wav, meter) = sink.into_inner();
+//! let loudness = meter.integrated_loudness().unwrap();
+//! let gain = meter.replay_gain().unwrap();
+//! assert!((loudness + gain - -18.0).abs() < 1e-9);
+//! assert!(meter.sample_peak() > 0.0);
+//! ```
+
+use crate::export::AudioSink;
+use crate::OplError;
+
+/// The reference loudness of ReplayGain 2.0, in LUFS.
+pub const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+/// The loudness below which blocks are always gated out, in LUFS.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// How far below the mean loudness of the blocks left by the absolute gate the relative gate
+/// is, in LU.
+const RELATIVE_GATE_LU: f64 = 10.0;
+/// The number of 100ms steps in a 400ms gating block.
+const STEPS_PER_BLOCK: usize = 4;
+
+/// Convert a mean square to a loudness, in LUFS.
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// A biquad filter in transposed direct form II.
+#[derive(Copy, Clone, Debug)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// Create the two stages of the K-weighting filter of BS.1770 for a sample rate: a high shelf
+/// modeling the head, and a high pass. The coefficients are derived for any rate, and match
+/// those given in BS.1770 at 48kHz.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+    [shelf, high_pass]
+}
+
+/// The `LoudnessMeter` struct measures the loudness and peak level of 2 channel audio.
+#[derive(Clone, Debug)]
+pub struct LoudnessMeter {
+    filters: [[Biquad; 2]; 2],
+    /// The number of frames in a 100ms step.
+    step_frames: usize,
+    /// The sum of the squares of the K-weighted samples of the step being measured.
+    step_sum: f64,
+    step_position: usize,
+    /// The mean square of every whole step measured so far.
+    steps: Vec<f64>,
+    /// The mean square of every 400ms block that has passed the absolute gate.
+    blocks: Vec<f64>,
+    peak: i16,
+}
+
+impl LoudnessMeter {
+    /// Create a new `LoudnessMeter`, with nothing measured yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate of the audio to measure.
+    pub fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate.max(10);
+        LoudnessMeter {
+            filters: [k_weighting(sample_rate), k_weighting(sample_rate)],
+            step_frames: sample_rate as usize / 10,
+            step_sum: 0.0,
+            step_position: 0,
+            steps: Vec::new(),
+            blocks: Vec::new(),
+            peak: 0,
+        }
+    }
+
+    /// Measure more audio, following on from the audio measured before.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - 2 channel, interleaved samples. A trailing odd sample is ignored.
+    pub fn process(&mut self, samples: &[i16]) {
+        for frame in samples.chunks_exact(2) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                self.peak = self.peak.max(sample.saturating_abs());
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let weighted = high_pass.process(shelf.process(sample as f64 / 32768.0));
+                self.step_sum += weighted * weighted;
+            }
+            self.step_position += 1;
+            if self.step_position == self.step_frames {
+                self.steps.push(self.step_sum / self.step_frames as f64);
+                self.step_sum = 0.0;
+                self.step_position = 0;
+                if let Some(block) = self.steps.last_chunk::<STEPS_PER_BLOCK>() {
+                    let mean_square = block.iter().sum::<f64>() / STEPS_PER_BLOCK as f64;
+                    if loudness(mean_square) > ABSOLUTE_GATE_LUFS {
+                        self.blocks.push(mean_square);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the integrated loudness of the audio measured so far, in LUFS, or `None` if it
+    /// is all silence, or shorter than a single 400ms block.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::loudness::LoudnessMeter;
+    ///
+    /// // A 1kHz sine at -20dBFS on both channels measures -20 LUFS.
+    /// let mut meter = LoudnessMeter::new(48000);
+    /// let samples: Vec<i16> = (0..48000 * 2)
+    ///     .map(|i| {
+    ///         let phase = (i / 2) as f64 * 1000.0 / 48000.0 * std::f64::consts::TAU;
+    ///         (phase.sin() * 0.1 * 32768.0) as i16
+    ///     })
+    ///     .collect();
+    /// meter.process(&samples);
+    /// assert!((meter.integrated_loudness().unwrap() - -20.0).abs() < 0.05);
+    ///
+    /// assert_eq!(LoudnessMeter::new(48000).integrated_loudness(), None);
+    /// ```
+    pub fn integrated_loudness(&self) -> Option<f64> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let mean = self.blocks.iter().sum::<f64>() / self.blocks.len() as f64;
+        let gate = loudness(mean) - RELATIVE_GATE_LU;
+        let (sum, count) = self
+            .blocks
+            .iter()
+            .filter(|&&block| loudness(block) > gate)
+            .fold((0.0, 0), |(sum, count), &block| (sum + block, count + 1));
+        Some(loudness(sum / count as f64))
+    }
+
+    /// Return the ReplayGain 2.0 track gain of the audio measured so far, in dB: the gain that
+    /// brings its integrated loudness to `REPLAYGAIN_REFERENCE_LUFS`. Returns `None` if the
+    /// loudness cannot be measured.
+    pub fn replay_gain(&self) -> Option<f64> {
+        self.integrated_loudness()
+            .map(|lufs| REPLAYGAIN_REFERENCE_LUFS - lufs)
+    }
+
+    /// Return the largest sample magnitude measured so far, where 1.0 is full scale. This is
+    /// the peak that ReplayGain tags record alongside the gain.
+    pub fn sample_peak(&self) -> f64 {
+        self.peak as f64 / 32768.0
+    }
+
+    /// Return the loudness of the most recent 400ms of audio, in LUFS, as shown by a
+    /// momentary loudness meter, or `None` before 400ms have been measured.
+    pub fn momentary_loudness(&self) -> Option<f64> {
+        let block = self.steps.last_chunk::<STEPS_PER_BLOCK>()?;
+        Some(loudness(block.iter().sum::<f64>() / STEPS_PER_BLOCK as f64))
+    }
+}
+
+/// Measuring audio by writing it to the meter, so that it can be rendered into like a file.
+impl AudioSink for LoudnessMeter {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        self.process(samples);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), OplError> {
+        Ok(())
+    }
+}
+
+/// The `MeteredSink` struct passes audio on to another sink, measuring its loudness on the way.
+#[derive(Debug)]
+pub struct MeteredSink<S> {
+    sink: S,
+    meter: LoudnessMeter,
+}
+
+impl<S: AudioSink> MeteredSink<S> {
+    /// Create a new `MeteredSink` around a sink, which must have been created with 2 channels.
+    ///
+    /// # Arguments
+    ///
+    /// * `sink`        - The sink to pass audio on to.
+    /// * `sample_rate` - The sample rate of the audio.
+    pub fn new(sink: S, sample_rate: u32) -> Self {
+        MeteredSink {
+            sink,
+            meter: LoudnessMeter::new(sample_rate),
+        }
+    }
+
+    /// Return the meter, to read the loudness of the audio written so far.
+    pub fn meter(&self) -> &LoudnessMeter {
+        &self.meter
+    }
+
+    /// Consume the `MeteredSink`, returning the sink and the meter.
+    pub fn into_inner(self) -> (S, LoudnessMeter) {
+        (self.sink, self.meter)
+    }
+}
+
+impl<S: AudioSink> AudioSink for MeteredSink<S> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<(), OplError> {
+        self.meter.process(samples);
+        self.sink.write_samples(samples)
+    }
+
+    fn finish(&mut self) -> Result<(), OplError> {
+        self.sink.finish()
+    }
+}
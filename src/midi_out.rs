@@ -0,0 +1,347 @@
+//! Experimental conversion of OPL register logs to approximate MIDI, for transcription.
+//!
+//! An OPL register log says nothing about notes directly: a note is a channel's key-on bit
+//! going up and down, its pitch is whatever frequency the channel's F-Num, block and operator
+//! multipliers add up to, and its loudness is an attenuation. `log_to_midi_events` replays a
+//! log through a `register_cache::RegisterCache` around a chip, which keeps the shadow of every
+//! register written, and reads the pitch of each channel back with
+//! `Opl3Chip::channel_frequency_hz`. From that it guesses MIDI notes:
+//!
+//! * Each of the 18 OPL channels is played on a MIDI channel of its own, skipping the GM
+//!   percussion channel 10. The last three OPL channels share with the first three.
+//! * A key-on plays the note nearest the channel's pitch, with a velocity from the total level
+//!   of its carrier, and a key-off ends it.
+//! * A pitch change while a note is keyed, such as a slide or vibrato, becomes a pitch bend if
+//!   it stays within `MidiExportOptions::pitch_bend_range` of the note, and starts a new note
+//!   otherwise.
+//! * The drums of rhythm mode are played on the percussion channel as GM drums.
+//!
+//! Instruments are not guessed, so every channel plays program 0, and the result is a sketch of
+//! the music to be cleaned up by hand rather than a faithful copy. `log_to_smf` writes the
+//! notes as a type 0 Standard MIDI File, at 120 beats per minute.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::event_log::{EventLog, OplEvent};
+//! use opl3_rs::midi_out::{log_to_midi_events, log_to_smf, MidiExportOptions};
+//!
+//! // A4 for half a second: F-Num 580 in block 4 on channel 0, with its carrier at full level.
+//! let mut log = EventLog::new(1000);
+//! for (register, value) in [(0x23, 0x01), (0x43, 0x00), (0xA0, 0x44), (0xB0, 0x32)] {
+//!     log.push(OplEvent { time: 0, register, value });
+//! }
+//! log.push(OplEvent { time: 500, register: 0xB0, value: 0x12 });
+//!
+//! let options = MidiExportOptions::default();
+//! let events = log_to_midi_events(&log, &options);
+//! let messages: Vec<_> = events.iter().map(|event| (event.tick, event.message)).collect();
+//! assert_eq!(messages, vec![(0, [0x90, 69, 127]), (480, [0x80, 69, 0])]);
+//!
+//! let smf = log_to_smf(&log, &options);
+//! assert!(smf.starts_with(b"MThd"));
+//! ```
+
+use crate::event_log::EventLog;
+use crate::opl_core::OplCore;
+use crate::register_cache::RegisterCache;
+use crate::registers::{
+    reg_for_channel, reg_for_operator, register_address, ChannelParam, Drum, OperatorParam,
+};
+use crate::Opl3Chip;
+
+/// The MIDI channel of GM percussion, channel 10 counting from 1.
+const PERCUSSION_CHANNEL: u8 = 9;
+/// The register holding the rhythm mode enable and the drum key-on bits.
+const RHYTHM_REGISTER: u16 = 0xBD;
+/// The tempo the converted notes are timed at, in microseconds per beat, for 120 beats per
+/// minute.
+const TEMPO_USEC: u64 = 500_000;
+/// How far a pitch can be from a note and still play it without a bend, in semitones. F-Num
+/// tables are rarely exact, and are often a few cents out across the board.
+const IN_TUNE_SEMITONES: f64 = 0.05;
+/// The center of the 14-bit pitch bend range.
+const BEND_CENTER: u16 = 0x2000;
+
+/// The `MidiExportOptions` struct configures the conversion of a log to MIDI.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MidiExportOptions {
+    /// The resolution of the converted notes, in ticks per beat.
+    pub ticks_per_beat: u16,
+    /// The pitch bend range of the MIDI synth the notes are played on, in semitones. Pitch
+    /// changes beyond it start new notes. 0 disables pitch bends, so that every change of note
+    /// starts a new one.
+    pub pitch_bend_range: u8,
+}
+
+impl Default for MidiExportOptions {
+    fn default() -> Self {
+        MidiExportOptions {
+            ticks_per_beat: 480,
+            pitch_bend_range: 2,
+        }
+    }
+}
+
+/// The `MidiEvent` struct is a MIDI channel message at a time in ticks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MidiEvent {
+    /// The time of the message, in ticks from the start of the log.
+    pub tick: u64,
+    /// The message: a note on, note off or pitch bend.
+    pub message: [u8; 3],
+}
+
+/// The note a channel is playing.
+#[derive(Copy, Clone, Debug)]
+struct SoundingNote {
+    note: u8,
+    bend: u16,
+}
+
+/// The state of the conversion.
+struct Converter {
+    chip: RegisterCache<Opl3Chip>,
+    options: MidiExportOptions,
+    notes: [Option<SoundingNote>; 18],
+    drums: u8,
+    events: Vec<MidiEvent>,
+    tick: u64,
+}
+
+impl Converter {
+    /// Return the MIDI channel an OPL channel is played on.
+    fn midi_channel(channel: u8) -> u8 {
+        let channel = channel % 15;
+        channel + (channel >= PERCUSSION_CHANNEL) as u8
+    }
+
+    /// Return the value of a register, or 0 if it has not been written.
+    fn register(&self, reg: u16) -> u8 {
+        self.chip.cached(reg).unwrap_or(0)
+    }
+
+    fn rhythm(&self) -> bool {
+        self.register(RHYTHM_REGISTER) & 0x20 != 0
+    }
+
+    fn push(&mut self, status: u8, data1: u8, data2: u8) {
+        self.events.push(MidiEvent {
+            tick: self.tick,
+            message: [status, data1 & 0x7F, data2 & 0x7F],
+        });
+    }
+
+    /// Return the pitch of a channel as a fractional MIDI note, or `None` if it is silent.
+    fn pitch(&self, channel: u8) -> Option<f64> {
+        let hz = self.chip.core().channel_frequency_hz(channel as usize);
+        (hz > 0.0).then(|| 69.0 + 12.0 * (hz / 440.0).log2())
+    }
+
+    /// Return the velocity of a channel's note, from the total level of its carrier.
+    fn velocity(&self, channel: u8) -> u8 {
+        let level = reg_for_operator(channel, 1, OperatorParam::Level).map_or(0, |(file, reg)| {
+            self.register(register_address(file, reg)) & 0x3F
+        });
+        (127 - level as u16 * 127 / 63).max(1) as u8
+    }
+
+    /// Return the 14-bit pitch bend that shifts a note to a pitch, or `None` if the pitch is
+    /// out of the bend range.
+    fn bend(&self, note: u8, pitch: f64) -> Option<u16> {
+        let range = self.options.pitch_bend_range as f64;
+        let offset = pitch - note as f64;
+        if offset.abs() < IN_TUNE_SEMITONES {
+            return Some(BEND_CENTER);
+        }
+        if range == 0.0 {
+            return (offset.abs() < 0.5).then_some(BEND_CENTER);
+        }
+        if offset.abs() > range {
+            return None;
+        }
+        let bend = BEND_CENTER as f64 * (1.0 + offset / range);
+        Some(bend.round().clamp(0.0, 0x3FFF as f64) as u16)
+    }
+
+    fn pitch_bend(&mut self, channel: u8, bend: u16) {
+        let midi = Converter::midi_channel(channel);
+        self.push(0xE0 | midi, bend as u8, (bend >> 7) as u8);
+    }
+
+    fn note_on(&mut self, channel: u8) {
+        let Some(pitch) = self.pitch(channel) else {
+            return;
+        };
+        let note = pitch.round().clamp(0.0, 127.0) as u8;
+        let midi = Converter::midi_channel(channel);
+        let bend = self.bend(note, pitch).unwrap_or(BEND_CENTER);
+        if bend != BEND_CENTER {
+            self.pitch_bend(channel, bend);
+        }
+        let velocity = self.velocity(channel);
+        self.push(0x90 | midi, note, velocity);
+        self.notes[channel as usize] = Some(SoundingNote { note, bend });
+    }
+
+    fn note_off(&mut self, channel: u8) {
+        if let Some(sounding) = self.notes[channel as usize].take() {
+            let midi = Converter::midi_channel(channel);
+            self.push(0x80 | midi, sounding.note, 0);
+            if sounding.bend != BEND_CENTER {
+                self.pitch_bend(channel, BEND_CENTER);
+            }
+        }
+    }
+
+    /// Follow a change of pitch on a keyed channel.
+    fn retune(&mut self, channel: u8) {
+        let (Some(sounding), Some(pitch)) = (self.notes[channel as usize], self.pitch(channel))
+        else {
+            return;
+        };
+        match self.bend(sounding.note, pitch) {
+            Some(bend) if bend == sounding.bend => {}
+            Some(bend) => {
+                self.pitch_bend(channel, bend);
+                self.notes[channel as usize] = Some(SoundingNote { bend, ..sounding });
+            }
+            None => {
+                self.note_off(channel);
+                self.note_on(channel);
+            }
+        }
+    }
+
+    /// Return whether a channel's key-on bit is set, and not overridden by rhythm mode.
+    fn keyed(&self, channel: u8) -> bool {
+        if self.rhythm() && (6..9).contains(&channel) {
+            return false;
+        }
+        reg_for_channel(channel, ChannelParam::KeyOnBlock)
+            .is_some_and(|(file, reg)| self.register(register_address(file, reg)) & 0x20 != 0)
+    }
+
+    /// Apply a register write, and convert what it changed.
+    fn write(&mut self, register: u16, value: u8) {
+        let keyed: [bool; 18] = std::array::from_fn(|channel| self.keyed(channel as u8));
+        self.chip.write_register(register, value);
+
+        if register == RHYTHM_REGISTER {
+            let drums = if self.rhythm() { value & 0x1F } else { 0 };
+            self.set_drums(drums);
+        }
+
+        for channel in 0..18u8 {
+            match (keyed[channel as usize], self.keyed(channel)) {
+                (false, true) => self.note_on(channel),
+                (true, false) => self.note_off(channel),
+                (true, true) => self.retune(channel),
+                (false, false) => {}
+            }
+        }
+    }
+
+    /// Play the drums whose key-on bits have changed.
+    fn set_drums(&mut self, drums: u8) {
+        for drum in Drum::ALL {
+            let bit = drum.key_bit();
+            let note = match drum {
+                Drum::BassDrum => 36,
+                Drum::Snare => 38,
+                Drum::TomTom => 47,
+                Drum::Cymbal => 49,
+                Drum::HiHat => 42,
+            };
+            match (self.drums & bit != 0, drums & bit != 0) {
+                (false, true) => self.push(0x90 | PERCUSSION_CHANNEL, note, 100),
+                (true, false) => self.push(0x80 | PERCUSSION_CHANNEL, note, 0),
+                _ => {}
+            }
+        }
+        self.drums = drums;
+    }
+}
+
+/// Convert a register log to approximate MIDI messages. The log is replayed through a chip, so
+/// it should start from a reset chip, as logs recorded from the start of a song do.
+///
+/// # Arguments
+///
+/// * `log`     - The register log to convert.
+/// * `options` - The timing resolution and pitch bend range.
+///
+/// # Returns
+///
+/// The messages, in order, ending with a note off for every note still sounding at the end of
+/// the log.
+pub fn log_to_midi_events(log: &EventLog, options: &MidiExportOptions) -> Vec<MidiEvent> {
+    let mut converter = Converter {
+        chip: RegisterCache::new(Opl3Chip::new(44100)),
+        options: *options,
+        notes: [None; 18],
+        drums: 0,
+        events: Vec::new(),
+        tick: 0,
+    };
+    let ticks_per_second = options.ticks_per_beat as u64 * 1_000_000 / TEMPO_USEC;
+    let tick_rate = log.tick_rate().max(1) as u64;
+    for event in log.iter() {
+        converter.tick = event.time * ticks_per_second / tick_rate;
+        converter.write(event.register, event.value);
+    }
+    for channel in 0..18 {
+        converter.note_off(channel);
+    }
+    converter.set_drums(0);
+    converter.events
+}
+
+/// Convert a register log to a type 0 Standard MIDI File, as with `log_to_midi_events`.
+///
+/// # Arguments
+///
+/// * `log`     - The register log to convert.
+/// * `options` - The timing resolution and pitch bend range.
+///
+/// # Returns
+///
+/// The contents of the file.
+pub fn log_to_smf(log: &EventLog, options: &MidiExportOptions) -> Vec<u8> {
+    let mut track = Vec::new();
+    // The tempo, as a meta event at tick 0.
+    track.extend_from_slice(&[0x00, 0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&(TEMPO_USEC as u32).to_be_bytes()[1..]);
+    let mut last_tick = 0;
+    for event in log_to_midi_events(log, options) {
+        push_variable_length(&mut track, event.tick - last_tick);
+        track.extend_from_slice(&event.message);
+        last_tick = event.tick;
+    }
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    let mut smf = Vec::with_capacity(22 + track.len());
+    smf.extend_from_slice(b"MThd");
+    smf.extend_from_slice(&6u32.to_be_bytes());
+    smf.extend_from_slice(&0u16.to_be_bytes());
+    smf.extend_from_slice(&1u16.to_be_bytes());
+    smf.extend_from_slice(&options.ticks_per_beat.max(1).to_be_bytes());
+    smf.extend_from_slice(b"MTrk");
+    smf.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    smf.extend_from_slice(&track);
+    smf
+}
+
+/// Append a MIDI variable length quantity.
+fn push_variable_length(out: &mut Vec<u8>, value: u64) {
+    let value = value.min(0x0FFF_FFFF);
+    let mut shift = 21;
+    while shift > 0 && value >> shift == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        out.push((value >> shift) as u8 & 0x7F | 0x80);
+        shift -= 7;
+    }
+    out.push(value as u8 & 0x7F);
+}
@@ -0,0 +1,1588 @@
+//! A General MIDI synthesizer playing instrument banks on an `Opl3Chip`.
+//!
+//! `OplMidiSynth` turns MIDI channel messages into register writes, in the way MIDI drivers for
+//! AdLib and Sound Blaster cards do. Instruments come from a `bank::BankSet`, such as one read
+//! from a WOPL or OP2 file, and are played on the 18 channels of the chip in OPL3 mode.
+//! 4-operator instruments take a pair of channels, and double-voice instruments two channels.
+//! When every channel is in use, the voice released longest ago is reused, and failing that, the
+//! oldest held note.
+//!
+//! The OPL3 can only pan each channel hard left, centre or hard right. With soft panning enabled
+//! by `OplMidiSynth::set_soft_pan`, each note is played twice, on a channel panned hard left and
+//! one panned hard right, with their levels weighted by the MIDI channel's pan, for smooth
+//! panning at the cost of half the polyphony.
+//!
+//! When many notes play the same instrument, their operators can lock in phase and sound thin.
+//! `OplMidiSynth::set_random_detune` detunes each voice by a small random amount instead, from a
+//! generator started from a seed, so that a render can be reproduced exactly.
+//!
+//! For more polyphony, `OplMidiSynth::with_chips` plays on up to `MAX_CHIPS` chips at once, as
+//! libADLMIDI's "number of chips" option does, and mixes their output. Voices are allocated over
+//! the channels of every chip as if they were one large chip, so a note always goes to the same
+//! chip for the same sequence of messages.
+//!
+//! Banks are selected as libADLMIDI selects them. A melodic channel plays the program set by its
+//! last program change from the bank whose MSB and LSB match its last bank select (controllers 0
+//! and 32), falling back to the same program of bank 0 if there is no such bank, or if the
+//! instrument is blank in it. Drum channels play the instrument for each note from the
+//! percussion bank whose LSB matches their program, as GS drum kits are selected with program
+//! changes, falling back to the first percussion bank. Fixed notes set in the bank, such as by the
+//! percussion flags of OP2 and WOPL banks, are always played at their own pitch.
+//!
+//! MIDI channel 10 (index 9) is the only drum channel until a SysEx message says otherwise. The
+//! GM, GM2, GS and XG reset messages are recognized, and select the `MidiStandard` that decides
+//! which other messages switch channels into drum mode: the GS "use for rhythm part" parameter in
+//! any mode, bank MSB 126 and 127 in XG mode, and bank MSB 120 in GM2 mode. As on XG and GM2
+//! synthesizers, a bank select only takes effect at the next program change.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::bank::{Bank, BankSet};
+//! use opl3_rs::midi_synth::OplMidiSynth;
+//! use opl3_rs::opl_core::OplCore;
+//! use opl3_rs::patch::{Operator, OperatorPair, Patch};
+//!
+//! let mut piano = Bank::new("Piano");
+//! piano.patches.push(Patch::new(OperatorPair {
+//!     modulator: Operator::new(0x01, 0x11, 0xF2, 0x74, 0x00),
+//!     carrier: Operator::new(0x01, 0x00, 0xF2, 0x34, 0x00),
+//!     feedback_connection: 0x06,
+//! }));
+//! let banks = BankSet { melodic: vec![piano], percussion: Vec::new() };
+//!
+//! let mut synth = OplMidiSynth::new(44100, banks);
+//! // Note on, middle C, on MIDI channel 1.
+//! synth.process_message(&[0x90, 60, 100]);
+//! let mut buffer = [0i16; 1024 * 2];
+//! synth.generate_stream(&mut buffer).unwrap();
+//! assert!(buffer.iter().any(|&sample| sample != 0));
+//! ```
+
+use std::sync::Arc;
+
+use crate::bank::{Bank, BankSet};
+use crate::channel_allocator::{ChannelAllocator, CHANNELS_PER_CHIP};
+use crate::opl_core::OplCore;
+use crate::patch::{write_note, OperatorPair, Patch, PatchKind};
+use crate::registers::{
+    reg_for_channel, reg_for_operator, register_address, ChannelParam, OperatorParam, MODE_REGISTER,
+};
+use crate::{Opl3Chip, OplError};
+
+/// The MIDI channel, counting from 0, that plays percussion after a reset.
+pub const PERCUSSION_CHANNEL: u8 = 9;
+
+/// The most chips `OplMidiSynth` can play on.
+pub const MAX_CHIPS: usize = 4;
+
+/// The number of 2-operator channels of the chip.
+const VOICES: usize = CHANNELS_PER_CHIP;
+
+/// The allocation priority of held notes, and of notes held only by a pedal, which are stolen
+/// first.
+const HELD_PRIORITY: u8 = 1;
+const PEDAL_PRIORITY: u8 = 0;
+
+/// The register enabling 4-operator channels.
+const FOUR_OP_REGISTER: u16 = 0x104;
+
+/// The `MidiStandard` enum is the MIDI standard selected by the last reset SysEx message. It
+/// decides whether bank selects switch channels between melodic and drum mode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MidiStandard {
+    /// General MIDI, the mode after creating or resetting the synth. Only MIDI channel 10 plays
+    /// drums.
+    #[default]
+    Gm,
+    /// General MIDI 2, where bank MSB 120 selects drums and any other MSB selects melodic
+    /// instruments.
+    Gm2,
+    /// Roland GS, where drum channels are chosen by the "use for rhythm part" SysEx parameter.
+    Gs,
+    /// Yamaha XG, where bank MSB 127 selects drum kits, 126 selects SFX kits, and any other MSB
+    /// selects melodic instruments.
+    Xg,
+}
+
+/// The `VolumeCurve` enum selects how `OplMidiSynth` maps note velocity, channel volume
+/// (controller 7) and expression (controller 11) to the attenuation of carrier operators. MIDI
+/// drivers of the time used noticeably different curves, so matching the original driver's curve
+/// keeps the balance between instruments right.
+#[derive(Clone, Default)]
+pub enum VolumeCurve {
+    /// Make the amplitude proportional to the product of velocity, volume and expression, by
+    /// converting it to decibels. This is the default, and the curve used by most General MIDI
+    /// synthesizers.
+    #[default]
+    Logarithmic,
+    /// Make the attenuation proportional to the product of velocity, volume and expression, as
+    /// drivers that scaled the output level register directly did. Quiet notes are louder than
+    /// with `Logarithmic`.
+    Linear,
+    /// A custom curve, called with the velocity, volume and expression, each from 0 to 127, and
+    /// returning the attenuation in output level steps of 0.75dB, from 0 to 63. Larger values are
+    /// treated as 63.
+    Custom(Arc<dyn Fn(u8, u8, u8) -> u8 + Send + Sync>),
+}
+
+impl VolumeCurve {
+    /// Return the attenuation to add to the output level of carrier operators, in steps of
+    /// 0.75dB.
+    ///
+    /// # Arguments
+    ///
+    /// * `velocity`   - The velocity of the note, from 0 to 127.
+    /// * `volume`     - The channel volume, from 0 to 127.
+    /// * `expression` - The channel expression, from 0 to 127.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::midi_synth::VolumeCurve;
+    ///
+    /// assert_eq!(VolumeCurve::Logarithmic.attenuation(127, 127, 127), 0);
+    /// // Half the velocity is 6dB, or 8 steps, quieter.
+    /// assert_eq!(VolumeCurve::Logarithmic.attenuation(64, 127, 127), 8);
+    /// assert_eq!(VolumeCurve::Linear.attenuation(64, 127, 127), 31);
+    /// assert_eq!(VolumeCurve::Linear.attenuation(0, 127, 127), 63);
+    /// ```
+    pub fn attenuation(&self, velocity: u8, volume: u8, expression: u8) -> u8 {
+        let gain = (velocity.min(127) as f64 / 127.0)
+            * (volume.min(127) as f64 / 127.0)
+            * (expression.min(127) as f64 / 127.0);
+        match self {
+            VolumeCurve::Logarithmic if gain > 0.0 => {
+                (-20.0 * gain.log10() / 0.75).round().min(63.0) as u8
+            }
+            VolumeCurve::Logarithmic => 63,
+            VolumeCurve::Linear => (63.0 * (1.0 - gain)).round() as u8,
+            VolumeCurve::Custom(curve) => curve(velocity, volume, expression).min(63),
+        }
+    }
+}
+
+impl std::fmt::Debug for VolumeCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VolumeCurve::Logarithmic => write!(f, "Logarithmic"),
+            VolumeCurve::Linear => write!(f, "Linear"),
+            VolumeCurve::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// The state set on a MIDI channel by controllers and program changes.
+#[derive(Copy, Clone, Debug)]
+struct MidiChannel {
+    program: u8,
+    bank_msb: u8,
+    bank_lsb: u8,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    /// The pitch bend, from -8192 to 8191.
+    bend: i16,
+    /// The selected registered parameter, or `RPN_NULL`.
+    rpn: u16,
+    /// RPN 0, pitch bend sensitivity: semitones in the MSB and cents in the LSB.
+    bend_range: u16,
+    /// RPN 1, fine tuning, where 8192 is in tune and each step is 1/8192 of a semitone.
+    fine_tune: u16,
+    /// RPN 2, coarse tuning, where an MSB of 64 is in tune and each step is a semitone.
+    coarse_tune: u16,
+    sustain: bool,
+    sostenuto: bool,
+    /// Whether the channel plays drums from the percussion banks.
+    drums: bool,
+}
+
+/// The value of the registered parameter number that selects no parameter.
+const RPN_NULL: u16 = 0x3FFF;
+
+impl Default for MidiChannel {
+    fn default() -> Self {
+        MidiChannel {
+            program: 0,
+            bank_msb: 0,
+            bank_lsb: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            bend: 0,
+            rpn: RPN_NULL,
+            bend_range: 2 << 7,
+            fine_tune: 0x2000,
+            coarse_tune: 0x2000,
+            sustain: false,
+            sostenuto: false,
+            drums: false,
+        }
+    }
+}
+
+impl MidiChannel {
+    /// Reset the controllers as described by General MIDI recommended practice RP-015, releasing
+    /// the pedals. Program, bank, volume, pan and the tuning parameters are kept.
+    fn reset_controllers(&mut self) {
+        self.expression = 127;
+        self.bend = 0;
+        self.rpn = RPN_NULL;
+        self.sustain = false;
+        self.sostenuto = false;
+    }
+
+    /// Set the MSB or LSB of the selected registered parameter. Returns `true` if the pitch of
+    /// the channel changed.
+    fn data_entry(&mut self, value: u8, msb: bool) -> bool {
+        let parameter = match self.rpn {
+            0 => &mut self.bend_range,
+            1 => &mut self.fine_tune,
+            2 => &mut self.coarse_tune,
+            _ => return false,
+        };
+        *parameter = if msb {
+            (*parameter & 0x7F) | (value as u16) << 7
+        } else {
+            (*parameter & !0x7F) | value as u16
+        };
+        true
+    }
+
+    /// Return the pitch offset of the channel, in semitones, from the pitch bend and tuning.
+    fn pitch_offset(&self) -> f64 {
+        let range = (self.bend_range >> 7) as f64 + (self.bend_range & 0x7F).min(99) as f64 / 100.0;
+        let coarse = (self.coarse_tune >> 7) as f64 - 64.0;
+        let fine = (self.fine_tune as f64 - 8192.0) / 8192.0;
+        self.bend as f64 / 8192.0 * range + coarse + fine
+    }
+}
+
+/// A reference to a patch in the synth's banks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct PatchRef {
+    percussion: bool,
+    bank: usize,
+    index: usize,
+}
+
+/// The outputs a chip channel plays on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Output {
+    /// Left, right or both, whichever is nearest the MIDI channel's pan.
+    Pan,
+    /// The left half of a soft-panned note.
+    Left,
+    /// The right half of a soft-panned note.
+    Right,
+}
+
+/// The note a chip channel is playing, or last played.
+#[derive(Copy, Clone, Debug)]
+struct VoiceNote {
+    channel: u8,
+    note: u8,
+    velocity: u8,
+    patch: PatchRef,
+    /// The pair of the patch played by this chip channel.
+    pair: usize,
+    /// Whether this chip channel is the first of a 4-operator channel.
+    four_op: bool,
+    output: Output,
+    /// The random detune of the voice, in semitones.
+    detune: f64,
+}
+
+/// The state of one chip channel. The second channel of a 4-operator note plays nothing of its
+/// own.
+#[derive(Copy, Clone, Debug, Default)]
+struct Voice {
+    note: Option<VoiceNote>,
+    keyed: bool,
+    /// Whether the note has been released while held by a pedal, and will be keyed off when the
+    /// pedal is released.
+    deferred_off: bool,
+    /// Whether the note is held by the sostenuto pedal.
+    sostenuto: bool,
+}
+
+/// The `OplMidiSynth` struct plays MIDI channel messages on an `Opl3Chip` using instruments from
+/// a `BankSet`.
+pub struct OplMidiSynth {
+    chips: Vec<Opl3Chip>,
+    banks: BankSet,
+    channels: [MidiChannel; 16],
+    /// The channels of every chip, `VOICES` per chip.
+    voices: Vec<Voice>,
+    allocator: ChannelAllocator,
+    /// The value of register 0x104 of each chip.
+    four_op_masks: Vec<u8>,
+    /// The output of the second and later chips, before mixing.
+    scratch: Vec<i16>,
+    volume_curve: VolumeCurve,
+    standard: MidiStandard,
+    soft_pan: bool,
+    /// The largest random detune, in cents.
+    detune_depth: f64,
+    detune_seed: u32,
+    /// The state of the xorshift generator drawing random detunes.
+    detune_state: u64,
+}
+
+impl OplMidiSynth {
+    /// Create a new `OplMidiSynth` playing on one chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    /// * `banks`       - The instruments to play.
+    pub fn new(sample_rate: u32, banks: BankSet) -> Self {
+        let mut synth = OplMidiSynth {
+            chips: vec![Opl3Chip::new(sample_rate)],
+            banks,
+            channels: initial_channels(MidiStandard::Gm),
+            voices: vec![Voice::default(); VOICES],
+            allocator: ChannelAllocator::new(1),
+            four_op_masks: vec![0],
+            scratch: Vec::new(),
+            volume_curve: VolumeCurve::default(),
+            standard: MidiStandard::Gm,
+            soft_pan: false,
+            detune_depth: 0.0,
+            detune_seed: 0,
+            detune_state: detune_state(0),
+        };
+        synth.chips[0].write_register(MODE_REGISTER, 0x01);
+        synth
+    }
+
+    /// Create a new `OplMidiSynth` playing on several chips, with 18 channels each. The output
+    /// of the chips is summed, saturating at the limits of 16-bit samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    /// * `banks`       - The instruments to play.
+    /// * `chips`       - The number of chips, from 1 to `MAX_CHIPS`.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the new `OplMidiSynth` on success or an `OplError` on failure.
+    /// The error is `OplError::InvalidFormat` if the number of chips is out of range.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut bank = Bank::new("Strings");
+    /// bank.patches.push(Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x21, 0x18, 0x71, 0x15, 0x00),
+    ///     carrier: Operator::new(0x21, 0x00, 0x71, 0x15, 0x00),
+    ///     feedback_connection: 0x0A,
+    /// }));
+    /// let banks = BankSet { melodic: vec![bank], percussion: Vec::new() };
+    /// let mut synth = OplMidiSynth::with_chips(44100, banks, 2).unwrap();
+    /// for note in 30..70 {
+    ///     synth.note_on(0, note, 100);
+    /// }
+    /// assert_eq!(synth.active_voices(), 36);
+    /// ```
+    pub fn with_chips(sample_rate: u32, banks: BankSet, chips: usize) -> Result<Self, OplError> {
+        if !(1..=MAX_CHIPS).contains(&chips) {
+            return Err(OplError::InvalidFormat("chip count"));
+        }
+        let mut synth = Self::new(sample_rate, banks);
+        for _ in 1..chips {
+            let mut chip = Opl3Chip::new(sample_rate);
+            chip.write_register(MODE_REGISTER, 0x01);
+            synth.chips.push(chip);
+        }
+        synth.voices = vec![Voice::default(); VOICES * chips];
+        synth.allocator = ChannelAllocator::new(chips);
+        synth.four_op_masks = vec![0; chips];
+        Ok(synth)
+    }
+
+    /// Allocate the buffer used to mix the chips, so that generating up to a number of samples at
+    /// a time never allocates. Only synths playing on more than one chip need the buffer. Note
+    /// and controller events never allocate. See the `realtime` module.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - The largest number of samples that will be generated at once.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::alloc::System;
+    ///
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::opl_core::OplCore;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    /// use opl3_rs::realtime::{assert_no_alloc, CheckedAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: CheckedAllocator<System> = CheckedAllocator::new(System);
+    ///
+    /// fn main() {
+    ///     let mut bank = Bank::new("Piano");
+    ///     bank.patches.push(Patch::new(OperatorPair {
+    ///         modulator: Operator::new(0x01, 0x10, 0xF2, 0x74, 0x00),
+    ///         carrier: Operator::new(0x01, 0x00, 0xF2, 0x74, 0x00),
+    ///         feedback_connection: 0x00,
+    ///     }));
+    ///     let banks = BankSet { melodic: vec![bank], percussion: Vec::new() };
+    ///     let mut synth = OplMidiSynth::with_chips(44100, banks, 2).unwrap();
+    ///     synth.reserve(512 * 2);
+    ///     let mut buffer = vec![0i16; 512 * 2];
+    ///
+    ///     assert_no_alloc(|| {
+    ///         for note in 40..80 {
+    ///             synth.process_message(&[0x90, note, 100]);
+    ///             synth.generate_stream(&mut buffer).unwrap();
+    ///         }
+    ///         synth.process_message(&[0xB0, 64, 127]);
+    ///         synth.process_message(&[0x80, 60, 0]);
+    ///         synth.process_message(&[0xE0, 0x00, 0x50]);
+    ///         synth.generate_stream(&mut buffer).unwrap();
+    ///     });
+    /// }
+    /// ```
+    pub fn reserve(&mut self, samples: usize) {
+        if self.chips.len() > 1 {
+            self.scratch
+                .reserve_exact(samples.saturating_sub(self.scratch.len()));
+        }
+    }
+
+    /// Return the banks being played.
+    pub fn banks(&self) -> &BankSet {
+        &self.banks
+    }
+
+    /// Replace the banks being played. Every sounding note is stopped.
+    ///
+    /// # Arguments
+    ///
+    /// * `banks` - The new instruments to play.
+    pub fn set_banks(&mut self, banks: BankSet) {
+        self.all_sound_off();
+        self.banks = banks;
+    }
+
+    /// Set the curve mapping velocity, volume and expression to output level. Held notes are
+    /// updated at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `curve` - The curve to use.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use opl3_rs::midi_synth::{OplMidiSynth, VolumeCurve};
+    ///
+    /// let mut synth = OplMidiSynth::new(44100, Default::default());
+    /// // Ignore velocity, as some early drivers did.
+    /// synth.set_volume_curve(VolumeCurve::Custom(Arc::new(|_velocity, volume, expression| {
+    ///     VolumeCurve::Logarithmic.attenuation(127, volume, expression)
+    /// })));
+    /// ```
+    pub fn set_volume_curve(&mut self, curve: VolumeCurve) {
+        self.volume_curve = curve;
+        for channel in 0..16 {
+            self.update_voices(channel, Self::write_levels);
+        }
+    }
+
+    /// Return the curve mapping velocity, volume and expression to output level.
+    pub fn volume_curve(&self) -> &VolumeCurve {
+        &self.volume_curve
+    }
+
+    /// Enable or disable soft panning, where each note is played on a channel panned hard left and
+    /// one panned hard right, with levels weighted by the pan. Held notes keep playing as they
+    /// started. Soft panning is disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to pan notes softly.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::opl_core::OplCore;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut bank = Bank::new("Organ");
+    /// bank.patches.push(Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x01, 0x10, 0xF0, 0x77, 0x00),
+    ///     carrier: Operator::new(0x01, 0x00, 0xF0, 0x77, 0x00),
+    ///     feedback_connection: 0x00,
+    /// }));
+    /// let banks = BankSet { melodic: vec![bank], percussion: Vec::new() };
+    ///
+    /// let peaks = |soft_pan| {
+    ///     let mut synth = OplMidiSynth::new(44100, banks.clone());
+    ///     synth.set_soft_pan(soft_pan);
+    ///     synth.control_change(0, 10, 96);
+    ///     synth.note_on(0, 60, 127);
+    ///     let mut buffer = vec![0i16; 2048 * 2];
+    ///     synth.generate_stream(&mut buffer).unwrap();
+    ///     let peak = |side: usize| buffer.iter().skip(side).step_by(2).map(|s| s.abs()).max();
+    ///     (peak(0).unwrap(), peak(1).unwrap())
+    /// };
+    /// // Without soft panning, the note is hard right.
+    /// assert_eq!(peaks(false).0, 0);
+    /// let (left, right) = peaks(true);
+    /// assert!(left > 0 && left < right);
+    /// ```
+    pub fn set_soft_pan(&mut self, enabled: bool) {
+        self.soft_pan = enabled;
+    }
+
+    /// Return whether soft panning is enabled.
+    pub fn soft_pan(&self) -> bool {
+        self.soft_pan
+    }
+
+    /// Detune each voice started from now on by a random amount, up to a depth either way. The
+    /// amounts come from a generator started from the seed, so the same messages played from the
+    /// same seed always give the same output. Resetting the synth restarts the generator.
+    /// Random detune is disabled by default, with a depth of 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `depth` - The largest detune, in cents. A depth of 0 disables random detune.
+    /// * `seed`  - The seed of the generator.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::opl_core::OplCore;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut bank = Bank::new("Strings");
+    /// bank.patches.push(Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x21, 0x18, 0x71, 0x15, 0x00),
+    ///     carrier: Operator::new(0x21, 0x00, 0x71, 0x15, 0x00),
+    ///     feedback_connection: 0x0A,
+    /// }));
+    /// let banks = BankSet { melodic: vec![bank], percussion: Vec::new() };
+    ///
+    /// let render = |depth, seed| {
+    ///     let mut synth = OplMidiSynth::new(44100, banks.clone());
+    ///     synth.set_random_detune(depth, seed);
+    ///     for channel in 0..4 {
+    ///         synth.note_on(channel, 60, 100);
+    ///     }
+    ///     let mut buffer = vec![0i16; 4096 * 2];
+    ///     synth.generate_stream(&mut buffer).unwrap();
+    ///     buffer
+    /// };
+    /// assert_eq!(render(10.0, 1), render(10.0, 1));
+    /// assert_ne!(render(10.0, 1), render(10.0, 2));
+    /// assert_ne!(render(10.0, 1), render(0.0, 1));
+    /// ```
+    pub fn set_random_detune(&mut self, depth: f64, seed: u32) {
+        self.detune_depth = depth.abs();
+        self.detune_seed = seed;
+        self.detune_state = detune_state(seed);
+    }
+
+    /// Return the largest random detune, in cents.
+    pub fn random_detune_depth(&self) -> f64 {
+        self.detune_depth
+    }
+
+    /// Return the seed of the random detune generator.
+    pub fn random_detune_seed(&self) -> u32 {
+        self.detune_seed
+    }
+
+    /// Return the first chip the synth plays on.
+    pub fn chip(&self) -> &Opl3Chip {
+        &self.chips[0]
+    }
+
+    /// Return the first chip the synth plays on. Registers written directly may be overwritten by
+    /// the synth at any time.
+    pub fn chip_mut(&mut self) -> &mut Opl3Chip {
+        &mut self.chips[0]
+    }
+
+    /// Return every chip the synth plays on.
+    pub fn chips(&self) -> &[Opl3Chip] {
+        &self.chips
+    }
+
+    /// Return every chip the synth plays on. Registers written directly may be overwritten by the
+    /// synth at any time.
+    pub fn chips_mut(&mut self) -> &mut [Opl3Chip] {
+        &mut self.chips
+    }
+
+    /// Return the MIDI standard selected by the last reset SysEx message.
+    pub fn standard(&self) -> MidiStandard {
+        self.standard
+    }
+
+    /// Return the number of voices holding notes. 4-operator notes count once, and double-voice
+    /// notes twice. Soft-panned notes count twice as much again.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut bank = Bank::new("Organ");
+    /// bank.patches.push(Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x01, 0x10, 0xF0, 0x00, 0x00),
+    ///     carrier: Operator::new(0x01, 0x00, 0xF0, 0x00, 0x00),
+    ///     feedback_connection: 0x00,
+    /// }));
+    /// let mut synth = OplMidiSynth::new(44100, BankSet { melodic: vec![bank], percussion: Vec::new() });
+    ///
+    /// // Hold a note on every channel, then play two more: they steal the two oldest notes.
+    /// for note in 40..58 {
+    ///     synth.note_on(0, note, 100);
+    /// }
+    /// synth.note_on(0, 70, 100);
+    /// synth.note_on(0, 71, 100);
+    /// assert_eq!(synth.active_voices(), 18);
+    /// synth.note_off(0, 40);
+    /// synth.note_off(0, 41);
+    /// assert_eq!(synth.active_voices(), 18);
+    /// synth.note_off(0, 70);
+    /// assert_eq!(synth.active_voices(), 17);
+    /// ```
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.keyed).count()
+    }
+
+    /// Handle a MIDI channel message: note on and off, control change, program change and pitch
+    /// bend, or a SysEx message, starting with 0xF0, as described for `system_exclusive`. Other
+    /// messages, and messages that are too short, are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message, starting with its status byte.
+    pub fn process_message(&mut self, message: &[u8]) {
+        let [status, data @ ..] = message else {
+            return;
+        };
+        if *status == 0xF0 {
+            self.system_exclusive(data);
+            return;
+        }
+        let channel = status & 0x0F;
+        let data1 = data.first().map(|&b| b & 0x7F);
+        let data2 = data.get(1).map(|&b| b & 0x7F);
+        match (status & 0xF0, data1, data2) {
+            (0x80, Some(note), Some(_)) => self.note_off(channel, note),
+            (0x90, Some(note), Some(velocity)) => self.note_on(channel, note, velocity),
+            (0xB0, Some(controller), Some(value)) => {
+                self.control_change(channel, controller, value)
+            }
+            (0xC0, Some(program), _) => self.program_change(channel, program),
+            (0xE0, Some(low), Some(high)) => {
+                self.pitch_bend(channel, (high as u16) << 7 | low as u16)
+            }
+            _ => {}
+        }
+    }
+
+    /// Start a note. A velocity of 0 stops the note instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel`  - The MIDI channel, from 0 to 15.
+    /// * `note`     - The note, from 0 to 127.
+    /// * `velocity` - The velocity, from 0 to 127.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::{OplMidiSynth, PERCUSSION_CHANNEL};
+    /// use opl3_rs::opl_core::OplCore;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// // A drum kit where every note plays the same tom at a fixed pitch.
+    /// let tom = Patch {
+    ///     fixed_note: Some(45),
+    ///     ..Patch::new(OperatorPair {
+    ///         modulator: Operator::new(0x00, 0x08, 0xF8, 0xFF, 0x00),
+    ///         carrier: Operator::new(0x00, 0x00, 0xF5, 0xF4, 0x00),
+    ///         feedback_connection: 0x0E,
+    ///     })
+    /// };
+    /// let mut kit = Bank::new("Toms");
+    /// kit.patches = vec![tom; 128];
+    /// let banks = BankSet { melodic: Vec::new(), percussion: vec![kit] };
+    ///
+    /// let render = |note| {
+    ///     let mut synth = OplMidiSynth::new(44100, banks.clone());
+    ///     synth.note_on(PERCUSSION_CHANNEL, note, 127);
+    ///     let mut buffer = vec![0i16; 2048 * 2];
+    ///     synth.generate_stream(&mut buffer).unwrap();
+    ///     buffer
+    /// };
+    /// assert!(render(41).iter().any(|&sample| sample != 0));
+    /// assert_eq!(render(41), render(50));
+    /// ```
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        let channel = channel & 0x0F;
+        if velocity == 0 {
+            self.note_off(channel, note);
+            return;
+        }
+        let Some(patch_ref) = self.patch_ref(channel, note) else {
+            return;
+        };
+        // A note played again is restarted.
+        self.note_off(channel, note);
+
+        let kind = lookup(&self.banks, patch_ref).kind;
+        let note = VoiceNote {
+            channel,
+            note,
+            velocity,
+            patch: patch_ref,
+            pair: 0,
+            four_op: kind == PatchKind::FourOp,
+            output: Output::Pan,
+            detune: 0.0,
+        };
+        let outputs: &[Output] = if self.soft_pan {
+            &[Output::Left, Output::Right]
+        } else {
+            &[Output::Pan]
+        };
+        let pairs = if kind == PatchKind::DoubleVoice { 2 } else { 1 };
+        for &output in outputs {
+            for pair in 0..pairs {
+                let voice = self.allocate(note.four_op);
+                let detune = self.next_detune();
+                self.start_voice(
+                    voice,
+                    VoiceNote {
+                        pair,
+                        output,
+                        detune,
+                        ..note
+                    },
+                );
+            }
+        }
+    }
+
+    /// Release a note. While the sustain pedal is down, or the note is held by the sostenuto
+    /// pedal, the note keeps playing until the pedal is released.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The MIDI channel, from 0 to 15.
+    /// * `note`    - The note, from 0 to 127.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut bank = Bank::new("Piano");
+    /// bank.patches.push(Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x01, 0x10, 0xF2, 0x74, 0x00),
+    ///     carrier: Operator::new(0x01, 0x00, 0xF2, 0x74, 0x00),
+    ///     feedback_connection: 0x00,
+    /// }));
+    /// let mut synth = OplMidiSynth::new(44100, BankSet { melodic: vec![bank], percussion: Vec::new() });
+    ///
+    /// synth.control_change(0, 66, 0);
+    /// synth.note_on(0, 48, 100);
+    /// synth.control_change(0, 66, 127);
+    /// synth.control_change(0, 64, 127);
+    /// synth.note_on(0, 60, 100);
+    /// synth.note_off(0, 48);
+    /// synth.note_off(0, 60);
+    /// assert_eq!(synth.active_voices(), 2);
+    /// // Releasing the sustain pedal ends the note the sostenuto pedal does not hold.
+    /// synth.control_change(0, 64, 0);
+    /// assert_eq!(synth.active_voices(), 1);
+    /// synth.control_change(0, 66, 0);
+    /// assert_eq!(synth.active_voices(), 0);
+    /// ```
+    pub fn note_off(&mut self, channel: u8, note: u8) {
+        for voice in 0..self.voices.len() {
+            let v = &self.voices[voice];
+            if v.keyed
+                && v.note
+                    .is_some_and(|n| n.channel == channel && n.note == note)
+            {
+                self.release(voice);
+            }
+        }
+    }
+
+    /// Handle a control change. Bank select (0 and 32), volume (7), pan (10), expression (11),
+    /// sustain (64), sostenuto (66), all sound off (120), reset all controllers (121) and all
+    /// notes off (123) are supported. All notes off leaves notes held by the pedals playing.
+    ///
+    /// Registered parameters are selected with controllers 101 and 100, and set with data entry
+    /// (6 and 38). RPN 0 sets the pitch bend range, in semitones and cents, which defaults to 2
+    /// semitones. RPN 1 and 2 set the fine and coarse tuning of the channel. Resetting all
+    /// controllers keeps these, as General MIDI recommends.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel`    - The MIDI channel, from 0 to 15.
+    /// * `controller` - The controller number, from 0 to 127.
+    /// * `value`      - The new value, from 0 to 127.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::opl_core::OplCore;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let organ = |level| {
+    ///     Patch::new(OperatorPair {
+    ///         modulator: Operator::new(0x01, 0x10, 0xF0, 0x77, 0x00),
+    ///         carrier: Operator::new(0x01, level, 0xF0, 0x77, 0x00),
+    ///         feedback_connection: 0x00,
+    ///     })
+    /// };
+    /// let mut loud = Bank::new("Loud");
+    /// loud.patches.push(organ(0x00));
+    /// let mut quiet = Bank { msb: 8, ..Bank::new("Quiet") };
+    /// quiet.patches.push(organ(0x20));
+    /// let banks = BankSet { melodic: vec![loud, quiet], percussion: Vec::new() };
+    ///
+    /// let peak = |msb| {
+    ///     let mut synth = OplMidiSynth::new(44100, banks.clone());
+    ///     synth.control_change(0, 0, msb);
+    ///     synth.program_change(0, 0);
+    ///     synth.note_on(0, 60, 127);
+    ///     let mut buffer = vec![0i16; 2048 * 2];
+    ///     synth.generate_stream(&mut buffer).unwrap();
+    ///     buffer.iter().map(|sample| sample.abs()).max().unwrap()
+    /// };
+    /// assert!(peak(8) < peak(0));
+    /// // There is no bank 9, so bank 0 is played instead.
+    /// assert_eq!(peak(9), peak(0));
+    /// ```
+    pub fn control_change(&mut self, channel: u8, controller: u8, value: u8) {
+        let channel = channel & 0x0F;
+        let state = &mut self.channels[channel as usize];
+        match controller {
+            0 => state.bank_msb = value,
+            32 => state.bank_lsb = value,
+            7 => {
+                state.volume = value;
+                self.update_voices(channel, Self::write_levels);
+            }
+            10 => {
+                state.pan = value;
+                self.update_voices(channel, Self::write_output);
+                self.update_voices(channel, Self::write_levels);
+            }
+            11 => {
+                state.expression = value;
+                self.update_voices(channel, Self::write_levels);
+            }
+            64 => {
+                state.sustain = value >= 64;
+                self.release_pedals(channel);
+            }
+            66 => {
+                let down = value >= 64;
+                if down != state.sostenuto {
+                    state.sostenuto = down;
+                    // Pressing the pedal holds the notes playing now, and only those.
+                    for voice in self.voices.iter_mut() {
+                        if voice.note.is_some_and(|n| n.channel == channel) {
+                            voice.sostenuto = down && voice.keyed && !voice.deferred_off;
+                        }
+                    }
+                    self.release_pedals(channel);
+                }
+            }
+            120 => self.stop_channel(channel),
+            101 => state.rpn = (state.rpn & 0x7F) | (value as u16) << 7,
+            100 => state.rpn = (state.rpn & !0x7F) | value as u16,
+            // Non-registered parameters are not supported, so selecting one deselects the RPN.
+            98 | 99 => state.rpn = RPN_NULL,
+            6 | 38 => {
+                let pitch_changed = state.data_entry(value, controller == 6);
+                if pitch_changed {
+                    self.update_voices(channel, Self::write_frequency);
+                }
+            }
+            121 => {
+                state.reset_controllers();
+                for voice in self.voices.iter_mut() {
+                    if voice.note.is_some_and(|n| n.channel == channel) {
+                        voice.sostenuto = false;
+                    }
+                }
+                self.release_pedals(channel);
+                self.update_voices(channel, Self::write_levels);
+                self.update_voices(channel, Self::write_frequency);
+            }
+            123 => {
+                for voice in 0..self.voices.len() {
+                    let v = &self.voices[voice];
+                    if v.keyed && v.note.is_some_and(|n| n.channel == channel) {
+                        self.release(voice);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Select the instrument a channel plays. Notes already playing keep their instrument.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The MIDI channel, from 0 to 15.
+    /// * `program` - The program, from 0 to 127.
+    pub fn program_change(&mut self, channel: u8, program: u8) {
+        let state = &mut self.channels[channel as usize & 0x0F];
+        state.program = program & 0x7F;
+        match self.standard {
+            MidiStandard::Gm2 => state.drums = state.bank_msb == 120,
+            MidiStandard::Xg => state.drums = matches!(state.bank_msb, 126 | 127),
+            MidiStandard::Gm | MidiStandard::Gs => {}
+        }
+    }
+
+    /// Handle a SysEx message. The GM 1 and GM 2 system on and GM system off messages, the GS
+    /// reset, the XG system on and XG all parameters reset messages, and the GS "use for rhythm
+    /// part" parameter are recognized, for any device ID. Resets stop every note and reset every
+    /// channel. Other messages are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The message after the 0xF0 status byte, with or without the final 0xF7.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::{MidiStandard, OplMidiSynth};
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut kit = Bank::new("Drums");
+    /// kit.patches = vec![Patch::default(); 128];
+    /// kit.patches[38] = Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x00, 0x00, 0xF8, 0x06, 0x00),
+    ///     carrier: Operator::new(0x00, 0x00, 0xF6, 0x06, 0x00),
+    ///     feedback_connection: 0x0E,
+    /// });
+    /// let banks = BankSet { melodic: Vec::new(), percussion: vec![kit] };
+    /// let mut synth = OplMidiSynth::new(44100, banks);
+    ///
+    /// // GS reset, then use part 1 (MIDI channel 1) for drums.
+    /// synth.process_message(&[0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41, 0xF7]);
+    /// synth.system_exclusive(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x11, 0x15, 0x02, 0x18]);
+    /// assert_eq!(synth.standard(), MidiStandard::Gs);
+    /// synth.note_on(0, 38, 100);
+    /// assert_eq!(synth.active_voices(), 1);
+    ///
+    /// // A truncated message is ignored.
+    /// synth.process_message(&[0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0xF7]);
+    /// assert_eq!(synth.standard(), MidiStandard::Gs);
+    /// assert_eq!(synth.active_voices(), 1);
+    ///
+    /// // XG system on, then select a drum kit on MIDI channel 2.
+    /// synth.system_exclusive(&[0x43, 0x10, 0x4C, 0x00, 0x00, 0x7E, 0x00, 0xF7]);
+    /// assert_eq!(synth.active_voices(), 0);
+    /// synth.control_change(1, 0, 127);
+    /// synth.note_on(1, 38, 100);
+    /// assert_eq!(synth.active_voices(), 0);
+    /// synth.program_change(1, 0);
+    /// synth.note_on(1, 38, 100);
+    /// assert_eq!(synth.active_voices(), 1);
+    /// ```
+    pub fn system_exclusive(&mut self, data: &[u8]) {
+        let data = data.strip_suffix(&[0xF7]).unwrap_or(data);
+        match data {
+            [0x7E, _, 0x09, 0x01 | 0x02] => self.reset_standard(MidiStandard::Gm),
+            [0x7E, _, 0x09, 0x03] => self.reset_standard(MidiStandard::Gm2),
+            [0x41, _, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, _] => {
+                self.reset_standard(MidiStandard::Gs)
+            }
+            [0x43, device, 0x4C, 0x00, 0x00, 0x7E | 0x7F, 0x00] if device & 0xF0 == 0x10 => {
+                self.reset_standard(MidiStandard::Xg)
+            }
+            [0x41, _, 0x42, 0x12, 0x40, part, 0x15, mode, checksum] if part & 0xF0 == 0x10 => {
+                let sum = 0x40 + *part as u32 + 0x15 + *mode as u32 + *checksum as u32;
+                if sum & 0x7F != 0 {
+                    return;
+                }
+                // Part 10 comes first in the address map, then parts 1-9 and 11-16.
+                let channel = match part & 0x0F {
+                    0 => PERCUSSION_CHANNEL,
+                    part @ 1..=9 => part - 1,
+                    part => part,
+                };
+                let drums = *mode != 0;
+                if self.channels[channel as usize].drums != drums {
+                    self.stop_channel(channel);
+                    self.channels[channel as usize].drums = drums;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Bend the pitch of a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The MIDI channel, from 0 to 15.
+    /// * `value`   - The 14-bit bend value, where 8192 is no bend.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::opl_core::OplCore;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut bank = Bank::new("Sine");
+    /// bank.patches.push(Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x01, 0x3F, 0xF0, 0x00, 0x00),
+    ///     carrier: Operator::new(0x01, 0x00, 0xF0, 0x00, 0x00),
+    ///     feedback_connection: 0x00,
+    /// }));
+    /// let banks = BankSet { melodic: vec![bank], percussion: Vec::new() };
+    /// let render = |setup: &dyn Fn(&mut OplMidiSynth)| {
+    ///     let mut synth = OplMidiSynth::new(44100, banks.clone());
+    ///     setup(&mut synth);
+    ///     let mut buffer = vec![0i16; 4096 * 2];
+    ///     synth.generate_stream(&mut buffer).unwrap();
+    ///     buffer
+    /// };
+    ///
+    /// // With a bend range of 12 semitones, a full bend up plays an octave higher.
+    /// let octave_up = render(&|synth| synth.note_on(0, 72, 127));
+    /// let bent = render(&|synth| {
+    ///     for (controller, value) in [(101, 0), (100, 0), (6, 12), (38, 0)] {
+    ///         synth.control_change(0, controller, value);
+    ///     }
+    ///     synth.pitch_bend(0, 0x3FFF);
+    ///     synth.note_on(0, 60, 127);
+    /// });
+    /// // The full bend is one step short of 12 semitones, so the pitch is 0.15 cents flat.
+    /// let close = octave_up.iter().zip(&bent).filter(|(a, b)| (**a - **b).abs() < 400).count();
+    /// assert!(close > octave_up.len() * 9 / 10);
+    /// ```
+    pub fn pitch_bend(&mut self, channel: u8, value: u16) {
+        let channel = channel & 0x0F;
+        self.channels[channel as usize].bend = (value.min(0x3FFF) as i16) - 8192;
+        self.update_voices(channel, Self::write_frequency);
+    }
+
+    /// Stop every note immediately, without waiting for its release.
+    pub fn all_sound_off(&mut self) {
+        for channel in 0..16 {
+            self.stop_channel(channel);
+        }
+    }
+
+    /// Render a block of planar audio, handling MIDI messages timed within it, as audio plugin
+    /// hosts deliver them. Each message is handled as by `process_message`, before the sample at
+    /// its frame is rendered; messages out of order are handled at the frame of the one before,
+    /// and messages past the end of the block after its last sample. The audio is scaled to the
+    /// range -1.0 to 1.0, as by `Opl3Chip::generate_stream_into_planar_f32`.
+    ///
+    /// # Arguments
+    ///
+    /// * `messages` - The MIDI messages, each with the frame of the block it plays at.
+    /// * `left`     - A mutable reference to a slice of f32 that will be filled with the left
+    ///                channel.
+    /// * `right`    - A mutable reference to a slice of f32 that will be filled with the right
+    ///                channel. Only as many frames as the shorter buffer holds are rendered.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::bank::{Bank, BankSet};
+    /// use opl3_rs::midi_synth::OplMidiSynth;
+    /// use opl3_rs::patch::{Operator, OperatorPair, Patch};
+    ///
+    /// let mut bank = Bank::new("Strings");
+    /// bank.patches.push(Patch::new(OperatorPair {
+    ///     modulator: Operator::new(0x21, 0x18, 0x71, 0x15, 0x00),
+    ///     carrier: Operator::new(0x21, 0x00, 0x71, 0x15, 0x00),
+    ///     feedback_connection: 0x0A,
+    /// }));
+    /// let banks = BankSet { melodic: vec![bank], percussion: Vec::new() };
+    /// let mut synth = OplMidiSynth::new(44100, banks);
+    ///
+    /// let (mut left, mut right) = (vec![0.0f32; 512], vec![0.0f32; 512]);
+    /// let messages = [(100, &[0x90, 60, 100][..])];
+    /// synth.render_planar_timed(messages, &mut left, &mut right);
+    /// assert!(left[..100].iter().all(|&sample| sample == 0.0));
+    /// assert!(left[100..].iter().any(|&sample| sample != 0.0));
+    /// ```
+    pub fn render_planar_timed<'a>(
+        &mut self,
+        messages: impl IntoIterator<Item = (usize, &'a [u8])>,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        let frames = left.len().min(right.len());
+        let mut start = 0;
+        for (frame, message) in messages {
+            let frame = frame.clamp(start, frames);
+            self.render_planar(&mut left[start..frame], &mut right[start..frame]);
+            self.process_message(message);
+            start = frame;
+        }
+        self.render_planar(&mut left[start..frames], &mut right[start..frames]);
+    }
+
+    /// Return the random detune for the next voice, in semitones.
+    fn next_detune(&mut self) -> f64 {
+        if self.detune_depth == 0.0 {
+            return 0.0;
+        }
+        self.detune_state ^= self.detune_state << 13;
+        self.detune_state ^= self.detune_state >> 7;
+        self.detune_state ^= self.detune_state << 17;
+        let uniform = (self.detune_state >> 11) as f64 / (1u64 << 53) as f64;
+        (uniform * 2.0 - 1.0) * self.detune_depth / 100.0
+    }
+
+    /// Render planar audio from every chip, mixed.
+    fn render_planar(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let Some((first, rest)) = self.chips.split_first_mut() else {
+            return;
+        };
+        _ = first.generate_stream_into_planar_f32(left, right);
+        let mut block = ([0.0f32; 256], [0.0f32; 256]);
+        for chip in rest {
+            for (left, right) in left.chunks_mut(256).zip(right.chunks_mut(256)) {
+                let (chip_left, chip_right) =
+                    (&mut block.0[..left.len()], &mut block.1[..left.len()]);
+                _ = chip.generate_stream_into_planar_f32(chip_left, chip_right);
+                for (out, &sample) in left.iter_mut().zip(chip_left.iter()) {
+                    *out += sample;
+                }
+                for (out, &sample) in right.iter_mut().zip(chip_right.iter()) {
+                    *out += sample;
+                }
+            }
+        }
+    }
+
+    /// Stop every note and reset every channel for a MIDI standard.
+    fn reset_standard(&mut self, standard: MidiStandard) {
+        self.all_sound_off();
+        self.channels = initial_channels(standard);
+        self.standard = standard;
+    }
+
+    /// Return the patch a note on a channel plays, if any.
+    fn patch_ref(&self, channel: u8, note: u8) -> Option<PatchRef> {
+        let state = &self.channels[channel as usize];
+        if state.drums {
+            return find_bank(&self.banks.percussion, |bank| bank.lsb == state.program)
+                .and_then(|bank| playable(&self.banks, true, bank, note as usize))
+                .or_else(|| playable(&self.banks, true, 0, note as usize));
+        }
+        let program = state.program as usize;
+        find_bank(&self.banks.melodic, |bank| {
+            bank.msb == state.bank_msb && bank.lsb == state.bank_lsb
+        })
+        .and_then(|bank| playable(&self.banks, false, bank, program))
+        .or_else(|| {
+            let bank = find_bank(&self.banks.melodic, |bank| bank.msb == 0 && bank.lsb == 0)?;
+            playable(&self.banks, false, bank, program)
+        })
+    }
+
+    /// Return the chip channel to play a new note on, stopping whatever it was playing. For
+    /// 4-operator notes, the first channel of a pair is returned, and 4-operator mode is enabled
+    /// for the pair.
+    fn allocate(&mut self, four_op: bool) -> usize {
+        // There is always at least one chip, so there is always a channel to claim.
+        let Some(allocation) = self.allocator.claim(four_op, HELD_PRIORITY) else {
+            return 0;
+        };
+        for voice in allocation.evicted.into_iter().flatten() {
+            self.stop_voice(voice);
+        }
+        self.write_four_op(allocation.channel / VOICES);
+        allocation.channel
+    }
+
+    /// Stop whatever a chip channel is playing, including the rest of its 4-operator channel.
+    fn free(&mut self, voice: usize) {
+        let Some(owner) = self.allocator.owner(voice) else {
+            return;
+        };
+        self.stop_voice(owner);
+        self.allocator.free(owner);
+        self.write_four_op(owner / VOICES);
+    }
+
+    /// Key off and silence the note of a chip channel. The allocator is left alone, as the
+    /// channel has either been given to a new note already or is about to be freed.
+    fn stop_voice(&mut self, voice: usize) {
+        if self.voices[voice].note.is_none() {
+            return;
+        }
+        self.write_key_off(voice);
+        self.silence(voice);
+        self.voices[voice].note = None;
+    }
+
+    /// Write the allocator's 4-operator pairs of a chip to its register 0x104, if they changed.
+    fn write_four_op(&mut self, chip: usize) {
+        let mask = self.allocator.four_op_mask(chip);
+        if self.four_op_masks[chip] != mask {
+            self.four_op_masks[chip] = mask;
+            self.chips[chip].write_register(FOUR_OP_REGISTER, mask);
+        }
+    }
+
+    /// Write a note's instrument to a chip channel and key it on.
+    fn start_voice(&mut self, voice: usize, note: VoiceNote) {
+        let patch = lookup(&self.banks, note.patch);
+        let (chip, channel) = (&mut self.chips[voice / VOICES], (voice % VOICES) as u8);
+        // Channels are always in range, so the writes cannot fail.
+        if note.four_op {
+            _ = patch.write_to(chip, channel);
+        } else {
+            _ = patch.pairs[note.pair].write_to(chip, channel);
+        }
+        self.voices[voice] = Voice {
+            note: Some(note),
+            keyed: true,
+            ..Voice::default()
+        };
+        self.write_output(voice);
+        self.write_levels(voice);
+        self.write_frequency(voice);
+    }
+
+    /// Key off a chip channel, keeping its note so that it can still be stolen last.
+    fn key_off(&mut self, voice: usize) {
+        if self.voices[voice].keyed {
+            self.allocator.release(voice);
+            self.write_key_off(voice);
+        }
+    }
+
+    /// Key off a chip channel, without marking it released in the allocator.
+    fn write_key_off(&mut self, voice: usize) {
+        if !self.voices[voice].keyed {
+            return;
+        }
+        self.voices[voice].keyed = false;
+        self.voices[voice].deferred_off = false;
+        self.voices[voice].sostenuto = false;
+        self.write_frequency(voice);
+    }
+
+    /// Key off a chip channel whose note has been released, unless a pedal holds it.
+    fn release(&mut self, voice: usize) {
+        let Some(note) = self.voices[voice].note else {
+            return;
+        };
+        if self.channels[note.channel as usize].sustain || self.voices[voice].sostenuto {
+            self.voices[voice].deferred_off = true;
+            self.allocator.set_priority(voice, PEDAL_PRIORITY);
+        } else {
+            self.key_off(voice);
+        }
+    }
+
+    /// Key off the released notes of a MIDI channel that the pedals no longer hold.
+    fn release_pedals(&mut self, channel: u8) {
+        let sustain = self.channels[channel as usize].sustain;
+        for voice in 0..self.voices.len() {
+            let v = &self.voices[voice];
+            if v.deferred_off
+                && !sustain
+                && !v.sostenuto
+                && v.note.is_some_and(|n| n.channel == channel)
+            {
+                self.key_off(voice);
+            }
+        }
+    }
+
+    /// Silence a chip channel at once, by setting its release rate to the fastest.
+    fn silence(&mut self, voice: usize) {
+        let operators = if self.voices[voice].note.is_some_and(|note| note.four_op) {
+            4
+        } else {
+            2
+        };
+        let (chip, channel) = (&mut self.chips[voice / VOICES], (voice % VOICES) as u8);
+        for operator in 0..operators {
+            if let Some((file, reg)) =
+                reg_for_operator(channel, operator, OperatorParam::SustainRelease)
+            {
+                chip.write_register(register_address(file, reg), 0x0F);
+            }
+        }
+    }
+
+    /// Key off every chip channel playing a note on a MIDI channel, and silence it.
+    fn stop_channel(&mut self, channel: u8) {
+        for voice in 0..self.voices.len() {
+            if self.voices[voice]
+                .note
+                .is_some_and(|n| n.channel == channel)
+            {
+                self.free(voice);
+            }
+        }
+    }
+
+    /// Call `update` for every chip channel holding a note on a MIDI channel.
+    fn update_voices(&mut self, channel: u8, update: fn(&mut Self, usize)) {
+        for voice in 0..self.voices.len() {
+            let v = &self.voices[voice];
+            if v.keyed && v.note.is_some_and(|n| n.channel == channel) {
+                update(self, voice);
+            }
+        }
+    }
+
+    /// Write the output levels of a chip channel's carriers, from the note's velocity and the
+    /// MIDI channel's volume, expression and, for soft-panned notes, pan.
+    fn write_levels(&mut self, voice: usize) {
+        let Some(note) = self.voices[voice].note else {
+            return;
+        };
+        let state = &self.channels[note.channel as usize];
+        let attenuation =
+            (self
+                .volume_curve
+                .attenuation(note.velocity, state.volume, state.expression)
+                + pan_attenuation(state.pan, note.output))
+            .min(0x3F);
+
+        let patch = lookup(&self.banks, note.patch);
+        let pairs: &[OperatorPair] = if note.four_op {
+            &patch.pairs
+        } else {
+            &patch.pairs[note.pair..note.pair + 1]
+        };
+        let operators = pairs.iter().flat_map(|pair| [pair.modulator, pair.carrier]);
+        let carriers = carriers(pairs);
+        let (chip, channel) = (&mut self.chips[voice / VOICES], (voice % VOICES) as u8);
+        for (index, operator) in operators.enumerate() {
+            if carriers & (1 << index) == 0 {
+                continue;
+            }
+            let level = (operator.level & 0x3F) + attenuation;
+            if let Some((file, reg)) = reg_for_operator(channel, index as u8, OperatorParam::Level)
+            {
+                chip.write_register(
+                    register_address(file, reg),
+                    (operator.level & 0xC0) | level.min(0x3F),
+                );
+            }
+        }
+    }
+
+    /// Write a chip channel's feedback, connection and output select bits, from the MIDI
+    /// channel's pan or the half of a soft-panned note the channel plays.
+    fn write_output(&mut self, voice: usize) {
+        let Some(note) = self.voices[voice].note else {
+            return;
+        };
+        let output = match (note.output, self.channels[note.channel as usize].pan) {
+            (Output::Left, _) | (Output::Pan, 0..=42) => 0x10,
+            (Output::Right, _) | (Output::Pan, 86..) => 0x20,
+            (Output::Pan, _) => 0x30,
+        };
+        let patch = lookup(&self.banks, note.patch);
+        let channels: &[(usize, usize)] = if note.four_op {
+            &[(0, 0), (1, 3)]
+        } else {
+            &[(note.pair, 0)]
+        };
+        let (chip, channel) = (&mut self.chips[voice / VOICES], voice % VOICES);
+        for &(pair, offset) in channels {
+            if let Some((file, reg)) =
+                reg_for_channel((channel + offset) as u8, ChannelParam::FeedbackConnection)
+            {
+                let value = output | (patch.pairs[pair].feedback_connection & 0x0F);
+                chip.write_register(register_address(file, reg), value);
+            }
+        }
+    }
+
+    /// Write the frequency and key-on bit of a chip channel, from its note and random detune and
+    /// the MIDI channel's pitch bend and tuning.
+    fn write_frequency(&mut self, voice: usize) {
+        let Some(note) = self.voices[voice].note else {
+            return;
+        };
+        let offset = self.channels[note.channel as usize].pitch_offset() + note.detune;
+        let pitch = lookup(&self.banks, note.patch).pitch(note.pair, note.note) + offset;
+        write_note(
+            &mut self.chips[voice / VOICES],
+            (voice % VOICES) as u8,
+            pitch,
+            self.voices[voice].keyed,
+        );
+    }
+}
+
+/// Resetting also stops every note and resets every MIDI channel. Every chip is reset, and
+/// registers written directly are written to every chip.
+impl OplCore for OplMidiSynth {
+    fn sample_rate(&self) -> u32 {
+        self.chips[0].sample_rate()
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        for chip in self.chips.iter_mut() {
+            chip.reset(sample_rate);
+            chip.write_register(MODE_REGISTER, 0x01);
+        }
+        self.channels = initial_channels(MidiStandard::Gm);
+        self.standard = MidiStandard::Gm;
+        self.voices.fill(Voice::default());
+        self.allocator.reset();
+        self.four_op_masks.fill(0);
+        self.detune_state = detune_state(self.detune_seed);
+    }
+
+    /// Registers written directly may be overwritten by the synth at any time.
+    fn write_register(&mut self, reg: u16, value: u8) {
+        for chip in self.chips.iter_mut() {
+            chip.write_register(reg, value);
+        }
+    }
+
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let (first, rest) = self.chips.split_first_mut().ok_or(OplError::InvalidState)?;
+        let generated = first.generate_stream(buffer)?;
+        if rest.is_empty() {
+            return Ok(generated);
+        }
+        self.scratch.resize(buffer.len(), 0);
+        for chip in rest {
+            chip.generate_stream(&mut self.scratch)?;
+            for (sample, &other) in buffer.iter_mut().zip(&self.scratch) {
+                *sample = sample.saturating_add(other);
+            }
+        }
+        Ok(generated)
+    }
+}
+
+/// Return the starting state of the random detune generator for a seed. The state of a xorshift
+/// generator must not be 0.
+fn detune_state(seed: u32) -> u64 {
+    (seed as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1
+}
+
+/// Return the state of the MIDI channels after a reset for a MIDI standard.
+fn initial_channels(standard: MidiStandard) -> [MidiChannel; 16] {
+    let mut channels = [MidiChannel::default(); 16];
+    let drums = &mut channels[PERCUSSION_CHANNEL as usize];
+    drums.drums = true;
+    drums.bank_msb = match standard {
+        MidiStandard::Gm2 => 120,
+        MidiStandard::Xg => 127,
+        MidiStandard::Gm | MidiStandard::Gs => 0,
+    };
+    channels
+}
+
+/// Return the extra attenuation of a chip channel's carriers for the pan of its MIDI channel, in
+/// steps of 0.75 dB. Either half of a soft-panned note is at full level from the centre to its
+/// own side, and fades out linearly towards the other side.
+fn pan_attenuation(pan: u8, output: Output) -> u8 {
+    let pan = pan.min(127) as f64;
+    let gain = match output {
+        Output::Pan => return 0,
+        Output::Left => (127.0 - pan) / 63.5,
+        Output::Right => pan / 63.5,
+    };
+    if gain > 0.0 {
+        (-20.0 * gain.min(1.0).log10() / 0.75).round().min(63.0) as u8
+    } else {
+        63
+    }
+}
+
+/// Return the index of the first bank matching a predicate.
+fn find_bank(banks: &[Bank], predicate: impl Fn(&Bank) -> bool) -> Option<usize> {
+    banks.iter().position(predicate)
+}
+
+/// Return a reference to a patch, if it exists and is not blank.
+fn playable(banks: &BankSet, percussion: bool, bank: usize, index: usize) -> Option<PatchRef> {
+    let patch_ref = PatchRef {
+        percussion,
+        bank,
+        index,
+    };
+    let list = if percussion {
+        &banks.percussion
+    } else {
+        &banks.melodic
+    };
+    let patch = list.get(bank)?.patches.get(index)?;
+    let blank = patch
+        .pairs
+        .iter()
+        .all(|pair| *pair == OperatorPair::default());
+    (!blank).then_some(patch_ref)
+}
+
+/// Return the patch a reference points to.
+fn lookup(banks: &BankSet, patch_ref: PatchRef) -> &Patch {
+    let list = if patch_ref.percussion {
+        &banks.percussion
+    } else {
+        &banks.melodic
+    };
+    &list[patch_ref.bank].patches[patch_ref.index]
+}
+
+/// Return a mask of the operators reaching the output, for one pair or a 4-operator pair of
+/// pairs, from their connection bits.
+fn carriers(pairs: &[OperatorPair]) -> u8 {
+    let connection = |pair: &OperatorPair| pair.feedback_connection & 0x01 != 0;
+    match pairs {
+        [pair] if connection(pair) => 0b0011,
+        [_] => 0b0010,
+        [first, second] => match (connection(first), connection(second)) {
+            (false, false) => 0b1000,
+            (true, false) => 0b1001,
+            (false, true) => 0b1010,
+            (true, true) => 0b1101,
+        },
+        _ => 0,
+    }
+}
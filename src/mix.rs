@@ -0,0 +1,170 @@
+//! Helpers for placing the YMF262's four DAC outputs into common speaker layouts.
+//!
+//! The OPL3 has two stereo DAC pairs: A/B, which nearly every card connects as left/right, and
+//! C/D, which is rarely connected at all. These helpers take `Frame4` samples as produced by
+//! `Opl3Chip::generate_frame4_stream` and either spread the outputs over a surround layout or fold
+//! them down to stereo.
+
+use crate::{Frame4, OplError};
+
+/// A multichannel speaker layout that the four DAC outputs can be placed into.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpeakerLayout {
+    /// Four channels, interleaved as front left, front right, rear left, rear right.
+    /// A and B are placed in the front, C and D in the rear.
+    Quad,
+    /// Six channels, interleaved in the conventional WAV/SMPTE order of front left, front right,
+    /// center, LFE, surround left, surround right. A and B are placed in the front, C and D in the
+    /// surrounds. The center and LFE channels are silent.
+    Surround51,
+}
+
+impl SpeakerLayout {
+    /// Return the number of interleaved channels in this layout.
+    pub fn channels(&self) -> usize {
+        match self {
+            SpeakerLayout::Quad => 4,
+            SpeakerLayout::Surround51 => 6,
+        }
+    }
+}
+
+/// Place 4-channel frames into a multichannel speaker layout.
+///
+/// # Arguments
+///
+/// * `frames` - The 4-channel frames to convert.
+/// * `layout` - The speaker layout to produce.
+/// * `out`    - A mutable reference to a buffer that will receive the interleaved output. It must
+///              hold at least `frames.len() * layout.channels()` samples.
+///
+/// # Returns
+///
+/// A Result containing either `()` on success or an `OplError` on failure.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::Frame4;
+/// use opl3_rs::mix::{to_layout, SpeakerLayout};
+///
+/// let frames = [Frame4 { a: 1, b: 2, c: 3, d: 4 }];
+/// let mut out = [0i16; 6];
+/// to_layout(&frames, SpeakerLayout::Surround51, &mut out).unwrap();
+/// assert_eq!(out, [1, 2, 0, 0, 3, 4]);
+/// ```
+pub fn to_layout(
+    frames: &[Frame4],
+    layout: SpeakerLayout,
+    out: &mut [i16],
+) -> Result<(), OplError> {
+    let channels = layout.channels();
+    if out.len() < frames.len() * channels {
+        return Err(OplError::BufferUndersized);
+    }
+    for (frame, out) in frames.iter().zip(out.chunks_exact_mut(channels)) {
+        match layout {
+            SpeakerLayout::Quad => out.copy_from_slice(&[frame.a, frame.b, frame.c, frame.d]),
+            SpeakerLayout::Surround51 => {
+                out.copy_from_slice(&[frame.a, frame.b, 0, 0, frame.c, frame.d])
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The `StereoFold` struct folds all four DAC outputs down to a stereo pair, with a configurable
+/// (left, right) gain for each output.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StereoFold {
+    /// The (left, right) gain applied to output A.
+    pub a: (f32, f32),
+    /// The (left, right) gain applied to output B.
+    pub b: (f32, f32),
+    /// The (left, right) gain applied to output C.
+    pub c: (f32, f32),
+    /// The (left, right) gain applied to output D.
+    pub d: (f32, f32),
+}
+
+impl Default for StereoFold {
+    /// The default fold keeps A and B as left and right at unity gain, and mixes C and D into left
+    /// and right at -3dB.
+    fn default() -> Self {
+        StereoFold {
+            a: (1.0, 0.0),
+            b: (0.0, 1.0),
+            c: (std::f32::consts::FRAC_1_SQRT_2, 0.0),
+            d: (0.0, std::f32::consts::FRAC_1_SQRT_2),
+        }
+    }
+}
+
+impl StereoFold {
+    /// Fold a single 4-channel frame down to stereo. The result is clipped to the i16 range.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame to fold.
+    ///
+    /// # Returns
+    ///
+    /// The left and right samples.
+    pub fn fold_frame(&self, frame: Frame4) -> [i16; 2] {
+        let inputs = [
+            (frame.a, self.a),
+            (frame.b, self.b),
+            (frame.c, self.c),
+            (frame.d, self.d),
+        ];
+        let (mut left, mut right) = (0.0f32, 0.0f32);
+        for (sample, (gain_l, gain_r)) in inputs {
+            left += sample as f32 * gain_l;
+            right += sample as f32 * gain_r;
+        }
+        [clip(left), clip(right)]
+    }
+
+    /// Fold a slice of 4-channel frames down to interleaved stereo.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The 4-channel frames to fold.
+    /// * `out`    - A mutable reference to a buffer that will receive the interleaved stereo
+    ///              output. It must hold at least `frames.len() * 2` samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Frame4;
+    /// use opl3_rs::mix::StereoFold;
+    ///
+    /// let fold = StereoFold {
+    ///     c: (1.0, 0.0),
+    ///     d: (0.0, 1.0),
+    ///     ..Default::default()
+    /// };
+    /// let frames = [Frame4 { a: 100, b: 200, c: 10, d: 20 }];
+    /// let mut out = [0i16; 2];
+    /// fold.fold(&frames, &mut out).unwrap();
+    /// assert_eq!(out, [110, 220]);
+    /// ```
+    pub fn fold(&self, frames: &[Frame4], out: &mut [i16]) -> Result<(), OplError> {
+        if out.len() < frames.len() * 2 {
+            return Err(OplError::BufferUndersized);
+        }
+        for (frame, out) in frames.iter().zip(out.chunks_exact_mut(2)) {
+            out.copy_from_slice(&self.fold_frame(*frame));
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn clip(sample: f32) -> i16 {
+    sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
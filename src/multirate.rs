@@ -0,0 +1,176 @@
+//! Rendering once at the chip's native sample rate and resampling to several output rates.
+//!
+//! Emulators sometimes need the same OPL output at two rates, such as 48kHz for playback and
+//! 11025Hz for a period-accurate recording. Rendering two chips in lockstep doubles the cost of
+//! synthesis. `RateSplitter` instead renders one chip at its native rate and feeds every sample to
+//! two `Resampler`s.
+//!
+//! `Resampler` uses the same linear interpolation as Nuked-OPL3's own resampler, so each output is
+//! identical to what `Opl3Chip::generate_stream` produces on a chip created at that rate.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::multirate::{RateSplitter, NATIVE_SAMPLE_RATE};
+//!
+//! let mut chip = Opl3Chip::new(NATIVE_SAMPLE_RATE);
+//! let mut splitter = RateSplitter::new(48000, 11025);
+//! let (mut playback, mut stream) = (Vec::new(), Vec::new());
+//! // Render 100ms.
+//! splitter.render(&mut chip, 4972, &mut playback, &mut stream).unwrap();
+//!
+//! // The output matches a chip rendering at the output rate directly.
+//! let mut reference = vec![0i16; playback.len()];
+//! Opl3Chip::new(48000).generate_stream(&mut reference).unwrap();
+//! assert_eq!(playback, reference);
+//! ```
+
+use crate::{Opl3Chip, OplError};
+
+/// The sample rate that the OPL3 generates samples at, its 14.31818MHz clock divided by 288.
+pub const NATIVE_SAMPLE_RATE: u32 = 49716;
+
+/// The number of fractional bits of the resampler position, as in Nuked-OPL3.
+const RESAMPLER_FRACTION_BITS: u32 = 10;
+
+/// Extra fractional bits used so the rate ratio can be adjusted finely. The nominal ratio is a
+/// whole multiple of these, which keeps the output identical to Nuked-OPL3 when unadjusted.
+const RESAMPLER_EXTRA_BITS: u32 = 12;
+
+/// The `Resampler` struct converts 2 channel audio at the native sample rate to another rate.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    sample_rate: u32,
+    nominal_ratio: i64,
+    rate_ratio: i64,
+    position: i64,
+    old: [i16; 2],
+    new: [i16; 2],
+}
+
+impl Resampler {
+    /// Create a new `Resampler`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to convert to.
+    pub fn new(sample_rate: u32) -> Self {
+        let nominal_ratio = (((sample_rate as i64) << RESAMPLER_FRACTION_BITS)
+            / NATIVE_SAMPLE_RATE as i64)
+            << RESAMPLER_EXTRA_BITS;
+        Resampler {
+            sample_rate,
+            nominal_ratio,
+            rate_ratio: nominal_ratio,
+            position: 0,
+            old: [0; 2],
+            new: [0; 2],
+        }
+    }
+
+    /// Return the sample rate this resampler converts to.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Scale the output rate by `ratio`, for dynamic rate control. With a ratio above 1.0, the
+    /// resampler produces slightly more samples per native sample, and slightly fewer below 1.0.
+    /// The position is kept, so the ratio can be changed between any two samples without a
+    /// discontinuity. A ratio of exactly 1.0 restores output identical to Nuked-OPL3's.
+    ///
+    /// # Arguments
+    ///
+    /// * `ratio` - The output rate as a multiple of the nominal sample rate.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::multirate::Resampler;
+    ///
+    /// let mut resampler = Resampler::new(48000);
+    /// resampler.set_rate_ratio(1.01);
+    /// let mut out = Vec::new();
+    /// for _ in 0..49716 {
+    ///     resampler.push([0, 0], &mut out);
+    /// }
+    /// assert!((out.len() / 2).abs_diff(48480) < 100);
+    /// ```
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.rate_ratio = ((self.nominal_ratio as f64 * ratio) as i64).max(1);
+    }
+
+    /// Feed one native sample to the resampler, appending any output samples it completes.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The left and right native sample.
+    /// * `out`   - A vector that receives interleaved stereo samples at the output rate.
+    pub fn push(&mut self, frame: [i16; 2], out: &mut Vec<i16>) {
+        // Output samples are interpolated between the previous two native samples, so they trail
+        // the input by one native sample, exactly as in Opl3GenerateResampled.
+        while self.position < self.rate_ratio {
+            for channel in 0..2 {
+                let old = self.old[channel] as i64 * (self.rate_ratio - self.position);
+                let new = self.new[channel] as i64 * self.position;
+                out.push(((old + new) / self.rate_ratio) as i16);
+            }
+            self.position += 1 << (RESAMPLER_FRACTION_BITS + RESAMPLER_EXTRA_BITS);
+        }
+        self.old = self.new;
+        self.new = frame;
+        self.position -= self.rate_ratio;
+    }
+}
+
+/// The `RateSplitter` struct renders a chip at its native rate and resamples the output to two
+/// sample rates at once.
+#[derive(Clone, Debug)]
+pub struct RateSplitter {
+    primary: Resampler,
+    secondary: Resampler,
+}
+
+impl RateSplitter {
+    /// Create a new `RateSplitter`.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_rate`   - The sample rate of the first output.
+    /// * `secondary_rate` - The sample rate of the second output.
+    pub fn new(primary_rate: u32, secondary_rate: u32) -> Self {
+        RateSplitter {
+            primary: Resampler::new(primary_rate),
+            secondary: Resampler::new(secondary_rate),
+        }
+    }
+
+    /// Render native samples from a chip and append the resampled audio to both outputs.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip`          - The chip to render from. Its own sample rate is not used.
+    /// * `native_frames` - The number of samples to render at the native rate of 49716Hz.
+    /// * `primary`       - A vector that receives interleaved stereo samples at the primary rate.
+    /// * `secondary`     - A vector that receives interleaved stereo samples at the secondary
+    ///                     rate.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    pub fn render(
+        &mut self,
+        chip: &mut Opl3Chip,
+        native_frames: usize,
+        primary: &mut Vec<i16>,
+        secondary: &mut Vec<i16>,
+    ) -> Result<(), OplError> {
+        let mut frame = [0i16; 2];
+        for _ in 0..native_frames {
+            chip.generate(&mut frame)?;
+            self.primary.push(frame, primary);
+            self.secondary.push(frame, secondary);
+        }
+        Ok(())
+    }
+}
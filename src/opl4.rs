@@ -0,0 +1,572 @@
+//! The YMF278 (OPL4), pairing the FM section of this crate with a wavetable section.
+//!
+//! The YMF278 contains a complete OPL3 FM section alongside 24 channels of sample playback from
+//! an external ROM and RAM. Sound cards such as the Moonsound and Audician need both halves.
+//! `Opl4Chip` runs an `Opl3Chip` for the FM section and emulates the wavetable section itself,
+//! mixing the two at the output with the levels set by the mixing registers 0xF8 and 0xF9.
+//!
+//! The wavetable section is a simplified model. Wave headers, the three sample formats, looping,
+//! pitch, total level, panning and mixing follow the YMF278 register map, but the envelope
+//! generator is an approximation and the LFO, vibrato, tremolo, pseudo-reverb and damping
+//! parameters are stored without taking effect. Both output channels selected by register
+//! 0x68-0x7F bit 4 are mixed into the same stereo output.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::opl4::Opl4Chip;
+//!
+//! // A ROM holding one wave: its 12 byte header, then a 64 sample 8-bit square wave at 0x100.
+//! let mut rom = vec![0u8; 0x140];
+//! rom[..12].copy_from_slice(&[
+//!     0x00, 0x01, 0x00, // 8-bit samples, starting at 0x100
+//!     0x00, 0x00,       // Loop from the first sample
+//!     0xFF, 0xC0,       // End after 64 samples, stored negated
+//!     0x00, 0xF0, 0x00, 0x0F, 0x00,
+//! ]);
+//! for (i, sample) in rom[0x100..].iter_mut().enumerate() {
+//!     *sample = if i < 32 { 0x40 } else { 0xC0 };
+//! }
+//!
+//! let mut chip = Opl4Chip::new(44100);
+//! chip.load_rom(&rom).unwrap();
+//! chip.write_pcm_register(0x50, 0x00); // Channel 0 at full level
+//! chip.write_pcm_register(0x20, 0x00); // Wave number high bit and F-number low bits
+//! chip.write_pcm_register(0x38, 0x00); // Octave 0 and F-number high bits
+//! chip.write_pcm_register(0x08, 0x00); // Wave 0, which loads its header
+//! chip.write_pcm_register(0x68, 0x80); // Key on
+//!
+//! let mut buffer = [0i16; 256 * 2];
+//! assert_eq!(chip.generate_stream(&mut buffer).unwrap(), 256);
+//! assert!(buffer.iter().any(|&sample| sample != 0));
+//! ```
+
+use crate::{Opl3Chip, OplError};
+
+/// The size of the wavetable memory address space, 4MB.
+pub const MEMORY_SIZE: usize = 0x40_0000;
+
+/// The number of wavetable channels.
+pub const PCM_CHANNELS: usize = 24;
+
+/// The rate at which the wavetable section plays samples at octave 0 and F-number 0, in Hz.
+pub const PCM_SAMPLE_RATE: u32 = 44100;
+
+/// The number of wave numbers below which headers are always read from the start of memory.
+const ROM_WAVES: u16 = 384;
+
+/// The size of a wave header, in bytes.
+const HEADER_SIZE: usize = 12;
+
+/// Attenuation is measured in units of 0.09375dB, the resolution of the envelope generator. 64
+/// units are 6dB, which halves the amplitude.
+const MAX_ATTENUATION: u32 = 0x3FF;
+
+/// The number of fractional bits of the envelope attenuation.
+const ENVELOPE_FRACTION_BITS: u32 = 12;
+
+/// The number of fractional bits of the sample position.
+const POSITION_FRACTION_BITS: u32 = 16;
+
+/// Panning attenuation of the left and right outputs for each panpot value, in attenuation units.
+/// Values of 1024 and above mute the output.
+const PAN_LEFT: [u32; 16] = [
+    0, 32, 64, 96, 128, 160, 192, 1024, 1024, 0, 0, 0, 0, 0, 0, 0,
+];
+const PAN_RIGHT: [u32; 16] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1024, 1024, 192, 160, 128, 96, 64, 32,
+];
+
+/// The reset values of the FM and PCM mixing registers. The FM section starts at -9dB.
+const FM_MIX_RESET: u8 = 0x1B;
+const PCM_MIX_RESET: u8 = 0x00;
+
+/// The amplitude for each of the 64 attenuation steps within 6dB, as a fraction of 65536.
+const GAIN_TABLE: [u32; 64] = gain_table();
+
+/// The `SampleFormat` enum lists the sample formats a wave can be stored in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// 8-bit signed samples.
+    #[default]
+    Bits8,
+    /// 12-bit signed samples, packed two to every 3 bytes.
+    Bits12,
+    /// 16-bit signed big-endian samples.
+    Bits16,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum EnvelopeStage {
+    Attack,
+    Decay1,
+    Decay2,
+    Release,
+    #[default]
+    Off,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct WaveChannel {
+    wave: u16,
+    fnum: u16,
+    octave: i8,
+    total_level: u8,
+    pan: u8,
+    key_on: bool,
+    attack: u8,
+    decay1: u8,
+    decay_level: u8,
+    decay2: u8,
+    rate_correction: u8,
+    release: u8,
+    format: SampleFormat,
+    start: u32,
+    loop_start: u32,
+    end: u32,
+    position: u64,
+    step: u64,
+    stage: EnvelopeStage,
+    attenuation: u32,
+}
+
+/// The `Opl4Chip` struct is a YMF278, an FM section and a wavetable section mixed together.
+pub struct Opl4Chip {
+    fm: Opl3Chip,
+    sample_rate: u32,
+    memory: Vec<u8>,
+    rom_size: usize,
+    registers: [u8; 256],
+    channels: [WaveChannel; PCM_CHANNELS],
+    memory_address: u32,
+}
+
+impl Opl4Chip {
+    /// Create a new `Opl4Chip`, with empty wavetable memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut chip = Opl4Chip {
+            fm: Opl3Chip::new(sample_rate),
+            sample_rate: sample_rate.max(1),
+            memory: vec![0; MEMORY_SIZE],
+            rom_size: 0,
+            registers: [0; 256],
+            channels: [WaveChannel::default(); PCM_CHANNELS],
+            memory_address: 0,
+        };
+        chip.reset_pcm();
+        chip
+    }
+
+    /// Reset both sections of the chip. The contents of wavetable memory are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn reset(&mut self, sample_rate: u32) {
+        self.fm.reset(sample_rate);
+        self.sample_rate = sample_rate.max(1);
+        self.reset_pcm();
+    }
+
+    /// Load a wave ROM at the start of wavetable memory. The ROM cannot be written through
+    /// register 0x06 afterwards, while the memory above it can, as RAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `rom` - The ROM image, such as the 2MB YRW801 used by the Moonsound.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), OplError> {
+        if rom.len() > MEMORY_SIZE {
+            return Err(OplError::InvalidFormat("OPL4 ROM"));
+        }
+        self.memory[..rom.len()].copy_from_slice(rom);
+        self.rom_size = rom.len();
+        Ok(())
+    }
+
+    /// Copy data into wavetable memory, such as samples that a driver would upload to RAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address to copy the data to.
+    /// * `data`    - The data to copy.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    pub fn load_ram(&mut self, address: usize, data: &[u8]) -> Result<(), OplError> {
+        let end = address
+            .checked_add(data.len())
+            .filter(|&end| end <= MEMORY_SIZE)
+            .ok_or(OplError::BufferUndersized)?;
+        self.memory[address..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Return the contents of wavetable memory.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Return the FM section.
+    pub fn fm(&self) -> &Opl3Chip {
+        &self.fm
+    }
+
+    /// Return the FM section, for writing its registers.
+    pub fn fm_mut(&mut self) -> &mut Opl3Chip {
+        &mut self.fm
+    }
+
+    /// Write to a wavetable register. On hardware these are reached through the second pair of
+    /// ports, once the NEW2 bit of FM register 0x105 is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg`   - The register to write.
+    /// * `value` - The value to write.
+    pub fn write_pcm_register(&mut self, reg: u8, value: u8) {
+        self.registers[reg as usize] = value;
+        match reg {
+            0x03 => self.set_memory_address(0x3F_0000, (value as u32 & 0x3F) << 16),
+            0x04 => self.set_memory_address(0x00_FF00, (value as u32) << 8),
+            0x05 => self.set_memory_address(0x00_00FF, value as u32),
+            0x06 => {
+                let address = self.memory_address as usize;
+                if address >= self.rom_size {
+                    self.memory[address] = value;
+                }
+                self.increment_memory_address();
+            }
+            0x08..=0x1F => {
+                let channel = (reg - 0x08) as usize;
+                let high = self.channels[channel].wave & 0x100;
+                self.channels[channel].wave = high | value as u16;
+                self.load_header(channel);
+            }
+            0x20..=0x37 => {
+                let channel = &mut self.channels[(reg - 0x20) as usize];
+                channel.wave = (channel.wave & 0xFF) | (value as u16 & 0x01) << 8;
+                channel.fnum = (channel.fnum & 0x380) | (value as u16 >> 1);
+                self.update_step((reg - 0x20) as usize);
+            }
+            0x38..=0x4F => {
+                let channel = &mut self.channels[(reg - 0x38) as usize];
+                channel.fnum = (channel.fnum & 0x7F) | (value as u16 & 0x07) << 7;
+                // The octave is a signed 4-bit value.
+                channel.octave = (value as i8) >> 4;
+                self.update_step((reg - 0x38) as usize);
+            }
+            0x50..=0x67 => self.channels[(reg - 0x50) as usize].total_level = value >> 1,
+            0x68..=0x7F => {
+                let index = (reg - 0x68) as usize;
+                let channel = &mut self.channels[index];
+                let key_on = value & 0x80 != 0;
+                channel.pan = value & 0x0F;
+                if key_on && !channel.key_on {
+                    channel.position = 0;
+                    channel.attenuation = MAX_ATTENUATION << ENVELOPE_FRACTION_BITS;
+                    channel.stage = EnvelopeStage::Attack;
+                } else if !key_on && channel.key_on && channel.stage != EnvelopeStage::Off {
+                    channel.stage = EnvelopeStage::Release;
+                }
+                channel.key_on = key_on;
+                self.update_step(index);
+            }
+            0x98..=0xAF => {
+                let channel = &mut self.channels[(reg - 0x98) as usize];
+                (channel.attack, channel.decay1) = (value >> 4, value & 0x0F);
+            }
+            0xB0..=0xC7 => {
+                let channel = &mut self.channels[(reg - 0xB0) as usize];
+                (channel.decay_level, channel.decay2) = (value >> 4, value & 0x0F);
+            }
+            0xC8..=0xDF => {
+                let channel = &mut self.channels[(reg - 0xC8) as usize];
+                (channel.rate_correction, channel.release) = (value >> 4, value & 0x0F);
+            }
+            _ => {}
+        }
+    }
+
+    /// Read a wavetable register. Reading register 0x06 returns the byte of wavetable memory at
+    /// the current memory address and advances the address, as on hardware.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register to read.
+    pub fn read_pcm_register(&mut self, reg: u8) -> u8 {
+        if reg == 0x06 {
+            let value = self.memory[self.memory_address as usize];
+            self.increment_memory_address();
+            return value;
+        }
+        self.registers[reg as usize]
+    }
+
+    /// Generate a stream of 2 channel, interleaved audio samples, with the FM and wavetable
+    /// sections mixed together.
+    ///
+    /// One frame is generated for every two samples in the buffer. If the buffer has an odd
+    /// length, its last sample is left unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure.
+    pub fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let frames = buffer.len() / 2;
+        let buffer = &mut buffer[..frames * 2];
+        self.fm.generate_stream(buffer)?;
+
+        let fm_mix = mix_attenuation(self.registers[0xF8]);
+        let pcm_mix = mix_attenuation(self.registers[0xF9]);
+        for frame in buffer.chunks_exact_mut(2) {
+            let pcm = self.generate_pcm(pcm_mix);
+            let fm = [
+                apply_attenuation(frame[0] as i32, fm_mix[0]),
+                apply_attenuation(frame[1] as i32, fm_mix[1]),
+            ];
+            for (sample, (fm, pcm)) in frame.iter_mut().zip(fm.into_iter().zip(pcm)) {
+                *sample = (fm + pcm).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            }
+        }
+        Ok(frames)
+    }
+
+    fn reset_pcm(&mut self) {
+        self.registers = [0; 256];
+        self.registers[0xF8] = FM_MIX_RESET;
+        self.registers[0xF9] = PCM_MIX_RESET;
+        self.channels = [WaveChannel::default(); PCM_CHANNELS];
+        self.memory_address = 0;
+    }
+
+    fn set_memory_address(&mut self, mask: u32, bits: u32) {
+        self.memory_address = (self.memory_address & !mask) | bits;
+    }
+
+    fn increment_memory_address(&mut self) {
+        self.memory_address = (self.memory_address + 1) % MEMORY_SIZE as u32;
+    }
+
+    fn read_memory(&self, address: u32) -> u8 {
+        self.memory[address as usize % MEMORY_SIZE]
+    }
+
+    /// Load the header of a channel's wave, which sets its sample location and format and its
+    /// LFO, envelope and tremolo registers.
+    fn load_header(&mut self, channel: usize) {
+        let wave = self.channels[channel].wave;
+        let header_bank = (self.registers[0x02] >> 2) & 0x07;
+        let base = if wave >= ROM_WAVES && header_bank != 0 {
+            header_bank as usize * 0x8_0000 + (wave - ROM_WAVES) as usize * HEADER_SIZE
+        } else {
+            wave as usize * HEADER_SIZE
+        };
+        let mut header = [0u8; HEADER_SIZE];
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = self.read_memory((base + i) as u32);
+        }
+
+        let state = &mut self.channels[channel];
+        state.format = match header[0] >> 6 {
+            0 => SampleFormat::Bits8,
+            1 => SampleFormat::Bits12,
+            _ => SampleFormat::Bits16,
+        };
+        state.start = (header[0] as u32 & 0x3F) << 16 | (header[1] as u32) << 8 | header[2] as u32;
+        state.loop_start = u16::from_be_bytes([header[3], header[4]]) as u32;
+        // The end is stored as the negated length.
+        state.end = (!u16::from_be_bytes([header[5], header[6]])) as u32 + 1;
+        state.position = 0;
+
+        for (group, value) in [0x80, 0x98, 0xB0, 0xC8, 0xE0].into_iter().zip(&header[7..]) {
+            self.write_pcm_register(group + channel as u8, *value);
+        }
+    }
+
+    /// Recompute the sample step of a channel from its F-number and octave, for the output rate.
+    fn update_step(&mut self, channel: usize) {
+        let state = &mut self.channels[channel];
+        let shift = state.octave as i32 + (POSITION_FRACTION_BITS as i32 - 10);
+        let base = 1024 + state.fnum as u64;
+        let step = if shift >= 0 {
+            base << shift
+        } else {
+            base >> -shift
+        };
+        state.step = step * PCM_SAMPLE_RATE as u64 / self.sample_rate as u64;
+    }
+
+    /// Generate the next stereo sample of the wavetable section.
+    fn generate_pcm(&mut self, mix: [u32; 2]) -> [i32; 2] {
+        let mut out = [0i32; 2];
+        for index in 0..PCM_CHANNELS {
+            if self.channels[index].stage == EnvelopeStage::Off {
+                continue;
+            }
+            self.advance_envelope(index);
+            let sample = self.read_sample(index) as i32;
+            let channel = &mut self.channels[index];
+            let level =
+                (channel.attenuation >> ENVELOPE_FRACTION_BITS) + channel.total_level as u32 * 4;
+            let pan = [
+                PAN_LEFT[channel.pan as usize],
+                PAN_RIGHT[channel.pan as usize],
+            ];
+            for side in 0..2 {
+                out[side] += apply_attenuation(sample, level + pan[side] + mix[side]);
+            }
+            advance_position(channel);
+        }
+        out
+    }
+
+    fn read_sample(&self, channel: usize) -> i16 {
+        let state = &self.channels[channel];
+        let index = (state.position >> POSITION_FRACTION_BITS) as u32;
+        let sample = match state.format {
+            SampleFormat::Bits8 => (self.read_memory(state.start + index) as u16) << 8,
+            SampleFormat::Bits12 => {
+                let address = state.start + index / 2 * 3;
+                let middle = self.read_memory(address + 1) as u16;
+                if index & 1 == 0 {
+                    (self.read_memory(address) as u16) << 8 | (middle & 0xF0)
+                } else {
+                    (self.read_memory(address + 2) as u16) << 8 | (middle & 0x0F) << 4
+                }
+            }
+            SampleFormat::Bits16 => {
+                let address = state.start + index * 2;
+                u16::from_be_bytes([self.read_memory(address), self.read_memory(address + 1)])
+            }
+        };
+        sample as i16
+    }
+
+    fn advance_envelope(&mut self, channel: usize) {
+        let sample_rate = self.sample_rate as u64;
+        let state = &mut self.channels[channel];
+        let rate = match state.stage {
+            EnvelopeStage::Attack => state.attack,
+            EnvelopeStage::Decay1 => state.decay1,
+            EnvelopeStage::Decay2 => state.decay2,
+            EnvelopeStage::Release => state.release,
+            EnvelopeStage::Off => return,
+        };
+        let rate = effective_rate(rate, state.rate_correction, state.octave, state.fnum);
+        let increment = if rate == 0 {
+            0
+        } else {
+            (((4 + (rate & 3) as u64) << (rate >> 2)) * PCM_SAMPLE_RATE as u64 / sample_rate) as u32
+        };
+        let max = MAX_ATTENUATION << ENVELOPE_FRACTION_BITS;
+
+        match state.stage {
+            EnvelopeStage::Attack => {
+                // Attack rate 15 is instant. Attacks run 4 times faster than decays.
+                state.attenuation = if rate >= 60 {
+                    0
+                } else {
+                    state.attenuation.saturating_sub(increment * 4)
+                };
+                if state.attenuation == 0 {
+                    state.stage = EnvelopeStage::Decay1;
+                }
+            }
+            EnvelopeStage::Decay1 => {
+                state.attenuation = (state.attenuation + increment).min(max);
+                // Each step of the decay level is 3dB, and level 15 decays all the way.
+                let decay_level = match state.decay_level {
+                    15 => max,
+                    level => (level as u32 * 32) << ENVELOPE_FRACTION_BITS,
+                };
+                if state.attenuation >= decay_level {
+                    state.stage = EnvelopeStage::Decay2;
+                }
+            }
+            EnvelopeStage::Decay2 | EnvelopeStage::Release => {
+                state.attenuation = (state.attenuation + increment).min(max);
+                if state.attenuation >= max && state.stage == EnvelopeStage::Release {
+                    state.stage = EnvelopeStage::Off;
+                }
+            }
+            EnvelopeStage::Off => {}
+        }
+    }
+}
+
+/// Advance a channel's sample position by one output sample, looping at the end of the wave.
+fn advance_position(channel: &mut WaveChannel) {
+    channel.position += channel.step;
+    let end = (channel.end as u64) << POSITION_FRACTION_BITS;
+    if channel.position >= end {
+        let loop_start = (channel.loop_start as u64) << POSITION_FRACTION_BITS;
+        if loop_start >= end {
+            channel.position = loop_start;
+        } else {
+            channel.position = loop_start + (channel.position - end) % (end - loop_start);
+        }
+    }
+}
+
+/// Combine an envelope rate with the rate correction, which scales rates with pitch. Rate 0 stays
+/// at 0 and rate 15 is always the fastest rate.
+fn effective_rate(rate: u8, correction: u8, octave: i8, fnum: u16) -> u8 {
+    match rate {
+        0 => 0,
+        15 => 63,
+        _ => {
+            let correction = if correction == 15 {
+                0
+            } else {
+                (octave as i32 + correction as i32) * 2 + (fnum >> 9 & 1) as i32
+            };
+            (rate as i32 * 4 + correction).clamp(0, 63) as u8
+        }
+    }
+}
+
+/// Return the left and right attenuation set by a mixing register. Each step is 3dB, and level 7
+/// mutes the output.
+fn mix_attenuation(register: u8) -> [u32; 2] {
+    let level = |bits: u8| match bits & 0x07 {
+        7 => 1024,
+        bits => bits as u32 * 32,
+    };
+    [level(register), level(register >> 3)]
+}
+
+/// Scale a sample by an attenuation in units of 0.09375dB.
+fn apply_attenuation(sample: i32, attenuation: u32) -> i32 {
+    let shift = attenuation >> 6;
+    if shift >= 16 {
+        return 0;
+    }
+    ((sample * GAIN_TABLE[(attenuation & 63) as usize] as i32) >> 16) >> shift
+}
+
+/// Build the amplitude table by repeated multiplication by 2^(-1/64), in 16-bit fixed point.
+const fn gain_table() -> [u32; 64] {
+    let mut table = [0u32; 64];
+    let mut gain: u64 = 65536 << 16;
+    let mut i = 0;
+    while i < 64 {
+        table[i] = (gain >> 16) as u32;
+        gain = (gain * 64830) >> 16;
+        i += 1;
+    }
+    table
+}
@@ -0,0 +1,118 @@
+//! A common interface to the FM chips emulated by this crate.
+//!
+//! `OplCore` is implemented by `Opl3Chip`, and by `Opl4Chip` and `OpllChip` when their features
+//! are enabled. Code that only writes registers and renders audio, such as
+//! `export::render_event_log_with`, can be written once against the trait and used with any
+//! of them.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::opl_core::OplCore;
+//!
+//! fn render_note(core: &mut dyn OplCore) -> Vec<i16> {
+//!     core.write_register(0xB0, 0x32);
+//!     let mut buffer = vec![0i16; 1024 * 2];
+//!     core.generate_stream(&mut buffer).unwrap();
+//!     buffer
+//! }
+//!
+//! let mut chip = Opl3Chip::new(44100);
+//! assert_eq!(render_note(&mut chip).len(), 2048);
+//! ```
+
+use crate::{Opl3Chip, OplError};
+
+/// The `OplCore` trait is implemented by every chip that can be driven by register writes and
+/// rendered to stereo audio.
+pub trait OplCore {
+    /// Return the sample rate the chip generates audio at.
+    fn sample_rate(&self) -> u32;
+
+    /// Reset the chip to its power-on state.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    fn reset(&mut self, sample_rate: u32);
+
+    /// Write a value to a register. The meaning of the register address depends on the chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg`   - The register to write.
+    /// * `value` - The value to write.
+    fn write_register(&mut self, reg: u16, value: u8);
+
+    /// Generate a stream of 2 channel, interleaved audio samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure.
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError>;
+}
+
+impl OplCore for Opl3Chip {
+    fn sample_rate(&self) -> u32 {
+        Opl3Chip::sample_rate(self)
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        Opl3Chip::reset(self, sample_rate);
+    }
+
+    fn write_register(&mut self, reg: u16, value: u8) {
+        Opl3Chip::write_register(self, reg, value);
+    }
+
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        Opl3Chip::generate_stream(self, buffer)
+    }
+}
+
+/// FM registers are written with their 9-bit OPL3 addresses. The wavetable registers are reached
+/// through `Opl4Chip::write_pcm_register`.
+#[cfg(feature = "opl4")]
+impl OplCore for crate::opl4::Opl4Chip {
+    fn sample_rate(&self) -> u32 {
+        self.fm().sample_rate()
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        crate::opl4::Opl4Chip::reset(self, sample_rate);
+    }
+
+    fn write_register(&mut self, reg: u16, value: u8) {
+        self.fm_mut().write_register(reg, value);
+    }
+
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        crate::opl4::Opl4Chip::generate_stream(self, buffer)
+    }
+}
+
+/// Registers are written with their OPLL addresses, and the high byte of `reg` is ignored.
+#[cfg(feature = "opll")]
+impl OplCore for crate::opll::OpllChip {
+    fn sample_rate(&self) -> u32 {
+        crate::opll::OpllChip::sample_rate(self)
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        crate::opll::OpllChip::reset(self, sample_rate);
+    }
+
+    fn write_register(&mut self, reg: u16, value: u8) {
+        crate::opll::OpllChip::write_register(self, reg as u8, value);
+    }
+
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        crate::opll::OpllChip::generate_stream(self, buffer)
+    }
+}
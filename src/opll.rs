@@ -0,0 +1,517 @@
+//! The YM2413 (OPLL), the cost-reduced sibling of the OPL2 used for MSX-Music and the Sega Master
+//! System FM unit.
+//!
+//! The OPLL has 9 two-operator channels, but only one instrument can be defined by the user at a
+//! time. The other 15 instruments, and the 3 instruments used by rhythm mode, are fixed in an
+//! internal patch ROM, given here as `PATCH_ROM`. In rhythm mode, channels 6-8 play bass drum,
+//! snare drum, tom-tom, top cymbal and hi-hat instead.
+//!
+//! `OpllChip` is a pure Rust model of the chip, in the style of the OPL2 synthesis in Nuked-OPL3:
+//! it uses the same log-sin and exponent tables, envelope rates and rhythm phase generation. It
+//! runs at the native rate of 49716Hz for a 3.579545MHz clock, and is resampled to the output rate
+//! with `multirate::Resampler`. The chip is mono, so both output channels carry the same signal.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::opll::OpllChip;
+//!
+//! let mut chip = OpllChip::new(44100);
+//! chip.write_register(0x30, 0x30); // Channel 0: piano at full volume
+//! chip.write_register(0x10, 0xAC); // F-number low bits
+//! chip.write_register(0x20, 0x18); // Key on, block 4
+//!
+//! let mut buffer = [0i16; 1024 * 2];
+//! assert_eq!(chip.generate_stream(&mut buffer).unwrap(), 1024);
+//! assert!(buffer.iter().any(|&sample| sample != 0));
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+use crate::multirate::Resampler;
+use crate::OplError;
+
+/// The instrument ROM of the YM2413, in the layout of registers 0x00-0x07. Entry 0 is the user
+/// instrument and is always zero here. Entries 1-15 are the melodic instruments and entries 16-18
+/// the rhythm instruments, for bass drum, hi-hat and snare drum, and tom-tom and top cymbal.
+pub const PATCH_ROM: [[u8; 8]; 19] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // User instrument
+    [0x71, 0x61, 0x1E, 0x17, 0xD0, 0x78, 0x00, 0x17], // Violin
+    [0x13, 0x41, 0x1A, 0x0D, 0xD8, 0xF7, 0x23, 0x13], // Guitar
+    [0x13, 0x01, 0x99, 0x00, 0xF2, 0xC4, 0x21, 0x23], // Piano
+    [0x11, 0x61, 0x0E, 0x07, 0x8D, 0x64, 0x70, 0x27], // Flute
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28], // Clarinet
+    [0x31, 0x22, 0x16, 0x05, 0xE0, 0x71, 0x00, 0x18], // Oboe
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07], // Trumpet
+    [0x33, 0x21, 0x2D, 0x13, 0xB0, 0x70, 0x00, 0x07], // Organ
+    [0x61, 0x61, 0x1B, 0x06, 0x64, 0x65, 0x10, 0x17], // Horn
+    [0x41, 0x61, 0x0B, 0x18, 0x85, 0xF0, 0x81, 0x07], // Synthesizer
+    [0x33, 0x01, 0x83, 0x11, 0xEA, 0xEF, 0x10, 0x04], // Harpsichord
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12], // Vibraphone
+    [0x61, 0x50, 0x0C, 0x05, 0xD2, 0xF5, 0x40, 0x42], // Synthesizer bass
+    [0x01, 0x01, 0x55, 0x03, 0xE9, 0x90, 0x03, 0x02], // Acoustic bass
+    [0x41, 0x41, 0x89, 0x03, 0xF1, 0xE4, 0xC0, 0x13], // Electric guitar
+    [0x01, 0x01, 0x18, 0x0F, 0xDF, 0xF8, 0x6A, 0x6D], // Bass drum
+    [0x01, 0x01, 0x00, 0x00, 0xC8, 0xD8, 0xA7, 0x68], // Hi-hat and snare drum
+    [0x05, 0x01, 0x00, 0x00, 0xF8, 0xAA, 0x59, 0x55], // Tom-tom and top cymbal
+];
+
+/// The number of channels.
+pub const OPLL_CHANNELS: usize = 9;
+
+/// Frequency multipliers, doubled.
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+/// Key scale level attenuation by the top 4 bits of the F-number, as in Nuked-OPL3.
+const KSL_ROM: [i32; 16] = [
+    0, 32, 40, 45, 48, 51, 53, 55, 56, 58, 59, 60, 61, 62, 63, 64,
+];
+
+/// Shifts applied to the key scale level for 0, 1.5, 3 and 6dB per octave.
+const KSL_SHIFT: [u32; 4] = [8, 2, 1, 0];
+
+/// The 8 step vibrato pattern, in units of a quarter of the top F-number bits.
+const VIBRATO: [i32; 8] = [0, 1, 2, 1, 0, -1, -2, -1];
+
+/// The envelope is 9 bits, in units of 0.1875dB, with 16 fractional bits.
+const ENVELOPE_FRACTION_BITS: u32 = 16;
+const MAX_ENVELOPE: u32 = 0x1FF << ENVELOPE_FRACTION_BITS;
+
+/// The register holding the rhythm mode enable bit and the rhythm key bits.
+const RHYTHM_REGISTER: usize = 0x0E;
+
+/// The hi-hat, snare drum, tom-tom and top cymbal operators, by operator index.
+const HI_HAT: usize = 14;
+const SNARE_DRUM: usize = 15;
+const TOM_TOM: usize = 16;
+const TOP_CYMBAL: usize = 17;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Operator {
+    phase: u32,
+    envelope: u32,
+    stage: EnvelopeStage,
+    key: bool,
+    attenuation: u32,
+    out: i16,
+    prev_out: i16,
+}
+
+impl Operator {
+    /// Return the 10-bit phase, where 1024 is one cycle.
+    fn phase_out(&self) -> u32 {
+        (self.phase >> 9) & 0x3FF
+    }
+
+    /// Render the operator's output for a phase, keeping its previous output for feedback.
+    fn render(&mut self, phase: u32, half_sine: bool) -> i16 {
+        let tables = tables();
+        let phase = phase & 0x3FF;
+        let negative = phase & 0x200 != 0;
+        let out = if negative && half_sine {
+            0
+        } else {
+            let index = if phase & 0x100 != 0 {
+                (phase & 0xFF) ^ 0xFF
+            } else {
+                phase & 0xFF
+            };
+            let level =
+                (tables.log_sin[index as usize] as u32 + (self.attenuation << 3)).min(0x1FFF);
+            let out = ((tables.exp[(level & 0xFF) as usize] as i32) << 1) >> (level >> 8);
+            if negative {
+                !out
+            } else {
+                out
+            }
+        };
+        self.prev_out = self.out;
+        self.out = out as i16;
+        self.out
+    }
+
+    /// Advance the envelope by one sample.
+    fn update_envelope(
+        &mut self,
+        params: &OperatorPatch,
+        key: bool,
+        sustain: bool,
+        fnum: u32,
+        block: u8,
+    ) {
+        if key && !self.key {
+            self.stage = EnvelopeStage::Attack;
+            self.phase = 0;
+        } else if !key && self.key {
+            self.stage = EnvelopeStage::Release;
+        }
+        self.key = key;
+
+        let rate = match self.stage {
+            EnvelopeStage::Attack => params.attack,
+            EnvelopeStage::Decay => params.decay,
+            // Sustained instruments hold at the sustain level while keyed, and percussive ones
+            // keep decaying at the release rate.
+            EnvelopeStage::Sustain if params.sustained => 0,
+            EnvelopeStage::Sustain => params.release,
+            EnvelopeStage::Release if sustain => 5,
+            EnvelopeStage::Release if params.sustained => params.release,
+            EnvelopeStage::Release => 7,
+            EnvelopeStage::Off => return,
+        };
+        let rate = if rate == 0 {
+            0
+        } else {
+            let key_scale = (block as u32) << 1 | fnum >> 8;
+            let key_scale = if params.key_scale_rate {
+                key_scale
+            } else {
+                key_scale >> 2
+            };
+            (rate as u32 * 4 + key_scale).min(63)
+        };
+        let increment = if rate == 0 {
+            0
+        } else {
+            (4 + (rate & 3)) << ((rate >> 2) + 2)
+        };
+
+        match self.stage {
+            EnvelopeStage::Attack => {
+                if rate >= 60 {
+                    self.envelope = 0;
+                } else {
+                    let step = (((self.envelope >> ENVELOPE_FRACTION_BITS) + 1) * increment) >> 3;
+                    self.envelope = self.envelope.saturating_sub(step);
+                }
+                if self.envelope == 0 {
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                self.envelope = (self.envelope + increment).min(MAX_ENVELOPE);
+                // Each step of the sustain level is 3dB, and level 15 decays all the way.
+                let sustain_level = match params.sustain_level {
+                    15 => MAX_ENVELOPE,
+                    level => (level as u32 * 16) << ENVELOPE_FRACTION_BITS,
+                };
+                if self.envelope >= sustain_level {
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain | EnvelopeStage::Release => {
+                self.envelope = (self.envelope + increment).min(MAX_ENVELOPE);
+                if self.envelope >= MAX_ENVELOPE && self.stage == EnvelopeStage::Release {
+                    self.stage = EnvelopeStage::Off;
+                }
+            }
+            EnvelopeStage::Off => {}
+        }
+    }
+}
+
+/// The parameters of one operator, decoded from an instrument.
+#[derive(Copy, Clone, Debug)]
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: u8,
+    key_scale_level: u8,
+    half_sine: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    fn decode(patch: &[u8; 8], carrier: bool) -> Self {
+        let i = carrier as usize;
+        OperatorPatch {
+            tremolo: patch[i] & 0x80 != 0,
+            vibrato: patch[i] & 0x40 != 0,
+            sustained: patch[i] & 0x20 != 0,
+            key_scale_rate: patch[i] & 0x10 != 0,
+            multiplier: patch[i] & 0x0F,
+            key_scale_level: patch[2 + i] >> 6,
+            half_sine: patch[3] & if carrier { 0x10 } else { 0x08 } != 0,
+            attack: patch[4 + i] >> 4,
+            decay: patch[4 + i] & 0x0F,
+            sustain_level: patch[6 + i] >> 4,
+            release: patch[6 + i] & 0x0F,
+        }
+    }
+}
+
+struct Tables {
+    log_sin: [u16; 256],
+    exp: [u16; 256],
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut tables = Tables {
+            log_sin: [0; 256],
+            exp: [0; 256],
+        };
+        for i in 0..256 {
+            let angle = (i as f64 + 0.5) * std::f64::consts::PI / 512.0;
+            tables.log_sin[i] = (-angle.sin().log2() * 256.0).round() as u16;
+            tables.exp[i] = (2048.0 * (-(i as f64 + 1.0) / 256.0).exp2()).round() as u16;
+        }
+        tables
+    })
+}
+
+/// The `OpllChip` struct is an emulated YM2413.
+pub struct OpllChip {
+    sample_rate: u32,
+    registers: [u8; 0x40],
+    operators: [Operator; OPLL_CHANNELS * 2],
+    counter: u32,
+    noise: u32,
+    resampler: Resampler,
+    output: VecDeque<i16>,
+    scratch: Vec<i16>,
+}
+
+impl OpllChip {
+    /// Create a new `OpllChip`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn new(sample_rate: u32) -> Self {
+        OpllChip {
+            sample_rate,
+            registers: [0; 0x40],
+            operators: [Operator {
+                envelope: MAX_ENVELOPE,
+                ..Default::default()
+            }; OPLL_CHANNELS * 2],
+            counter: 0,
+            noise: 1,
+            resampler: Resampler::new(sample_rate),
+            output: VecDeque::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Reset the chip to its power-on state.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn reset(&mut self, sample_rate: u32) {
+        *self = OpllChip::new(sample_rate);
+    }
+
+    /// Return the sample rate the chip generates audio at.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Write a value to a register. Registers 0x00-0x07 define the user instrument, 0x0E controls
+    /// rhythm mode, and 0x10-0x38 hold the F-number, block, key, instrument and volume of each
+    /// channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg`   - The register to write, in the range 0x00..=0x3F.
+    /// * `value` - The value to write.
+    pub fn write_register(&mut self, reg: u8, value: u8) {
+        if let Some(register) = self.registers.get_mut(reg as usize) {
+            *register = value;
+        }
+    }
+
+    /// Return the value last written to a register.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register to read, in the range 0x00..=0x3F.
+    pub fn register(&self, reg: u8) -> u8 {
+        self.registers.get(reg as usize).copied().unwrap_or(0)
+    }
+
+    /// Return `true` if rhythm mode is enabled (register 0x0E bit 5).
+    pub fn rhythm_mode(&self) -> bool {
+        self.registers[RHYTHM_REGISTER] & 0x20 != 0
+    }
+
+    /// Generate a single sample at the native rate of 49716Hz, without resampling.
+    pub fn generate_native(&mut self) -> i16 {
+        self.clock()
+    }
+
+    /// Generate a stream of 2 channel, interleaved audio samples at the output sample rate. The
+    /// buffer may have any length, and a frame that is cut off at the end of the buffer is
+    /// completed at the start of the next call, as with `Opl3Chip::generate_stream`.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated by the chip on success, or an
+    /// `OplError` on failure. A frame that was started by the previous call is not counted again.
+    pub fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        // The queue holds whole frames, unless the previous call took the left sample of one.
+        let starts_with_left = self.output.len() % 2 == 0;
+        while self.output.len() < buffer.len() {
+            let sample = self.clock();
+            self.resampler.push([sample, sample], &mut self.scratch);
+            self.output.extend(self.scratch.drain(..));
+        }
+        let requested = buffer.len();
+        for (sample, generated) in buffer.iter_mut().zip(self.output.drain(..requested)) {
+            *sample = generated;
+        }
+        Ok((requested + starts_with_left as usize) / 2)
+    }
+
+    /// Return the instrument played by a channel, taking rhythm mode into account.
+    fn patch(&self, channel: usize) -> [u8; 8] {
+        if self.rhythm_mode() && channel >= 6 {
+            return PATCH_ROM[16 + channel - 6];
+        }
+        match self.registers[0x30 + channel] >> 4 {
+            0 => self.registers[..8].try_into().unwrap_or_default(),
+            instrument => PATCH_ROM[instrument as usize],
+        }
+    }
+
+    /// Run the chip for one native sample.
+    fn clock(&mut self) -> i16 {
+        let rhythm = self.rhythm_mode();
+        let rhythm_keys = self.registers[RHYTHM_REGISTER];
+        let tremolo_position = (self.counter >> 6) % 210;
+        let tremolo = if tremolo_position < 105 {
+            tremolo_position
+        } else {
+            209 - tremolo_position
+        } >> 2;
+        let vibrato = VIBRATO[((self.counter >> 10) & 7) as usize];
+
+        // Advance the phase and envelope of every operator, and work out its attenuation.
+        let mut patches = [[0u8; 8]; OPLL_CHANNELS];
+        for (channel, patch) in patches.iter_mut().enumerate() {
+            *patch = self.patch(channel);
+            let control = self.registers[0x20 + channel];
+            let fnum = self.registers[0x10 + channel] as u32 | (control as u32 & 0x01) << 8;
+            let block = (control >> 1) & 0x07;
+            let sustain = control & 0x20 != 0;
+            let key = control & 0x10 != 0;
+            let volume = self.registers[0x30 + channel] & 0x0F;
+
+            // Rhythm mode keys the drums from register 0x0E, and the instrument bits of channels
+            // 7 and 8 become the hi-hat and tom-tom volumes.
+            let rhythm_key = |bit: u8| key || rhythm_keys & bit != 0;
+            let (mod_key, car_key, mod_level) = match channel {
+                6 if rhythm => (rhythm_key(0x10), rhythm_key(0x10), patch[2] & 0x3F),
+                7 if rhythm => (
+                    rhythm_key(0x01),
+                    rhythm_key(0x08),
+                    (self.registers[0x37] >> 4) * 4,
+                ),
+                8 if rhythm => (
+                    rhythm_key(0x04),
+                    rhythm_key(0x02),
+                    (self.registers[0x38] >> 4) * 4,
+                ),
+                _ => (key, key, patch[2] & 0x3F),
+            };
+            let ksl = (KSL_ROM[(fnum >> 5) as usize] << 2) - ((8 - block as i32) << 5);
+            let ksl = ksl.max(0) as u32;
+
+            for (slot, carrier, key, level) in [
+                (channel * 2, false, mod_key, mod_level),
+                (channel * 2 + 1, true, car_key, volume * 4),
+            ] {
+                let params = OperatorPatch::decode(patch, carrier);
+                let op = &mut self.operators[slot];
+                let fnum = if params.vibrato {
+                    (fnum as i32 + (((fnum >> 6) as i32 * vibrato) >> 2)) as u32
+                } else {
+                    fnum
+                };
+                let increment = ((fnum << block) * MULTIPLIERS[params.multiplier as usize]) >> 1;
+                op.phase = op.phase.wrapping_add(increment);
+                op.update_envelope(&params, key, sustain, fnum, block);
+
+                op.attenuation = (op.envelope >> ENVELOPE_FRACTION_BITS)
+                    + ((level as u32) << 2)
+                    + (ksl >> KSL_SHIFT[params.key_scale_level as usize]);
+                if params.tremolo {
+                    op.attenuation += tremolo;
+                }
+                op.attenuation = op.attenuation.min(0x1FF);
+            }
+        }
+
+        // Rhythm phase generation uses bits of the hi-hat and top cymbal phases, as on the OPL2.
+        let hh = self.operators[HI_HAT].phase_out();
+        let tc = self.operators[TOP_CYMBAL].phase_out();
+        let bit = |value: u32, n: u32| (value >> n) & 1;
+        let rm_xor =
+            (bit(hh, 2) ^ bit(hh, 7)) | (bit(hh, 3) ^ bit(tc, 5)) | (bit(tc, 3) ^ bit(tc, 5));
+        let noise = self.noise & 1;
+
+        let mut output = 0i32;
+        for (channel, patch) in patches.iter().enumerate() {
+            let (modulator, carrier) = (channel * 2, channel * 2 + 1);
+            let mod_params = OperatorPatch::decode(patch, false);
+            let car_params = OperatorPatch::decode(patch, true);
+
+            if rhythm && channel >= 7 {
+                // The hi-hat, snare drum, tom-tom and top cymbal each sound on their own, at
+                // double volume.
+                for (slot, params) in [(modulator, mod_params), (carrier, car_params)] {
+                    let phase = match slot {
+                        HI_HAT => (rm_xor << 9) | if rm_xor ^ noise != 0 { 0xD0 } else { 0x34 },
+                        SNARE_DRUM => (bit(hh, 8) << 9) | ((bit(hh, 8) ^ noise) << 8),
+                        TOP_CYMBAL => (rm_xor << 9) | 0x80,
+                        _ => self.operators[TOM_TOM].phase_out(),
+                    };
+                    output += self.operators[slot].render(phase, params.half_sine) as i32 * 2;
+                }
+                continue;
+            }
+
+            let feedback = patch[3] & 0x07;
+            let mod_op = &mut self.operators[modulator];
+            let feedback_phase = match feedback {
+                0 => 0,
+                feedback => (mod_op.out as i32 + mod_op.prev_out as i32) >> (9 - feedback),
+            };
+            let phase = (mod_op.phase_out() as i32 + feedback_phase) as u32;
+            let modulation = mod_op.render(phase, mod_params.half_sine) as i32;
+
+            let car_op = &mut self.operators[carrier];
+            let phase = (car_op.phase_out() as i32 + modulation) as u32;
+            let out = car_op.render(phase, car_params.half_sine) as i32;
+            // The bass drum is also doubled.
+            output += if rhythm && channel == 6 { out * 2 } else { out };
+        }
+
+        let n_bit = ((self.noise >> 14) ^ self.noise) & 1;
+        self.noise = (self.noise >> 1) | (n_bit << 22);
+        self.counter = self.counter.wrapping_add(1);
+        output.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
@@ -0,0 +1,180 @@
+//! Write delay constants and a pacing utility for driving real OPL hardware.
+//!
+//! The OPL chips need time to latch each address and data write. The YM3812 (OPL2) is slow,
+//! needing 12 cycles of its 3.58MHz clock after an address write and 84 cycles after a data
+//! write. The YMF262 (OPL3) needs 32 cycles of its 14.32MHz clock after each. Nuked-OPL3 accepts
+//! writes at any rate, but hardware passthrough devices (such as OPL2LPT or an ISA card behind a
+//! USB bridge) must respect these delays or writes will be lost.
+
+/// The standard YM3812 master clock, in Hz.
+pub const YM3812_CLOCK: u32 = 3_579_545;
+/// The standard YMF262 master clock, in Hz.
+pub const YMF262_CLOCK: u32 = 14_318_180;
+
+/// The number of YM3812 clock cycles to wait after writing the address register.
+pub const OPL2_ADDRESS_DELAY_CYCLES: u32 = 12;
+/// The number of YM3812 clock cycles to wait after writing the data register.
+pub const OPL2_DATA_DELAY_CYCLES: u32 = 84;
+/// The number of YMF262 clock cycles to wait after writing the address register.
+pub const OPL3_ADDRESS_DELAY_CYCLES: u32 = 32;
+/// The number of YMF262 clock cycles to wait after writing the data register.
+pub const OPL3_DATA_DELAY_CYCLES: u32 = 32;
+
+/// The time to wait after writing the YM3812 address register, in microseconds (about 3.4us).
+pub const OPL2_ADDRESS_DELAY_USEC: f64 =
+    OPL2_ADDRESS_DELAY_CYCLES as f64 * 1_000_000.0 / YM3812_CLOCK as f64;
+/// The time to wait after writing the YM3812 data register, in microseconds (about 23.5us).
+pub const OPL2_DATA_DELAY_USEC: f64 =
+    OPL2_DATA_DELAY_CYCLES as f64 * 1_000_000.0 / YM3812_CLOCK as f64;
+/// The time to wait after writing the YMF262 address register, in microseconds (about 2.2us).
+pub const OPL3_ADDRESS_DELAY_USEC: f64 =
+    OPL3_ADDRESS_DELAY_CYCLES as f64 * 1_000_000.0 / YMF262_CLOCK as f64;
+/// The time to wait after writing the YMF262 data register, in microseconds (about 2.2us).
+pub const OPL3_DATA_DELAY_USEC: f64 =
+    OPL3_DATA_DELAY_CYCLES as f64 * 1_000_000.0 / YMF262_CLOCK as f64;
+
+/// The type of chip that writes are being paced for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChipType {
+    /// The YM3812 (OPL2).
+    Opl2,
+    /// The YMF262 (OPL3).
+    Opl3,
+}
+
+impl ChipType {
+    /// Return the chip's master clock, in Hz.
+    pub fn clock(&self) -> u32 {
+        match self {
+            ChipType::Opl2 => YM3812_CLOCK,
+            ChipType::Opl3 => YMF262_CLOCK,
+        }
+    }
+
+    /// Return the number of clock cycles to wait after writing the address register.
+    pub fn address_delay_cycles(&self) -> u32 {
+        match self {
+            ChipType::Opl2 => OPL2_ADDRESS_DELAY_CYCLES,
+            ChipType::Opl3 => OPL3_ADDRESS_DELAY_CYCLES,
+        }
+    }
+
+    /// Return the number of clock cycles to wait after writing the data register.
+    pub fn data_delay_cycles(&self) -> u32 {
+        match self {
+            ChipType::Opl2 => OPL2_DATA_DELAY_CYCLES,
+            ChipType::Opl3 => OPL3_DATA_DELAY_CYCLES,
+        }
+    }
+
+    /// Return the time to wait after writing the address register, in microseconds.
+    pub fn address_delay_usec(&self) -> f64 {
+        match self {
+            ChipType::Opl2 => OPL2_ADDRESS_DELAY_USEC,
+            ChipType::Opl3 => OPL3_ADDRESS_DELAY_USEC,
+        }
+    }
+
+    /// Return the time to wait after writing the data register, in microseconds.
+    pub fn data_delay_usec(&self) -> f64 {
+        match self {
+            ChipType::Opl2 => OPL2_DATA_DELAY_USEC,
+            ChipType::Opl3 => OPL3_DATA_DELAY_USEC,
+        }
+    }
+
+    /// Return the minimum time between the starts of two consecutive register writes, in
+    /// microseconds.
+    pub fn write_interval_usec(&self) -> f64 {
+        self.address_delay_usec() + self.data_delay_usec()
+    }
+}
+
+/// A register write scheduled by a `Pacer`, with the times at which the address and data ports
+/// should be written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PacedWrite {
+    /// The time to write the address port, in microseconds since the pacer was created.
+    pub address_usec: f64,
+    /// The time to write the data port, in microseconds since the pacer was created.
+    pub data_usec: f64,
+    /// The register to write, in the range 0x000..=0x1FF. Addresses 0x100 and above are in the
+    /// secondary register file.
+    pub register: u16,
+    /// The value to write.
+    pub value: u8,
+}
+
+/// The `Pacer` struct spaces a list of register writes so that each one respects the write
+/// delays of the target chip.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::pacing::{ChipType, Pacer, OPL2_ADDRESS_DELAY_USEC};
+///
+/// let mut pacer = Pacer::new(ChipType::Opl2);
+/// let writes = pacer.pace(&[(0x20, 0x01), (0x40, 0x10)]);
+/// assert_eq!(writes[0].address_usec, 0.0);
+/// assert_eq!(writes[0].data_usec, OPL2_ADDRESS_DELAY_USEC);
+/// assert_eq!(writes[1].address_usec, ChipType::Opl2.write_interval_usec());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    chip: ChipType,
+    time_usec: f64,
+}
+
+impl Pacer {
+    /// Create a new `Pacer` for the specified chip, starting at time 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip` - The type of chip that writes will be sent to.
+    pub fn new(chip: ChipType) -> Self {
+        Pacer {
+            chip,
+            time_usec: 0.0,
+        }
+    }
+
+    /// Return the time at which the next write can start, in microseconds.
+    pub fn time_usec(&self) -> f64 {
+        self.time_usec
+    }
+
+    /// Insert a pause before the next write, such as the time between two events in a song.
+    ///
+    /// # Arguments
+    ///
+    /// * `usec` - The length of the pause, in microseconds.
+    pub fn wait_usec(&mut self, usec: f64) {
+        self.time_usec += usec.max(0.0);
+    }
+
+    /// Schedule a list of register writes, each starting as soon as the previous one completes.
+    ///
+    /// # Arguments
+    ///
+    /// * `writes` - A list of (register, value) pairs to write, in order.
+    ///
+    /// # Returns
+    ///
+    /// The scheduled writes.
+    pub fn pace(&mut self, writes: &[(u16, u8)]) -> Vec<PacedWrite> {
+        writes
+            .iter()
+            .map(|&(register, value)| {
+                let address_usec = self.time_usec;
+                let data_usec = address_usec + self.chip.address_delay_usec();
+                self.time_usec = data_usec + self.chip.data_delay_usec();
+                PacedWrite {
+                    address_usec,
+                    data_usec,
+                    register,
+                    value,
+                }
+            })
+            .collect()
+    }
+}
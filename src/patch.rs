@@ -0,0 +1,384 @@
+//! Instrument patches, and rendering single notes of them.
+//!
+//! A `Patch` holds the register values that make up an FM instrument: one `OperatorPair` for a
+//! 2-operator instrument, or two for a 4-operator instrument or a double-voice instrument, which
+//! plays two 2-operator voices at once. Register values are kept as they are written to the chip,
+//! as in the common bank formats, so patches load and play without any conversion.
+//!
+//! `audition` plays one note of a patch on a scratch chip and returns the audio, for bank editors
+//! and for checking instrument conversions.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::patch::{audition, Operator, OperatorPair, Patch};
+//!
+//! let organ = Patch::new(OperatorPair {
+//!     modulator: Operator::new(0x01, 0x10, 0xF0, 0x77, 0x00),
+//!     carrier: Operator::new(0x01, 0x00, 0xF0, 0x77, 0x00),
+//!     feedback_connection: 0x00,
+//! });
+//! let audio = audition(&organ, 60, 100).unwrap();
+//! assert!(audio.iter().any(|&sample| sample != 0));
+//! ```
+
+use crate::opl_core::OplCore;
+use crate::registers::{
+    reg_for_channel, reg_for_operator, register_address, ChannelParam, OperatorParam, MODE_REGISTER,
+};
+use crate::{Opl3Chip, OplError};
+
+/// The sample rate `audition` renders at.
+pub const AUDITION_SAMPLE_RATE: u32 = 44100;
+
+/// The longest `audition` holds a note for, in milliseconds.
+pub const AUDITION_MAX_HOLD_MS: u32 = 60_000;
+
+/// The longest release `audition` renders after the note is released, in milliseconds.
+const AUDITION_MAX_RELEASE_MS: u32 = 5000;
+
+/// The `Operator` struct holds the register values of one operator.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Operator {
+    /// Tremolo, vibrato, sustain, key scale rate and frequency multiplier (register 0x20).
+    pub characteristics: u8,
+    /// Key scale level and output level (register 0x40).
+    pub level: u8,
+    /// Attack and decay rates (register 0x60).
+    pub attack_decay: u8,
+    /// Sustain level and release rate (register 0x80).
+    pub sustain_release: u8,
+    /// Waveform select (register 0xE0).
+    pub waveform: u8,
+}
+
+impl Operator {
+    /// Create a new `Operator` from its register values.
+    ///
+    /// # Arguments
+    ///
+    /// * `characteristics` - The value of register 0x20.
+    /// * `level`           - The value of register 0x40.
+    /// * `attack_decay`    - The value of register 0x60.
+    /// * `sustain_release` - The value of register 0x80.
+    /// * `waveform`        - The value of register 0xE0.
+    pub fn new(
+        characteristics: u8,
+        level: u8,
+        attack_decay: u8,
+        sustain_release: u8,
+        waveform: u8,
+    ) -> Self {
+        Operator {
+            characteristics,
+            level,
+            attack_decay,
+            sustain_release,
+            waveform,
+        }
+    }
+
+    /// Return the register values, with the parameter each is written to.
+    pub fn registers(&self) -> [(OperatorParam, u8); 5] {
+        [
+            (OperatorParam::Characteristics, self.characteristics),
+            (OperatorParam::Level, self.level),
+            (OperatorParam::AttackDecay, self.attack_decay),
+            (OperatorParam::SustainRelease, self.sustain_release),
+            (OperatorParam::Waveform, self.waveform),
+        ]
+    }
+}
+
+/// The `OperatorPair` struct holds the register values of a 2-operator voice.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OperatorPair {
+    /// The first operator, which modulates the carrier in FM connection.
+    pub modulator: Operator,
+    /// The second operator.
+    pub carrier: Operator,
+    /// Feedback and connection (bits 0-3 of register 0xC0). The output select bits are ignored,
+    /// and both outputs are enabled when the pair is written.
+    pub feedback_connection: u8,
+}
+
+impl OperatorPair {
+    /// Write the pair to a channel's operators 0 and 1, with both outputs enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `core`    - The core to write to.
+    /// * `channel` - The channel, from 0 to 17.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure. The error is
+    /// `OplError::RegisterOutOfRange` if the channel is out of range.
+    pub fn write_to(&self, core: &mut dyn OplCore, channel: u8) -> Result<(), OplError> {
+        self.write_operators(core, channel, 0)?;
+        let (file, reg) = reg_for_channel(channel, ChannelParam::FeedbackConnection)
+            .ok_or(OplError::RegisterOutOfRange)?;
+        core.write_register(
+            register_address(file, reg),
+            0x30 | (self.feedback_connection & 0x0F),
+        );
+        Ok(())
+    }
+
+    /// Write the operators to operators `first` and `first + 1` of a channel.
+    fn write_operators(
+        &self,
+        core: &mut dyn OplCore,
+        channel: u8,
+        first: u8,
+    ) -> Result<(), OplError> {
+        for (operator, registers) in [(first, &self.modulator), (first + 1, &self.carrier)] {
+            for (param, value) in registers.registers() {
+                let (file, reg) = reg_for_operator(channel, operator, param)
+                    .ok_or(OplError::RegisterOutOfRange)?;
+                core.write_register(register_address(file, reg), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `PatchKind` enum lists the ways a `Patch` can use its operator pairs.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PatchKind {
+    /// A 2-operator instrument, using only the first pair.
+    #[default]
+    TwoOp,
+    /// A 4-operator instrument. The first pair is written to a channel from 0-2 or 9-11, and the
+    /// second to the channel 3 above it.
+    FourOp,
+    /// Two 2-operator voices played together on separate channels, as used by OP2 and WOPL banks
+    /// to thicken a sound.
+    DoubleVoice,
+}
+
+/// The `Patch` struct is an FM instrument.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Patch {
+    /// The name of the instrument, which may be empty.
+    pub name: String,
+    /// How the operator pairs are used.
+    pub kind: PatchKind,
+    /// The operator pairs. The second is unused by 2-operator instruments.
+    pub pairs: [OperatorPair; 2],
+    /// The number of semitones each pair is transposed by when played.
+    pub note_offsets: [i8; 2],
+    /// A fine detune of the second pair of double-voice instruments, in 64ths of a semitone.
+    pub detune: i8,
+    /// The note always played by the instrument, whatever note is requested, as used by
+    /// percussion instruments.
+    pub fixed_note: Option<u8>,
+}
+
+impl Patch {
+    /// Create a new 2-operator `Patch`, with no name or transposition.
+    ///
+    /// # Arguments
+    ///
+    /// * `pair` - The operator pair of the instrument.
+    pub fn new(pair: OperatorPair) -> Self {
+        Patch {
+            pairs: [pair, OperatorPair::default()],
+            ..Default::default()
+        }
+    }
+
+    /// Return the number of channels the instrument plays on: one for 2-operator instruments, or
+    /// two otherwise.
+    pub fn channels(&self) -> u8 {
+        match self.kind {
+            PatchKind::TwoOp => 1,
+            PatchKind::FourOp | PatchKind::DoubleVoice => 2,
+        }
+    }
+
+    /// Return the pitch one of the pairs plays for a note, with the fixed note, note offset and
+    /// detune applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `pair` - The pair, 0 or 1.
+    /// * `note` - The MIDI note requested.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::patch::Patch;
+    ///
+    /// let patch = Patch {
+    ///     note_offsets: [-12, 0],
+    ///     detune: 16,
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(patch.pitch(0, 60), 48.0);
+    /// assert_eq!(patch.pitch(1, 60), 60.25);
+    /// ```
+    pub fn pitch(&self, pair: usize, note: u8) -> f64 {
+        let note = self.fixed_note.unwrap_or(note) as f64;
+        let detune = if pair == 1 {
+            self.detune as f64 / 64.0
+        } else {
+            0.0
+        };
+        note + self.note_offsets[pair & 1] as f64 + detune
+    }
+
+    /// Write the instrument to a channel. 4-operator instruments are written to the channel and
+    /// the channel 3 above it; 4-operator mode is not enabled for the pair, and must be enabled
+    /// through register 0x104. For double-voice instruments, only the first pair is written, and
+    /// the second should be written to another channel with `OperatorPair::write_to`.
+    ///
+    /// # Arguments
+    ///
+    /// * `core`    - The core to write to.
+    /// * `channel` - The channel, from 0 to 17, or for 4-operator instruments, 0-2 or 9-11.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure. The error is
+    /// `OplError::RegisterOutOfRange` if the channel is out of range.
+    pub fn write_to(&self, core: &mut dyn OplCore, channel: u8) -> Result<(), OplError> {
+        if self.kind != PatchKind::FourOp {
+            return self.pairs[0].write_to(core, channel);
+        }
+        if channel % 9 >= 3 || channel >= 18 {
+            return Err(OplError::RegisterOutOfRange);
+        }
+        self.pairs[0].write_to(core, channel)?;
+        self.pairs[1].write_operators(core, channel, 2)?;
+        let (file, reg) = reg_for_channel(channel + 3, ChannelParam::FeedbackConnection)
+            .ok_or(OplError::RegisterOutOfRange)?;
+        core.write_register(
+            register_address(file, reg),
+            0x30 | (self.pairs[1].feedback_connection & 0x0F),
+        );
+        Ok(())
+    }
+}
+
+/// Return the frequency number and block that play a MIDI note most accurately.
+///
+/// # Arguments
+///
+/// * `note` - The note, where 69 is A4 at 440Hz. Fractional notes are pitch bent.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::patch::note_to_fnum;
+///
+/// assert_eq!(note_to_fnum(69.0), (580, 4));
+/// // An octave up raises the block by one, keeping the frequency number.
+/// assert_eq!(note_to_fnum(81.0), (580, 5));
+/// ```
+pub fn note_to_fnum(note: f64) -> (u16, u8) {
+    hz_to_fnum(440.0 * ((note - 69.0) / 12.0).exp2())
+}
+
+/// Return the frequency number and block that play a frequency in Hz most accurately, with a
+/// multiplier of 1.
+pub(crate) fn hz_to_fnum(frequency: f64) -> (u16, u8) {
+    let native_rate = crate::pacing::YMF262_CLOCK as f64 / 288.0;
+    for block in 0..8u8 {
+        let fnum = (frequency * (1 << (20 - block)) as f64 / native_rate).round();
+        if fnum < 1024.0 {
+            return (fnum.max(0.0) as u16, block);
+        }
+    }
+    (1023, 7)
+}
+
+/// Write the frequency of a note to a channel, and key it on or off.
+pub(crate) fn write_note(core: &mut dyn OplCore, channel: u8, note: f64, key_on: bool) {
+    let (fnum, block) = note_to_fnum(note);
+    let Some((file, reg)) = reg_for_channel(channel, ChannelParam::FnumLow) else {
+        return;
+    };
+    core.write_register(register_address(file, reg), fnum as u8);
+    let value = (key_on as u8) << 5 | (block << 2) | (fnum >> 8) as u8;
+    core.write_register(register_address(file, reg + 0x10), value);
+}
+
+/// Play one note of a patch on a new chip, returning the audio at `AUDITION_SAMPLE_RATE`. The
+/// note is held for `duration_ms` milliseconds, then released, and rendering continues until the
+/// chip falls silent, or for at most 5 seconds.
+///
+/// # Arguments
+///
+/// * `patch`       - The patch to play.
+/// * `note`        - The MIDI note to play, where 69 is A4 at 440Hz. Ignored if the patch has a
+///                   fixed note.
+/// * `duration_ms` - How long to hold the note for, in milliseconds, up to
+///                   `AUDITION_MAX_HOLD_MS`.
+///
+/// # Returns
+///
+/// A Result containing either the audio on success or an `OplError` on failure. The error is
+/// `OplError::DurationTooLong` if the duration is longer than `AUDITION_MAX_HOLD_MS`.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::patch::{audition, AUDITION_SAMPLE_RATE, Operator, OperatorPair, Patch};
+/// use opl3_rs::OplError;
+///
+/// let pluck = Patch::new(OperatorPair {
+///     modulator: Operator::new(0x01, 0x10, 0xF2, 0x74, 0x00),
+///     carrier: Operator::new(0x01, 0x00, 0xF2, 0x34, 0x00),
+///     feedback_connection: 0x06,
+/// });
+/// let audio = audition(&pluck, 48, 200).unwrap();
+/// // The release is rendered after the note, until it has decayed.
+/// let held = (AUDITION_SAMPLE_RATE / 5) as usize * 2;
+/// assert!(audio.len() > held);
+/// assert!(audio[held..].iter().any(|&sample| sample.abs() > 100));
+/// assert!(audio[audio.len() - 64..].iter().all(|sample| sample.abs() <= 1));
+///
+/// assert!(matches!(
+///     audition(&pluck, 48, u32::MAX),
+///     Err(OplError::DurationTooLong(u32::MAX))
+/// ));
+/// ```
+pub fn audition(patch: &Patch, note: u8, duration_ms: u32) -> Result<Vec<i16>, OplError> {
+    if duration_ms > AUDITION_MAX_HOLD_MS {
+        return Err(OplError::DurationTooLong(duration_ms));
+    }
+    let mut chip = Opl3Chip::new(AUDITION_SAMPLE_RATE);
+    chip.write_register(MODE_REGISTER, 0x01);
+    let channels: &[u8] = match patch.kind {
+        PatchKind::TwoOp => &[0],
+        PatchKind::FourOp => {
+            chip.write_register(0x104, 0x01);
+            &[0]
+        }
+        PatchKind::DoubleVoice => &[0, 1],
+    };
+    // Writes to channels 0 and 1 are in range, so these cannot fail.
+    _ = patch.write_to(&mut chip, 0);
+    if patch.kind == PatchKind::DoubleVoice {
+        _ = patch.pairs[1].write_to(&mut chip, 1);
+    }
+
+    for (pair, &channel) in channels.iter().enumerate() {
+        write_note(&mut chip, channel, patch.pitch(pair, note), true);
+    }
+    let mut audio = chip.render((AUDITION_SAMPLE_RATE as u64 * duration_ms as u64 / 1000) as usize);
+    for (pair, &channel) in channels.iter().enumerate() {
+        write_note(&mut chip, channel, patch.pitch(pair, note), false);
+    }
+
+    let block = 512;
+    let max_blocks = AUDITION_SAMPLE_RATE * AUDITION_MAX_RELEASE_MS / 1000 / block;
+    for _ in 0..max_blocks {
+        if chip.is_idle() {
+            break;
+        }
+        audio.extend(chip.render(block as usize));
+    }
+    Ok(audio)
+}
@@ -0,0 +1,211 @@
+//! Playing digitized audio through an FM channel, by rewriting its output level.
+//!
+//! The OPL has no DAC, but demos and games of the DOS era still played samples through it: a
+//! channel is set up to output a constant level, and its total level register is rewritten for
+//! every sample, turning the channel into a crude 6-bit logarithmic DAC. `OplPcmPlayer` sets up
+//! the channel and works out the writes for a recording.
+//!
+//! The channel's carrier plays the square waveform 6 at a frequency number of 0. Keying on resets
+//! the phase to 0, where the square wave is at its positive peak, and with no frequency the phase
+//! never moves, so the operator outputs its envelope level as a constant. The output is unipolar:
+//! samples are played offset by half of full scale, so the audio has a DC offset, which the
+//! high-pass filter of a sound card's output removes. Waveform 6 needs OPL3 mode, which the setup
+//! enables.
+//!
+//! Each sample of the recording is one register write, so a recording at 22kHz needs 22 000
+//! writes per second. That also makes the player a simple load test for hardware backends: feed
+//! them `timed_writes` and listen for the recording.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::calibration::measure_frequency;
+//! use opl3_rs::pcm::OplPcmPlayer;
+//! use opl3_rs::Opl3Chip;
+//!
+//! // A 1kHz square wave, recorded at 8kHz.
+//! let recording: Vec<i16> = (0..4000).map(|i| if i % 8 < 4 { 20000 } else { -20000 }).collect();
+//! let player = OplPcmPlayer::new(0, 8000).unwrap();
+//! let mut chip = Opl3Chip::new(44100);
+//! let audio = player.render(&mut chip, &recording).unwrap();
+//! assert_eq!(audio.len(), 22050 * 2);
+//! let hz = measure_frequency(&audio, 44100, 0).unwrap();
+//! assert!((hz - 1000.0).abs() < 5.0);
+//! ```
+
+use crate::opl_core::OplCore;
+use crate::registers::{
+    reg_for_channel, reg_for_operator, register_address, ChannelParam, OperatorParam, MODE_REGISTER,
+};
+use crate::{Opl3Chip, OplError, TimedWrite};
+
+/// The attenuation of each step of the total level, in dB.
+const LEVEL_STEP_DB: f64 = 0.75;
+
+/// The `OplPcmPlayer` struct plays recordings through one channel of a chip.
+#[derive(Clone, Debug)]
+pub struct OplPcmPlayer {
+    channel: u8,
+    pcm_rate: u32,
+    /// The register holding the carrier's total level.
+    level_register: u16,
+    /// The output of the channel at each total level, as a share of full scale, loudest first.
+    levels: [f64; 64],
+}
+
+impl OplPcmPlayer {
+    /// Create a new `OplPcmPlayer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel`  - The channel to play through, from 0 to 17.
+    /// * `pcm_rate` - The sample rate of the recordings to play.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the new `OplPcmPlayer` on success or an `OplError` on failure.
+    /// The error is `OplError::RegisterOutOfRange` if the channel is out of range, and
+    /// `OplError::InvalidSampleRate` if the sample rate is 0.
+    pub fn new(channel: u8, pcm_rate: u32) -> Result<Self, OplError> {
+        if pcm_rate == 0 {
+            return Err(OplError::InvalidSampleRate(pcm_rate));
+        }
+        let (file, reg) = reg_for_operator(channel, 1, OperatorParam::Level)
+            .ok_or(OplError::RegisterOutOfRange)?;
+        let mut levels = [0.0; 64];
+        for (level, db) in levels
+            .iter_mut()
+            .zip((0..64).map(|tl| tl as f64 * LEVEL_STEP_DB))
+        {
+            *level = 10f64.powf(-db / 20.0);
+        }
+        Ok(OplPcmPlayer {
+            channel,
+            pcm_rate,
+            level_register: register_address(file, reg),
+            levels,
+        })
+    }
+
+    /// Return the channel played through.
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Return the number of register writes a second of audio takes, which is the sample rate
+    /// of the recordings.
+    pub fn writes_per_second(&self) -> u32 {
+        self.pcm_rate
+    }
+
+    /// Return the writes that set up the channel as a DAC, at its quietest until the first sample.
+    pub fn setup_writes(&self) -> Vec<(u16, u8)> {
+        let operator = |operator, param| {
+            reg_for_operator(self.channel, operator, param)
+                .map(|(file, reg)| register_address(file, reg))
+        };
+        let channel = |param| {
+            reg_for_channel(self.channel, param).map(|(file, reg)| register_address(file, reg))
+        };
+        // The modulator is silent and the channel additive, so the carrier is heard alone. The
+        // carrier attacks at once and holds at full level, and the key-on comes last so that it
+        // starts from the settings above.
+        [
+            (Some(MODE_REGISTER), 0x01),
+            (operator(0, OperatorParam::Level), 0x3F),
+            (operator(1, OperatorParam::Characteristics), 0x20),
+            (operator(1, OperatorParam::Level), 0x3F),
+            (operator(1, OperatorParam::AttackDecay), 0xF0),
+            (operator(1, OperatorParam::SustainRelease), 0x00),
+            (operator(1, OperatorParam::Waveform), 0x06),
+            (channel(ChannelParam::FeedbackConnection), 0x31),
+            (channel(ChannelParam::FnumLow), 0x00),
+            (channel(ChannelParam::KeyOnBlock), 0x20),
+        ]
+        .into_iter()
+        .filter_map(|(reg, value)| Some((reg?, value)))
+        .collect()
+    }
+
+    /// Set up the channel as a DAC, at its quietest until the first sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `core` - The core to write to.
+    pub fn setup(&self, core: &mut dyn OplCore) {
+        for (reg, value) in self.setup_writes() {
+            core.write_register(reg, value);
+        }
+    }
+
+    /// Return the total level that plays a sample most closely.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - The sample.
+    pub fn level_for(&self, sample: i16) -> u8 {
+        let target = (sample as f64 + 32768.0) / 65535.0;
+        // The levels fall with the total level, so the closest is next to where the target
+        // would be inserted.
+        let index = self.levels.partition_point(|&level| level > target);
+        let closest = match index {
+            0 => 0,
+            64 => 63,
+            _ if self.levels[index - 1] - target < target - self.levels[index] => index - 1,
+            _ => index,
+        };
+        closest as u8
+    }
+
+    /// Return the total level writes that play a recording, one per sample, at their offsets
+    /// in output samples from the start of the recording.
+    ///
+    /// # Arguments
+    ///
+    /// * `pcm`         - The recording, as mono samples.
+    /// * `sample_rate` - The sample rate of the chip's output.
+    pub fn timed_writes(&self, pcm: &[i16], sample_rate: u32) -> Vec<TimedWrite> {
+        pcm.iter()
+            .enumerate()
+            .map(|(i, &sample)| TimedWrite {
+                offset: (i as u64 * sample_rate as u64 / self.pcm_rate as u64) as usize,
+                register: self.level_register,
+                value: self.level_for(sample),
+            })
+            .collect()
+    }
+
+    /// Return the number of output samples a recording plays for.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples`     - The number of samples in the recording.
+    /// * `sample_rate` - The sample rate of the chip's output.
+    pub fn output_len(&self, samples: usize, sample_rate: u32) -> usize {
+        (samples as u64 * sample_rate as u64).div_ceil(self.pcm_rate as u64) as usize
+    }
+
+    /// Set up the channel and play a recording on a chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip` - The chip to play on.
+    /// * `pcm`  - The recording, as mono samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the audio, as 2 channel interleaved samples at the chip's
+    /// sample rate, on success or an `OplError` on failure.
+    pub fn render(&self, chip: &mut Opl3Chip, pcm: &[i16]) -> Result<Vec<i16>, OplError> {
+        let sample_rate = chip.sample_rate();
+        self.setup(chip);
+        let writes: Vec<(usize, u16, u8)> = self
+            .timed_writes(pcm, sample_rate)
+            .into_iter()
+            .map(|write| (write.offset, write.register, write.value))
+            .collect();
+        let mut audio = vec![0i16; self.output_len(pcm.len(), sample_rate) * 2];
+        chip.write_registers_timed(&writes, &mut audio)?;
+        Ok(audio)
+    }
+}
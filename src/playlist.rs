@@ -0,0 +1,417 @@
+//! A playlist of VGM files, played one after another with a gap or a crossfade.
+//!
+//! `Playlist` owns the tracks of a jukebox and plays them on fresh `vgm_player::VgmPlayer`s,
+//! one per track, so that nothing carries over from one song to the next. A track with a loop
+//! point has its looped part played a set number of times, and a track without one plays to the
+//! end given in its header. Between tracks there is either a gap of silence or a crossfade, in
+//! which the next track fades in over the end of the one before, while the two are mixed.
+//!
+//! Each track has a gain of its own, to even out files recorded at different levels, and a
+//! callback is called with the index of every track as it starts.
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//!
+//! use opl3_rs::event_log::{EventLog, OplEvent};
+//! use opl3_rs::playlist::{Playlist, Transition};
+//!
+//! // Two half second songs.
+//! let song = |f_number: u8| {
+//!     let mut log = EventLog::new(44100);
+//!     for (register, value) in [(0x63, 0xF0), (0xA0, f_number), (0xB0, 0x31)] {
+//!         log.push(OplEvent { time: 0, register, value });
+//!     }
+//!     log.push(OplEvent { time: 22050, register: 0xB0, value: 0x11 });
+//!     log.to_vgm()
+//! };
+//!
+//! let mut playlist = Playlist::new(44100);
+//! playlist.push(&song(0x98), 1.0).unwrap();
+//! playlist.push(&song(0x40), 0.5).unwrap();
+//! playlist.set_transition(Transition::Crossfade { ms: 100 });
+//! let started = Arc::new(Mutex::new(Vec::new()));
+//! let log = started.clone();
+//! playlist.set_on_track_change(move |track| log.lock().unwrap().push(track));
+//!
+//! let mut buffer = vec![0i16; 1024 * 2];
+//! let mut frames = 0;
+//! while !playlist.is_finished() {
+//!     frames += playlist.generate_stream(&mut buffer).unwrap();
+//! }
+//! assert_eq!(*started.lock().unwrap(), vec![0, 1]);
+//! // The second song starts 100ms before the end of the first.
+//! assert_eq!(frames, 22050 * 2 - 4410);
+//! ```
+
+use std::fmt;
+
+use crate::vgm_player::VgmPlayer;
+use crate::OplError;
+
+/// The number of frames rendered at a time.
+const BLOCK_FRAMES: usize = 256;
+
+/// The `Transition` enum selects how a `Playlist` moves from one track to the next.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// Stop each track at its end, and play a number of milliseconds of silence before the next.
+    Gap {
+        /// The length of the silence, in milliseconds. 0 plays the tracks back to back.
+        ms: u32,
+    },
+    /// Start the next track a number of milliseconds before the end of each track, fading the
+    /// end of one out while the start of the other fades in.
+    Crossfade {
+        /// The length of the crossfade, in milliseconds.
+        ms: u32,
+    },
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Transition::Gap { ms: 2000 }
+    }
+}
+
+struct Track {
+    data: Vec<u8>,
+    gain: f32,
+}
+
+/// A track being played.
+struct Voice {
+    index: usize,
+    player: VgmPlayer,
+    /// The frame the track ends at, or `None` to play until the player finishes.
+    end: Option<u64>,
+    gain: f32,
+}
+
+impl Voice {
+    /// Return the number of frames left before the track ends, if its end is known.
+    fn remaining(&self) -> Option<u64> {
+        self.end
+            .map(|end| end.saturating_sub(self.player.position_frames()))
+    }
+
+    fn has_ended(&self) -> bool {
+        self.remaining() == Some(0) || self.end.is_none() && self.player.is_finished()
+    }
+}
+
+/// The `Playlist` struct plays a list of VGM files in order, with transitions between them.
+pub struct Playlist {
+    sample_rate: u32,
+    tracks: Vec<Track>,
+    transition: Transition,
+    loops: u32,
+    repeat: bool,
+    on_track_change: Option<Box<dyn FnMut(usize) + Send>>,
+    /// The index of the track to start next.
+    next: usize,
+    current: Option<Voice>,
+    /// The next track, while it is fading in over the end of the current one.
+    incoming: Option<Voice>,
+    /// The number of frames of silence left before the next track starts.
+    gap: u64,
+    finished: bool,
+    scratch: Vec<i16>,
+    mix: Vec<f32>,
+}
+
+impl Playlist {
+    /// Create a new, empty `Playlist`. Tracks are separated by a gap of 2 seconds, and looped
+    /// parts are played twice, until changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn new(sample_rate: u32) -> Self {
+        Playlist {
+            sample_rate,
+            tracks: Vec::new(),
+            transition: Transition::default(),
+            loops: 2,
+            repeat: false,
+            on_track_change: None,
+            next: 0,
+            current: None,
+            incoming: None,
+            gap: 0,
+            finished: false,
+            scratch: Vec::new(),
+            mix: Vec::new(),
+        }
+    }
+
+    /// Add a track to the end of the playlist.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of an uncompressed VGM file.
+    /// * `gain` - The gain to play the track at, where 1.0 leaves it unchanged.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the index of the track on success or an `OplError` if the
+    /// file could not be played, as returned by `VgmPlayer::new`.
+    pub fn push(&mut self, data: &[u8], gain: f32) -> Result<usize, OplError> {
+        VgmPlayer::new(data, self.sample_rate)?;
+        self.tracks.push(Track {
+            data: data.to_vec(),
+            gain,
+        });
+        self.finished = false;
+        Ok(self.tracks.len() - 1)
+    }
+
+    /// Return the number of tracks.
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Return `true` if the playlist has no tracks.
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// Set the gain of a track, which takes effect the next time the track starts.
+    ///
+    /// # Arguments
+    ///
+    /// * `track` - The index of the track.
+    /// * `gain`  - The gain to play the track at, where 1.0 leaves it unchanged.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if there is no such track.
+    pub fn set_gain(&mut self, track: usize, gain: f32) -> Result<(), OplError> {
+        let track = self
+            .tracks
+            .get_mut(track)
+            .ok_or(OplError::RegisterOutOfRange)?;
+        track.gain = gain;
+        Ok(())
+    }
+
+    /// Set the transition between tracks, which takes effect from the next one.
+    ///
+    /// # Arguments
+    ///
+    /// * `transition` - The transition.
+    pub fn set_transition(&mut self, transition: Transition) {
+        self.transition = transition;
+    }
+
+    /// Set the number of times the looped part of a track with a loop point is played.
+    ///
+    /// # Arguments
+    ///
+    /// * `loops` - The number of times, where 1 plays the track through once.
+    pub fn set_loops(&mut self, loops: u32) {
+        self.loops = loops.max(1);
+    }
+
+    /// Enable or disable repeating. When enabled, the first track follows the last, and the
+    /// playlist never finishes.
+    pub fn set_repeat(&mut self, repeat: bool) {
+        self.repeat = repeat;
+        self.finished &= !repeat;
+    }
+
+    /// Set a callback to call with the index of each track as it starts. During a crossfade,
+    /// it is called when the next track starts to fade in.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - The callback, called from `generate_stream`.
+    pub fn set_on_track_change(&mut self, callback: impl FnMut(usize) + Send + 'static) {
+        self.on_track_change = Some(Box::new(callback));
+    }
+
+    /// Return the index of the track being played, or of the track fading in during a
+    /// crossfade. Returns `None` between tracks and once the playlist has finished.
+    pub fn current_track(&self) -> Option<usize> {
+        self.incoming
+            .as_ref()
+            .or(self.current.as_ref())
+            .map(|voice| voice.index)
+    }
+
+    /// Start playing a track at once, without a transition.
+    ///
+    /// # Arguments
+    ///
+    /// * `track` - The index of the track.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if there is no such track.
+    pub fn skip_to(&mut self, track: usize) -> Result<(), OplError> {
+        if track >= self.tracks.len() {
+            return Err(OplError::RegisterOutOfRange);
+        }
+        self.incoming = None;
+        self.gap = 0;
+        self.finished = false;
+        self.current = Some(self.start(track)?);
+        self.next = track + 1;
+        Ok(())
+    }
+
+    /// Return `true` once the last track has ended, unless repeating.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Generate a stream of 2 channel, interleaved audio samples. Once the playlist has
+    /// finished, silence is generated.
+    ///
+    /// One frame is generated for every two samples in the buffer. If the buffer has an odd
+    /// length, its last sample is left unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated before the playlist finished
+    /// on success, or an `OplError` if a track contains an undefined command.
+    pub fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let frames = buffer.len() / 2;
+        self.mix.clear();
+        self.mix.resize(frames * 2, 0.0);
+        let mut done = 0;
+        while done < frames && !self.finished {
+            if self.gap > 0 {
+                let block = self.gap.min((frames - done) as u64);
+                self.gap -= block;
+                done += block as usize;
+                continue;
+            }
+            let Some(current) = &self.current else {
+                match self.next_track() {
+                    Some(track) => self.current = Some(self.start(track)?),
+                    None => self.finished = true,
+                }
+                continue;
+            };
+
+            let fade = match self.transition {
+                Transition::Crossfade { ms } => ms as u64 * self.sample_rate as u64 / 1000,
+                Transition::Gap { .. } => 0,
+            };
+            let remaining = current.remaining();
+            let mut block = (frames - done).min(BLOCK_FRAMES);
+            if let Some(remaining) = remaining {
+                block = block.min(remaining as usize);
+                if self.incoming.is_none() && remaining > fade {
+                    block = block.min((remaining - fade) as usize);
+                } else if self.incoming.is_none() && fade > 0 {
+                    if let Some(track) = self.next_track() {
+                        self.incoming = Some(self.start(track)?);
+                    }
+                }
+            }
+
+            let out = &mut self.mix[done * 2..(done + block) * 2];
+            let crossfading = self.incoming.is_some();
+            let fade_out = |frame: usize| match remaining {
+                Some(remaining) if crossfading => {
+                    (remaining - frame as u64) as f32 / fade.max(1) as f32
+                }
+                _ => 1.0,
+            };
+            if let Some(current) = &mut self.current {
+                mix_voice(current, &mut self.scratch, out, fade_out)?;
+            }
+            if let Some(incoming) = &mut self.incoming {
+                mix_voice(incoming, &mut self.scratch, out, |frame| {
+                    1.0 - fade_out(frame)
+                })?;
+            }
+            done += block;
+
+            if self.current.as_ref().is_some_and(Voice::has_ended) {
+                self.current = self.incoming.take();
+                let more = self.repeat || self.next < self.tracks.len();
+                if let (None, true, Transition::Gap { ms }) = (&self.current, more, self.transition)
+                {
+                    self.gap = ms as u64 * self.sample_rate as u64 / 1000;
+                }
+            }
+        }
+
+        for (sample, &mixed) in buffer.iter_mut().zip(&self.mix) {
+            *sample = mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+        Ok(done)
+    }
+
+    /// Return the index of the track to play next, and move on from it, or `None` if the
+    /// playlist has run out.
+    fn next_track(&mut self) -> Option<usize> {
+        if self.next >= self.tracks.len() && self.repeat {
+            self.next = 0;
+        }
+        let track = (self.next < self.tracks.len()).then_some(self.next)?;
+        self.next += 1;
+        Some(track)
+    }
+
+    /// Create the player for a track, and call the track change callback.
+    fn start(&mut self, index: usize) -> Result<Voice, OplError> {
+        let track = &self.tracks[index];
+        let mut player = VgmPlayer::new(&track.data, self.sample_rate)?;
+        player.set_looping(true);
+        let end = player.length_frames(self.loops);
+        if let Some(callback) = &mut self.on_track_change {
+            callback(index);
+        }
+        Ok(Voice {
+            index,
+            player,
+            end,
+            gain: track.gain,
+        })
+    }
+}
+
+/// Render a block from a track and add it to the mix, with a gain for each frame on top of the
+/// track's own.
+fn mix_voice(
+    voice: &mut Voice,
+    scratch: &mut Vec<i16>,
+    out: &mut [f32],
+    gain: impl Fn(usize) -> f32,
+) -> Result<(), OplError> {
+    scratch.resize(out.len(), 0);
+    voice.player.generate_stream(scratch)?;
+    for (i, (out, frame)) in out
+        .chunks_exact_mut(2)
+        .zip(scratch.chunks_exact(2))
+        .enumerate()
+    {
+        let gain = voice.gain * gain(i);
+        out[0] += frame[0] as f32 * gain;
+        out[1] += frame[1] as f32 * gain;
+    }
+    Ok(())
+}
+
+impl fmt::Debug for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Playlist")
+            .field("sample_rate", &self.sample_rate)
+            .field("tracks", &self.tracks.len())
+            .field("transition", &self.transition)
+            .field("loops", &self.loops)
+            .field("repeat", &self.repeat)
+            .field("current_track", &self.current_track())
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
@@ -0,0 +1,195 @@
+//! A fixed-capacity, allocation-free queue of timestamped register writes.
+//!
+//! `CommandQueue` is a single-producer, single-consumer ring buffer for passing register writes
+//! from an interrupt handler or RTOS task to the task that renders audio. Neither side ever
+//! blocks or allocates, so it is safe to push from an interrupt handler. Each write carries a
+//! timestamp, in whatever unit the application uses, and the renderer applies writes as its own
+//! clock passes them.
+//!
+//! A queue is split into a `Producer` and a `Consumer`, which can be moved to different contexts.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::event_log::OplEvent;
+//! use opl3_rs::queue::CommandQueue;
+//!
+//! let mut queue: CommandQueue<16> = CommandQueue::new();
+//! let (mut producer, mut consumer) = queue.split();
+//!
+//! // In the interrupt handler:
+//! producer.push(OplEvent { time: 10, register: 0x20, value: 0x01 }).unwrap();
+//! producer.push(OplEvent { time: 20, register: 0xB0, value: 0x32 }).unwrap();
+//!
+//! // In the render task, with a sample clock at 15:
+//! let mut chip = Opl3Chip::new(44100);
+//! assert_eq!(consumer.apply_until(&mut chip, 15), 1);
+//! assert_eq!(consumer.len(), 1);
+//! ```
+
+#![cfg_attr(feature = "fixed-point", deny(clippy::float_arithmetic))]
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::event_log::OplEvent;
+use crate::Opl3Chip;
+
+const EMPTY_EVENT: OplEvent = OplEvent {
+    time: 0,
+    register: 0,
+    value: 0,
+};
+
+/// The `CommandQueue` struct is a ring buffer holding up to `N` timestamped register writes.
+pub struct CommandQueue<const N: usize> {
+    slots: [UnsafeCell<OplEvent>; N],
+    // Both indices increase without bound, wrapping on overflow. A slot's index is the position
+    // modulo `N`, and the queue holds `tail - head` events.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Safety: the producer only writes slots outside `head..tail` and the consumer only reads slots
+// inside it, and the indices are published with release/acquire ordering.
+unsafe impl<const N: usize> Sync for CommandQueue<N> {}
+
+impl<const N: usize> Default for CommandQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> CommandQueue<N> {
+    /// Create a new, empty `CommandQueue`. This is a `const fn`, so a queue can be placed in a
+    /// `static`.
+    pub const fn new() -> Self {
+        CommandQueue {
+            slots: [const { UnsafeCell::new(EMPTY_EVENT) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the number of writes the queue can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Split the queue into its producer and consumer halves.
+    pub fn split(&mut self) -> (Producer<'_, N>, Consumer<'_, N>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+
+    fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+}
+
+/// The `Producer` struct is the writing half of a `CommandQueue`.
+pub struct Producer<'a, const N: usize> {
+    queue: &'a CommandQueue<N>,
+}
+
+impl<const N: usize> Producer<'_, N> {
+    /// Append a register write to the queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The register write and its timestamp. Timestamps should not decrease from one
+    ///             write to the next.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success, or the write itself if the queue is full.
+    pub fn push(&mut self, event: OplEvent) -> Result<(), OplEvent> {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let head = self.queue.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= N {
+            return Err(event);
+        }
+        // Safety: the slot is outside `head..tail`, so the consumer is not reading it.
+        unsafe {
+            *self.queue.slots[tail % N].get() = event;
+        }
+        self.queue
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Return `true` if the queue is full.
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= N
+    }
+}
+
+/// The `Consumer` struct is the reading half of a `CommandQueue`.
+pub struct Consumer<'a, const N: usize> {
+    queue: &'a CommandQueue<N>,
+}
+
+impl<const N: usize> Consumer<'_, N> {
+    /// Return the oldest write in the queue without removing it.
+    pub fn peek(&self) -> Option<OplEvent> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        if head == self.queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // Safety: the slot is inside `head..tail`, so the producer is not writing it.
+        Some(unsafe { *self.queue.slots[head % N].get() })
+    }
+
+    /// Remove and return the oldest write in the queue.
+    pub fn pop(&mut self) -> Option<OplEvent> {
+        let event = self.peek()?;
+        let head = self.queue.head.load(Ordering::Relaxed);
+        self.queue
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(event)
+    }
+
+    /// Remove and return the oldest write in the queue, if its timestamp is at or before `time`.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The current time of the renderer.
+    pub fn pop_until(&mut self, time: u64) -> Option<OplEvent> {
+        match self.peek() {
+            Some(event) if event.time <= time => self.pop(),
+            _ => None,
+        }
+    }
+
+    /// Write every queued register write with a timestamp at or before `time` to a chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip` - The chip to write to.
+    /// * `time` - The current time of the renderer.
+    ///
+    /// # Returns
+    ///
+    /// The number of writes applied.
+    pub fn apply_until(&mut self, chip: &mut Opl3Chip, time: u64) -> usize {
+        let mut applied = 0;
+        while let Some(event) = self.pop_until(time) {
+            chip.write_register(event.register, event.value);
+            applied += 1;
+        }
+        applied
+    }
+
+    /// Return the number of writes in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Return `true` if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
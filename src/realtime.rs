@@ -0,0 +1,141 @@
+//! Checking that code running on a real-time audio thread does not allocate.
+//!
+//! An audio callback that allocates can miss its deadline while the allocator takes a lock or
+//! asks the operating system for memory. Once set up, the hot paths of this crate never
+//! allocate: generating audio and writing registers on an `Opl3Chip`, buffered writes, pushing
+//! to and applying a `queue::CommandQueue`, and note, controller and generation calls on a
+//! `midi_synth::OplMidiSynth`. A few buffers grow on first use, and can be allocated up front:
+//!
+//! * `Opl3Device::with_capacity` allocates the buffer returned by `Opl3Device::generate_frame`
+//!   and the list of strict mode warnings.
+//! * `OplMidiSynth::reserve` allocates the buffer the synth mixes its chips in.
+//!
+//! To check this in an application, install `CheckedAllocator` as the global allocator, and run
+//! the real-time code inside `assert_no_alloc`, which panics in debug builds if the code calls
+//! the allocator on the current thread. `count_allocations` returns the number of calls instead.
+//! Without `CheckedAllocator` installed, no calls are seen.
+//!
+//! # Example
+//!
+//! ```
+//! use std::alloc::System;
+//!
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::realtime::{assert_no_alloc, count_allocations, CheckedAllocator};
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: CheckedAllocator<System> = CheckedAllocator::new(System);
+//!
+//! fn main() {
+//!     let mut chip = Opl3Chip::new(44100);
+//!     let mut buffer = vec![0i16; 512 * 2];
+//!     assert_no_alloc(|| {
+//!         chip.write_register(0xA0, 0x98);
+//!         chip.write_register_buffered(0xB0, 0x31);
+//!         chip.generate_stream(&mut buffer).unwrap();
+//!     });
+//!
+//!     let (_, calls) = count_allocations(|| vec![0u8; 16]);
+//!     assert_eq!(calls, 1);
+//! }
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+
+thread_local! {
+    /// The number of `count_allocations` calls running on this thread.
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+    /// The number of allocator calls made on this thread while `DEPTH` was above 0.
+    static CALLS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The `CheckedAllocator` struct wraps a global allocator, counting the calls made to it inside
+/// `count_allocations` and `assert_no_alloc`. Allocating, reallocating and freeing all count.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CheckedAllocator<A> {
+    inner: A,
+}
+
+impl<A> CheckedAllocator<A> {
+    /// Wrap an allocator. This is a `const fn`, so that it can initialize the
+    /// `#[global_allocator]` static.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The allocator to pass calls on to, usually `std::alloc::System`.
+    pub const fn new(inner: A) -> Self {
+        CheckedAllocator { inner }
+    }
+}
+
+/// Count an allocator call, if a check is running on this thread. The thread locals have no
+/// destructors, but may still be gone while the thread exits, in which case nothing is counted.
+fn record_call() {
+    _ = DEPTH.try_with(|depth| {
+        if depth.get() > 0 {
+            _ = CALLS.try_with(|calls| calls.set(calls.get() + 1));
+        }
+    });
+}
+
+// Safety: every call is passed on to the inner allocator unchanged.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CheckedAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record_call();
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record_call();
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record_call();
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record_call();
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Run a closure, counting the allocator calls it makes on the current thread. Calls are only
+/// seen when `CheckedAllocator` is the global allocator.
+///
+/// # Arguments
+///
+/// * `f` - The closure to run.
+///
+/// # Returns
+///
+/// A tuple of the closure's result and the number of allocator calls it made.
+pub fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, u64) {
+    let before = CALLS.with(Cell::get);
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let result = f();
+    DEPTH.with(|depth| depth.set(depth.get() - 1));
+    (result, CALLS.with(Cell::get) - before)
+}
+
+/// Run a closure that must not allocate. In debug builds, this panics after the closure returns
+/// if it called the allocator on the current thread. Calls are only seen when `CheckedAllocator`
+/// is the global allocator.
+///
+/// # Arguments
+///
+/// * `f` - The closure to run.
+///
+/// # Returns
+///
+/// The closure's result.
+pub fn assert_no_alloc<R>(f: impl FnOnce() -> R) -> R {
+    let (result, calls) = count_allocations(f);
+    debug_assert!(
+        calls == 0,
+        "{calls} allocator calls in code that must not allocate"
+    );
+    result
+}
@@ -0,0 +1,179 @@
+//! Dropping register writes that would not change anything.
+//!
+//! Players and drivers often rewrite every register of an instrument on each note, even when
+//! most of them already hold the right value. `RegisterCache` wraps any `OplCore` and keeps a
+//! shadow copy of the registers it has written, passing on only the writes that change a value.
+//! This cuts the traffic to real hardware driven over a slow link, and wrapping a recorder such
+//! as `session::SessionRecorder` gives logs without the redundant writes.
+//!
+//! Some writes matter even when the value is unchanged: drivers rewrite the key-on registers to
+//! mark note boundaries in logs, and writing the timer control register restarts or acknowledges
+//! the timers. These are always passed on by default, and the set of exceptions can be changed
+//! with `RegisterCache::set_always_write`.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::opl_core::OplCore;
+//! use opl3_rs::register_cache::RegisterCache;
+//!
+//! let mut chip = RegisterCache::new(Opl3Chip::new(44100));
+//! for _ in 0..4 {
+//!     chip.write_register(0x20, 0x01);
+//!     chip.write_register(0xA0, 0x98);
+//!     chip.write_register(0xB0, 0x31);
+//! }
+//! // The key-on register is always written, the others only the first time.
+//! assert_eq!(chip.passed_writes(), 6);
+//! assert_eq!(chip.dropped_writes(), 6);
+//! ```
+
+use crate::opl_core::OplCore;
+use crate::OplError;
+
+/// The number of register addresses tracked by `RegisterCache`. Writes to higher addresses are
+/// always passed on.
+const CACHED_REGISTERS: usize = 0x200;
+
+/// The `RegisterCache` struct wraps an `OplCore`, dropping writes of the value a register already
+/// holds.
+#[derive(Clone, Debug)]
+pub struct RegisterCache<C: OplCore> {
+    core: C,
+    shadow: [Option<u8>; CACHED_REGISTERS],
+    always_write: [bool; CACHED_REGISTERS],
+    passed: u64,
+    dropped: u64,
+}
+
+impl<C: OplCore> RegisterCache<C> {
+    /// Wrap a core. Nothing is assumed about the registers' values, so the first write to each
+    /// register is always passed on.
+    ///
+    /// The timer registers 0x02-0x04, the key-on registers 0xB0-0xB8 and 0x1B0-0x1B8, and the
+    /// rhythm register 0xBD are always written.
+    ///
+    /// # Arguments
+    ///
+    /// * `core` - The core to wrap.
+    pub fn new(core: C) -> Self {
+        let mut cache = RegisterCache {
+            core,
+            shadow: [None; CACHED_REGISTERS],
+            always_write: [false; CACHED_REGISTERS],
+            passed: 0,
+            dropped: 0,
+        };
+        for reg in (0x02..=0x04).chain(0xB0..=0xB8).chain(0x1B0..=0x1B8) {
+            cache.always_write[reg] = true;
+        }
+        cache.always_write[0xBD] = true;
+        cache
+    }
+
+    /// Set whether writes to a register are always passed on, even when the value is unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg`     - The register, from 0x000 to 0x1FF.
+    /// * `enabled` - Whether to always pass writes on.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure. The error is
+    /// `OplError::RegisterOutOfRange` if the register is above 0x1FF.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    /// use opl3_rs::opl_core::OplCore;
+    /// use opl3_rs::register_cache::RegisterCache;
+    ///
+    /// let mut chip = RegisterCache::new(Opl3Chip::new(44100));
+    /// chip.set_always_write(0xB0, false).unwrap();
+    /// chip.write_register(0xB0, 0x31);
+    /// chip.write_register(0xB0, 0x31);
+    /// assert_eq!(chip.dropped_writes(), 1);
+    /// ```
+    pub fn set_always_write(&mut self, reg: u16, enabled: bool) -> Result<(), OplError> {
+        let flag = self
+            .always_write
+            .get_mut(reg as usize)
+            .ok_or(OplError::RegisterOutOfRange)?;
+        *flag = enabled;
+        Ok(())
+    }
+
+    /// Return the value last passed on to a register, or `None` if it has not been written since
+    /// the cache was created, reset or invalidated.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register to read.
+    pub fn cached(&self, reg: u16) -> Option<u8> {
+        self.shadow.get(reg as usize).copied().flatten()
+    }
+
+    /// Forget every cached value, so that the next write to each register is passed on. Call this
+    /// when the registers may have been changed behind the cache's back, such as after another
+    /// program used the hardware.
+    pub fn invalidate(&mut self) {
+        self.shadow = [None; CACHED_REGISTERS];
+    }
+
+    /// Return the number of writes passed on to the core.
+    pub fn passed_writes(&self) -> u64 {
+        self.passed
+    }
+
+    /// Return the number of writes dropped because they would not change a register.
+    pub fn dropped_writes(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Return the wrapped core.
+    pub fn core(&self) -> &C {
+        &self.core
+    }
+
+    /// Return the wrapped core, for calling methods outside of `OplCore`. Writes made directly
+    /// to the core are not seen by the cache; call `invalidate` afterwards if needed.
+    pub fn core_mut(&mut self) -> &mut C {
+        &mut self.core
+    }
+
+    /// Remove the cache, returning the core.
+    pub fn into_inner(self) -> C {
+        self.core
+    }
+}
+
+/// Resetting the core also invalidates the cache.
+impl<C: OplCore> OplCore for RegisterCache<C> {
+    fn sample_rate(&self) -> u32 {
+        self.core.sample_rate()
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        self.core.reset(sample_rate);
+        self.invalidate();
+    }
+
+    fn write_register(&mut self, reg: u16, value: u8) {
+        if let Some(cached) = self.shadow.get_mut(reg as usize) {
+            if *cached == Some(value) && !self.always_write[reg as usize] {
+                self.dropped += 1;
+                return;
+            }
+            *cached = Some(value);
+        }
+        self.passed += 1;
+        self.core.write_register(reg, value);
+    }
+
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        self.core.generate_stream(buffer)
+    }
+}
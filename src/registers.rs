@@ -0,0 +1,329 @@
+//! Helpers for mapping channel and operator coordinates to register addresses and back.
+//!
+//! The OPL3 has 18 channels of 2 operators each, split across two register files of 9 channels.
+//! Within a register file, the operator registers of a channel are not at the channel's index:
+//! the modulator of channel `n` is at offset `n % 3 + n / 3 * 8`, and its carrier 3 registers
+//! later. In 4-operator mode, channels 0-2 and 9-11 are paired with the channel 3 above them,
+//! whose operators become operators 2 and 3 of the pair.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::OplRegisterFile;
+//! use opl3_rs::registers::{reg_for_operator, operator_for_reg, OperatorParam};
+//!
+//! // The carrier of channel 10 is in the secondary register file.
+//! let (file, reg) = reg_for_operator(10, 1, OperatorParam::AttackDecay).unwrap();
+//! assert_eq!((file, reg), (OplRegisterFile::Secondary, 0x64));
+//! assert_eq!(operator_for_reg(file, reg), Some((10, 1, OperatorParam::AttackDecay)));
+//! ```
+
+use crate::OplRegisterFile;
+
+/// The `OperatorParam` enum lists the per-operator register groups. Each value is the base
+/// address of its group.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OperatorParam {
+    /// Tremolo, vibrato, sustain, key scale rate and frequency multiplier (0x20-0x35).
+    Characteristics = 0x20,
+    /// Key scale level and output level (0x40-0x55).
+    Level = 0x40,
+    /// Attack and decay rates (0x60-0x75).
+    AttackDecay = 0x60,
+    /// Sustain level and release rate (0x80-0x95).
+    SustainRelease = 0x80,
+    /// Waveform select (0xE0-0xF5).
+    Waveform = 0xE0,
+}
+
+const OPERATOR_PARAMS: [OperatorParam; 5] = [
+    OperatorParam::Characteristics,
+    OperatorParam::Level,
+    OperatorParam::AttackDecay,
+    OperatorParam::SustainRelease,
+    OperatorParam::Waveform,
+];
+
+/// The `ChannelParam` enum lists the per-channel register groups. Each value is the base address
+/// of its group.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChannelParam {
+    /// The low 8 bits of the frequency number (0xA0-0xA8).
+    FnumLow = 0xA0,
+    /// Key-on, block and the high 2 bits of the frequency number (0xB0-0xB8).
+    KeyOnBlock = 0xB0,
+    /// Output channels, feedback and connection (0xC0-0xC8).
+    FeedbackConnection = 0xC0,
+}
+
+const CHANNEL_PARAMS: [ChannelParam; 3] = [
+    ChannelParam::FnumLow,
+    ChannelParam::KeyOnBlock,
+    ChannelParam::FeedbackConnection,
+];
+
+/// Return the register file and index of a channel. Channels 0-8 are in the primary register
+/// file and 9-17 in the secondary register file.
+fn channel_file(channel: u8) -> (OplRegisterFile, u8) {
+    if channel < 9 {
+        (OplRegisterFile::Primary, channel)
+    } else {
+        (OplRegisterFile::Secondary, channel - 9)
+    }
+}
+
+/// Return the register holding one of an operator's parameters.
+///
+/// # Arguments
+///
+/// * `channel`  - The channel, from 0 to 17.
+/// * `operator` - The operator within the channel. Operators 0 and 1 are the channel's own
+///                modulator and carrier. Operators 2 and 3 are those of its 4-operator partner,
+///                and are only valid on channels 0-2 and 9-11.
+/// * `param`    - The parameter to address.
+///
+/// # Returns
+///
+/// The register file and register index, or `None` if the channel or operator is out of range.
+pub fn reg_for_operator(
+    channel: u8,
+    operator: u8,
+    param: OperatorParam,
+) -> Option<(OplRegisterFile, u8)> {
+    if channel >= 18 {
+        return None;
+    }
+    let (file, index) = channel_file(channel);
+    let index = match operator {
+        0 | 1 => index,
+        2 | 3 if index < 3 => index + 3,
+        _ => return None,
+    };
+    let offset = index % 3 + index / 3 * 8 + (operator & 1) * 3;
+    Some((file, param as u8 + offset))
+}
+
+/// Return the register holding one of a channel's parameters.
+///
+/// # Arguments
+///
+/// * `channel` - The channel, from 0 to 17.
+/// * `param`   - The parameter to address.
+///
+/// # Returns
+///
+/// The register file and register index, or `None` if the channel is out of range.
+pub fn reg_for_channel(channel: u8, param: ChannelParam) -> Option<(OplRegisterFile, u8)> {
+    if channel >= 18 {
+        return None;
+    }
+    let (file, index) = channel_file(channel);
+    Some((file, param as u8 + index))
+}
+
+/// Return the operator whose parameter a register holds. This is the inverse of
+/// `reg_for_operator`, always returning the 2-operator coordinates.
+///
+/// # Arguments
+///
+/// * `file` - The register file of the register.
+/// * `reg`  - The register index.
+///
+/// # Returns
+///
+/// The channel, operator (0 or 1) and parameter, or `None` if the register is not an operator
+/// register.
+pub fn operator_for_reg(file: OplRegisterFile, reg: u8) -> Option<(u8, u8, OperatorParam)> {
+    let param = OPERATOR_PARAMS
+        .into_iter()
+        .find(|&param| (param as u8..param as u8 + 0x16).contains(&reg))?;
+    let offset = reg - param as u8;
+    // Offsets 6, 7, 14 and 15 within each group are unused.
+    if offset % 8 >= 6 {
+        return None;
+    }
+    let index = offset / 8 * 3 + offset % 8 % 3;
+    let operator = offset % 8 / 3;
+    Some((file_base(file) + index, operator, param))
+}
+
+/// Return the channel whose parameter a register holds. This is the inverse of
+/// `reg_for_channel`.
+///
+/// # Arguments
+///
+/// * `file` - The register file of the register.
+/// * `reg`  - The register index.
+///
+/// # Returns
+///
+/// The channel and parameter, or `None` if the register is not a channel register.
+pub fn channel_for_reg(file: OplRegisterFile, reg: u8) -> Option<(u8, ChannelParam)> {
+    let param = CHANNEL_PARAMS
+        .into_iter()
+        .find(|&param| (param as u8..param as u8 + 9).contains(&reg))?;
+    Some((file_base(file) + reg - param as u8, param))
+}
+
+/// Return the 9-bit register address used by `Opl3Chip::write_register` for a register in a
+/// register file.
+///
+/// # Arguments
+///
+/// * `file` - The register file of the register.
+/// * `reg`  - The register index.
+pub fn register_address(file: OplRegisterFile, reg: u8) -> u16 {
+    match file {
+        OplRegisterFile::Primary => reg as u16,
+        OplRegisterFile::Secondary => reg as u16 | 0x100,
+    }
+}
+
+/// The address of the mode register, which enables OPL3 mode.
+pub const MODE_REGISTER: u16 = 0x105;
+
+/// The `ModeFlags` struct describes the bits of the mode register, 0x105.
+///
+/// Only the NEW bit has any effect in this core. The NEW2 bit is defined by the YMF278 (OPL4),
+/// where it gives access to the wavetable section, and the YMF262 ignores it. Nuked-OPL3 ignores
+/// it too: writing it does not change the output, and the FM section behaves exactly as with
+/// NEW alone. Users emulating the FM block of a YMF278 should decode NEW2 themselves before
+/// passing the write on. The remaining bits are unused.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::Opl3Chip;
+/// use opl3_rs::registers::{ModeFlags, MODE_REGISTER};
+///
+/// let render = |flags: ModeFlags| {
+///     let mut chip = Opl3Chip::new(44100);
+///     chip.write_register(MODE_REGISTER, flags.value());
+///     // A note on channel 9, in the secondary register file.
+///     chip.write_register(0x120, 0x01);
+///     chip.write_register(0x123, 0x01);
+///     chip.write_register(0x143, 0x00);
+///     chip.write_register(0x163, 0xF0);
+///     chip.write_register(0x1A0, 0x98);
+///     chip.write_register(0x1B0, 0x31);
+///     chip.render(1024)
+/// };
+///
+/// let opl3 = render(ModeFlags { new: true, new2: false });
+/// let opl4 = render(ModeFlags { new: true, new2: true });
+/// assert_eq!(opl3, opl4);
+/// assert!(opl3.iter().any(|&sample| sample != 0));
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModeFlags {
+    /// Enables OPL3 mode (bit 0): the secondary register file, 4-operator channels, the extra
+    /// waveforms and the output select bits of registers 0xC0-0xC8. With it clear, the chip
+    /// behaves as an OPL2.
+    pub new: bool,
+    /// The OPL4 NEW2 bit (bit 1). It has no effect.
+    pub new2: bool,
+}
+
+impl ModeFlags {
+    /// Decode a value written to the mode register. Unused bits are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value of register 0x105.
+    pub fn from_value(value: u8) -> Self {
+        ModeFlags {
+            new: value & 0x01 != 0,
+            new2: value & 0x02 != 0,
+        }
+    }
+
+    /// Return the value to write to the mode register.
+    pub fn value(&self) -> u8 {
+        self.new as u8 | (self.new2 as u8) << 1
+    }
+}
+
+fn file_base(file: OplRegisterFile) -> u8 {
+    match file {
+        OplRegisterFile::Primary => 0,
+        OplRegisterFile::Secondary => 9,
+    }
+}
+
+/// The `Drum` enum lists the drums of rhythm mode. While rhythm mode is enabled, the operators
+/// of channels 6 to 8 belong to the drums, which are keyed on by the bits of register 0xBD
+/// rather than by the channels' own key-on bits.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::registers::Drum;
+///
+/// assert_eq!(Drum::for_operator(7, 1), Some(Drum::Snare));
+/// assert_eq!(Drum::BassDrum.operators(), &[(6, 0), (6, 1)]);
+/// assert_eq!(Drum::Snare.key_bit(), 0x08);
+/// // Channel 5 is not part of rhythm mode.
+/// assert_eq!(Drum::for_operator(5, 0), None);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Drum {
+    /// The bass drum, played by both operators of channel 6 as a normal FM voice.
+    BassDrum,
+    /// The hi-hat, played by the modulator of channel 7.
+    HiHat,
+    /// The snare drum, played by the carrier of channel 7.
+    Snare,
+    /// The tom-tom, played by the modulator of channel 8.
+    TomTom,
+    /// The top cymbal, played by the carrier of channel 8.
+    Cymbal,
+}
+
+impl Drum {
+    /// Every drum, in operator order.
+    pub const ALL: [Drum; 5] = [
+        Drum::BassDrum,
+        Drum::HiHat,
+        Drum::Snare,
+        Drum::TomTom,
+        Drum::Cymbal,
+    ];
+
+    /// Return the operators the drum is played by, as channel and operator pairs.
+    pub fn operators(self) -> &'static [(u8, u8)] {
+        match self {
+            Drum::BassDrum => &[(6, 0), (6, 1)],
+            Drum::HiHat => &[(7, 0)],
+            Drum::Snare => &[(7, 1)],
+            Drum::TomTom => &[(8, 0)],
+            Drum::Cymbal => &[(8, 1)],
+        }
+    }
+
+    /// Return the bit of register 0xBD that keys the drum on.
+    pub fn key_bit(self) -> u8 {
+        match self {
+            Drum::BassDrum => 0x10,
+            Drum::Snare => 0x08,
+            Drum::TomTom => 0x04,
+            Drum::Cymbal => 0x02,
+            Drum::HiHat => 0x01,
+        }
+    }
+
+    /// Return the drum that owns an operator in rhythm mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel`  - The channel, from 0 to 17.
+    /// * `operator` - The operator within the channel, 0 or 1.
+    ///
+    /// # Returns
+    ///
+    /// The drum, or `None` if the operator is not part of rhythm mode.
+    pub fn for_operator(channel: u8, operator: u8) -> Option<Drum> {
+        Drum::ALL
+            .into_iter()
+            .find(|drum| drum.operators().contains(&(channel, operator)))
+    }
+}
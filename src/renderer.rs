@@ -0,0 +1,363 @@
+//! A dedicated rendering thread that keeps emulated audio in sync with the audio device.
+//!
+//! An emulator produces register writes on its own clock, while the audio device consumes samples
+//! on another. The two never run at exactly the same speed, so a buffer between them slowly
+//! fills up or drains until audio lags or drops out. `RendererThread` owns a chip on a thread of
+//! its own, renders it up to the emulated time of each write, and resamples its output by up to
+//! ±0.5% to keep the buffer near a target fill level. At that scale the pitch change is
+//! inaudible.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::renderer::RendererThread;
+//!
+//! let renderer = RendererThread::spawn(48000, 1024);
+//!
+//! // On the emulation thread, with times in microseconds of emulated time:
+//! renderer.write(0, 0x20, 0x01).unwrap();
+//! renderer.write(10, 0xB0, 0x32).unwrap();
+//! renderer.advance_to(100_000).unwrap();
+//!
+//! // In the audio callback:
+//! let mut buffer = [0i16; 256 * 2];
+//! let frames = renderer.read(&mut buffer);
+//! assert!(frames <= 256);
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::event_log::OplEvent;
+use crate::multirate::{Resampler, NATIVE_SAMPLE_RATE};
+use crate::time_source::{TimeSource, WallClock};
+use crate::{Opl3Chip, OplError};
+
+/// The largest adjustment made to the output rate, as a fraction of the nominal rate.
+pub const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+enum RenderCommand {
+    Write(OplEvent),
+    AdvanceTo(u64),
+}
+
+/// The `Telemetry` struct reports what a `RendererThread` has been doing, to help tell audio
+/// glitches caused by the renderer apart from those caused by the code around it. It is returned
+/// by `RendererThread::telemetry`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Telemetry {
+    /// The number of stereo frames rendered since the thread started.
+    pub frames_generated: u64,
+    /// The number of calls to `read` that could not be filled completely from rendered audio.
+    pub underruns: u64,
+    /// The total number of frames filled with silence by underrunning reads.
+    pub underrun_frames: u64,
+    /// The number of blocks rendered. A block is rendered for every write or `advance_to` call
+    /// that moves emulated time forward.
+    pub blocks_rendered: u64,
+    /// The average time taken to render a block.
+    pub average_render_time: Duration,
+    /// The longest time taken to render a block.
+    pub max_render_time: Duration,
+    /// The number of writes and `advance_to` calls waiting for the thread to process them.
+    pub pending_commands: usize,
+    /// The number of rendered frames waiting to be read.
+    pub buffered_frames: usize,
+    /// The current adjustment to the output rate.
+    pub rate_ratio: f64,
+}
+
+struct Output {
+    samples: VecDeque<i16>,
+    rate_ratio: f64,
+    telemetry: Telemetry,
+    total_render_time: Duration,
+}
+
+struct Shared {
+    output: Mutex<Output>,
+    ready: Condvar,
+    pending: AtomicUsize,
+}
+
+/// The `RendererThread` struct owns a chip running on a dedicated thread.
+///
+/// Dropping the `RendererThread` stops the thread.
+pub struct RendererThread {
+    commands: Option<Sender<RenderCommand>>,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RendererThread {
+    /// Start a new rendering thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate`   - The nominal sample rate of the audio device.
+    /// * `target_frames` - The number of stereo frames to keep buffered between the renderer and
+    ///                     the audio device. Larger targets absorb more jitter at the cost of
+    ///                     latency.
+    pub fn spawn(sample_rate: u32, target_frames: usize) -> Self {
+        RendererThread::spawn_with_time_source(sample_rate, target_frames, WallClock::new())
+    }
+
+    /// Start a new rendering thread that times how long it takes to render each block with a
+    /// time source, rather than the wall clock. With a `time_source::ManualTime`, the render
+    /// times in the telemetry are all zero unless the time is advanced.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate`   - The nominal sample rate of the audio device.
+    /// * `target_frames` - The number of stereo frames to keep buffered between the renderer and
+    ///                     the audio device.
+    /// * `time`          - The time source the rendering thread reads.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use opl3_rs::renderer::RendererThread;
+    /// use opl3_rs::time_source::ManualTime;
+    ///
+    /// let renderer = RendererThread::spawn_with_time_source(48000, 1024, ManualTime::new());
+    /// renderer.write(0, 0xB0, 0x32).unwrap();
+    /// renderer.advance_to(100_000).unwrap();
+    /// renderer.wait_for(4000);
+    /// assert_eq!(renderer.telemetry().max_render_time, Duration::ZERO);
+    /// ```
+    pub fn spawn_with_time_source<T>(sample_rate: u32, target_frames: usize, time: T) -> Self
+    where
+        T: TimeSource + Send + 'static,
+    {
+        let (commands, receiver) = channel();
+        let shared = Arc::new(Shared {
+            output: Mutex::new(Output {
+                samples: VecDeque::new(),
+                rate_ratio: 1.0,
+                telemetry: Telemetry::default(),
+                total_render_time: Duration::ZERO,
+            }),
+            ready: Condvar::new(),
+            pending: AtomicUsize::new(0),
+        });
+
+        let thread_shared = shared.clone();
+        let handle = std::thread::spawn(move || {
+            let mut chip = Opl3Chip::new(NATIVE_SAMPLE_RATE);
+            let mut resampler = Resampler::new(sample_rate);
+            let mut native_samples = 0u64;
+            let mut rendered = Vec::new();
+
+            for command in receiver {
+                thread_shared.pending.fetch_sub(1, Ordering::Relaxed);
+                let started = time.now();
+                let time_usec = match command {
+                    RenderCommand::Write(event) => event.time,
+                    RenderCommand::AdvanceTo(time_usec) => time_usec,
+                };
+                let target = time_usec * NATIVE_SAMPLE_RATE as u64 / 1_000_000;
+                let mut frame = [0i16; 2];
+                while native_samples < target {
+                    _ = chip.generate(&mut frame);
+                    resampler.push(frame, &mut rendered);
+                    native_samples += 1;
+                }
+                if let RenderCommand::Write(event) = command {
+                    chip.write_register(event.register, event.value);
+                }
+                if rendered.is_empty() {
+                    continue;
+                }
+
+                let render_time = time.now().saturating_sub(started);
+                let Ok(mut output) = thread_shared.output.lock() else {
+                    return;
+                };
+                output.telemetry.frames_generated += rendered.len() as u64 / 2;
+                output.telemetry.blocks_rendered += 1;
+                output.telemetry.max_render_time =
+                    output.telemetry.max_render_time.max(render_time);
+                output.total_render_time += render_time;
+                output.samples.extend(rendered.drain(..));
+                // Produce fewer samples when the buffer is fuller than the target, and more when
+                // it is emptier, in proportion to the error.
+                let fill = output.samples.len() / 2;
+                let error = (fill as f64 - target_frames as f64) / target_frames.max(1) as f64;
+                output.rate_ratio = 1.0
+                    - (error * MAX_RATE_ADJUSTMENT)
+                        .clamp(-MAX_RATE_ADJUSTMENT, MAX_RATE_ADJUSTMENT);
+                resampler.set_rate_ratio(output.rate_ratio);
+                thread_shared.ready.notify_all();
+            }
+        });
+
+        RendererThread {
+            commands: Some(commands),
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Queue a register write.
+    ///
+    /// # Arguments
+    ///
+    /// * `time_usec` - The emulated time of the write, in microseconds. Times should not decrease
+    ///                 from one call to the next.
+    /// * `register`  - The register to write, in the range 0x000..=0x1FF.
+    /// * `value`     - The value to write.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or `OplError::StreamClosed` if the rendering
+    /// thread has stopped.
+    pub fn write(&self, time_usec: u64, register: u16, value: u8) -> Result<(), OplError> {
+        self.send(RenderCommand::Write(OplEvent {
+            time: time_usec,
+            register,
+            value,
+        }))
+    }
+
+    /// Tell the renderer that emulated time has reached `time_usec`, so that it can render up to
+    /// that point even if no writes are made.
+    ///
+    /// # Arguments
+    ///
+    /// * `time_usec` - The current emulated time, in microseconds.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or `OplError::StreamClosed` if the rendering
+    /// thread has stopped.
+    pub fn advance_to(&self, time_usec: u64) -> Result<(), OplError> {
+        self.send(RenderCommand::AdvanceTo(time_usec))
+    }
+
+    /// Read rendered audio, without blocking.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a buffer that receives stereo, interleaved samples.
+    ///              Any part of the buffer that cannot be filled from rendered audio is filled with
+    ///              silence.
+    ///
+    /// # Returns
+    ///
+    /// The number of frames read from rendered audio.
+    pub fn read(&self, buffer: &mut [i16]) -> usize {
+        let mut read = 0;
+        if let Ok(mut output) = self.shared.output.lock() {
+            let available = output.samples.len().min(buffer.len()) & !1;
+            for (sample, rendered) in buffer.iter_mut().zip(output.samples.drain(..available)) {
+                *sample = rendered;
+            }
+            read = available;
+            if read < buffer.len() {
+                output.telemetry.underruns += 1;
+                output.telemetry.underrun_frames += (buffer.len() - read) as u64 / 2;
+            }
+        }
+        buffer[read..].fill(0);
+        read / 2
+    }
+
+    /// Block until at least `frames` frames of rendered audio are buffered, or the rendering
+    /// thread has stopped.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The number of frames to wait for.
+    pub fn wait_for(&self, frames: usize) {
+        let Ok(mut output) = self.shared.output.lock() else {
+            return;
+        };
+        while output.samples.len() / 2 < frames && self.is_running() {
+            output = match self.shared.ready.wait(output) {
+                Ok(output) => output,
+                Err(_) => return,
+            };
+        }
+    }
+
+    /// Return the number of rendered frames waiting to be read.
+    pub fn buffered_frames(&self) -> usize {
+        self.shared
+            .output
+            .lock()
+            .map(|output| output.samples.len() / 2)
+            .unwrap_or(0)
+    }
+
+    /// Return the current adjustment to the output rate. 1.0 is the nominal rate, and the
+    /// adjustment stays within `MAX_RATE_ADJUSTMENT` of it.
+    pub fn rate_ratio(&self) -> f64 {
+        self.shared
+            .output
+            .lock()
+            .map(|output| output.rate_ratio)
+            .unwrap_or(1.0)
+    }
+
+    /// Return the renderer's telemetry.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::renderer::RendererThread;
+    ///
+    /// let renderer = RendererThread::spawn(48000, 1024);
+    /// let mut buffer = [0i16; 256 * 2];
+    /// // Nothing has been rendered yet, so this read underruns.
+    /// renderer.read(&mut buffer);
+    /// assert_eq!(renderer.telemetry().underruns, 1);
+    /// ```
+    pub fn telemetry(&self) -> Telemetry {
+        let pending_commands = self.shared.pending.load(Ordering::Relaxed);
+        let Ok(output) = self.shared.output.lock() else {
+            return Telemetry::default();
+        };
+        let blocks = output.telemetry.blocks_rendered.max(1) as u32;
+        Telemetry {
+            average_render_time: output.total_render_time / blocks,
+            pending_commands,
+            buffered_frames: output.samples.len() / 2,
+            rate_ratio: output.rate_ratio,
+            ..output.telemetry
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    fn send(&self, command: RenderCommand) -> Result<(), OplError> {
+        self.shared.pending.fetch_add(1, Ordering::Relaxed);
+        let sent = self
+            .commands
+            .as_ref()
+            .and_then(|commands| commands.send(command).ok());
+        if sent.is_none() {
+            self.shared.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        sent.ok_or(OplError::StreamClosed)
+    }
+}
+
+impl Drop for RendererThread {
+    fn drop(&mut self) {
+        // Closing the command channel ends the thread's receive loop.
+        self.commands = None;
+        if let Some(handle) = self.handle.take() {
+            _ = handle.join();
+        }
+    }
+}
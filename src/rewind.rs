@@ -0,0 +1,240 @@
+//! A rewind buffer for emulators, built on top of `Opl3Device` snapshots.
+//!
+//! The `RewindBuffer` captures the state of an `Opl3Device` at a fixed interval and keeps a ring
+//! of the most recent states in compressed form. Most of a Nuked-OPL3 instance is idle operator
+//! state and an empty write buffer, so states compress very well.
+
+use std::collections::VecDeque;
+
+use crate::{Opl3Device, Opl3DeviceState, OplError};
+
+/// A single retained point in a `RewindBuffer`.
+struct RewindPoint {
+    time_usec: f64,
+    packed: Vec<u8>,
+}
+
+/// The `RewindBuffer` struct periodically snapshots an `Opl3Device` into a ring of compressed
+/// states, and can restore the device to any retained point.
+pub struct RewindBuffer {
+    interval_usec: f64,
+    capacity: usize,
+    time_usec: f64,
+    elapsed_usec: f64,
+    points: VecDeque<RewindPoint>,
+}
+
+impl RewindBuffer {
+    /// Create a new, empty `RewindBuffer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval_ms` - The interval, in milliseconds, at which snapshots are captured by `run`.
+    /// * `capacity`    - The maximum number of snapshots to retain. When the buffer is full, the
+    ///                   oldest snapshot is discarded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::rewind::RewindBuffer;
+    ///
+    /// // Keep 10 seconds of rewind history at 100ms granularity.
+    /// let rewind = RewindBuffer::new(100, 100);
+    /// ```
+    pub fn new(interval_ms: u32, capacity: usize) -> Self {
+        RewindBuffer {
+            interval_usec: interval_ms.max(1) as f64 * 1000.0,
+            capacity: capacity.max(1),
+            time_usec: 0.0,
+            elapsed_usec: 0.0,
+            points: VecDeque::with_capacity(capacity.max(1)),
+        }
+    }
+
+    /// Advance the rewind buffer's clock, capturing a snapshot of the device each time the
+    /// configured interval elapses. This is intended to be called alongside `Opl3Device::run`,
+    /// with the same elapsed time.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device to snapshot.
+    /// * `usec`   - The number of microseconds that have passed since the last call to `run`.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a snapshot was captured during this call.
+    pub fn run(&mut self, device: &Opl3Device, usec: f64) -> bool {
+        self.time_usec += usec;
+        self.elapsed_usec += usec;
+        if self.elapsed_usec < self.interval_usec {
+            return false;
+        }
+        // Only one snapshot is useful per call, no matter how many intervals have elapsed.
+        self.elapsed_usec %= self.interval_usec;
+        self.capture(device);
+        true
+    }
+
+    /// Immediately capture a snapshot of the device, independent of the configured interval.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device to snapshot.
+    pub fn capture(&mut self, device: &Opl3Device) {
+        if self.points.len() == self.capacity {
+            self.points.pop_front();
+        }
+        self.points.push_back(RewindPoint {
+            time_usec: self.time_usec,
+            packed: compress(&device.snapshot().to_bytes()),
+        });
+    }
+
+    /// Return the number of snapshots currently retained.
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Return `true` if no snapshots are retained.
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Discard all retained snapshots.
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.elapsed_usec = 0.0;
+    }
+
+    /// Return the time, in microseconds of `run` time, at which the specified snapshot was taken.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The snapshot to query, where 0 is the most recent snapshot.
+    pub fn point_time(&self, index: usize) -> Option<f64> {
+        self.point(index).map(|point| point.time_usec)
+    }
+
+    /// Return the total number of bytes used by the retained, compressed snapshots.
+    pub fn memory_usage(&self) -> usize {
+        self.points.iter().map(|point| point.packed.len()).sum()
+    }
+
+    /// Restore the device to the specified snapshot, without discarding any snapshots.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device to restore.
+    /// * `index`  - The snapshot to restore, where 0 is the most recent snapshot.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    pub fn restore(&self, device: &mut Opl3Device, index: usize) -> Result<(), OplError> {
+        let point = self.point(index).ok_or(OplError::RewindUnavailable)?;
+        let state = Opl3DeviceState::from_bytes(&decompress(&point.packed)?)?;
+        device.restore(&state);
+        Ok(())
+    }
+
+    /// Rewind the device to the specified snapshot and discard all snapshots newer than it, so
+    /// that emulation can continue from that point.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device to restore.
+    /// * `index`  - The snapshot to restore, where 0 is the most recent snapshot.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Device;
+    /// use opl3_rs::rewind::RewindBuffer;
+    ///
+    /// let mut device = Opl3Device::new(44100);
+    /// let mut rewind = RewindBuffer::new(100, 50);
+    ///
+    /// // Run for one second of emulated time, 10ms at a time.
+    /// for _ in 0..100 {
+    ///     device.run(10_000.0);
+    ///     rewind.run(&device, 10_000.0);
+    /// }
+    /// assert_eq!(rewind.len(), 10);
+    ///
+    /// // Go back half a second.
+    /// rewind.rewind(&mut device, 5).unwrap();
+    /// assert_eq!(rewind.len(), 5);
+    /// ```
+    pub fn rewind(&mut self, device: &mut Opl3Device, index: usize) -> Result<(), OplError> {
+        self.restore(device, index)?;
+        let keep = self.points.len() - index;
+        if let Some(point) = self.points.get(keep - 1) {
+            self.time_usec = point.time_usec;
+        }
+        self.points.truncate(keep);
+        self.elapsed_usec = 0.0;
+        Ok(())
+    }
+
+    fn point(&self, index: usize) -> Option<&RewindPoint> {
+        self.points
+            .len()
+            .checked_sub(index + 1)
+            .and_then(|i| self.points.get(i))
+    }
+}
+
+// Snapshots are compressed with a simple run-length scheme. A control byte with the high bit set
+// encodes a run of (n & 0x7F) + 1 zero bytes; otherwise it is followed by n + 1 literal bytes.
+const RUN_FLAG: u8 = 0x80;
+const MAX_RUN: usize = 128;
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(data.len() / 4);
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&b| b == 0)
+            .count();
+        if zeros > 1 {
+            packed.push(RUN_FLAG | (zeros - 1) as u8);
+            i += zeros;
+            continue;
+        }
+        // Collect literals until the next run of at least two zeros.
+        let start = i;
+        while i < data.len()
+            && i - start < MAX_RUN
+            && !(data[i] == 0 && data.get(i + 1) == Some(&0))
+        {
+            i += 1;
+        }
+        packed.push((i - start - 1) as u8);
+        packed.extend_from_slice(&data[start..i]);
+    }
+    packed
+}
+
+fn decompress(packed: &[u8]) -> Result<Vec<u8>, OplError> {
+    let mut data = Vec::with_capacity(packed.len() * 4);
+    let mut i = 0;
+    while i < packed.len() {
+        let control = packed[i];
+        i += 1;
+        let len = (control & !RUN_FLAG) as usize + 1;
+        if control & RUN_FLAG != 0 {
+            data.resize(data.len() + len, 0);
+        } else {
+            let literals = packed.get(i..i + len).ok_or(OplError::InvalidState)?;
+            data.extend_from_slice(literals);
+            i += len;
+        }
+    }
+    Ok(data)
+}
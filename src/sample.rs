@@ -0,0 +1,276 @@
+//! Conversion of generated audio to other sample formats.
+//!
+//! The chips generate 16-bit integer samples. The `Sample` trait, implemented for `i16`, `i32`,
+//! `f32` and `f64`, defines how those map to each format, so that conversions are done the same
+//! way everywhere: integers are scaled to their full range, and floating point samples are
+//! normalized so that `i16::MIN` maps to -1.0. `Opl3Chip::generate_stream_as` and `generate_as`
+//! render directly into a buffer of any `Sample` type. Going the other way, `Dither` quantizes
+//! processed audio back to 16 bits with triangular dither.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//!
+//! let mut chip = Opl3Chip::new(44100);
+//! chip.write_register(0x63, 0xF0);
+//! chip.write_register(0xB0, 0x32);
+//! let mut buffer = [0f32; 1024 * 2];
+//! chip.generate_stream_as(&mut buffer).unwrap();
+//! assert!(buffer.iter().all(|sample| (-1.0..1.0).contains(sample)));
+//! ```
+
+use crate::opl_core::OplCore;
+use crate::{Opl3Chip, OplError};
+
+/// The `Sample` trait is implemented by the sample formats audio can be generated in.
+pub trait Sample: Copy + Default + Send + 'static {
+    /// Convert a 16-bit sample, as generated by the chips.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - The sample to convert.
+    fn from_i16(sample: i16) -> Self;
+
+    /// Convert a normalized sample, where -1.0 is `i16::MIN`. Integer formats round to nearest
+    /// and saturate.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - The sample to convert.
+    fn from_f64(sample: f64) -> Self;
+
+    /// Return the sample normalized, where -1.0 is `i16::MIN`.
+    fn to_f64(self) -> f64;
+}
+
+impl Sample for i16 {
+    fn from_i16(sample: i16) -> Self {
+        sample
+    }
+
+    fn from_f64(sample: f64) -> Self {
+        (sample * 32768.0)
+            .round()
+            .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64 / 32768.0
+    }
+}
+
+impl Sample for i32 {
+    fn from_i16(sample: i16) -> Self {
+        (sample as i32) << 16
+    }
+
+    fn from_f64(sample: f64) -> Self {
+        (sample * 2_147_483_648.0)
+            .round()
+            .clamp(i32::MIN as f64, i32::MAX as f64) as i32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64 / 2_147_483_648.0
+    }
+}
+
+impl Sample for f32 {
+    fn from_i16(sample: i16) -> Self {
+        sample as f32 / 32768.0
+    }
+
+    fn from_f64(sample: f64) -> Self {
+        sample as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Sample for f64 {
+    fn from_i16(sample: i16) -> Self {
+        sample as f64 / 32768.0
+    }
+
+    fn from_f64(sample: f64) -> Self {
+        sample
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+/// Convert samples from one format to another, without dither.
+///
+/// # Arguments
+///
+/// * `input`  - The samples to convert.
+/// * `output` - The buffer to write the converted samples to.
+///
+/// # Returns
+///
+/// A Result containing either `()` on success or an `OplError` on failure. The error is
+/// `OplError::BufferUndersized` if `output` is shorter than `input`.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::sample::convert;
+///
+/// let mut output = [0i32; 3];
+/// convert(&[0.5f32, -1.0, 2.0], &mut output).unwrap();
+/// assert_eq!(output, [0x4000_0000, i32::MIN, i32::MAX]);
+/// ```
+pub fn convert<S: Sample, T: Sample>(input: &[S], output: &mut [T]) -> Result<(), OplError> {
+    if output.len() < input.len() {
+        return Err(OplError::BufferUndersized);
+    }
+    for (output, &input) in output.iter_mut().zip(input) {
+        *output = T::from_f64(input.to_f64());
+    }
+    Ok(())
+}
+
+/// The number of samples rendered at a time by `generate_as`.
+const CHUNK_SAMPLES: usize = 512;
+
+/// Generate a stream of 2 channel, interleaved audio samples from any core, in any sample format.
+/// The core's `generate_stream` is called with consecutive chunks of the buffer, so odd lengths
+/// are handled as the core handles them.
+///
+/// # Arguments
+///
+/// * `core`   - The core to generate audio from.
+/// * `buffer` - A mutable reference to a slice that will be filled with audio samples.
+///
+/// # Returns
+///
+/// A Result containing either the number of frames generated on success or an `OplError` on
+/// failure.
+pub fn generate_as<S: Sample, C: OplCore + ?Sized>(
+    core: &mut C,
+    buffer: &mut [S],
+) -> Result<usize, OplError> {
+    let mut chunk = [0i16; CHUNK_SAMPLES];
+    let mut frames = 0;
+    for output in buffer.chunks_mut(CHUNK_SAMPLES) {
+        let chunk = &mut chunk[..output.len()];
+        frames += core.generate_stream(chunk)?;
+        for (output, &sample) in output.iter_mut().zip(chunk.iter()) {
+            *output = S::from_i16(sample);
+        }
+    }
+    Ok(frames)
+}
+
+impl Opl3Chip {
+    /// Generate a stream of 2 channel, interleaved audio samples in any sample format. See
+    /// `generate_stream`.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut native = [0i16; 300];
+    /// Opl3Chip::new(44100).generate_stream(&mut native).unwrap();
+    /// let mut wide = [0i32; 300];
+    /// Opl3Chip::new(44100).generate_stream_as(&mut wide).unwrap();
+    /// assert!(native.iter().zip(&wide).all(|(&a, &b)| (a as i32) << 16 == b));
+    /// ```
+    pub fn generate_stream_as<S: Sample>(&mut self, buffer: &mut [S]) -> Result<usize, OplError> {
+        generate_as(self, buffer)
+    }
+}
+
+/// The `Dither` struct quantizes samples to 16 bits, adding triangular (TPDF) dither of up to one
+/// least significant bit so that quiet, processed audio does not pick up quantization distortion.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Dither {
+    state: u32,
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dither {
+    /// Create a new `Dither`. The noise sequence is always the same, so output is reproducible.
+    pub fn new() -> Self {
+        Dither { state: 0x2545_F491 }
+    }
+
+    /// Quantize a sample to 16 bits.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample` - The sample to quantize.
+    pub fn quantize<S: Sample>(&mut self, sample: S) -> i16 {
+        let noise = self.uniform() - self.uniform();
+        (sample.to_f64() * 32768.0 + noise)
+            .round()
+            .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+
+    /// Quantize samples to 16 bits.
+    ///
+    /// # Arguments
+    ///
+    /// * `input`  - The samples to quantize.
+    /// * `output` - The buffer to write the quantized samples to.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure. The error is
+    /// `OplError::BufferUndersized` if `output` is shorter than `input`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::sample::Dither;
+    ///
+    /// // A level between two steps comes out as a mix of the steps around it.
+    /// let input = vec![100.25f32 / 32768.0; 4096];
+    /// let mut output = vec![0i16; 4096];
+    /// Dither::new().quantize_slice(&input, &mut output).unwrap();
+    /// assert!(output.iter().all(|&sample| (99..=101).contains(&sample)));
+    /// let mean = output.iter().map(|&sample| sample as f64).sum::<f64>() / 4096.0;
+    /// assert!((mean - 100.25).abs() < 0.1);
+    /// ```
+    pub fn quantize_slice<S: Sample>(
+        &mut self,
+        input: &[S],
+        output: &mut [i16],
+    ) -> Result<(), OplError> {
+        if output.len() < input.len() {
+            return Err(OplError::BufferUndersized);
+        }
+        for (output, &input) in output.iter_mut().zip(input) {
+            *output = self.quantize(input);
+        }
+        Ok(())
+    }
+
+    /// Return a uniformly distributed value in [0, 1), from a xorshift generator.
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f64 / 4_294_967_296.0
+    }
+}
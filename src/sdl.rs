@@ -0,0 +1,142 @@
+//! An SDL2 audio callback playing a chip, fed by a `queue::CommandQueue`.
+//!
+//! Emulator frontends built on SDL open an audio device with a callback that SDL calls from its
+//! own audio thread. `OplSdlCallback` is that callback for an `Opl3Chip`: the emulation thread
+//! pushes register writes to the `Producer` half of a `CommandQueue`, and the callback pops them
+//! from the `Consumer` half and plays each at the frame it is timestamped with. Neither side
+//! blocks or allocates.
+//!
+//! Timestamps are in frames of output since the device was opened, so an emulator that runs
+//! ahead of the audio by a buffer or so can schedule its writes exactly. Writes timestamped in
+//! the past are played at once. `OplSdlCallback::open` opens a device and creates the chip at
+//! the sample rate SDL actually obtained, which may differ from the one asked for.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::event_log::OplEvent;
+//! use opl3_rs::queue::CommandQueue;
+//! use opl3_rs::sdl::OplSdlCallback;
+//! use opl3_rs::Opl3Chip;
+//! use sdl2::audio::AudioCallback;
+//!
+//! let mut queue: CommandQueue<64> = CommandQueue::new();
+//! let (mut producer, consumer) = queue.split();
+//! // With a device, this would be `OplSdlCallback::open(&audio, consumer, 44100, 512)`.
+//! let mut callback = OplSdlCallback::new(Opl3Chip::new(44100), consumer);
+//!
+//! // On the emulation thread, a note keyed on 300 frames in.
+//! for (time, register, value) in [(0, 0x63, 0xF0), (0, 0xA0, 0x98), (300, 0xB0, 0x31)] {
+//!     producer.push(OplEvent { time, register, value }).unwrap();
+//! }
+//!
+//! // On SDL's audio thread.
+//! let mut out = [0i16; 512 * 2];
+//! callback.callback(&mut out);
+//! assert!(out[..300 * 2].iter().all(|&sample| sample == 0));
+//! assert!(out[300 * 2..].iter().any(|&sample| sample != 0));
+//! assert_eq!(callback.frames_played(), 512);
+//! ```
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+use crate::queue::Consumer;
+use crate::{Opl3Chip, OplError};
+
+/// The `OplSdlCallback` struct is an SDL2 audio callback rendering a chip, applying the register
+/// writes of a `CommandQueue` as it goes.
+pub struct OplSdlCallback<'a, const N: usize> {
+    chip: Opl3Chip,
+    consumer: Consumer<'a, N>,
+    /// The number of frames rendered since the callback was created.
+    frames: u64,
+}
+
+impl<'a, const N: usize> OplSdlCallback<'a, N> {
+    /// Create a new `OplSdlCallback`.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip`     - The chip to render, at the sample rate of the device.
+    /// * `consumer` - The consumer half of the queue the register writes are pushed to.
+    pub fn new(chip: Opl3Chip, consumer: Consumer<'a, N>) -> Self {
+        OplSdlCallback {
+            chip,
+            consumer,
+            frames: 0,
+        }
+    }
+
+    /// Open an SDL playback device for a new chip. The device starts paused, and plays once
+    /// `resume` is called on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `audio`       - The SDL audio subsystem.
+    /// * `consumer`    - The consumer half of the queue the register writes are pushed to.
+    /// * `sample_rate` - The sample rate to ask for. The chip is created at the rate obtained.
+    /// * `frames`      - The size of SDL's buffer, in frames, which sets the latency.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the device on success or an `OplError` on failure. The error
+    /// is `OplError::Backend` if SDL cannot open the device.
+    pub fn open(
+        audio: &AudioSubsystem,
+        consumer: Consumer<'a, N>,
+        sample_rate: u32,
+        frames: u16,
+    ) -> Result<AudioDevice<Self>, OplError> {
+        let desired = AudioSpecDesired {
+            freq: Some(sample_rate as i32),
+            channels: Some(2),
+            samples: Some(frames),
+        };
+        audio
+            .open_playback(None, &desired, |spec| {
+                OplSdlCallback::new(Opl3Chip::new(spec.freq as u32), consumer)
+            })
+            .map_err(OplError::Backend)
+    }
+
+    /// Return a reference to the chip.
+    pub fn chip(&self) -> &Opl3Chip {
+        &self.chip
+    }
+
+    /// Return a mutable reference to the chip, to write registers outside of the queue or
+    /// change its settings. From outside the callback, lock the device first.
+    pub fn chip_mut(&mut self) -> &mut Opl3Chip {
+        &mut self.chip
+    }
+
+    /// Return the number of frames played so far, which is the timestamp of the next frame.
+    pub fn frames_played(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl<const N: usize> AudioCallback for OplSdlCallback<'_, N> {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        let frames = out.len() / 2;
+        let mut done = 0;
+        while done < frames {
+            let now = self.frames + done as u64;
+            while let Some(event) = self.consumer.pop_until(now) {
+                self.chip.write_register(event.register, event.value);
+            }
+            let end = self.consumer.peek().map_or(frames, |event| {
+                ((event.time - self.frames) as usize).min(frames)
+            });
+            let span = &mut out[done * 2..end * 2];
+            if self.chip.generate_stream(span).is_err() {
+                span.fill(0);
+            }
+            done = end;
+        }
+        self.frames += frames as u64;
+    }
+}
@@ -0,0 +1,175 @@
+//! A small builder for scripting register sequences.
+//!
+//! A `Sequence` is a list of register writes separated by waits, built up with chained calls. It
+//! can be rendered to audio, or converted to a list of `TimedWrite`s or an `EventLog`. This makes
+//! examples, tests and bug reports short and reproducible.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::sequence::Sequence;
+//!
+//! let audio = Sequence::new()
+//!     .write(0x20, 0x01)
+//!     .write(0x23, 0x01)
+//!     .write(0x43, 0x00)
+//!     .write(0x63, 0xF0)
+//!     .write(0x83, 0x77)
+//!     .key_on(0, 0x244, 4)
+//!     .wait_ms(50)
+//!     .key_off(0)
+//!     .wait_ms(50)
+//!     .render(44100);
+//! assert_eq!(audio.len(), 4410 * 2);
+//! ```
+
+use crate::event_log::{EventLog, OplEvent};
+use crate::{Opl3Device, TimedWrite};
+
+/// Sequences are timed in microseconds.
+const SEQUENCE_TICK_RATE: u32 = 1_000_000;
+
+/// The `Sequence` struct is a list of register writes separated by waits.
+#[derive(Clone, Debug)]
+pub struct Sequence {
+    log: EventLog,
+    time_usec: u64,
+    b0: [u8; 18],
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sequence {
+    /// Create a new, empty `Sequence`.
+    pub fn new() -> Self {
+        Sequence {
+            log: EventLog::new(SEQUENCE_TICK_RATE),
+            time_usec: 0,
+            b0: [0; 18],
+        }
+    }
+
+    /// Append a register write at the current time.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The register to write, in the range 0x000..=0x1FF. Addresses 0x100 and
+    ///                above are in the secondary register file.
+    /// * `value`    - The value to write.
+    pub fn write(mut self, register: u16, value: u8) -> Self {
+        if let Some(channel) = b0_channel(register) {
+            self.b0[channel] = value;
+        }
+        self.log.push(OplEvent {
+            time: self.time_usec,
+            register,
+            value,
+        });
+        self
+    }
+
+    /// Advance the current time.
+    ///
+    /// # Arguments
+    ///
+    /// * `ms` - The number of milliseconds to wait.
+    pub fn wait_ms(self, ms: u32) -> Self {
+        self.wait_us(ms as u64 * 1000)
+    }
+
+    /// Advance the current time.
+    ///
+    /// # Arguments
+    ///
+    /// * `us` - The number of microseconds to wait.
+    pub fn wait_us(mut self, us: u64) -> Self {
+        self.time_usec += us;
+        self
+    }
+
+    /// Set a channel's frequency and start a note on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, from 0 to 17.
+    /// * `fnum`    - The 10-bit frequency number.
+    /// * `block`   - The 3-bit octave block.
+    pub fn key_on(self, channel: u8, fnum: u16, block: u8) -> Self {
+        let (a0, b0) = channel_registers(channel);
+        let b0_value = 0x20 | ((block & 0x07) << 2) | ((fnum >> 8) as u8 & 0x03);
+        self.write(a0, fnum as u8).write(b0, b0_value)
+    }
+
+    /// Release the note on a channel, keeping its frequency.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, from 0 to 17.
+    pub fn key_off(self, channel: u8) -> Self {
+        let (_, b0) = channel_registers(channel);
+        let value = self.b0[channel as usize % 18] & !0x20;
+        self.write(b0, value)
+    }
+
+    /// Return the current time, which is the total length of the sequence, in microseconds.
+    pub fn duration_us(&self) -> u64 {
+        self.time_usec
+    }
+
+    /// Return the sequence as an event log, timed in microseconds.
+    pub fn to_event_log(&self) -> EventLog {
+        self.log.clone()
+    }
+
+    /// Return the sequence as a list of writes timed in samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to compute sample offsets at.
+    pub fn to_timed_writes(&self, sample_rate: u32) -> Vec<TimedWrite> {
+        self.log
+            .iter()
+            .map(|event| TimedWrite {
+                offset: usec_to_samples(event.time, sample_rate),
+                register: event.register,
+                value: event.value,
+            })
+            .collect()
+    }
+
+    /// Render the sequence to interleaved stereo audio, from time 0 to the end of the last wait.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to render at.
+    pub fn render(&self, sample_rate: u32) -> Vec<i16> {
+        let mut device = Opl3Device::new(sample_rate);
+        let samples = usec_to_samples(self.time_usec, sample_rate);
+        device
+            .generate_frame(samples, &self.to_timed_writes(sample_rate))
+            .to_vec()
+    }
+}
+
+fn usec_to_samples(usec: u64, sample_rate: u32) -> usize {
+    (usec * sample_rate as u64 / SEQUENCE_TICK_RATE as u64) as usize
+}
+
+/// Return the A0 and B0 register addresses of a channel.
+fn channel_registers(channel: u8) -> (u16, u16) {
+    let channel = channel as u16 % 18;
+    let (bank, index) = ((channel / 9) << 8, channel % 9);
+    (bank | (0xA0 + index), bank | (0xB0 + index))
+}
+
+/// Return the channel whose key-on register is `register`, if any.
+fn b0_channel(register: u16) -> Option<usize> {
+    match register & 0xFF {
+        reg @ 0xB0..=0xB8 => Some(((register >> 8) & 1) as usize * 9 + (reg - 0xB0) as usize),
+        _ => None,
+    }
+}
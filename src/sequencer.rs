@@ -0,0 +1,293 @@
+//! Scheduling notes and register writes on a tick grid, for music and jingles in games.
+//!
+//! A `Sequencer` holds a list of `SequencerEvent`s, each at a tick, and plays them while
+//! rendering audio from any `OplCore`. Ticks are a fraction of a beat, and the tempo can be set
+//! at any time, by the game or by a `SequencerEvent::Tempo` in the sequence, so that music can
+//! speed up or slow down with the action. A range of ticks can be looped, for background music.
+//!
+//! Notes are played directly on chip channels, with the levels of the channel's patch. For more
+//! notes than there are channels, the channels can be picked with a
+//! `channel_allocator::ChannelAllocator`. 4-operator patches need 4-operator mode enabled for
+//! their channel through register 0x104, which can be done with a `SequencerEvent::Write`.
+//!
+//! Events take effect on the first sample at or after the exact time of their tick, so the
+//! timing does not drift however the audio is split into blocks.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::patch::{Operator, OperatorPair, Patch};
+//! use opl3_rs::sequencer::{Sequencer, SequencerEvent};
+//! use opl3_rs::Opl3Chip;
+//!
+//! let chime = Patch::new(OperatorPair {
+//!     modulator: Operator::new(0x01, 0x10, 0xF2, 0x74, 0x00),
+//!     carrier: Operator::new(0x01, 0x00, 0xF2, 0x34, 0x00),
+//!     feedback_connection: 0x06,
+//! });
+//! // Two notes, each half a beat long, at 120 beats per minute.
+//! let mut sequencer = Sequencer::new(4, 120.0);
+//! sequencer.schedule(0, SequencerEvent::Patch { channel: 0, patch: chime });
+//! for (tick, note) in [(0, 72), (2, 76)] {
+//!     sequencer.schedule(tick, SequencerEvent::NoteOn { channel: 0, note });
+//!     sequencer.schedule(tick + 2, SequencerEvent::NoteOff { channel: 0 });
+//! }
+//! assert_eq!(sequencer.samples_per_tick(44100), 5512.5);
+//!
+//! let mut chip = Opl3Chip::new(44100);
+//! let mut audio = vec![0i16; 11025 * 2];
+//! sequencer.render(&mut chip, &mut audio).unwrap();
+//! assert!(audio.iter().any(|&sample| sample.abs() > 1000));
+//! assert_eq!(sequencer.tick(), 2);
+//! assert!(!sequencer.is_finished());
+//!
+//! // The last note off is at tick 4, the first sample after the end of the audio so far.
+//! sequencer.render(&mut chip, &mut audio).unwrap();
+//! assert!(!sequencer.is_finished());
+//! sequencer.render(&mut chip, &mut audio[..2]).unwrap();
+//! assert!(sequencer.is_finished());
+//! ```
+
+use std::ops::Range;
+
+use crate::opl_core::OplCore;
+use crate::patch::{write_note, Patch};
+use crate::OplError;
+
+/// The number of 2-operator channels of an OPL3.
+const CHANNELS: usize = 18;
+
+/// The `SequencerEvent` enum is something a `Sequencer` does at a tick.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SequencerEvent {
+    /// Key on a note on a chip channel, from 0 to 17.
+    NoteOn {
+        /// The chip channel.
+        channel: u8,
+        /// The MIDI note, where 69 is A4 at 440Hz.
+        note: u8,
+    },
+    /// Key off the note playing on a chip channel, letting it release.
+    NoteOff {
+        /// The chip channel.
+        channel: u8,
+    },
+    /// Write an instrument to a chip channel, for the notes played on it after.
+    Patch {
+        /// The chip channel.
+        channel: u8,
+        /// The instrument.
+        patch: Patch,
+    },
+    /// Write a register directly.
+    Write {
+        /// The register, in the range 0x000..=0x1FF.
+        register: u16,
+        /// The value to write.
+        value: u8,
+    },
+    /// Change the tempo, in beats per minute, from this tick on.
+    Tempo(f64),
+}
+
+/// The `Sequencer` struct plays `SequencerEvent`s on a tick grid while rendering audio.
+#[derive(Clone, Debug)]
+pub struct Sequencer {
+    /// The events, in the order they are played.
+    events: Vec<(u64, SequencerEvent)>,
+    ticks_per_beat: u32,
+    bpm: f64,
+    loop_range: Option<Range<u64>>,
+    /// The next tick to play.
+    tick: u64,
+    /// The index of the next event to play.
+    position: usize,
+    /// The number of samples to render before the next tick.
+    remaining: f64,
+    /// The note playing on each channel.
+    notes: [Option<u8>; CHANNELS],
+}
+
+impl Sequencer {
+    /// Create a new, empty `Sequencer`, at tick 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticks_per_beat` - The number of ticks in a beat.
+    /// * `bpm`            - The tempo, in beats per minute.
+    pub fn new(ticks_per_beat: u32, bpm: f64) -> Self {
+        Sequencer {
+            events: Vec::new(),
+            ticks_per_beat: ticks_per_beat.max(1),
+            bpm,
+            loop_range: None,
+            tick: 0,
+            position: 0,
+            remaining: 0.0,
+            notes: [None; CHANNELS],
+        }
+    }
+
+    /// Schedule an event. Events at the same tick are played in the order they were scheduled.
+    /// Events scheduled before the current tick are not played until the sequence loops or is
+    /// rewound.
+    ///
+    /// # Arguments
+    ///
+    /// * `tick`  - The tick to play the event at.
+    /// * `event` - The event.
+    pub fn schedule(&mut self, tick: u64, event: SequencerEvent) {
+        let index = self.events.partition_point(|&(t, _)| t <= tick);
+        if tick < self.tick {
+            self.position += 1;
+        }
+        self.events.insert(index, (tick, event));
+    }
+
+    /// Remove every event and rewind to tick 0. Notes still playing are not keyed off; see
+    /// `stop`.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.rewind();
+    }
+
+    /// Return the tempo, in beats per minute.
+    pub fn tempo(&self) -> f64 {
+        self.bpm
+    }
+
+    /// Set the tempo, taking effect from the next tick.
+    ///
+    /// # Arguments
+    ///
+    /// * `bpm` - The tempo, in beats per minute.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.bpm = bpm;
+    }
+
+    /// Return the number of ticks in a beat.
+    pub fn ticks_per_beat(&self) -> u32 {
+        self.ticks_per_beat
+    }
+
+    /// Return the length of a tick at the current tempo, in samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate audio is rendered at.
+    pub fn samples_per_tick(&self, sample_rate: u32) -> f64 {
+        sample_rate as f64 * 60.0 / (self.bpm.max(f64::MIN_POSITIVE) * self.ticks_per_beat as f64)
+    }
+
+    /// Loop a range of ticks, or stop looping. When the end of the range is reached, playback
+    /// jumps back to its start.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The ticks to loop, or `None` to play to the end and stop.
+    pub fn set_loop(&mut self, range: Option<Range<u64>>) {
+        self.loop_range = range.filter(|range| !range.is_empty());
+    }
+
+    /// Return the next tick to be played.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Return `true` if every event has been played and the sequence does not loop.
+    pub fn is_finished(&self) -> bool {
+        self.loop_range.is_none() && self.position == self.events.len()
+    }
+
+    /// Start playing from tick 0 again.
+    pub fn rewind(&mut self) {
+        self.seek(0);
+        self.remaining = 0.0;
+    }
+
+    /// Key off every note playing, so that a sequence stopped early does not leave notes
+    /// hanging.
+    ///
+    /// # Arguments
+    ///
+    /// * `core` - The core the sequence plays on.
+    pub fn stop(&mut self, core: &mut dyn OplCore) {
+        for channel in 0..CHANNELS as u8 {
+            self.note_off(core, channel);
+        }
+    }
+
+    /// Render audio, playing the events that fall within it.
+    ///
+    /// # Arguments
+    ///
+    /// * `core`   - The core to play on and render from.
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with 2 channel,
+    ///              interleaved audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames rendered on success or an `OplError` on
+    /// failure.
+    pub fn render(
+        &mut self,
+        core: &mut dyn OplCore,
+        buffer: &mut [i16],
+    ) -> Result<usize, OplError> {
+        let frames = buffer.len() / 2;
+        let mut done = 0;
+        while done < frames {
+            while self.remaining <= 0.0 {
+                self.play_tick(core);
+                self.remaining += self.samples_per_tick(core.sample_rate());
+            }
+            let run = (self.remaining.ceil() as usize).clamp(1, frames - done);
+            core.generate_stream(&mut buffer[done * 2..(done + run) * 2])?;
+            self.remaining -= run as f64;
+            done += run;
+        }
+        Ok(frames)
+    }
+
+    /// Play the events of the next tick, and move on to the tick after it.
+    fn play_tick(&mut self, core: &mut dyn OplCore) {
+        while let Some((tick, event)) = self.events.get(self.position) {
+            if *tick > self.tick {
+                break;
+            }
+            self.position += 1;
+            match event.clone() {
+                SequencerEvent::NoteOn { channel, note } => {
+                    if (channel as usize) < CHANNELS {
+                        write_note(core, channel, note as f64, true);
+                        self.notes[channel as usize] = Some(note);
+                    }
+                }
+                SequencerEvent::NoteOff { channel } => self.note_off(core, channel),
+                // Channels out of range are ignored.
+                SequencerEvent::Patch { channel, patch } => _ = patch.write_to(core, channel),
+                SequencerEvent::Write { register, value } => core.write_register(register, value),
+                SequencerEvent::Tempo(bpm) => self.bpm = bpm,
+            }
+        }
+        self.tick += 1;
+        if let Some(range) = &self.loop_range {
+            if self.tick == range.end {
+                self.seek(range.start);
+            }
+        }
+    }
+
+    /// Key off the note playing on a channel.
+    fn note_off(&mut self, core: &mut dyn OplCore, channel: u8) {
+        if let Some(note) = self.notes.get_mut(channel as usize).and_then(Option::take) {
+            write_note(core, channel, note as f64, false);
+        }
+    }
+
+    /// Move to a tick without playing the events before it.
+    fn seek(&mut self, tick: u64) {
+        self.tick = tick;
+        self.position = self.events.partition_point(|&(t, _)| t < tick);
+    }
+}
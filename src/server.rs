@@ -0,0 +1,384 @@
+//! A small HTTP server that streams a playlist of event logs as audio, enabled with the `server`
+//! feature.
+//!
+//! Each client that connects is sent the playlist rendered as a 16-bit stereo WAV stream, using
+//! HTTP chunked transfer encoding, so it can be played directly by a browser or a media player.
+//! Every connection gets its own chip, driven through the `ChunkStream` and `ChunkSender` of the
+//! `async` feature. The server only uses the standard library, and runs one thread per client,
+//! up to `ServerConfig::max_clients`; clients beyond that are answered with 503 Service
+//! Unavailable. Clients must send their whole request within 10 seconds, and in no more than 8KB,
+//! and must not stop reading the stream for 10 seconds, or are dropped.
+//!
+//! Only uncompressed WAV over HTTP is served. Ogg and WebSocket streaming are not implemented.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+
+use crate::event_log::EventLog;
+use crate::stream::{ChipCommand, ChunkStream};
+use crate::time_source::{TimeSource, WallClock};
+use crate::Opl3Chip;
+
+/// The longest a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest a write to a client may block, as when it has stopped reading.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest request accepted, in bytes, including its headers.
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// The `ServerConfig` struct configures a `PlaylistServer`.
+#[derive(Copy, Clone, Debug)]
+pub struct ServerConfig {
+    /// The sample rate of the audio stream.
+    pub sample_rate: u32,
+    /// The number of stereo samples rendered per chunk. Register writes are applied at chunk
+    /// boundaries, so smaller chunks give more accurate timing at the cost of more overhead.
+    pub chunk_frames: usize,
+    /// Whether to start the playlist again after the last entry.
+    pub repeat: bool,
+    /// Whether to pace the stream in real time, as kept by the server's time source. If `false`,
+    /// audio is sent as fast as the client will receive it.
+    pub realtime: bool,
+    /// The most clients `PlaylistServer::serve` streams to at once.
+    pub max_clients: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            sample_rate: 44100,
+            chunk_frames: 256,
+            repeat: true,
+            realtime: true,
+            max_clients: 16,
+        }
+    }
+}
+
+/// The `PlaylistServer` struct streams a playlist of event logs to HTTP clients.
+pub struct PlaylistServer {
+    listener: TcpListener,
+    playlist: Arc<Vec<EventLog>>,
+    config: ServerConfig,
+    time: Arc<dyn TimeSource + Send + Sync>,
+    clients: Arc<AtomicUsize>,
+}
+
+impl PlaylistServer {
+    /// Create a new `PlaylistServer` listening on the specified address.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr`     - The address to listen on.
+    /// * `playlist` - The event logs to play, in order. Logs can be created from DRO and VGM files
+    ///                with `EventLog::from_dro` and `EventLog::from_vgm`.
+    /// * `config`   - The server configuration.
+    ///
+    /// # Returns
+    ///
+    /// The server, or an error if the address cannot be bound, or of kind `InvalidInput` if the
+    /// sample rate is outside `Opl3Chip::MIN_SAMPLE_RATE` to `Opl3Chip::MAX_SAMPLE_RATE`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::ErrorKind;
+    /// use opl3_rs::server::{PlaylistServer, ServerConfig};
+    ///
+    /// let config = ServerConfig { sample_rate: 0, ..Default::default() };
+    /// let error = PlaylistServer::bind("127.0.0.1:0", Vec::new(), config).err().unwrap();
+    /// assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    /// ```
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        playlist: Vec<EventLog>,
+        config: ServerConfig,
+    ) -> io::Result<Self> {
+        Opl3Chip::check_sample_rate(config.sample_rate)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        Ok(PlaylistServer {
+            listener: TcpListener::bind(addr)?,
+            playlist: Arc::new(playlist),
+            config,
+            time: Arc::new(WallClock::new()),
+            clients: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Set the time source real-time streams are paced by, which is the wall clock by default.
+    /// With a `time_source::ManualTime`, streams are paced in the source's time, which runs as
+    /// fast as the stream is rendered.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time source.
+    pub fn set_time_source(&mut self, time: impl TimeSource + Send + Sync + 'static) {
+        self.time = Arc::new(time);
+    }
+
+    /// Return the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept clients forever, streaming the playlist to each client on its own thread. Clients
+    /// that connect while `ServerConfig::max_clients` are already streaming are answered with
+    /// 503 Service Unavailable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::Read;
+    /// use std::net::TcpStream;
+    /// use opl3_rs::server::{PlaylistServer, ServerConfig};
+    ///
+    /// let config = ServerConfig { max_clients: 0, ..Default::default() };
+    /// let server = PlaylistServer::bind("127.0.0.1:0", Vec::new(), config).unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// std::thread::spawn(move || server.serve());
+    ///
+    /// let mut response = Vec::new();
+    /// TcpStream::connect(addr).unwrap().read_to_end(&mut response).unwrap();
+    /// assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    /// ```
+    pub fn serve(&self) -> io::Result<()> {
+        for client in self.listener.incoming() {
+            let mut client = client?;
+            if self.clients.fetch_add(1, Ordering::AcqRel) >= self.config.max_clients {
+                self.clients.fetch_sub(1, Ordering::AcqRel);
+                // The client may never read the response, so it is not waited for.
+                _ = client.set_nonblocking(true);
+                _ = client.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n");
+                continue;
+            }
+            let slot = ClientSlot(self.clients.clone());
+            let playlist = self.playlist.clone();
+            let config = self.config;
+            let time = self.time.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                serve_client(client, &playlist, &config, &*time)
+            });
+        }
+        Ok(())
+    }
+
+    /// Accept a single client and stream the playlist to it on the current thread. This returns
+    /// when the playlist ends or the client disconnects.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::Read;
+    /// use std::net::TcpStream;
+    /// use opl3_rs::event_log::{EventLog, OplEvent};
+    /// use opl3_rs::server::{PlaylistServer, ServerConfig};
+    ///
+    /// let mut log = EventLog::new(49716);
+    /// log.push(OplEvent { time: 0, register: 0xB0, value: 0x32 });
+    /// log.push(OplEvent { time: 4971, register: 0xB0, value: 0x12 });
+    ///
+    /// let config = ServerConfig { repeat: false, realtime: false, ..Default::default() };
+    /// let server = PlaylistServer::bind("127.0.0.1:0", vec![log], config).unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// let handle = std::thread::spawn(move || server.serve_one());
+    ///
+    /// let mut client = TcpStream::connect(addr).unwrap();
+    /// std::io::Write::write_all(&mut client, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    /// let mut response = Vec::new();
+    /// client.read_to_end(&mut response).unwrap();
+    /// handle.join().unwrap().unwrap();
+    ///
+    /// assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    /// assert!(response.ends_with(b"0\r\n\r\n"));
+    /// ```
+    pub fn serve_one(&self) -> io::Result<()> {
+        let (client, _) = self.listener.accept()?;
+        serve_client(client, &self.playlist, &self.config, &*self.time)
+    }
+}
+
+/// A place in the count of streaming clients, given up when the client's thread ends.
+struct ClientSlot(Arc<AtomicUsize>);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn serve_client(
+    client: TcpStream,
+    playlist: &[EventLog],
+    config: &ServerConfig,
+    time: &dyn TimeSource,
+) -> io::Result<()> {
+    match stream_playlist(client, playlist, config, time) {
+        // A client hanging up is the normal way for a stream to end, and one that never sends
+        // its request is dropped the same way.
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn stream_playlist(
+    mut client: TcpStream,
+    playlist: &[EventLog],
+    config: &ServerConfig,
+    time: &dyn TimeSource,
+) -> io::Result<()> {
+    // Read and discard the request. Every path serves the same stream.
+    client.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let request = DeadlineReader {
+        stream: client.try_clone()?,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    };
+    let mut reader = BufReader::new(request.take(MAX_REQUEST_BYTES));
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            // The client hung up, or sent more than a request could need.
+            if reader.get_ref().limit() == 0 {
+                client.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n")?;
+            }
+            return Ok(());
+        }
+        if line.len() <= 2 {
+            break;
+        }
+    }
+
+    client.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: audio/wav\r\n\
+          Cache-Control: no-cache\r\n\
+          Transfer-Encoding: chunked\r\n\r\n",
+    )?;
+    write_chunk(&mut client, &wav_stream_header(config.sample_rate))?;
+
+    let sample_rate = config.sample_rate;
+    let mut stream = Opl3Chip::new(sample_rate).into_chunk_stream(config.chunk_frames);
+    let sender = stream.sender();
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let start = time.now();
+    let mut total_samples = 0u64;
+    let mut bytes = Vec::with_capacity(stream.chunk_frames() * 4);
+
+    loop {
+        for log in playlist {
+            // The sender can't fail here, as the stream is owned by this function.
+            _ = sender.send(ChipCommand::Reset { sample_rate });
+            let mut events = log.iter().peekable();
+            let mut log_samples = 0u64;
+            while events.peek().is_some() {
+                let now = log_samples * log.tick_rate() as u64 / sample_rate as u64;
+                while let Some(event) = events.next_if(|event| event.time <= now) {
+                    _ = sender.write_register(event.register, event.value);
+                }
+
+                let chunk = next_chunk(&mut stream, &waker);
+                bytes.clear();
+                bytes.extend(chunk.iter().flat_map(|sample| sample.to_le_bytes()));
+                write_chunk(&mut client, &bytes)?;
+
+                log_samples += stream.chunk_frames() as u64;
+                total_samples += stream.chunk_frames() as u64;
+                if config.realtime {
+                    let due = Duration::from_secs_f64(total_samples as f64 / sample_rate as f64);
+                    if let Some(wait) = due.checked_sub(time.now().saturating_sub(start)) {
+                        time.sleep(wait);
+                    }
+                }
+            }
+        }
+        if !config.repeat || playlist.iter().all(|log| log.is_empty()) {
+            break;
+        }
+    }
+    // The terminating zero-length chunk.
+    client.write_all(b"0\r\n\r\n")?;
+    client.flush()
+}
+
+/// Poll the stream to completion on the current thread.
+fn next_chunk(stream: &mut ChunkStream, waker: &Waker) -> Vec<i16> {
+    let mut cx = Context::from_waker(waker);
+    loop {
+        if let Poll::Ready(Some(chunk)) = Pin::new(&mut *stream).poll_next(&mut cx) {
+            return chunk;
+        }
+        thread::park();
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// A reader that fails with `TimedOut` once a deadline has passed, however the reads are spaced.
+/// A socket's read timeout only bounds each read, so a client trickling its request a byte at a
+/// time would otherwise hold its connection open indefinitely.
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+fn write_chunk(client: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    write!(client, "{:X}\r\n", data.len())?;
+    client.write_all(data)?;
+    client.write_all(b"\r\n")
+}
+
+/// Build a WAV header for a stream of unknown length. The RIFF and data sizes are set to their
+/// maximum, which players treat as "read until the end of the stream".
+fn wav_stream_header(sample_rate: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&2u16.to_le_bytes()); // Channels
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    // `bind` limits the sample rate to `Opl3Chip::MAX_SAMPLE_RATE`, so the byte rate fits.
+    header.extend_from_slice(&(sample_rate * 4).to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes()); // Block alignment
+    header.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&(u32::MAX - 36).to_le_bytes());
+    header
+}
@@ -0,0 +1,495 @@
+//! Recording complete sessions with a chip, and replaying them to reproduce the exact audio.
+//!
+//! A `SessionRecorder` wraps an `Opl3Chip` and records everything that affects its output: the
+//! parameters it was created with, every register write, every change to its configuration and
+//! the size of every block of audio generated. Replaying the recording with `SessionReplayer`
+//! makes the same calls on a fresh chip, so the audio is identical down to the last sample,
+//! including the effects of idle skipping and odd buffer lengths. A session serializes to a single
+//! file that can be attached to a bug report.
+//!
+//! File layout, all values little-endian:
+//!
+//! | Offset | Size | Description                                   |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | Magic, `b"OPLS"`                              |
+//! | 4      | 1    | Format version, currently 1                   |
+//! | 5      | 4    | Sample rate                                   |
+//! | 9      | 4    | Master clock in Hz                            |
+//! | 13     | 8    | Rate ratio, as an f64                         |
+//! | 21     | 1    | 1 if idle skipping is enabled                 |
+//! | 22     | 4    | Initial noise generator state                 |
+//! | 26     | 1    | Length of the crate version string            |
+//! | 27     | n    | Crate version string which recorded the file  |
+//!
+//! The header is followed by the operations, each a tag byte and its operands: 0 is a write and
+//! 1 a buffered write (register u16, value u8), 2 generates audio (sample count u32), 3 sets the
+//! rate ratio (f64), 4 sets the master clock (u32) and 5 resets the chip (sample rate u32).
+//!
+//! A block of audio holds at most 1048576 samples, so that replaying a file never needs a larger
+//! buffer. Larger buffers are generated and recorded as several blocks.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::session::{SessionRecorder, SessionReplayer};
+//!
+//! let mut recorder = SessionRecorder::new(44100);
+//! let mut audio = Vec::new();
+//! for (register, value) in [(0x23, 0x01), (0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+//!     recorder.write_register(register, value);
+//!     let mut buffer = [0i16; 333];
+//!     recorder.generate_stream(&mut buffer).unwrap();
+//!     audio.extend_from_slice(&buffer);
+//! }
+//! let file = recorder.to_bytes();
+//!
+//! let replayer = SessionReplayer::from_bytes(&file).unwrap();
+//! assert_eq!(replayer.config().sample_rate, 44100);
+//! assert_eq!(replayer.replay().unwrap(), audio);
+//! ```
+
+use crate::opl_core::OplCore;
+use crate::{Opl3Chip, OplError};
+
+const MAGIC: &[u8; 4] = b"OPLS";
+const VERSION: u8 = 1;
+/// The most samples generated in one block.
+const MAX_BLOCK_SAMPLES: usize = 1 << 20;
+
+/// The `SessionConfig` struct holds the parameters a recorded chip was created with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionConfig {
+    /// The sample rate passed to `Opl3Chip::new`.
+    pub sample_rate: u32,
+    /// The master clock, as set by `Opl3Chip::set_master_clock`.
+    pub master_clock: u32,
+    /// The rate ratio, as set by `Opl3Chip::set_rate_ratio`.
+    pub rate_ratio: f64,
+    /// Whether idle skipping is enabled, as set by `Opl3Chip::set_idle_skip`.
+    pub idle_skip: bool,
+    /// The state of the rhythm mode noise generator when recording started.
+    pub noise: u32,
+    /// The version of this crate that recorded the session.
+    pub crate_version: String,
+}
+
+impl SessionConfig {
+    /// Return the configuration of a freshly created chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn new(sample_rate: u32) -> Self {
+        let chip = Opl3Chip::new(sample_rate);
+        SessionConfig {
+            sample_rate,
+            master_clock: chip.master_clock(),
+            rate_ratio: chip.rate_ratio(),
+            idle_skip: false,
+            noise: chip.noise_state(),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Create a chip with this configuration.
+    fn create_chip(&self) -> Opl3Chip {
+        let mut chip = Opl3Chip::new(self.sample_rate);
+        chip.set_master_clock(self.master_clock);
+        chip.set_rate_ratio(self.rate_ratio);
+        chip.set_idle_skip(self.idle_skip);
+        chip
+    }
+}
+
+/// The `SessionOp` enum represents a single recorded call on the chip.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SessionOp {
+    /// A register write, as made by `Opl3Chip::write_register`.
+    Write {
+        /// The register written.
+        register: u16,
+        /// The value written.
+        value: u8,
+    },
+    /// A buffered register write, as made by `Opl3Chip::write_register_buffered`.
+    WriteBuffered {
+        /// The register written.
+        register: u16,
+        /// The value written.
+        value: u8,
+    },
+    /// A call to `Opl3Chip::generate_stream` with a buffer of this many samples.
+    Generate(u32),
+    /// A call to `Opl3Chip::set_rate_ratio`.
+    SetRateRatio(f64),
+    /// A call to `Opl3Chip::set_master_clock`.
+    SetMasterClock(u32),
+    /// A call to `Opl3Chip::reset` with this sample rate.
+    Reset(u32),
+}
+
+/// The `SessionRecorder` struct wraps an `Opl3Chip`, recording every call made through it.
+///
+/// Calls made directly on the chip, outside of the recorder, are not recorded, so the chip is
+/// only available by shared reference.
+pub struct SessionRecorder {
+    chip: Opl3Chip,
+    config: SessionConfig,
+    ops: Vec<SessionOp>,
+}
+
+impl SessionRecorder {
+    /// Create a new `SessionRecorder` with a freshly created chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn new(sample_rate: u32) -> Self {
+        Self::with_config(SessionConfig::new(sample_rate))
+    }
+
+    /// Create a new `SessionRecorder` with a chip created with a configuration. The noise
+    /// generator state and crate version of `config` are replaced with those of the new chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration to create the chip with.
+    pub fn with_config(mut config: SessionConfig) -> Self {
+        let chip = config.create_chip();
+        config.noise = chip.noise_state();
+        config.crate_version = env!("CARGO_PKG_VERSION").to_string();
+        SessionRecorder {
+            chip,
+            config,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Return the chip being recorded.
+    pub fn chip(&self) -> &Opl3Chip {
+        &self.chip
+    }
+
+    /// Return the configuration the chip was created with.
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// Return the operations recorded so far.
+    pub fn operations(&self) -> &[SessionOp] {
+        &self.ops
+    }
+
+    /// Write a register and record the write. See `Opl3Chip::write_register`.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The register to write.
+    /// * `value`    - The value to write.
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        self.chip.write_register(register, value);
+        self.ops.push(SessionOp::Write { register, value });
+    }
+
+    /// Write a register through the buffered write queue and record the write. See
+    /// `Opl3Chip::write_register_buffered`.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The register to write.
+    /// * `value`    - The value to write.
+    pub fn write_register_buffered(&mut self, register: u16, value: u8) {
+        self.chip.write_register_buffered(register, value);
+        self.ops.push(SessionOp::WriteBuffered { register, value });
+    }
+
+    /// Set the rate ratio and record the change. See `Opl3Chip::set_rate_ratio`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ratio` - The output rate as a multiple of the nominal sample rate.
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.chip.set_rate_ratio(ratio);
+        self.ops.push(SessionOp::SetRateRatio(ratio));
+    }
+
+    /// Set the master clock and record the change. See `Opl3Chip::set_master_clock`.
+    ///
+    /// # Arguments
+    ///
+    /// * `hz` - The master clock frequency, in Hz.
+    pub fn set_master_clock(&mut self, hz: u32) {
+        self.chip.set_master_clock(hz);
+        self.ops.push(SessionOp::SetMasterClock(hz));
+    }
+
+    /// Reset the chip and record the reset. See `Opl3Chip::reset`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate to generate audio at.
+    pub fn reset(&mut self, sample_rate: u32) {
+        self.chip.reset(sample_rate);
+        self.ops.push(SessionOp::Reset(sample_rate));
+    }
+
+    /// Generate audio and record the size of the buffer. See `Opl3Chip::generate_stream`.
+    /// Buffers of more than 1048576 samples are generated and recorded in blocks of that size.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success or an `OplError` on
+    /// failure.
+    pub fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let mut frames = 0;
+        for block in buffer.chunks_mut(MAX_BLOCK_SAMPLES) {
+            frames += self.chip.generate_stream(block)?;
+            self.ops.push(SessionOp::Generate(block.len() as u32));
+        }
+        Ok(frames)
+    }
+
+    /// Serialize the session into the file format described in the module documentation.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let config = &self.config;
+        let mut bytes = Vec::with_capacity(32 + self.ops.len() * 4);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&config.sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&config.master_clock.to_le_bytes());
+        bytes.extend_from_slice(&config.rate_ratio.to_le_bytes());
+        bytes.push(config.idle_skip as u8);
+        bytes.extend_from_slice(&config.noise.to_le_bytes());
+        let version = &config.crate_version.as_bytes()[..config.crate_version.len().min(255)];
+        bytes.push(version.len() as u8);
+        bytes.extend_from_slice(version);
+        for op in &self.ops {
+            match *op {
+                SessionOp::Write { register, value } => {
+                    bytes.push(0);
+                    bytes.extend_from_slice(&register.to_le_bytes());
+                    bytes.push(value);
+                }
+                SessionOp::WriteBuffered { register, value } => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&register.to_le_bytes());
+                    bytes.push(value);
+                }
+                SessionOp::Generate(samples) => {
+                    bytes.push(2);
+                    bytes.extend_from_slice(&samples.to_le_bytes());
+                }
+                SessionOp::SetRateRatio(ratio) => {
+                    bytes.push(3);
+                    bytes.extend_from_slice(&ratio.to_le_bytes());
+                }
+                SessionOp::SetMasterClock(hz) => {
+                    bytes.push(4);
+                    bytes.extend_from_slice(&hz.to_le_bytes());
+                }
+                SessionOp::Reset(sample_rate) => {
+                    bytes.push(5);
+                    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl OplCore for SessionRecorder {
+    fn sample_rate(&self) -> u32 {
+        self.chip.sample_rate()
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        SessionRecorder::reset(self, sample_rate);
+    }
+
+    fn write_register(&mut self, reg: u16, value: u8) {
+        SessionRecorder::write_register(self, reg, value);
+    }
+
+    fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        SessionRecorder::generate_stream(self, buffer)
+    }
+}
+
+/// The `SessionReplayer` struct holds a recorded session, and reproduces its audio.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionReplayer {
+    config: SessionConfig,
+    ops: Vec<SessionOp>,
+}
+
+impl SessionReplayer {
+    /// Parse a session file produced by `SessionRecorder::to_bytes`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of the session file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the `SessionReplayer` on success or an `OplError` on failure.
+    /// The error is `OplError::InvalidFormat` if the file is truncated, generates a block of more
+    /// than 1048576 samples or is not a session file, and `OplError::InvalidSampleRate` if it
+    /// creates or resets the chip at a sample rate outside `Opl3Chip::MIN_SAMPLE_RATE` to
+    /// `Opl3Chip::MAX_SAMPLE_RATE`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::session::{SessionRecorder, SessionReplayer};
+    /// use opl3_rs::OplError;
+    ///
+    /// let mut recorder = SessionRecorder::new(44100);
+    /// recorder.generate_stream(&mut vec![0i16; 3 << 20]).unwrap();
+    /// let mut file = recorder.to_bytes();
+    /// assert_eq!(SessionReplayer::from_bytes(&file).unwrap().operations().len(), 3);
+    ///
+    /// // A block too large to replay is rejected.
+    /// file.push(2);
+    /// file.extend_from_slice(&u32::MAX.to_le_bytes());
+    /// assert!(matches!(
+    ///     SessionReplayer::from_bytes(&file),
+    ///     Err(OplError::InvalidFormat("session"))
+    /// ));
+    /// ```
+    pub fn from_bytes(data: &[u8]) -> Result<Self, OplError> {
+        let invalid = || OplError::InvalidFormat("session");
+        if data.len() < 27 || &data[0..4] != MAGIC || data[4] != VERSION {
+            return Err(invalid());
+        }
+        let u32_at = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        let f64_at = |offset: usize| {
+            data.get(offset..offset + 8)
+                .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+        };
+        let version_end = 27 + data[26] as usize;
+        let crate_version = data
+            .get(27..version_end)
+            .and_then(|b| std::str::from_utf8(b).ok())
+            .ok_or_else(invalid)?;
+        let config = SessionConfig {
+            sample_rate: u32_at(5).unwrap(),
+            master_clock: u32_at(9).unwrap(),
+            rate_ratio: f64_at(13).unwrap(),
+            idle_skip: data[21] != 0,
+            noise: u32_at(22).unwrap(),
+            crate_version: crate_version.to_string(),
+        };
+        Opl3Chip::check_sample_rate(config.sample_rate)?;
+
+        let mut ops = Vec::new();
+        let mut pos = version_end;
+        while pos < data.len() {
+            let operands = pos + 1;
+            let (op, size) = match data[pos] {
+                tag @ (0 | 1) => {
+                    let b = data.get(operands..operands + 3).ok_or_else(invalid)?;
+                    let register = u16::from_le_bytes([b[0], b[1]]);
+                    let value = b[2];
+                    if tag == 0 {
+                        (SessionOp::Write { register, value }, 3)
+                    } else {
+                        (SessionOp::WriteBuffered { register, value }, 3)
+                    }
+                }
+                2 => (
+                    SessionOp::Generate(u32_at(operands).ok_or_else(invalid)?),
+                    4,
+                ),
+                3 => (
+                    SessionOp::SetRateRatio(f64_at(operands).ok_or_else(invalid)?),
+                    8,
+                ),
+                4 => (
+                    SessionOp::SetMasterClock(u32_at(operands).ok_or_else(invalid)?),
+                    4,
+                ),
+                5 => (SessionOp::Reset(u32_at(operands).ok_or_else(invalid)?), 4),
+                _ => return Err(invalid()),
+            };
+            match op {
+                SessionOp::Generate(samples) if samples as usize > MAX_BLOCK_SAMPLES => {
+                    return Err(invalid());
+                }
+                SessionOp::Reset(sample_rate) => Opl3Chip::check_sample_rate(sample_rate)?,
+                _ => {}
+            }
+            ops.push(op);
+            pos = operands + size;
+        }
+        Ok(SessionReplayer { config, ops })
+    }
+
+    /// Return the configuration the recorded chip was created with.
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// Return the recorded operations.
+    pub fn operations(&self) -> &[SessionOp] {
+        &self.ops
+    }
+
+    /// Replay the session on a fresh chip.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the audio generated during the session on success or an
+    /// `OplError` on failure. The error is `OplError::InvalidState` if a fresh chip's noise
+    /// generator does not start in the recorded state, which means the session was recorded with
+    /// a different core and its audio cannot be reproduced.
+    pub fn replay(&self) -> Result<Vec<i16>, OplError> {
+        let mut audio = Vec::new();
+        self.replay_to(|samples| audio.extend_from_slice(samples))?;
+        Ok(audio)
+    }
+
+    /// Replay the session on a fresh chip, passing each block of audio to a callback as it is
+    /// generated, in the same block sizes as it was recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - A callback that receives each block of audio.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of samples generated on success or an `OplError` on
+    /// failure, as for `replay`.
+    pub fn replay_to<F: FnMut(&[i16])>(&self, mut sink: F) -> Result<usize, OplError> {
+        let mut chip = self.config.create_chip();
+        if chip.noise_state() != self.config.noise {
+            return Err(OplError::InvalidState);
+        }
+        let mut buffer = Vec::new();
+        let mut total = 0;
+        for op in &self.ops {
+            match *op {
+                SessionOp::Write { register, value } => chip.write_register(register, value),
+                SessionOp::WriteBuffered { register, value } => {
+                    chip.write_register_buffered(register, value)
+                }
+                SessionOp::Generate(samples) => {
+                    buffer.resize(samples as usize, 0);
+                    chip.generate_stream(&mut buffer)?;
+                    sink(&buffer);
+                    total += buffer.len();
+                }
+                SessionOp::SetRateRatio(ratio) => chip.set_rate_ratio(ratio),
+                SessionOp::SetMasterClock(hz) => chip.set_master_clock(hz),
+                SessionOp::Reset(sample_rate) => chip.reset(sample_rate),
+            }
+        }
+        Ok(total)
+    }
+}
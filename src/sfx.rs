@@ -0,0 +1,223 @@
+//! Borrowing channels from the music for sound effects.
+//!
+//! DOS games usually had a single FM chip for both music and sound effects. Their sound drivers
+//! took a channel away from the music when an effect played, and gave it back when the effect
+//! ended, with the music's instrument put back so that the next note on the channel sounded
+//! right. `SfxChannels` does the same on top of the `channel_allocator::ChannelAllocator` the
+//! music plays through.
+//!
+//! Borrowing a channel claims it from the allocator at the effect's priority, and saves the
+//! channel's registers. An effect only takes a channel from music notes, or other effects, of the
+//! same or a lower priority; if every channel is held at a higher priority, it is not played.
+//! Giving the channel back frees it in the allocator and writes the saved registers back, keyed
+//! off, as the music note that was playing has been stolen.
+//!
+//! The allocator must be for a single chip, the one `Opl3Device` that music and effects share.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::channel_allocator::ChannelAllocator;
+//! use opl3_rs::sfx::SfxChannels;
+//! use opl3_rs::{Opl3Device, OplRegisterFile};
+//!
+//! let mut device = Opl3Device::new(44100);
+//! let mut allocator = ChannelAllocator::new(1);
+//! // The music holds every channel, with a multiplier of 2 on the first operator.
+//! for _ in 0..18 {
+//!     allocator.claim(false, 1);
+//! }
+//! device.write_register(0x20, 0x02, OplRegisterFile::Primary, false);
+//!
+//! let mut sfx = SfxChannels::new();
+//! // A low priority effect is dropped, but a high priority one steals the oldest note.
+//! assert!(sfx.borrow(&mut allocator, &mut device, false, 0).is_none());
+//! let explosion = sfx.borrow(&mut allocator, &mut device, false, 5).unwrap();
+//! assert_eq!(explosion.channel, 0);
+//! assert_eq!(explosion.evicted, [Some(0), None]);
+//!
+//! device.write_register(0x20, 0x0F, OplRegisterFile::Primary, false);
+//! sfx.give_back(&mut allocator, &mut device, explosion.channel);
+//! assert_eq!(device.read_register(0x20, OplRegisterFile::Primary), 0x02);
+//! assert!(!sfx.is_borrowed(0));
+//! ```
+
+use crate::channel_allocator::{Allocation, ChannelAllocator, ChannelState, CHANNELS_PER_CHIP};
+use crate::registers::{reg_for_channel, reg_for_operator, ChannelParam, OperatorParam};
+use crate::{Opl3Device, OplRegisterFile};
+
+/// The register enabling 4-operator channels, in the secondary register file.
+const FOUR_OP_REGISTER: u8 = 0x04;
+
+/// The operator registers saved for each operator of a channel.
+const OPERATOR_PARAMS: [OperatorParam; 5] = [
+    OperatorParam::Characteristics,
+    OperatorParam::Level,
+    OperatorParam::AttackDecay,
+    OperatorParam::SustainRelease,
+    OperatorParam::Waveform,
+];
+
+/// The registers of one 2-operator channel, as they were before it was borrowed. The channel
+/// registers come last, ending with key-on and block.
+#[derive(Copy, Clone, Debug)]
+struct SavedChannel {
+    channel: usize,
+    registers: [(OplRegisterFile, u8, u8); 13],
+}
+
+/// The `SfxChannels` struct keeps track of the channels sound effects have borrowed from the
+/// music, and of the music's registers to put back.
+#[derive(Clone, Debug, Default)]
+pub struct SfxChannels {
+    /// The first channel of each effect playing, and whether it is a 4-operator effect.
+    active: Vec<(usize, bool)>,
+    saved: Vec<SavedChannel>,
+}
+
+impl SfxChannels {
+    /// Create a new `SfxChannels`, with no channels borrowed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Borrow a channel for a sound effect. The effect should then be written to the channel
+    /// returned, and the notes in `Allocation::evicted` stopped by the music engine; those may
+    /// include effects of a lower priority, which lose their channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `allocator` - The allocator the music plays through.
+    /// * `device`    - The device music and effects play on.
+    /// * `four_op`   - Whether the effect needs a 4-operator channel.
+    /// * `priority`  - The priority of the effect, compared to those of the music's notes.
+    ///
+    /// # Returns
+    ///
+    /// The channel borrowed, or `None` if every channel is held at a higher priority.
+    pub fn borrow(
+        &mut self,
+        allocator: &mut ChannelAllocator,
+        device: &mut Opl3Device,
+        four_op: bool,
+        priority: u8,
+    ) -> Option<Allocation> {
+        let channel = allocator.candidate(four_op)?;
+        let claimed: &[usize] = if four_op {
+            &[channel, channel + 3]
+        } else {
+            &[channel]
+        };
+        if channel >= CHANNELS_PER_CHIP
+            || claimed
+                .iter()
+                .any(|&c| matches!(allocator.state(c), ChannelState::Held(held) if held > priority))
+        {
+            return None;
+        }
+
+        let allocation = allocator.claim(four_op, priority)?;
+        // Effects that lost their channels give back the ones the new effect does not reuse.
+        for evicted in allocation.evicted.into_iter().flatten() {
+            if let Some(index) = self.active.iter().position(|&(c, _)| c == evicted) {
+                let (_, evicted_four_op) = self.active.swap_remove(index);
+                self.restore(device, evicted, claimed);
+                if evicted_four_op {
+                    self.restore(device, evicted + 3, claimed);
+                }
+            }
+        }
+        for &c in claimed {
+            if !self.saved.iter().any(|saved| saved.channel == c) {
+                self.saved.push(save(device, c));
+            }
+        }
+        self.active.push((channel, four_op));
+        write_four_op(allocator, device);
+        Some(allocation)
+    }
+
+    /// Give a borrowed channel back to the music, once its effect has finished. The music's
+    /// registers are written back, and the channel is freed in the allocator. Channels that are
+    /// not borrowed, or that another effect has stolen since, are left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `allocator` - The allocator the music plays through.
+    /// * `device`    - The device music and effects play on.
+    /// * `channel`   - The channel returned by `borrow`.
+    pub fn give_back(
+        &mut self,
+        allocator: &mut ChannelAllocator,
+        device: &mut Opl3Device,
+        channel: usize,
+    ) {
+        let Some(index) = self.active.iter().position(|&(c, _)| c == channel) else {
+            return;
+        };
+        let (_, four_op) = self.active.swap_remove(index);
+        allocator.free(channel);
+        write_four_op(allocator, device);
+        self.restore(device, channel, &[]);
+        if four_op {
+            self.restore(device, channel + 3, &[]);
+        }
+    }
+
+    /// Return `true` if a channel is borrowed by a sound effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, or either channel of a 4-operator effect.
+    pub fn is_borrowed(&self, channel: usize) -> bool {
+        self.active
+            .iter()
+            .any(|&(first, four_op)| first == channel || (four_op && first + 3 == channel))
+    }
+
+    /// Write the saved registers of a channel back, unless the channel is being reused.
+    fn restore(&mut self, device: &mut Opl3Device, channel: usize, reused: &[usize]) {
+        if reused.contains(&channel) {
+            return;
+        }
+        let Some(index) = self.saved.iter().position(|saved| saved.channel == channel) else {
+            return;
+        };
+        let saved = self.saved.swap_remove(index);
+        let last = saved.registers.len() - 1;
+        for (i, (file, reg, value)) in saved.registers.into_iter().enumerate() {
+            let value = if i == last { value & !0x20 } else { value };
+            device.write_register(reg, value, file, false);
+        }
+    }
+}
+
+/// Save the registers of a 2-operator channel.
+fn save(device: &Opl3Device, channel: usize) -> SavedChannel {
+    let index = channel as u8;
+    let operator_registers = (0..2)
+        .flat_map(|operator| OPERATOR_PARAMS.map(|param| reg_for_operator(index, operator, param)));
+    let channel_registers = [
+        ChannelParam::FnumLow,
+        ChannelParam::FeedbackConnection,
+        ChannelParam::KeyOnBlock,
+    ]
+    .map(|param| reg_for_channel(index, param));
+    let mut saved = SavedChannel {
+        channel,
+        registers: [(OplRegisterFile::Primary, 0, 0); 13],
+    };
+    let registers = operator_registers.chain(channel_registers).flatten();
+    for (slot, (file, reg)) in saved.registers.iter_mut().zip(registers) {
+        *slot = (file, reg, device.read_register(reg, file));
+    }
+    saved
+}
+
+/// Write the allocator's 4-operator pairs to register 0x104.
+fn write_four_op(allocator: &ChannelAllocator, device: &mut Opl3Device) {
+    let mask = allocator.four_op_mask(0);
+    if device.read_register(FOUR_OP_REGISTER, OplRegisterFile::Secondary) != mask {
+        device.write_register(FOUR_OP_REGISTER, mask, OplRegisterFile::Secondary, false);
+    }
+}
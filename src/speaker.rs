@@ -0,0 +1,132 @@
+//! Approximations of the sound of period hardware played through period speakers.
+//!
+//! Emulated output is a perfectly flat signal, while most people first heard OPL music through the
+//! output stage of an early 90s clone card and a small pair of desktop speakers. `SpeakerSimulation`
+//! is an `Effect` chaining a few `Biquad` filters from a `SpeakerPreset` to approximate that
+//! frequency response. The presets are voiced by ear rather than measured from specific hardware.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::effects::WithEffects;
+//! use opl3_rs::opl_core::OplCore;
+//! use opl3_rs::speaker::{SpeakerPreset, SpeakerSimulation};
+//!
+//! let speaker = SpeakerSimulation::new(SpeakerPreset::DesktopSpeakers, 44100);
+//! let mut chip = WithEffects::new(Opl3Chip::new(44100), speaker);
+//!
+//! chip.write_register(0xB0, 0x32);
+//! let mut buffer = [0i16; 1024 * 2];
+//! chip.generate_stream(&mut buffer).unwrap();
+//! ```
+
+use crate::effects::{Biquad, BiquadKind, Effect};
+
+/// The `SpeakerPreset` enum lists the output paths `SpeakerSimulation` can approximate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpeakerPreset {
+    /// The line output of a 1991 clone card: AC coupling removes the lowest bass, and the op-amp
+    /// output stage softens the top end.
+    CloneCard,
+    /// A clone card played through a small pair of unpowered desktop speakers: little bass below
+    /// 150Hz, a boxy resonance in the midrange, a forward upper midrange and no treble to speak of.
+    DesktopSpeakers,
+    /// A clone card played through a single small speaker, as in a cheap multimedia monitor: an
+    /// even narrower band, peaking around 2kHz.
+    MonitorSpeaker,
+}
+
+impl SpeakerPreset {
+    /// Return the filters making up the preset, in the order they are applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - The sample rate of the audio to filter.
+    pub fn filters(self, sample_rate: u32) -> Vec<Biquad> {
+        use BiquadKind::*;
+        let mut filters = vec![
+            Biquad::new(HighPass, sample_rate, 25.0, 0.707),
+            Biquad::new(LowPass, sample_rate, 12000.0, 0.707),
+            Biquad::new(HighShelf(-2.0), sample_rate, 6000.0, 0.707),
+        ];
+        match self {
+            SpeakerPreset::CloneCard => {}
+            SpeakerPreset::DesktopSpeakers => filters.extend([
+                Biquad::new(HighPass, sample_rate, 150.0, 0.9),
+                Biquad::new(Peaking(3.0), sample_rate, 450.0, 1.4),
+                Biquad::new(Peaking(4.0), sample_rate, 2500.0, 1.2),
+                Biquad::new(LowPass, sample_rate, 7000.0, 0.8),
+            ]),
+            SpeakerPreset::MonitorSpeaker => filters.extend([
+                Biquad::new(HighPass, sample_rate, 250.0, 1.0),
+                Biquad::new(Peaking(6.0), sample_rate, 2000.0, 1.0),
+                Biquad::new(LowPass, sample_rate, 5000.0, 0.9),
+            ]),
+        }
+        filters
+    }
+}
+
+/// The `SpeakerSimulation` struct filters audio to approximate a `SpeakerPreset`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpeakerSimulation {
+    preset: SpeakerPreset,
+    filters: Vec<Biquad>,
+}
+
+impl SpeakerSimulation {
+    /// Create a new `SpeakerSimulation`.
+    ///
+    /// # Arguments
+    ///
+    /// * `preset`      - The output path to approximate.
+    /// * `sample_rate` - The sample rate of the audio to filter.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::effects::Effect;
+    /// use opl3_rs::speaker::{SpeakerPreset, SpeakerSimulation};
+    ///
+    /// // A 50Hz tone loses most of its level through small desktop speakers.
+    /// let mut speaker = SpeakerSimulation::new(SpeakerPreset::DesktopSpeakers, 44100);
+    /// let mut buffer: Vec<i16> = (0..44100)
+    ///     .map(|i| ((i / 2) as f32 * 50.0 * std::f32::consts::TAU / 44100.0).sin())
+    ///     .map(|sample| (sample * 10000.0) as i16)
+    ///     .collect();
+    /// speaker.process(&mut buffer);
+    /// assert!(buffer[22050..].iter().all(|sample| sample.abs() < 2000));
+    /// ```
+    pub fn new(preset: SpeakerPreset, sample_rate: u32) -> Self {
+        SpeakerSimulation {
+            preset,
+            filters: preset.filters(sample_rate),
+        }
+    }
+
+    /// Return the preset being approximated.
+    pub fn preset(&self) -> SpeakerPreset {
+        self.preset
+    }
+}
+
+impl Effect for SpeakerSimulation {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for filter in self.filters.iter_mut() {
+            filter.process(buffer);
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            filter.reset();
+        }
+    }
+
+    fn set_dither(&mut self, enabled: bool) {
+        for filter in self.filters.iter_mut() {
+            filter.set_dither(enabled);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! A spectrum analyzer tap on the output, for the spectrum bars of player UIs.
+//!
+//! `SpectrumTap` is an `effects::Effect` that leaves audio unchanged. It mixes what passes
+//! through it to mono, and every time a window of samples has been collected, applies a Hann
+//! window and an FFT to it and publishes the magnitude of every frequency bin as a `Spectrum`.
+//! Windows are a fixed power of two long, from 256 to 4096 samples, and do not overlap, so a
+//! 1024 sample window at 44.1kHz gives a new spectrum about every 23ms, with bins about 43Hz
+//! apart.
+//!
+//! Spectra are published through a `triple_buffer`, so the UI thread reads the latest whenever
+//! it draws while the tap runs on the audio thread. Once the tap is created, analysis neither
+//! allocates nor copies the audio anywhere but its own window.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::calibration::test_tone;
+//! use opl3_rs::effects::Effect;
+//! use opl3_rs::spectrum::{SpectrumTap, WindowSize};
+//!
+//! let (mut tap, mut reader) = SpectrumTap::new(WindowSize::Size1024, 48000);
+//! let mut tone = test_tone(48000, 0, 1000.0, 0.1);
+//! tap.process(&mut tone);
+//!
+//! // In the UI thread:
+//! let spectrum = reader.read();
+//! assert_eq!(spectrum.magnitudes().len(), 512);
+//! let (peak, _) = spectrum
+//!     .magnitudes()
+//!     .iter()
+//!     .enumerate()
+//!     .max_by(|a, b| a.1.total_cmp(b.1))
+//!     .unwrap();
+//! assert!((spectrum.bin_hz(peak) - 1000.0).abs() < spectrum.bin_hz(1));
+//! ```
+
+use std::f64::consts::TAU;
+use std::fmt;
+
+use crate::effects::Effect;
+use crate::triple_buffer::{triple_buffer, Publisher, Reader};
+
+/// The number of bins of a spectrum of the largest window size.
+pub const MAX_BINS: usize = 2048;
+
+/// The `WindowSize` enum lists the window sizes a `SpectrumTap` can analyze, in samples.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WindowSize {
+    /// 256 samples, for 128 bins.
+    Size256,
+    /// 512 samples, for 256 bins.
+    Size512,
+    /// 1024 samples, for 512 bins.
+    #[default]
+    Size1024,
+    /// 2048 samples, for 1024 bins.
+    Size2048,
+    /// 4096 samples, for 2048 bins.
+    Size4096,
+}
+
+impl WindowSize {
+    /// Return the number of samples in a window.
+    pub fn samples(&self) -> usize {
+        match self {
+            WindowSize::Size256 => 256,
+            WindowSize::Size512 => 512,
+            WindowSize::Size1024 => 1024,
+            WindowSize::Size2048 => 2048,
+            WindowSize::Size4096 => 4096,
+        }
+    }
+
+    /// Return the number of frequency bins in a spectrum, half the number of samples.
+    pub fn bins(&self) -> usize {
+        self.samples() / 2
+    }
+}
+
+/// The `Spectrum` struct is the magnitude spectrum of one window of audio. It holds its bins
+/// inline, so that it can be published from the audio thread without allocating.
+#[derive(Copy, Clone)]
+pub struct Spectrum {
+    magnitudes: [f32; MAX_BINS],
+    bins: usize,
+    sample_rate: u32,
+    sequence: u64,
+}
+
+impl Spectrum {
+    /// Create an empty spectrum, with every bin at 0.
+    fn new(size: WindowSize, sample_rate: u32) -> Self {
+        Spectrum {
+            magnitudes: [0.0; MAX_BINS],
+            bins: size.bins(),
+            sample_rate,
+            sequence: 0,
+        }
+    }
+
+    /// Return the magnitude of each frequency bin, from 0Hz up to just below half the sample
+    /// rate. A full scale sine at the center of a bin has a magnitude of about 1.0.
+    pub fn magnitudes(&self) -> &[f32] {
+        &self.magnitudes[..self.bins]
+    }
+
+    /// Return the frequency at the center of a bin, in Hz.
+    ///
+    /// # Arguments
+    ///
+    /// * `bin` - The index of the bin.
+    pub fn bin_hz(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate as f32 / (self.bins * 2) as f32
+    }
+
+    /// Return the number of windows the tap had analyzed when it published this spectrum, so
+    /// that a reader can tell how many it missed. The empty spectrum a reader starts with is
+    /// numbered 0.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl fmt::Debug for Spectrum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spectrum")
+            .field("magnitudes", &self.magnitudes())
+            .field("sample_rate", &self.sample_rate)
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
+
+/// The `SpectrumTap` struct analyzes the audio passing through it, publishing a `Spectrum` for
+/// every window.
+pub struct SpectrumTap {
+    publisher: Publisher<Spectrum>,
+    spectrum: Spectrum,
+    /// The Hann window, scaled so that a full scale sine has a magnitude of 1.0.
+    window: Vec<f32>,
+    /// The mono samples of the window being collected.
+    input: Vec<f32>,
+    position: usize,
+    /// The cosine and sine of each twiddle factor of the FFT.
+    twiddles: Vec<(f32, f32)>,
+    real: Vec<f32>,
+    imaginary: Vec<f32>,
+}
+
+impl SpectrumTap {
+    /// Create a new `SpectrumTap`, and the reader its spectra are published to.
+    ///
+    /// # Arguments
+    ///
+    /// * `size`        - The size of the analysis window.
+    /// * `sample_rate` - The sample rate of the audio, used to label the bins.
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the tap, and the reader for the UI thread.
+    pub fn new(size: WindowSize, sample_rate: u32) -> (Self, Reader<Spectrum>) {
+        let len = size.samples();
+        let spectrum = Spectrum::new(size, sample_rate);
+        let (publisher, reader) = triple_buffer(spectrum);
+        let hann: Vec<f64> = (0..len)
+            .map(|i| 0.5 - 0.5 * (TAU * i as f64 / len as f64).cos())
+            .collect();
+        let scale = 2.0 / hann.iter().sum::<f64>();
+        let tap = SpectrumTap {
+            publisher,
+            spectrum,
+            window: hann.iter().map(|&w| (w * scale) as f32).collect(),
+            input: vec![0.0; len],
+            position: 0,
+            twiddles: (0..len / 2)
+                .map(|i| {
+                    let angle = -TAU * i as f64 / len as f64;
+                    (angle.cos() as f32, angle.sin() as f32)
+                })
+                .collect(),
+            real: vec![0.0; len],
+            imaginary: vec![0.0; len],
+        };
+        (tap, reader)
+    }
+
+    /// Return the most recent spectrum, on the thread the tap runs on.
+    pub fn spectrum(&self) -> &Spectrum {
+        &self.spectrum
+    }
+
+    /// Window and transform the collected samples, and publish their spectrum.
+    fn analyze(&mut self) {
+        let len = self.input.len();
+        let bits = len.trailing_zeros();
+        for (i, (&sample, &window)) in self.input.iter().zip(&self.window).enumerate() {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            self.real[j] = sample * window;
+            self.imaginary[j] = 0.0;
+        }
+
+        let mut half = 1;
+        while half < len {
+            let stride = len / (half * 2);
+            for start in (0..len).step_by(half * 2) {
+                for k in 0..half {
+                    let (cos, sin) = self.twiddles[k * stride];
+                    let (a, b) = (start + k, start + k + half);
+                    let re = self.real[b] * cos - self.imaginary[b] * sin;
+                    let im = self.real[b] * sin + self.imaginary[b] * cos;
+                    self.real[b] = self.real[a] - re;
+                    self.imaginary[b] = self.imaginary[a] - im;
+                    self.real[a] += re;
+                    self.imaginary[a] += im;
+                }
+            }
+            half *= 2;
+        }
+
+        let bins = self.spectrum.bins;
+        for (bin, magnitude) in self.spectrum.magnitudes[..bins].iter_mut().enumerate() {
+            *magnitude = self.real[bin].hypot(self.imaginary[bin]);
+        }
+        self.spectrum.sequence += 1;
+        self.publisher.publish(self.spectrum);
+    }
+}
+
+/// The tap leaves audio unchanged.
+impl Effect for SpectrumTap {
+    fn process(&mut self, buffer: &mut [i16]) {
+        for frame in buffer.chunks_exact(2) {
+            self.input[self.position] = (frame[0] as f32 + frame[1] as f32) / 65536.0;
+            self.position += 1;
+            if self.position == self.input.len() {
+                self.analyze();
+                self.position = 0;
+            }
+        }
+    }
+
+    /// Discard the samples collected toward the next window.
+    fn reset(&mut self) {
+        self.position = 0;
+    }
+}
@@ -0,0 +1,821 @@
+//! Snapshots of the internal state of `Opl3Chip` and `Opl3Device`.
+
+use crate::{bindings, Opl3Chip, Opl3Device, OplError, OplTimer, OPL_TICK_RATE};
+
+const CHIP_STATE_SIZE: usize = std::mem::size_of::<bindings::Opl3Chip>();
+
+/// The `Opl3ChipState` struct holds a complete copy of the internal state of a Nuked-OPL3
+/// instance, as captured by `Opl3Chip::snapshot`.
+///
+/// The Nuked-OPL3 chip struct contains pointers into itself, so a state cannot be restored by
+/// simply copying memory. The base address of the captured chip is recorded so that all internal
+/// pointers can be relocated when the state is restored into a different instance.
+#[derive(Clone)]
+pub struct Opl3ChipState {
+    base: usize,
+    data: Box<[u8]>,
+}
+
+impl Opl3ChipState {
+    /// Return the state of the rhythm mode noise generator captured in this state, as returned by
+    /// `Opl3Chip::noise_state`. It is restored along with the rest of the chip, so that snare,
+    /// hi-hat and cymbal output is bit-exact after a restore.
+    pub fn noise(&self) -> u32 {
+        let offset = std::mem::offset_of!(bindings::Opl3Chip, noise);
+        u32::from_ne_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+}
+
+impl Opl3Chip {
+    /// Capture the complete internal state of the Nuked-OPL3 instance, including envelope,
+    /// phase, LFO and noise generator state and the contents of the buffered write queue.
+    ///
+    /// # Returns
+    ///
+    /// An `Opl3ChipState` that can later be passed to `restore`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let state = chip.snapshot();
+    /// chip.write_register(0x20, 0x01);
+    /// chip.restore(&state);
+    /// ```
+    pub fn snapshot(&self) -> Opl3ChipState {
+        let data = unsafe { std::slice::from_raw_parts(self.chip as *const u8, CHIP_STATE_SIZE) };
+        Opl3ChipState {
+            base: self.chip as usize,
+            data: data.into(),
+        }
+    }
+
+    /// Restore the internal state of the Nuked-OPL3 instance from a previously captured
+    /// `Opl3ChipState`. The state does not need to have been captured from the same `Opl3Chip`.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The chip state to restore.
+    pub fn restore(&mut self, state: &Opl3ChipState) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                state.data.as_ptr(),
+                self.chip as *mut u8,
+                CHIP_STATE_SIZE,
+            );
+            self.relocate(state.base);
+        }
+    }
+
+    /// Rewrite every internal pointer of the chip, which currently point into a chip that lived
+    /// at `old_base`, to point into this chip instead.
+    unsafe fn relocate(&mut self, old_base: usize) {
+        let base = self.chip as *mut u8;
+        let chip = &mut *self.chip;
+        for slot in chip.slot.iter_mut() {
+            slot.channel = rebase(base, old_base, slot.channel);
+            slot.chip = rebase(base, old_base, slot.chip);
+            slot.mod_ = rebase(base, old_base, slot.mod_);
+            slot.trem = rebase(base, old_base, slot.trem);
+        }
+        for channel in chip.channel.iter_mut() {
+            for slotz in channel.slotz.iter_mut() {
+                *slotz = rebase(base, old_base, *slotz);
+            }
+            channel.pair = rebase(base, old_base, channel.pair);
+            channel.chip = rebase(base, old_base, channel.chip);
+            for out in channel.out.iter_mut() {
+                *out = rebase(base, old_base, *out);
+            }
+        }
+    }
+}
+
+/// Move a pointer that pointed into a chip at `old_base` to the same offset within the chip at
+/// `base`. Null pointers (such as the `pair` of channels that have no 4-op partner) are kept.
+fn rebase<T>(base: *mut u8, old_base: usize, ptr: *mut T) -> *mut T {
+    if ptr.is_null() {
+        ptr
+    } else {
+        base.wrapping_add((ptr as usize).wrapping_sub(old_base))
+            .cast()
+    }
+}
+
+/// The `Opl3DeviceState` struct holds a complete copy of the state of an `Opl3Device`, including
+/// its register file, timers and the internal Nuked-OPL3 instance.
+#[derive(Clone)]
+pub struct Opl3DeviceState {
+    addr_reg: [u8; 2],
+    sample_rate: u32,
+    registers: [[u8; 256]; 2],
+    timers: [OplTimer; 2],
+    samples_fpart: f64,
+    usec_accumulator: f64,
+    chip: Opl3ChipState,
+}
+
+impl Opl3DeviceState {
+    /// Return the register files captured in this state, primary first. Registers that were
+    /// never written read as 0.
+    pub fn registers(&self) -> &[[u8; 256]; 2] {
+        &self.registers
+    }
+
+    /// Return the state of the rhythm mode noise generator captured in this state.
+    pub fn noise(&self) -> u32 {
+        self.chip.noise()
+    }
+
+    /// Serialize the device state into a flat byte vector.
+    ///
+    /// The device fields are stored little-endian, but the chip is stored as a raw copy of the
+    /// Nuked-OPL3 struct, so the result is only meaningful on the platform that produced it.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(DEVICE_STATE_HEADER_SIZE + CHIP_STATE_SIZE);
+        bytes.extend_from_slice(&self.addr_reg);
+        bytes.extend_from_slice(&self.sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.registers[0]);
+        bytes.extend_from_slice(&self.registers[1]);
+        for timer in &self.timers {
+            write_timer(&mut bytes, timer);
+        }
+        bytes.extend_from_slice(&self.samples_fpart.to_le_bytes());
+        bytes.extend_from_slice(&self.usec_accumulator.to_le_bytes());
+        bytes.extend_from_slice(&(self.chip.base as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.chip.data);
+        bytes
+    }
+
+    /// Deserialize a device state previously produced by `to_bytes`.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, OplError> {
+        if bytes.len() != DEVICE_STATE_HEADER_SIZE + CHIP_STATE_SIZE {
+            return Err(OplError::InvalidState);
+        }
+        let mut reader = ByteReader { bytes, pos: 0 };
+
+        let addr_reg = [reader.u8(), reader.u8()];
+        let sample_rate = u32::from_le_bytes(reader.array());
+        let registers = [reader.array(), reader.array()];
+        let timers = [read_timer(&mut reader), read_timer(&mut reader)];
+        let samples_fpart = f64::from_le_bytes(reader.array());
+        let usec_accumulator = f64::from_le_bytes(reader.array());
+        let base = u64::from_le_bytes(reader.array()) as usize;
+        let data = bytes[reader.pos..].into();
+
+        Ok(Opl3DeviceState {
+            addr_reg,
+            sample_rate,
+            registers,
+            timers,
+            samples_fpart,
+            usec_accumulator,
+            chip: Opl3ChipState { base, data },
+        })
+    }
+}
+
+/// Size of the serialized `Opl3DeviceState` fields that precede the raw chip data.
+const DEVICE_STATE_HEADER_SIZE: usize = 2 + 4 + 512 + 2 * TIMER_SIZE + 8 + 8 + 8;
+
+/// Size of a serialized `OplTimer`.
+const TIMER_SIZE: usize = 17;
+
+/// Serialize a timer, little-endian.
+fn write_timer(bytes: &mut Vec<u8>, timer: &OplTimer) {
+    bytes.push(timer.enabled as u8);
+    bytes.push(timer.masked as u8);
+    bytes.extend_from_slice(&timer.rate.to_le_bytes());
+    bytes.push(timer.preset);
+    bytes.push(timer.counter);
+    bytes.extend_from_slice(&timer.usec_accumulator.to_le_bytes());
+    bytes.push(timer.elapsed as u8);
+}
+
+/// Deserialize a timer written by `write_timer`.
+fn read_timer(reader: &mut ByteReader) -> OplTimer {
+    OplTimer {
+        enabled: reader.u8() != 0,
+        masked: reader.u8() != 0,
+        rate: u32::from_le_bytes(reader.array()),
+        preset: reader.u8(),
+        counter: reader.u8(),
+        usec_accumulator: f64::from_le_bytes(reader.array()),
+        elapsed: reader.u8() != 0,
+    }
+}
+
+/// Return whether an accumulator is finite and in the range `0.0..limit`.
+fn in_range(value: f64, limit: f64) -> bool {
+    (0.0..limit).contains(&value)
+}
+
+/// A minimal cursor over a byte slice whose length has already been validated.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ByteReader<'_> {
+    fn u8(&mut self) -> u8 {
+        self.pos += 1;
+        self.bytes[self.pos - 1]
+    }
+
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut array = [0; N];
+        array.copy_from_slice(&self.bytes[self.pos..self.pos + N]);
+        self.pos += N;
+        array
+    }
+}
+
+impl Opl3Device {
+    /// Capture the complete state of the `Opl3Device`, suitable for implementing save states or
+    /// rewind in an emulator. Device statistics are not part of the captured state.
+    ///
+    /// # Returns
+    ///
+    /// An `Opl3DeviceState` that can later be passed to `restore`.
+    pub fn snapshot(&self) -> Opl3DeviceState {
+        Opl3DeviceState {
+            addr_reg: self.addr_reg,
+            sample_rate: self.sample_rate,
+            registers: self.registers,
+            timers: self.timers.clone(),
+            samples_fpart: self.samples_fpart,
+            usec_accumulator: self.usec_accumulator,
+            chip: self.inner_chip.snapshot(),
+        }
+    }
+
+    /// Restore the state of the `Opl3Device` from a previously captured `Opl3DeviceState`.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The device state to restore.
+    pub fn restore(&mut self, state: &Opl3DeviceState) {
+        self.addr_reg = state.addr_reg;
+        self.sample_rate = state.sample_rate;
+        self.registers = state.registers;
+        self.timers = state.timers.clone();
+        self.samples_fpart = state.samples_fpart;
+        self.usec_accumulator = state.usec_accumulator;
+        self.inner_chip.restore(&state.chip);
+    }
+}
+
+/// The magic bytes at the start of a savestate.
+const SAVESTATE_MAGIC: &[u8; 4] = b"OPST";
+
+/// Size of the device fields of a savestate: the address latches, sample rate, timers and
+/// fractional accumulators.
+const SAVESTATE_DEVICE_SIZE: usize = 2 + 4 + 2 * TIMER_SIZE + 8 + 8;
+
+/// The `StateRestore` enum reports how `Opl3Device::load_state` restored a savestate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StateRestore {
+    /// The core internals were restored, so the chip continues exactly where it was saved.
+    Exact,
+    /// The core internals were saved by a different core, so the chip was reset and the saved
+    /// register file written to it. Notes keep playing with the right instruments and pitches, but
+    /// envelopes, phases and the LFOs restart, and buffered writes that were pending are lost.
+    Registers,
+}
+
+/// The identifier of the core internals saved by this build. The internals are serialized field
+/// by field, so they only depend on the core version.
+const CORE_ID: &str = "nuked-opl3 1.8 fields";
+
+/// Return the registers in the order they are written when rebuilding a chip from a register
+/// file: the mode registers first, so that the rest land in the right mode, and the key-on and
+/// rhythm registers last, so that notes start with their instruments in place.
+pub(crate) fn replay_order() -> impl Iterator<Item = u16> {
+    let last = |reg: u16| reg == 0xBD || (0xB0..=0xB8).contains(&(reg & 0xFF));
+    let first = [0x105, 0x104];
+    let middle = (0..0x200).filter(move |reg| !first.contains(reg) && !last(*reg));
+    first
+        .into_iter()
+        .chain(middle)
+        .chain((0..0x200).filter(move |&reg| last(reg)))
+}
+
+impl Opl3Device {
+    /// The version of the savestate format written by `save_state`.
+    pub const SAVESTATE_VERSION: u8 = 2;
+
+    /// Save the complete state of the device in a versioned savestate, for storing on disk. Unlike
+    /// `snapshot`, a savestate can still be loaded by later versions of this crate.
+    ///
+    /// Savestates are portable: one saved on any platform loads exactly on any other.
+    ///
+    /// Savestate layout, all values little-endian:
+    ///
+    /// | Size | Description                                                          |
+    /// |------|----------------------------------------------------------------------|
+    /// | 4    | Magic, `b"OPST"`                                                     |
+    /// | 1    | Format version, currently 2                                          |
+    /// | 1    | Length of the core identifier                                        |
+    /// | n    | Core identifier, naming the core and the layout of its internals     |
+    /// | 2    | Address latches of the primary and secondary register files          |
+    /// | 4    | Sample rate                                                          |
+    /// | 34   | Timers 1 and 2: enabled, masked, rate (u32), preset, counter,        |
+    /// |      | accumulator (f64) and expired flag                                   |
+    /// | 8    | Fractional sample accumulator, as an f64                             |
+    /// | 8    | Timer tick accumulator, as an f64                                    |
+    /// | 512  | Register file, primary then secondary                                |
+    /// | 4    | Length of the core internals                                         |
+    /// | m    | Core internals                                                       |
+    ///
+    /// In version 2, the core internals are the fields of the Nuked-OPL3 struct in declaration
+    /// order: the channels, the slots, then the chip's own fields. Internal pointers are replaced
+    /// by a byte naming what they point to. In version 1, the core internals were the address the
+    /// chip was saved from, as a u64, and a raw copy of the struct. Those internals are never
+    /// restored, as their pointers cannot be trusted, so version 1 savestates are always rebuilt
+    /// from the registers.
+    ///
+    /// # Returns
+    ///
+    /// The savestate, to be passed to `load_state`.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(64 + 512 + INTERNALS_SIZE);
+        bytes.extend_from_slice(SAVESTATE_MAGIC);
+        bytes.push(Self::SAVESTATE_VERSION);
+        bytes.push(CORE_ID.len() as u8);
+        bytes.extend_from_slice(CORE_ID.as_bytes());
+        bytes.extend_from_slice(&self.addr_reg);
+        bytes.extend_from_slice(&self.sample_rate.to_le_bytes());
+        for timer in &self.timers {
+            write_timer(&mut bytes, timer);
+        }
+        bytes.extend_from_slice(&self.samples_fpart.to_le_bytes());
+        bytes.extend_from_slice(&self.usec_accumulator.to_le_bytes());
+        bytes.extend_from_slice(&self.registers[0]);
+        bytes.extend_from_slice(&self.registers[1]);
+        bytes.extend_from_slice(&(INTERNALS_SIZE as u32).to_le_bytes());
+        self.inner_chip.write_internals(&mut bytes);
+        bytes
+    }
+
+    /// Load a savestate written by `save_state`, by this or an earlier version of this crate.
+    ///
+    /// If the savestate's core identifier matches this build's, the core internals are restored
+    /// and the chip continues exactly where it was saved. If not, because the savestate was saved
+    /// with a different version of Nuked-OPL3, or is a version 1 savestate, whose internals are a
+    /// raw copy of the Nuked-OPL3 struct, the core internals cannot be trusted. The device fields
+    /// are still restored, and the chip is rebuilt from the saved register file instead, as
+    /// described by `StateRestore::Registers`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The savestate.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either how the state was restored on success or an `OplError` on
+    /// failure. The error is `OplError::InvalidState` if the data is not a savestate, is
+    /// truncated, holds accumulators the device could not have saved, or was written by a later
+    /// version of the format, and `OplError::InvalidSampleRate` if its sample rate is outside
+    /// `Opl3Chip::MIN_SAMPLE_RATE` to `Opl3Chip::MAX_SAMPLE_RATE`.
+    /// The device is unchanged on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::{Opl3Device, OplError, OplRegisterFile, StateRestore};
+    ///
+    /// let mut device = Opl3Device::new(44100);
+    /// device.write_register(0xA0, 0x98, OplRegisterFile::Primary, false);
+    /// device.write_register(0xB0, 0x31, OplRegisterFile::Primary, false);
+    /// device.generate_samples(&mut [0i16; 301 * 2]).unwrap();
+    /// let mut saved = device.save_state();
+    ///
+    /// let mut loaded = Opl3Device::new(44100);
+    /// assert_eq!(loaded.load_state(&saved).unwrap(), StateRestore::Exact);
+    /// let mut expected = vec![0i16; 512 * 2];
+    /// let mut actual = vec![0i16; 512 * 2];
+    /// device.generate_samples(&mut expected).unwrap();
+    /// loaded.generate_samples(&mut actual).unwrap();
+    /// assert_eq!(expected, actual);
+    ///
+    /// // Version 1 savestates are always rebuilt from their registers.
+    /// let mut old = saved.clone();
+    /// old[4] = 1;
+    /// assert_eq!(loaded.load_state(&old).unwrap(), StateRestore::Registers);
+    ///
+    /// // A savestate from another core is rebuilt from its registers.
+    /// saved[6] = b'?';
+    /// let mut rebuilt = Opl3Device::new(44100);
+    /// assert_eq!(rebuilt.load_state(&saved).unwrap(), StateRestore::Registers);
+    /// assert_eq!(rebuilt.snapshot().registers()[0][0xB0], 0x31);
+    /// assert!(rebuilt.load_state(&saved[..100]).is_err());
+    ///
+    /// // The sample rate follows the core id and the address registers.
+    /// let rate = 6 + saved[5] as usize + 2;
+    /// saved[rate..rate + 4].copy_from_slice(&0u32.to_le_bytes());
+    /// assert!(matches!(rebuilt.load_state(&saved), Err(OplError::InvalidSampleRate(0))));
+    ///
+    /// // So do the timers, then the sample and timer tick accumulators.
+    /// saved[rate..rate + 4].copy_from_slice(&44100u32.to_le_bytes());
+    /// let fpart = rate + 4 + 34;
+    /// saved[fpart..fpart + 8].copy_from_slice(&f64::NAN.to_le_bytes());
+    /// assert!(matches!(rebuilt.load_state(&saved), Err(OplError::InvalidState)));
+    /// ```
+    pub fn load_state(&mut self, data: &[u8]) -> Result<StateRestore, OplError> {
+        let field =
+            |start: usize, len: usize| data.get(start..start + len).ok_or(OplError::InvalidState);
+        if field(0, 4)? != SAVESTATE_MAGIC {
+            return Err(OplError::InvalidState);
+        }
+        let version = field(4, 1)?[0];
+        if version == 0 || version > Self::SAVESTATE_VERSION {
+            return Err(OplError::InvalidState);
+        }
+        let core_id_len = field(5, 1)?[0] as usize;
+        let core_id = field(6, core_id_len)?;
+        let device_start = 6 + core_id_len;
+        let internals_len_start = device_start + SAVESTATE_DEVICE_SIZE + 512;
+        let internals_len = u32::from_le_bytes(field(internals_len_start, 4)?.try_into().unwrap());
+        let internals = field(internals_len_start + 4, internals_len as usize)?;
+
+        let mut reader = ByteReader {
+            bytes: field(device_start, SAVESTATE_DEVICE_SIZE + 512)?,
+            pos: 0,
+        };
+        let addr_reg = [reader.u8(), reader.u8()];
+        let sample_rate = u32::from_le_bytes(reader.array());
+        let timers = [read_timer(&mut reader), read_timer(&mut reader)];
+        let samples_fpart = f64::from_le_bytes(reader.array());
+        let usec_accumulator = f64::from_le_bytes(reader.array());
+        let registers = [reader.array(), reader.array()];
+        Opl3Chip::check_sample_rate(sample_rate)?;
+        // The accumulators are drained in loops, so values the device could not have saved would
+        // stall it or leave it counting forever.
+        let timers_valid = timers
+            .iter()
+            .all(|timer| timer.rate > 0 && in_range(timer.usec_accumulator, timer.rate as f64));
+        if !timers_valid
+            || !in_range(samples_fpart, 1.0)
+            || !in_range(usec_accumulator, OPL_TICK_RATE)
+        {
+            return Err(OplError::InvalidState);
+        }
+
+        self.addr_reg = addr_reg;
+        self.sample_rate = sample_rate;
+        self.registers = registers;
+        self.timers = timers;
+        self.samples_fpart = samples_fpart;
+        self.usec_accumulator = usec_accumulator;
+        if version > 1
+            && core_id == CORE_ID.as_bytes()
+            && self
+                .inner_chip
+                .read_internals(sample_rate, internals)
+                .is_ok()
+        {
+            return Ok(StateRestore::Exact);
+        }
+        self.inner_chip.reset(sample_rate);
+        for reg in replay_order() {
+            let value = self.registers[reg as usize >> 8][reg as usize & 0xFF];
+            self.inner_chip.write_register(reg, value);
+        }
+        Ok(StateRestore::Registers)
+    }
+}
+
+/// Size of the serialized fields of a Nuked-OPL3 channel.
+const CHANNEL_SIZE: usize = 4 + 1 + 2 + 1 + 1 + 1 + 1 + 1 + 4 * 2 + 1;
+/// Size of the serialized fields of a Nuked-OPL3 slot.
+const SLOT_SIZE: usize = 2 + 2 + 1 + 2 + 2 + 2 + 4 + 1 + 12 + 4 + 4 + 2 + 1;
+/// Size of a serialized entry of the Nuked-OPL3 write buffer.
+const WRITEBUF_ENTRY_SIZE: usize = 8 + 2 + 1;
+/// Size of the serialized core internals of a version 2 savestate.
+const INTERNALS_SIZE: usize = 18 * CHANNEL_SIZE
+    + 36 * SLOT_SIZE
+    + 2
+    + 8
+    + 12
+    + 4
+    + 2
+    + 4 * 4
+    + 6
+    + 4
+    + 4
+    + 4 * 2
+    + 4 * 2
+    + 8
+    + 4
+    + 4
+    + 8
+    + WRITEBUF_SIZE * WRITEBUF_ENTRY_SIZE;
+/// The number of entries in the Nuked-OPL3 write buffer.
+const WRITEBUF_SIZE: usize = 1024;
+
+/// The pointer code of a slot's `out` field; add the slot index.
+const TARGET_OUT: u8 = 0;
+/// The pointer code of a slot's `fbmod` field; add the slot index.
+const TARGET_FBMOD: u8 = 36;
+/// The pointer code of the chip's `tremolo` field.
+const TARGET_TREMOLO: u8 = 0xFE;
+/// The pointer code of the chip's `zeromod` field.
+const TARGET_ZEROMOD: u8 = 0xFF;
+
+/// A field of the Nuked-OPL3 struct, serialized little-endian.
+trait Field: Sized {
+    fn put(self, bytes: &mut Vec<u8>);
+    fn take(reader: &mut ByteReader) -> Self;
+}
+
+macro_rules! impl_field {
+    ($($ty:ty),*) => {
+        $(impl Field for $ty {
+            fn put(self, bytes: &mut Vec<u8>) {
+                bytes.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn take(reader: &mut ByteReader) -> Self {
+                <$ty>::from_le_bytes(reader.array())
+            }
+        })*
+    };
+}
+
+impl_field!(u8, u16, u32, u64, i16, i32);
+
+impl Opl3Chip {
+    /// Serialize the fields of the Nuked-OPL3 struct for a version 2 savestate.
+    fn write_internals(&self, bytes: &mut Vec<u8>) {
+        let start = bytes.len();
+        // Safety: the chip is only read, and is not being written while `&self` is held.
+        let chip = unsafe { &*self.chip };
+        let zeromod = &chip.zeromod as *const i16;
+        let target = |ptr: *const i16| {
+            chip.slot
+                .iter()
+                .position(|slot| std::ptr::eq(&slot.out, ptr))
+                .map(|index| TARGET_OUT + index as u8)
+                .or_else(|| {
+                    chip.slot
+                        .iter()
+                        .position(|slot| std::ptr::eq(&slot.fbmod, ptr))
+                        .map(|index| TARGET_FBMOD + index as u8)
+                })
+                .unwrap_or_else(|| {
+                    debug_assert!(std::ptr::eq(ptr, zeromod));
+                    TARGET_ZEROMOD
+                })
+        };
+        for channel in &chip.channel {
+            for out in channel.out {
+                target(out).put(bytes);
+            }
+            channel.chtype.put(bytes);
+            channel.f_num.put(bytes);
+            channel.block.put(bytes);
+            channel.fb.put(bytes);
+            channel.con.put(bytes);
+            channel.alg.put(bytes);
+            channel.ksv.put(bytes);
+            channel.cha.put(bytes);
+            channel.chb.put(bytes);
+            channel.chc.put(bytes);
+            channel.chd.put(bytes);
+            channel.ch_num.put(bytes);
+        }
+        for slot in &chip.slot {
+            slot.out.put(bytes);
+            slot.fbmod.put(bytes);
+            target(slot.mod_).put(bytes);
+            slot.prout.put(bytes);
+            slot.eg_rout.put(bytes);
+            slot.eg_out.put(bytes);
+            slot.eg_inc.put(bytes);
+            slot.eg_gen.put(bytes);
+            slot.eg_rate.put(bytes);
+            slot.eg_ksl.put(bytes);
+            let trem = if std::ptr::eq(slot.trem, &chip.tremolo) {
+                TARGET_TREMOLO
+            } else {
+                TARGET_ZEROMOD
+            };
+            trem.put(bytes);
+            for reg in [
+                slot.reg_vib,
+                slot.reg_type,
+                slot.reg_ksr,
+                slot.reg_mult,
+                slot.reg_ksl,
+                slot.reg_tl,
+                slot.reg_ar,
+                slot.reg_dr,
+                slot.reg_sl,
+                slot.reg_rr,
+                slot.reg_wf,
+                slot.key,
+            ] {
+                reg.put(bytes);
+            }
+            slot.pg_reset.put(bytes);
+            slot.pg_phase.put(bytes);
+            slot.pg_phase_out.put(bytes);
+            slot.slot_num.put(bytes);
+        }
+        chip.timer.put(bytes);
+        chip.eg_timer.put(bytes);
+        for value in [
+            chip.eg_timerrem,
+            chip.eg_state,
+            chip.eg_add,
+            chip.eg_timer_lo,
+            chip.newm,
+            chip.nts,
+            chip.rhy,
+            chip.vibpos,
+            chip.vibshift,
+            chip.tremolo,
+            chip.tremolopos,
+            chip.tremoloshift,
+        ] {
+            value.put(bytes);
+        }
+        chip.noise.put(bytes);
+        chip.zeromod.put(bytes);
+        for mix in chip.mixbuff {
+            mix.put(bytes);
+        }
+        for bit in [
+            chip.rm_hh_bit2,
+            chip.rm_hh_bit3,
+            chip.rm_hh_bit7,
+            chip.rm_hh_bit8,
+            chip.rm_tc_bit3,
+            chip.rm_tc_bit5,
+        ] {
+            bit.put(bytes);
+        }
+        chip.rateratio.put(bytes);
+        chip.samplecnt.put(bytes);
+        for sample in chip.oldsamples.into_iter().chain(chip.samples) {
+            sample.put(bytes);
+        }
+        chip.writebuf_samplecnt.put(bytes);
+        chip.writebuf_cur.put(bytes);
+        chip.writebuf_last.put(bytes);
+        chip.writebuf_lasttime.put(bytes);
+        for entry in &chip.writebuf {
+            entry.time.put(bytes);
+            entry.reg.put(bytes);
+            entry.data.put(bytes);
+        }
+        debug_assert_eq!(bytes.len() - start, INTERNALS_SIZE);
+    }
+
+    /// Restore the fields of the Nuked-OPL3 struct from a version 2 savestate. The chip is reset
+    /// first, which sets up the pointers that never change. Fields that the core uses to index
+    /// its tables are masked to their hardware widths, so that a corrupt savestate cannot make it
+    /// read out of bounds.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either nothing on success or an `OplError::InvalidState` if the
+    /// internals are the wrong size, hold an unknown pointer code or a resampler position the
+    /// core could not have saved, in which case the chip is left partly restored and should be
+    /// reset.
+    fn read_internals(&mut self, sample_rate: u32, bytes: &[u8]) -> Result<(), OplError> {
+        if bytes.len() != INTERNALS_SIZE {
+            return Err(OplError::InvalidState);
+        }
+        self.reset(sample_rate);
+        let mut reader = ByteReader { bytes, pos: 0 };
+        let chip = self.chip;
+        // Safety: the pointers are all derived from `chip`, which stays valid and is not
+        // otherwise borrowed while `&mut self` is held.
+        unsafe {
+            let target = move |code: u8| -> Result<*mut i16, OplError> {
+                match code {
+                    TARGET_OUT..=35 => Ok(std::ptr::addr_of_mut!(
+                        (*chip).slot[(code - TARGET_OUT) as usize].out
+                    )),
+                    TARGET_FBMOD..=71 => Ok(std::ptr::addr_of_mut!(
+                        (*chip).slot[(code - TARGET_FBMOD) as usize].fbmod
+                    )),
+                    TARGET_ZEROMOD => Ok(std::ptr::addr_of_mut!((*chip).zeromod)),
+                    _ => Err(OplError::InvalidState),
+                }
+            };
+            for index in 0..18 {
+                let mut out = [std::ptr::null_mut(); 4];
+                for out in out.iter_mut() {
+                    *out = target(Field::take(&mut reader))?;
+                }
+                let channel = &mut (*chip).channel[index];
+                channel.out = out;
+                channel.chtype = Field::take(&mut reader);
+                channel.f_num = u16::take(&mut reader) & 0x3FF;
+                channel.block = Field::take(&mut reader);
+                channel.fb = Field::take(&mut reader);
+                channel.con = Field::take(&mut reader);
+                channel.alg = Field::take(&mut reader);
+                channel.ksv = Field::take(&mut reader);
+                channel.cha = Field::take(&mut reader);
+                channel.chb = Field::take(&mut reader);
+                channel.chc = Field::take(&mut reader);
+                channel.chd = Field::take(&mut reader);
+                channel.ch_num = Field::take(&mut reader);
+            }
+            for index in 0..36 {
+                let out = Field::take(&mut reader);
+                let fbmod = Field::take(&mut reader);
+                let mod_ = target(Field::take(&mut reader))?;
+                let slot = &mut (*chip).slot[index];
+                slot.out = out;
+                slot.fbmod = fbmod;
+                slot.mod_ = mod_;
+                slot.prout = Field::take(&mut reader);
+                slot.eg_rout = Field::take(&mut reader);
+                slot.eg_out = Field::take(&mut reader);
+                slot.eg_inc = Field::take(&mut reader);
+                slot.eg_gen = Field::take(&mut reader);
+                slot.eg_rate = Field::take(&mut reader);
+                slot.eg_ksl = Field::take(&mut reader);
+                slot.trem = match reader.u8() {
+                    TARGET_TREMOLO => std::ptr::addr_of_mut!((*chip).tremolo),
+                    TARGET_ZEROMOD => std::ptr::addr_of_mut!((*chip).zeromod).cast(),
+                    _ => return Err(OplError::InvalidState),
+                };
+                slot.reg_vib = reader.u8();
+                slot.reg_type = reader.u8();
+                slot.reg_ksr = reader.u8();
+                slot.reg_mult = reader.u8() & 0x0F;
+                slot.reg_ksl = reader.u8() & 0x03;
+                slot.reg_tl = reader.u8();
+                slot.reg_ar = reader.u8();
+                slot.reg_dr = reader.u8();
+                slot.reg_sl = reader.u8();
+                slot.reg_rr = reader.u8();
+                slot.reg_wf = reader.u8() & 0x07;
+                slot.key = reader.u8();
+                slot.pg_reset = Field::take(&mut reader);
+                slot.pg_phase = Field::take(&mut reader);
+                slot.pg_phase_out = Field::take(&mut reader);
+                slot.slot_num = Field::take(&mut reader);
+            }
+            let chip = &mut *chip;
+            chip.timer = Field::take(&mut reader);
+            chip.eg_timer = Field::take(&mut reader);
+            chip.eg_timerrem = reader.u8();
+            chip.eg_state = reader.u8();
+            chip.eg_add = reader.u8();
+            chip.eg_timer_lo = reader.u8() & 0x03;
+            chip.newm = reader.u8();
+            chip.nts = reader.u8();
+            chip.rhy = reader.u8();
+            chip.vibpos = reader.u8();
+            chip.vibshift = reader.u8();
+            chip.tremolo = reader.u8();
+            chip.tremolopos = reader.u8();
+            chip.tremoloshift = reader.u8();
+            chip.noise = Field::take(&mut reader);
+            chip.zeromod = Field::take(&mut reader);
+            for mix in chip.mixbuff.iter_mut() {
+                *mix = Field::take(&mut reader);
+            }
+            chip.rm_hh_bit2 = reader.u8();
+            chip.rm_hh_bit3 = reader.u8();
+            chip.rm_hh_bit7 = reader.u8();
+            chip.rm_hh_bit8 = reader.u8();
+            chip.rm_tc_bit3 = reader.u8();
+            chip.rm_tc_bit5 = reader.u8();
+            // The resampler divides by the ratio and generates a native sample for every ratio
+            // the counter holds, so both must be in the range the core leaves them in: the ratio
+            // is one the interpolation cannot overflow at, and the counter is below it, plus the
+            // one output sample it has just advanced by.
+            let rateratio: i32 = Field::take(&mut reader);
+            let samplecnt: i32 = Field::take(&mut reader);
+            if !(1..=Opl3Chip::MAX_RATE_RATIO as i32).contains(&rateratio)
+                || !(0..rateratio + (1 << 10)).contains(&samplecnt)
+            {
+                return Err(OplError::InvalidState);
+            }
+            chip.rateratio = rateratio;
+            chip.samplecnt = samplecnt;
+            for sample in chip.oldsamples.iter_mut().chain(chip.samples.iter_mut()) {
+                *sample = Field::take(&mut reader);
+            }
+            chip.writebuf_samplecnt = Field::take(&mut reader);
+            chip.writebuf_cur = u32::take(&mut reader) % WRITEBUF_SIZE as u32;
+            chip.writebuf_last = u32::take(&mut reader) % WRITEBUF_SIZE as u32;
+            chip.writebuf_lasttime = Field::take(&mut reader);
+            for entry in chip.writebuf.iter_mut() {
+                entry.time = Field::take(&mut reader);
+                entry.reg = Field::take(&mut reader);
+                entry.data = reader.u8();
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Asynchronous streaming of rendered audio, enabled with the `async` feature.
+//!
+//! `Opl3Chip::into_chunk_stream` turns a chip into a `futures_core::Stream` of fixed-size chunks
+//! of interleaved stereo audio. Register writes are sent to the stream through a `ChunkSender`,
+//! which never blocks and can be cloned and shared between tasks. Writes are applied at the start
+//! of the next chunk rendered, so the chunk size sets the latency of register writes.
+//!
+//! The stream is always ready and never ends. It renders audio as fast as it is polled, so a real
+//! time consumer should pace it, for example with an interval timer from its async runtime.
+
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::{Opl3Chip, OplError};
+
+/// A command sent to a `ChunkStream` through a `ChunkSender`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChipCommand {
+    /// Write a register directly, as with `Opl3Chip::write_register`.
+    Write {
+        /// The register to write, in the range 0x000..=0x1FF.
+        register: u16,
+        /// The value to write.
+        value: u8,
+    },
+    /// Write a register through the chip's write buffer, as with
+    /// `Opl3Chip::write_register_buffered`.
+    WriteBuffered {
+        /// The register to write, in the range 0x000..=0x1FF.
+        register: u16,
+        /// The value to write.
+        value: u8,
+    },
+    /// Reset the chip, as with `Opl3Chip::reset`.
+    Reset {
+        /// The sample rate to reinitialize the chip with.
+        sample_rate: u32,
+    },
+}
+
+/// The `ChunkSender` struct sends commands to a `ChunkStream`. Sending never blocks, so it is
+/// safe to use from async tasks.
+#[derive(Clone)]
+pub struct ChunkSender {
+    tx: Sender<ChipCommand>,
+}
+
+impl ChunkSender {
+    /// Send a command to the stream. The command is applied before the next chunk is rendered.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the stream has been dropped.
+    pub fn send(&self, command: ChipCommand) -> Result<(), OplError> {
+        self.tx.send(command).map_err(|_| OplError::StreamClosed)
+    }
+
+    /// Send a direct register write to the stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The register to write, in the range 0x000..=0x1FF.
+    /// * `value`    - The value to write.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the stream has been dropped.
+    pub fn write_register(&self, register: u16, value: u8) -> Result<(), OplError> {
+        self.send(ChipCommand::Write { register, value })
+    }
+
+    /// Send a buffered register write to the stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `register` - The register to write, in the range 0x000..=0x1FF.
+    /// * `value`    - The value to write.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if the stream has been dropped.
+    pub fn write_register_buffered(&self, register: u16, value: u8) -> Result<(), OplError> {
+        self.send(ChipCommand::WriteBuffered { register, value })
+    }
+}
+
+/// The `ChunkStream` struct is a `Stream` of chunks of interleaved stereo audio rendered from an
+/// `Opl3Chip` at the chip's configured sample rate.
+pub struct ChunkStream {
+    chip: Opl3Chip,
+    chunk_frames: usize,
+    tx: Sender<ChipCommand>,
+    rx: Receiver<ChipCommand>,
+}
+
+impl ChunkStream {
+    /// Return a new `ChunkSender` for sending commands to this stream.
+    pub fn sender(&self) -> ChunkSender {
+        ChunkSender {
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Return the number of stereo samples in each chunk.
+    pub fn chunk_frames(&self) -> usize {
+        self.chunk_frames
+    }
+
+    /// Consume the stream, returning the chip. Any commands that have not yet been applied are
+    /// discarded.
+    pub fn into_inner(self) -> Opl3Chip {
+        self.chip
+    }
+
+    fn apply_commands(&mut self) {
+        while let Ok(command) = self.rx.try_recv() {
+            match command {
+                ChipCommand::Write { register, value } => self.chip.write_register(register, value),
+                ChipCommand::WriteBuffered { register, value } => {
+                    self.chip.write_register_buffered(register, value)
+                }
+                ChipCommand::Reset { sample_rate } => self.chip.reset(sample_rate),
+            }
+        }
+    }
+}
+
+impl Stream for ChunkStream {
+    type Item = Vec<i16>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.apply_commands();
+        let mut chunk = vec![0i16; this.chunk_frames * 2];
+        // The chunk is never empty, so generation can't fail.
+        _ = this.chip.generate_stream(&mut chunk);
+        Poll::Ready(Some(chunk))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (usize::MAX, None)
+    }
+}
+
+impl Opl3Chip {
+    /// Convert the chip into a `Stream` of audio chunks. Use `ChunkStream::sender` to obtain a
+    /// `ChunkSender` for writing registers while the stream is running.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_frames` - The number of stereo samples in each chunk. Values below 1 are treated
+    ///                    as 1.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::pin::Pin;
+    /// use std::sync::Arc;
+    /// use std::task::{Context, Poll, Wake, Waker};
+    /// use futures_core::Stream;
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// // Chunks are rendered as they are polled, so nothing needs waking.
+    /// struct NoopWaker;
+    /// impl Wake for NoopWaker {
+    ///     fn wake(self: Arc<Self>) {}
+    /// }
+    ///
+    /// let mut stream = Opl3Chip::new(44100).into_chunk_stream(512);
+    /// let sender = stream.sender();
+    /// sender.write_register(0xB0, 0x32).unwrap();
+    ///
+    /// let waker = Waker::from(Arc::new(NoopWaker));
+    /// let mut cx = Context::from_waker(&waker);
+    /// match Pin::new(&mut stream).poll_next(&mut cx) {
+    ///     Poll::Ready(Some(chunk)) => assert_eq!(chunk.len(), 1024),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn into_chunk_stream(self, chunk_frames: usize) -> ChunkStream {
+        let (tx, rx) = mpsc::channel();
+        ChunkStream {
+            chip: self,
+            chunk_frames: chunk_frames.max(1),
+            tx,
+            rx,
+        }
+    }
+}
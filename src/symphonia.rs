@@ -0,0 +1,291 @@
+//! A `symphonia` format reader and decoder for the register log formats this crate can play.
+//!
+//! Media players built on `symphonia` find a format reader for a file by probing it, and a
+//! decoder for each of its tracks through a codec registry. `OplReader` recognises VGM, DOSBox Raw
+//! OPL (DRO) and event log files, and exposes each as a single stereo track of
+//! `CODEC_TYPE_OPL` packets. `OplDecoder` renders those packets to 16-bit audio with a
+//! `VgmPlayer`. Registering both is enough to add OPL playback to an existing frontend, with
+//! seeking, and any audio output it already uses, such as `cpal`.
+//!
+//! The whole file is kept in the track's codec parameters, and packets carry no data: each packet
+//! is a span of time for the decoder to render. Seeking backwards replays the file from the start.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::event_log::{EventLog, OplEvent};
+//! use opl3_rs::symphonia::{OplDecoder, OplReader};
+//! use symphonia_core::codecs::{CodecRegistry, DecoderOptions};
+//! use symphonia_core::formats::FormatOptions;
+//! use symphonia_core::io::MediaSourceStream;
+//! use symphonia_core::meta::MetadataOptions;
+//! use symphonia_core::probe::{Hint, Probe};
+//!
+//! let mut log = EventLog::new(1000);
+//! for (register, value) in [(0x23, 0x01), (0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+//!     log.push(OplEvent { time: 100, register, value });
+//! }
+//! let vgm = log.to_vgm();
+//!
+//! let mut probe = Probe::default();
+//! probe.register_all::<OplReader>();
+//! let mut codecs = CodecRegistry::new();
+//! codecs.register_all::<OplDecoder>();
+//!
+//! let source = MediaSourceStream::new(Box::new(std::io::Cursor::new(vgm)), Default::default());
+//! let probed = probe
+//!     .format(&Hint::new(), source, &FormatOptions::default(), &MetadataOptions::default())
+//!     .unwrap();
+//! let mut format = probed.format;
+//! let track = format.default_track().unwrap();
+//! let mut decoder = codecs.make(&track.codec_params, &DecoderOptions::default()).unwrap();
+//!
+//! let mut frames = 0;
+//! while let Ok(packet) = format.next_packet() {
+//!     frames += decoder.decode(&packet).unwrap().frames();
+//! }
+//! // A tenth of a second of music, followed by a second for the note to decay.
+//! assert_eq!(frames, 4410 + 44100);
+//! ```
+
+use std::borrow::Cow;
+use std::io::Read;
+use std::sync::Mutex;
+
+use symphonia_core::audio::{AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec};
+use symphonia_core::codecs::{
+    decl_codec_type, CodecDescriptor, CodecParameters, CodecType, Decoder, DecoderOptions,
+    FinalizeResult,
+};
+use symphonia_core::errors::{
+    decode_error, end_of_stream_error, seek_error, unsupported_error, Result, SeekErrorKind,
+};
+use symphonia_core::formats::{
+    Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+};
+use symphonia_core::io::MediaSourceStream;
+use symphonia_core::meta::{Metadata, MetadataLog};
+use symphonia_core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia_core::units::TimeBase;
+use symphonia_core::{support_codec, support_format};
+
+use crate::event_log::EventLog;
+use crate::vgm::{read_u32, VGM_SAMPLE_RATE};
+use crate::vgm_player::VgmPlayer;
+
+/// The codec of tracks produced by `OplReader`. The codec parameters hold the file as VGM data.
+pub const CODEC_TYPE_OPL: CodecType = decl_codec_type(b"opl");
+
+/// The number of frames of audio in each packet.
+const PACKET_FRAMES: u64 = 1024;
+
+/// The number of frames rendered after the end of the file, so that released notes decay.
+const TAIL_FRAMES: u64 = VGM_SAMPLE_RATE as u64;
+
+/// The `OplReader` struct is a `symphonia` format reader for VGM, DRO and event log files.
+///
+/// Files in other formats are converted to VGM when opened. Compressed (VGZ) files are not
+/// recognised, and loops are not followed.
+pub struct OplReader {
+    source: MediaSourceStream,
+    tracks: Vec<Track>,
+    metadata: MetadataLog,
+    frames: u64,
+    ts: u64,
+}
+
+impl QueryDescriptor for OplReader {
+    fn query() -> &'static [Descriptor] {
+        &[support_format!(
+            "opl",
+            "OPL register log",
+            &["vgm", "dro"],
+            &[],
+            &[b"Vgm ", b"DBRAWOPL", b"OPLE"]
+        )]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl FormatReader for OplReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+        let vgm = match data.get(0..4) {
+            Some(b"Vgm ") => data,
+            Some(b"DBRA") => match EventLog::from_dro(&data) {
+                Ok(log) => log.to_vgm(),
+                Err(_) => return decode_error("opl: invalid DRO file"),
+            },
+            Some(b"OPLE") => match EventLog::from_bytes(data) {
+                Ok(log) => log.to_vgm(),
+                Err(_) => return decode_error("opl: invalid event log"),
+            },
+            _ => return unsupported_error("opl: not a VGM, DRO or event log file"),
+        };
+        if VgmPlayer::new(&vgm, VGM_SAMPLE_RATE).is_err() {
+            return unsupported_error("opl: no playable chip in VGM file");
+        }
+
+        let frames = read_u32(&vgm, 0x18) as u64 + TAIL_FRAMES;
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_OPL)
+            .with_sample_rate(VGM_SAMPLE_RATE)
+            .with_time_base(TimeBase::new(1, VGM_SAMPLE_RATE))
+            .with_n_frames(frames)
+            .with_channels(Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
+            .with_max_frames_per_packet(PACKET_FRAMES)
+            .with_extra_data(vgm.into_boxed_slice());
+        Ok(OplReader {
+            source,
+            tracks: vec![Track::new(0, params)],
+            metadata: MetadataLog::default(),
+            frames,
+            ts: 0,
+        })
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &[]
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        let ts = match to {
+            SeekTo::TimeStamp { ts, track_id: 0 } => ts,
+            SeekTo::TimeStamp { .. } => return seek_error(SeekErrorKind::InvalidTrack),
+            SeekTo::Time { time, .. } => TimeBase::new(1, VGM_SAMPLE_RATE).calc_timestamp(time),
+        };
+        if ts > self.frames {
+            return seek_error(SeekErrorKind::OutOfRange);
+        }
+        self.ts = ts;
+        Ok(SeekedTo {
+            track_id: 0,
+            required_ts: ts,
+            actual_ts: ts,
+        })
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        if self.ts >= self.frames {
+            return end_of_stream_error();
+        }
+        let dur = PACKET_FRAMES.min(self.frames - self.ts);
+        let packet = Packet::new_from_slice(0, self.ts, dur, &[]);
+        self.ts += dur;
+        Ok(packet)
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
+
+/// The `OplDecoder` struct is a `symphonia` decoder for tracks produced by `OplReader`.
+pub struct OplDecoder {
+    params: CodecParameters,
+    // `Decoder` must be `Sync`, which the chips are not. The mutex is only ever accessed through
+    // `get_mut`, so it is never locked.
+    player: Mutex<VgmPlayer>,
+    ts: u64,
+    scratch: Vec<i16>,
+    buffer: AudioBuffer<i16>,
+}
+
+impl OplDecoder {
+    /// Render `frames` frames into the scratch buffer.
+    fn render(&mut self, frames: usize) -> Result<()> {
+        self.scratch.resize(frames * 2, 0);
+        let player = match self.player.get_mut() {
+            Ok(player) => player,
+            Err(_) => return decode_error("opl: player poisoned"),
+        };
+        if player.generate_stream(&mut self.scratch).is_err() {
+            return decode_error("opl: invalid VGM command");
+        }
+        self.ts += frames as u64;
+        Ok(())
+    }
+}
+
+impl Decoder for OplDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        if params.codec != CODEC_TYPE_OPL {
+            return unsupported_error("opl: invalid codec type");
+        }
+        let player = match params
+            .extra_data
+            .as_deref()
+            .map(|data| VgmPlayer::new(data, VGM_SAMPLE_RATE))
+        {
+            Some(Ok(player)) => player,
+            _ => return decode_error("opl: missing or invalid VGM data"),
+        };
+        let spec = SignalSpec::new(
+            VGM_SAMPLE_RATE,
+            Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+        );
+        Ok(OplDecoder {
+            params: params.clone(),
+            player: Mutex::new(player),
+            ts: 0,
+            scratch: Vec::new(),
+            buffer: AudioBuffer::new(PACKET_FRAMES, spec),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPL, "opl", "OPL register log")]
+    }
+
+    fn reset(&mut self) {}
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        if packet.ts < self.ts {
+            let data = self.params.extra_data.as_deref().unwrap_or_default();
+            match VgmPlayer::new(data, VGM_SAMPLE_RATE) {
+                Ok(player) => *self.player.get_mut().unwrap_or_else(|e| e.into_inner()) = player,
+                Err(_) => return decode_error("opl: invalid VGM data"),
+            }
+            self.ts = 0;
+        }
+        while self.ts < packet.ts {
+            self.render((packet.ts - self.ts).min(PACKET_FRAMES) as usize)?;
+        }
+
+        let frames = packet.dur.min(PACKET_FRAMES) as usize;
+        self.render(frames)?;
+        self.buffer.clear();
+        self.buffer.render_reserved(Some(frames));
+        let (left, right) = self.buffer.chan_pair_mut(0, 1);
+        for (i, frame) in self.scratch.chunks_exact(2).enumerate() {
+            left[i] = frame[0];
+            right[i] = frame[1];
+        }
+        Ok(AudioBufferRef::S16(Cow::Borrowed(&self.buffer)))
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        AudioBufferRef::S16(Cow::Borrowed(&self.buffer))
+    }
+}
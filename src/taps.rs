@@ -0,0 +1,105 @@
+//! Per-operator output taps, enabled with the `taps` feature.
+//!
+//! Each of the OPL3's 36 operators produces one output sample per chip sample, after its envelope
+//! has been applied but before the channel's connection routes it to another operator or to the
+//! mix. These taps read those outputs directly from the Nuked-OPL3 state, for algorithm
+//! visualizers and educational tools that show how FM modulation builds up the final waveform.
+//! They are meant for debugging and teaching rather than playback: reading them costs a pass over
+//! the chip state for every sample.
+//!
+//! Operators are indexed in Nuked-OPL3's slot order, 0-17 in the primary register file followed
+//! by 18-35 in the secondary register file. Use `operator_slot` to find the slot of a channel's
+//! operator.
+
+use crate::{bindings, Opl3Chip, OplError};
+
+/// The number of operators on the OPL3.
+pub const OPERATOR_COUNT: usize = 36;
+
+/// The output of every operator for one chip sample, indexed by slot.
+pub type OperatorTaps = [i16; OPERATOR_COUNT];
+
+/// The slot of the first operator of each channel. The second operator is 3 slots later.
+const CHANNEL_SLOTS: [usize; 18] = [
+    0, 1, 2, 6, 7, 8, 12, 13, 14, 18, 19, 20, 24, 25, 26, 30, 31, 32,
+];
+
+/// Return the slot index of one of a channel's operators.
+///
+/// # Arguments
+///
+/// * `channel`  - The channel, from 0 to 17.
+/// * `operator` - The operator within the channel, 1 (the modulator) or 2 (the carrier).
+///
+/// # Returns
+///
+/// The slot index, or `None` if the channel or operator is out of range.
+pub fn operator_slot(channel: u8, operator: u8) -> Option<usize> {
+    match operator {
+        1 | 2 => CHANNEL_SLOTS
+            .get(channel as usize)
+            .map(|slot| slot + (operator as usize - 1) * 3),
+        _ => None,
+    }
+}
+
+impl Opl3Chip {
+    /// Return the output of every operator for the most recently generated chip sample.
+    pub fn operator_outputs(&self) -> OperatorTaps {
+        let mut taps = [0; OPERATOR_COUNT];
+        // Safety: the chip pointer is valid for the lifetime of `self`.
+        let chip: &bindings::Opl3Chip = unsafe { &*self.chip };
+        for (tap, slot) in taps.iter_mut().zip(chip.slot.iter()) {
+            *tap = slot.out;
+        }
+        taps
+    }
+
+    /// Generate a stream of 2 channel, interleaved audio samples at the chip's native sample rate
+    /// of 49716Hz, recording the output of every operator for each sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a buffer slice that will be filled with stereo,
+    ///              interleaved audio samples.
+    /// * `taps`   - A mutable reference to a slice that will receive the operator outputs for
+    ///              each sample. It must hold at least `buffer.len() / 2` elements.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    /// use opl3_rs::taps::{operator_slot, OperatorTaps};
+    ///
+    /// let mut chip = Opl3Chip::new(49716);
+    /// chip.write_register(0x23, 0x01);
+    /// chip.write_register(0x63, 0xF0);
+    /// chip.write_register(0xB0, 0x32);
+    ///
+    /// let mut buffer = [0i16; 256 * 2];
+    /// let mut taps = [[0; 36]; 256];
+    /// chip.generate_with_taps(&mut buffer, &mut taps).unwrap();
+    ///
+    /// // The carrier of channel 0 is producing output.
+    /// let carrier = operator_slot(0, 2).unwrap();
+    /// assert!(taps.iter().any(|t: &OperatorTaps| t[carrier] != 0));
+    /// ```
+    pub fn generate_with_taps(
+        &mut self,
+        buffer: &mut [i16],
+        taps: &mut [OperatorTaps],
+    ) -> Result<(), OplError> {
+        if buffer.len() < 2 || taps.len() < buffer.len() / 2 {
+            return Err(OplError::BufferUndersized);
+        }
+        for (sample, tap) in buffer.chunks_exact_mut(2).zip(taps.iter_mut()) {
+            self.generate(sample)?;
+            *tap = self.operator_outputs();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+//! Bit-exact regression vectors for the Nuked-OPL3 core.
+//!
+//! Each vector is a canned register script that is rendered on a fresh `Opl3Chip`. The rendered
+//! output is hashed and compared against the hash produced by the reference Nuked-OPL3 build.
+//! A mismatch means the compiled core no longer behaves identically to the reference, usually
+//! due to a compiler, platform or vendored source change.
+
+use std::fmt;
+
+use crate::Opl3Chip;
+
+/// A single step of a test vector script.
+enum Step {
+    /// Write a register directly.
+    Write(u16, u8),
+    /// Write a register in buffered mode.
+    WriteBuffered(u16, u8),
+    /// Render the given number of native-rate 4-channel samples.
+    Render(u32),
+    /// Render the given number of resampled stereo samples.
+    RenderResampled(u32),
+}
+
+use Step::*;
+
+/// A canned register script and the hash of its reference output.
+struct TestVector {
+    name: &'static str,
+    sample_rate: u32,
+    steps: &'static [Step],
+    expected: u64,
+}
+
+const VECTORS: [TestVector; 5] = [
+    TestVector {
+        name: "opl2_melodic",
+        sample_rate: 49716,
+        steps: &[
+            Write(0x01, 0x20),
+            // Channel 0: FM, sine modulator into half-sine carrier.
+            Write(0x20, 0x21),
+            Write(0x40, 0x18),
+            Write(0x60, 0xF4),
+            Write(0x80, 0x56),
+            Write(0xE0, 0x00),
+            Write(0x23, 0x01),
+            Write(0x43, 0x00),
+            Write(0x63, 0xF3),
+            Write(0x83, 0x55),
+            Write(0xE3, 0x01),
+            Write(0xC0, 0x0A),
+            // Channel 1: additive, abs-sine and pulse-sine.
+            Write(0x21, 0x02),
+            Write(0x41, 0x08),
+            Write(0x61, 0xA5),
+            Write(0x81, 0x34),
+            Write(0xE1, 0x02),
+            Write(0x24, 0x04),
+            Write(0x44, 0x04),
+            Write(0x64, 0xC5),
+            Write(0x84, 0x34),
+            Write(0xE4, 0x03),
+            Write(0xC1, 0x01),
+            Write(0xA0, 0x41),
+            Write(0xB0, 0x32),
+            Write(0xA1, 0x81),
+            Write(0xB1, 0x2D),
+            Render(8192),
+            Write(0xB0, 0x12),
+            Write(0xB1, 0x0D),
+            Render(4096),
+        ],
+        expected: 0xab9f_e569_cef4_3d53,
+    },
+    TestVector {
+        name: "opl3_4op",
+        sample_rate: 49716,
+        steps: &[
+            Write(0x105, 0x01),
+            Write(0x104, 0x01),
+            // Channels 0 and 3 as a 4-op pair, all operators in series, output to all DACs.
+            Write(0x20, 0x01),
+            Write(0x40, 0x20),
+            Write(0x60, 0xF2),
+            Write(0x80, 0x24),
+            Write(0xE0, 0x04),
+            Write(0x23, 0x02),
+            Write(0x43, 0x18),
+            Write(0x63, 0xF2),
+            Write(0x83, 0x24),
+            Write(0xE3, 0x05),
+            Write(0x28, 0x01),
+            Write(0x48, 0x10),
+            Write(0x68, 0xE3),
+            Write(0x88, 0x34),
+            Write(0xE8, 0x06),
+            Write(0x2B, 0x01),
+            Write(0x4B, 0x00),
+            Write(0x6B, 0xE3),
+            Write(0x8B, 0x34),
+            Write(0xEB, 0x07),
+            Write(0xC0, 0xF6),
+            Write(0xC3, 0xF0),
+            Write(0xA0, 0x57),
+            Write(0xB0, 0x2D),
+            // A 2-op voice on the secondary register file, routed to the second DAC pair.
+            Write(0x120, 0x21),
+            Write(0x140, 0x12),
+            Write(0x160, 0xF5),
+            Write(0x180, 0x45),
+            Write(0x123, 0x21),
+            Write(0x143, 0x00),
+            Write(0x163, 0xF5),
+            Write(0x183, 0x45),
+            Write(0x1C0, 0xC4),
+            Write(0x1A0, 0x6B),
+            Write(0x1B0, 0x31),
+            Render(8192),
+            Write(0xB0, 0x0D),
+            Write(0x1B0, 0x11),
+            Render(4096),
+        ],
+        expected: 0xa42b_456b_f3b1_bd82,
+    },
+    TestVector {
+        name: "rhythm",
+        sample_rate: 49716,
+        steps: &[
+            Write(0x30, 0x01),
+            Write(0x50, 0x00),
+            Write(0x70, 0xF8),
+            Write(0x90, 0x66),
+            Write(0x33, 0x01),
+            Write(0x53, 0x00),
+            Write(0x73, 0xF6),
+            Write(0x93, 0x57),
+            Write(0x31, 0x01),
+            Write(0x51, 0x00),
+            Write(0x71, 0xF7),
+            Write(0x91, 0x35),
+            Write(0x34, 0x01),
+            Write(0x54, 0x00),
+            Write(0x74, 0xF8),
+            Write(0x94, 0x55),
+            Write(0x32, 0x01),
+            Write(0x52, 0x00),
+            Write(0x72, 0xF5),
+            Write(0x92, 0x35),
+            Write(0x35, 0x01),
+            Write(0x55, 0x00),
+            Write(0x75, 0xF5),
+            Write(0x95, 0x36),
+            Write(0xA6, 0x20),
+            Write(0xB6, 0x05),
+            Write(0xA7, 0x50),
+            Write(0xB7, 0x05),
+            Write(0xA8, 0x00),
+            Write(0xB8, 0x05),
+            Write(0xBD, 0x20),
+            Write(0xBD, 0x3F),
+            Render(6144),
+            Write(0xBD, 0x20),
+            Write(0xBD, 0x35),
+            Render(6144),
+        ],
+        expected: 0x4e3b_6e35_8745_d01e,
+    },
+    TestVector {
+        name: "lfo",
+        sample_rate: 49716,
+        steps: &[
+            Write(0xBD, 0xC0),
+            Write(0x20, 0xE1),
+            Write(0x40, 0x0C),
+            Write(0x60, 0xF0),
+            Write(0x80, 0x04),
+            Write(0x23, 0xE1),
+            Write(0x43, 0x00),
+            Write(0x63, 0xF0),
+            Write(0x83, 0x04),
+            Write(0xC0, 0x06),
+            Write(0xA0, 0x44),
+            Write(0xB0, 0x36),
+            Render(16384),
+        ],
+        expected: 0x5d2b_f4c9_aebe_0d31,
+    },
+    TestVector {
+        name: "resampled_buffered",
+        sample_rate: 44100,
+        steps: &[
+            WriteBuffered(0x20, 0x01),
+            WriteBuffered(0x40, 0x10),
+            WriteBuffered(0x60, 0xF4),
+            WriteBuffered(0x80, 0x46),
+            WriteBuffered(0x23, 0x01),
+            WriteBuffered(0x43, 0x00),
+            WriteBuffered(0x63, 0xF4),
+            WriteBuffered(0x83, 0x46),
+            WriteBuffered(0xC0, 0x08),
+            WriteBuffered(0xA0, 0x98),
+            WriteBuffered(0xB0, 0x31),
+            RenderResampled(8192),
+            WriteBuffered(0xB0, 0x11),
+            RenderResampled(4096),
+        ],
+        expected: 0xa12b_6ea8_51d4_33e4,
+    },
+];
+
+/// The result of rendering a single test vector.
+#[derive(Clone, Debug)]
+pub struct VectorResult {
+    /// The name of the test vector.
+    pub name: &'static str,
+    /// The hash of the reference Nuked-OPL3 output.
+    pub expected: u64,
+    /// The hash of the output produced by this build.
+    pub actual: u64,
+}
+
+impl VectorResult {
+    /// Return `true` if this build reproduced the reference output exactly.
+    pub fn passed(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+/// The `Report` struct contains the results of `verify_core`.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// The result of each test vector, in the order they were run.
+    pub results: Vec<VectorResult>,
+}
+
+impl Report {
+    /// Return `true` if every test vector reproduced the reference output exactly.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(VectorResult::passed)
+    }
+
+    /// Return an iterator over the test vectors that did not reproduce the reference output.
+    pub fn failures(&self) -> impl Iterator<Item = &VectorResult> {
+        self.results.iter().filter(|result| !result.passed())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            writeln!(
+                f,
+                "{:<20} {} (expected {:016x}, got {:016x})",
+                result.name,
+                if result.passed() { "ok" } else { "FAILED" },
+                result.expected,
+                result.actual
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Render every canned test vector on a fresh `Opl3Chip` and compare the output against the
+/// reference Nuked-OPL3 output.
+///
+/// # Returns
+///
+/// A `Report` describing the result of each test vector.
+///
+/// # Example
+///
+/// ```
+/// let report = opl3_rs::test_vectors::verify_core();
+/// assert!(report.passed(), "{}", report);
+/// ```
+pub fn verify_core() -> Report {
+    Report {
+        results: VECTORS
+            .iter()
+            .map(|vector| VectorResult {
+                name: vector.name,
+                expected: vector.expected,
+                actual: render(vector),
+            })
+            .collect(),
+    }
+}
+
+/// Render a test vector and return the FNV-1a hash of its output.
+fn render(vector: &TestVector) -> u64 {
+    let mut chip = Opl3Chip::new(vector.sample_rate);
+    let mut hasher = Fnv1a::new();
+    let mut sample = [0i16; 4];
+
+    for step in vector.steps {
+        match *step {
+            Write(reg, value) => chip.write_register(reg, value),
+            WriteBuffered(reg, value) => chip.write_register_buffered(reg, value),
+            Render(count) => {
+                for _ in 0..count {
+                    _ = chip.generate_4ch(&mut sample);
+                    hasher.write(&sample);
+                }
+            }
+            RenderResampled(count) => {
+                for _ in 0..count {
+                    _ = chip.generate_resampled(&mut sample[..2]);
+                    hasher.write(&sample[..2]);
+                }
+            }
+        }
+    }
+    hasher.finish()
+}
+
+/// A 64-bit FNV-1a hasher over little-endian sample bytes. The standard library's hashers make
+/// no stability guarantees across releases, so they can't be used for reference hashes.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, samples: &[i16]) {
+        for sample in samples {
+            for byte in sample.to_le_bytes() {
+                self.0 ^= byte as u64;
+                self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,134 @@
+//! Pluggable sources of host time, so that code paced by the wall clock can run headless.
+//!
+//! `clock::Clock`, `renderer::RendererThread` and the real-time pacing of `server::PlaylistServer`
+//! read the time and wait through a `TimeSource` rather than calling `Instant::now` and
+//! `thread::sleep` directly. They use `WallClock` by default, which is the real time. Giving
+//! them a `ManualTime` instead makes time stand still until it is advanced by hand, and makes
+//! every wait return at once, having advanced time by the length of the wait. Batch renders then
+//! run as fast as the machine allows, and tests see the same timings on every run.
+//!
+//! `ManualTime` is a handle to a shared time, so a clone kept by a test advances the time seen by
+//! a copy given to a renderer on another thread.
+//!
+//! # Example
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use opl3_rs::clock::Clock;
+//! use opl3_rs::time_source::{ManualTime, TimeSource};
+//!
+//! let time = ManualTime::new();
+//! let mut clock = Clock::with_time_source(48000, time.clone());
+//! clock.set_latency(0);
+//! for block in 0..=100u64 {
+//!     clock.sync_now(block * 480);
+//!     time.advance(Duration::from_millis(10));
+//! }
+//! // Exactly the nominal rate, as every block was synced right on time.
+//! assert!((clock.measured_rate() - 48000.0).abs() < 1e-6);
+//! assert_eq!(clock.sample_at(1_005_000), 48240);
+//!
+//! // Sleeping returns at once.
+//! time.sleep(Duration::from_secs(3600));
+//! assert_eq!(time.now(), Duration::from_millis(1010) + Duration::from_secs(3600));
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The `TimeSource` trait is a source of host time that can be waited on.
+pub trait TimeSource {
+    /// Return the time since the origin of the source.
+    fn now(&self) -> Duration;
+
+    /// Wait until a length of time has passed.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - The length of time to wait.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The `WallClock` struct is the real time, measured from the moment it was created.
+#[derive(Copy, Clone, Debug)]
+pub struct WallClock {
+    origin: Instant,
+}
+
+impl WallClock {
+    /// Create a new `WallClock` with its origin now.
+    pub fn new() -> Self {
+        WallClock {
+            origin: Instant::now(),
+        }
+    }
+
+    /// Return the `Instant` the clock's time is measured from.
+    pub fn origin(&self) -> Instant {
+        self.origin
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        WallClock::new()
+    }
+}
+
+impl TimeSource for WallClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// The `ManualTime` struct is a time that only moves when it is advanced, or when something
+/// sleeps on it. Clones share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct ManualTime {
+    /// The time since the origin, in nanoseconds.
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualTime {
+    /// Create a new `ManualTime` at time 0.
+    pub fn new() -> Self {
+        ManualTime::default()
+    }
+
+    /// Move the time forward.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - The length of time to move forward by.
+    pub fn advance(&self, duration: Duration) {
+        self.nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Set the time, which may move it backward.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The new time since the origin.
+    pub fn set(&self, now: Duration) {
+        self.nanos.store(now.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl TimeSource for ManualTime {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    /// Advance the time by `duration`, and return at once.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
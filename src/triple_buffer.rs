@@ -0,0 +1,132 @@
+//! A lock-free triple buffer for publishing snapshots from the audio thread to a UI thread.
+//!
+//! Meters and visualizers want to show the state of the chip, but must not lock the chip away
+//! from the audio thread to read it. Instead, the audio thread publishes a snapshot, such as an
+//! `inspect::ChipStatus`, after each block it renders, and the UI thread reads the latest one
+//! whenever it draws. Neither side ever blocks or allocates, and the reader always sees a whole
+//! snapshot, never one half written.
+//!
+//! Three slots are shared: the publisher writes into its back slot and swaps it with the middle
+//! slot, and the reader swaps the middle slot with its front slot when a newer snapshot is there.
+//! Snapshots published between two reads are skipped.
+//!
+//! # Example
+//!
+//! ```
+//! use std::thread;
+//!
+//! use opl3_rs::Opl3Chip;
+//! use opl3_rs::triple_buffer::triple_buffer;
+//!
+//! let mut chip = Opl3Chip::new(44100);
+//! let (mut publisher, mut reader) = triple_buffer(chip.status());
+//!
+//! let audio = thread::spawn(move || {
+//!     chip.write_register(0xA0, 0x98);
+//!     chip.write_register(0xB0, 0x31);
+//!     chip.render(735);
+//!     publisher.publish(chip.status());
+//! });
+//! audio.join().unwrap();
+//!
+//! // In the UI thread:
+//! assert!(reader.has_update());
+//! assert_eq!(reader.read().channels[0].f_number, 0x198);
+//! ```
+
+#![cfg_attr(feature = "fixed-point", deny(clippy::float_arithmetic))]
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+/// Set in `Shared::middle` when the middle slot holds a snapshot the reader has not taken.
+const FRESH: u8 = 0x04;
+/// The bits of `Shared::middle` holding the index of the middle slot.
+const INDEX: u8 = 0x03;
+
+/// The state shared by a `Publisher` and its `Reader`.
+struct Shared<T> {
+    slots: [UnsafeCell<T>; 3],
+    /// The index of the middle slot, and the `FRESH` flag.
+    middle: AtomicU8,
+}
+
+// Safety: the publisher only touches its back slot and the reader only its front slot. Slots
+// change hands through `middle`, with acquire/release ordering, and are never held by both.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// The `Publisher` struct is the writing half of a triple buffer, for the audio thread.
+pub struct Publisher<T> {
+    shared: Arc<Shared<T>>,
+    back: u8,
+}
+
+/// The `Reader` struct is the reading half of a triple buffer, for the UI thread.
+pub struct Reader<T> {
+    shared: Arc<Shared<T>>,
+    front: u8,
+}
+
+/// Create a triple buffer. This allocates the shared slots; publishing and reading afterwards
+/// never allocate.
+///
+/// # Arguments
+///
+/// * `initial` - The snapshot the reader sees until the first one is published.
+///
+/// # Returns
+///
+/// A tuple of the publisher and reader.
+pub fn triple_buffer<T: Clone + Send>(initial: T) -> (Publisher<T>, Reader<T>) {
+    let shared = Arc::new(Shared {
+        slots: [
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial),
+        ],
+        middle: AtomicU8::new(1),
+    });
+    let publisher = Publisher {
+        shared: shared.clone(),
+        back: 0,
+    };
+    let reader = Reader { shared, front: 2 };
+    (publisher, reader)
+}
+
+impl<T: Send> Publisher<T> {
+    /// Publish a snapshot, replacing any the reader has not taken yet. The snapshot it replaces
+    /// is dropped on this thread, so snapshots should not own heap memory if the publisher runs
+    /// on a real-time thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The snapshot to publish.
+    pub fn publish(&mut self, value: T) {
+        // Safety: the back slot belongs to the publisher until it is swapped into the middle.
+        unsafe {
+            *self.shared.slots[self.back as usize].get() = value;
+        }
+        let previous = self.shared.middle.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = previous & INDEX;
+    }
+}
+
+impl<T: Send> Reader<T> {
+    /// Return `true` if a snapshot has been published since the last `read`.
+    pub fn has_update(&self) -> bool {
+        self.shared.middle.load(Ordering::Relaxed) & FRESH != 0
+    }
+
+    /// Return the latest published snapshot, or the initial one if none has been published.
+    pub fn read(&mut self) -> &T {
+        if self.has_update() {
+            let previous = self.shared.middle.swap(self.front, Ordering::AcqRel);
+            self.front = previous & INDEX;
+        }
+        // Safety: the front slot belongs to the reader until it is swapped into the middle, which
+        // needs `&mut self`.
+        unsafe { &*self.shared.slots[self.front as usize].get() }
+    }
+}
@@ -0,0 +1,157 @@
+//! The exact frequency of every Block and F-Num, for building pitch tables in tune with the core.
+//!
+//! A channel's pitch is set by a 10-bit frequency number and a 3-bit block, or octave. Every
+//! sample the chip generates, the phase of an operator advances by the F-Num shifted left by the
+//! block, so the frequency they play is `fnum * native_rate / 2^(20 - block)`, where the native
+//! rate is the master clock divided by 288. That makes 8192 combinations, many of them giving the
+//! same frequency in different blocks. `TuningTable` lists the frequency of each, as the core
+//! plays it at a given master clock, so that trackers can pick the F-Num closest to each note of
+//! their own tuning instead of copying a table computed for another clock.
+//!
+//! The frequencies are those of an operator with a multiplier of 1. Each operator scales its
+//! channel's frequency by its own multiplier, which is one half for a MULT of 0.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::tuning::TuningTable;
+//! use opl3_rs::Opl3Chip;
+//!
+//! let table = TuningTable::default();
+//! assert!((table.hz(4, 580).unwrap() - 440.0).abs() < 0.05);
+//! assert_eq!(table.iter().count(), 8192);
+//!
+//! // The same frequency the chip reports for a channel playing it.
+//! let mut chip = Opl3Chip::new(44100);
+//! chip.write_register(0x23, 0x01);
+//! chip.write_register(0xA0, 0x44);
+//! chip.write_register(0xB0, 0x32);
+//! assert_eq!(chip.channel_frequency_hz(0), table.hz(4, 580).unwrap());
+//!
+//! // An equal tempered chromatic scale from A4, built from the closest F-Nums.
+//! let scale: Vec<_> = (0..12)
+//!     .map(|step| table.nearest(440.0 * (step as f64 / 12.0).exp2()).unwrap())
+//!     .collect();
+//! assert_eq!((scale[0].block, scale[0].fnum), (4, 580));
+//! ```
+
+use crate::{multirate, pacing, OplError};
+
+/// The number of blocks.
+pub const BLOCKS: u8 = 8;
+/// The number of frequency numbers in each block.
+pub const FNUMS: u16 = 1024;
+
+/// The `TuningEntry` struct is the frequency played by one combination of Block and F-Num.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TuningEntry {
+    /// The block, from 0 to 7.
+    pub block: u8,
+    /// The frequency number, from 0 to 1023.
+    pub fnum: u16,
+    /// The frequency played, in Hz.
+    pub hz: f64,
+}
+
+/// The `TuningTable` struct gives the frequency of every Block and F-Num at a master clock.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TuningTable {
+    master_clock: u32,
+    /// The rate at which the core generates samples at the master clock, in Hz.
+    native_rate: f64,
+}
+
+impl TuningTable {
+    /// Create a new `TuningTable` for a master clock.
+    ///
+    /// # Arguments
+    ///
+    /// * `master_clock` - The frequency of the clock driving the chip, in Hz, as passed to
+    ///                    `Opl3Chip::set_master_clock`.
+    pub fn new(master_clock: u32) -> Self {
+        let master_clock = master_clock.max(1);
+        // The core runs at exactly 49716Hz at the nominal clock, as Nuked-OPL3 does, and at the
+        // exact clock divided by 288 at any other.
+        let native_rate = if master_clock == pacing::YMF262_CLOCK {
+            multirate::NATIVE_SAMPLE_RATE as f64
+        } else {
+            master_clock as f64 / 288.0
+        };
+        TuningTable {
+            master_clock,
+            native_rate,
+        }
+    }
+
+    /// Return the master clock the table is for, in Hz.
+    pub fn master_clock(&self) -> u32 {
+        self.master_clock
+    }
+
+    /// Return the frequency played by a Block and F-Num.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - The block, from 0 to 7.
+    /// * `fnum`  - The frequency number, from 0 to 1023.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the frequency in Hz on success or an `OplError` if the block
+    /// or F-Num is out of range.
+    pub fn hz(&self, block: u8, fnum: u16) -> Result<f64, OplError> {
+        if block >= BLOCKS || fnum >= FNUMS {
+            return Err(OplError::RegisterOutOfRange);
+        }
+        Ok(self.frequency(block, fnum as f64))
+    }
+
+    /// Return an iterator over every Block and F-Num, by block and then by F-Num, so that
+    /// frequencies rise within each block.
+    pub fn iter(&self) -> impl Iterator<Item = TuningEntry> + '_ {
+        (0..BLOCKS).flat_map(move |block| {
+            (0..FNUMS).map(move |fnum| TuningEntry {
+                block,
+                fnum,
+                hz: self.frequency(block, fnum as f64),
+            })
+        })
+    }
+
+    /// Return the Block and F-Num that play a frequency most closely. Of the blocks that can
+    /// play it, the lowest is used, as its F-Nums are the closest together.
+    ///
+    /// # Arguments
+    ///
+    /// * `hz` - The frequency, in Hz.
+    ///
+    /// # Returns
+    ///
+    /// The closest entry, or `None` if the frequency is not positive or is above the highest the
+    /// chip can play.
+    pub fn nearest(&self, hz: f64) -> Option<TuningEntry> {
+        if hz.is_nan() || hz <= 0.0 {
+            return None;
+        }
+        (0..BLOCKS).find_map(|block| {
+            let fnum = (hz / self.frequency(block, 1.0)).round();
+            (fnum < FNUMS as f64).then(|| TuningEntry {
+                block,
+                fnum: fnum as u16,
+                hz: self.frequency(block, fnum),
+            })
+        })
+    }
+
+    /// Return the frequency of a block and F-Num, which need not be a whole number.
+    fn frequency(&self, block: u8, fnum: f64) -> f64 {
+        fnum * self.native_rate / (1u32 << (20 - block)) as f64
+    }
+}
+
+/// A table for a YMF262 at its nominal clock of 14.31818MHz.
+impl Default for TuningTable {
+    fn default() -> Self {
+        TuningTable::new(pacing::YMF262_CLOCK)
+    }
+}
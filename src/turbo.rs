@@ -0,0 +1,62 @@
+//! Fast-forward rendering for emulator turbo modes.
+//!
+//! When an emulator runs faster than real time, the chip has to be advanced by several seconds of
+//! emulated time for every second of audio played. `Opl3Chip::generate_fast_forward` does this
+//! cheaply: it generates samples at the chip's native rate, skipping the resampler, and keeps only
+//! the last native sample of each output sample. The result aliases, which is acceptable for the
+//! sped-up audio of a turbo mode, but every register write, envelope and timer still advances
+//! exactly as at normal speed.
+
+use crate::{Opl3Chip, OplError};
+
+impl Opl3Chip {
+    /// Generate 2 channel, interleaved audio samples while advancing the chip `speed` times
+    /// faster than real time.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a buffer slice that will be filled with stereo,
+    ///              interleaved audio samples at the chip's sample rate.
+    /// * `speed`  - The fast-forward factor. 1 advances the chip by the duration of the buffer,
+    ///              2 by twice its duration, and so on. 0 is treated as 1.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` on failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::Opl3Chip;
+    ///
+    /// let mut chip = Opl3Chip::new(44100);
+    /// let mut buffer = [0i16; 441 * 2];
+    /// // Advance the chip by 80ms while producing 10ms of audio.
+    /// chip.generate_fast_forward(&mut buffer, 8).unwrap();
+    /// ```
+    pub fn generate_fast_forward(
+        &mut self,
+        buffer: &mut [i16],
+        speed: u32,
+    ) -> Result<(), OplError> {
+        if buffer.len() < 2 {
+            return Err(OplError::BufferUndersized);
+        }
+        // The chip generates one native sample every 288 master clocks.
+        let step = speed.max(1) as u64 * self.master_clock as u64;
+        let divisor = self.sample_rate.max(1) as u64 * 288;
+        let mut last = [0i16; 2];
+        for frame in buffer.chunks_exact_mut(2) {
+            // Carry the fractional native sample across output samples, and across calls, so the
+            // chip advances by exactly `speed` times real time on average.
+            self.fast_forward_fpart += step;
+            let native_samples = self.fast_forward_fpart / divisor;
+            self.fast_forward_fpart %= divisor;
+            for _ in 0..native_samples {
+                self.generate(&mut last)?;
+            }
+            frame.copy_from_slice(&last);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Register write validation for `Opl3Device`'s strict mode.
+//!
+//! The OPL3 silently accepts writes to every address in both register files, but many addresses
+//! have no function and several registers contain bits that are undefined or reserved for test
+//! purposes. In strict mode, `Opl3Device` records such writes so that driver developers can find
+//! out when their code writes values that real hardware would ignore, or worse, act on.
+
+/// The maximum number of warnings retained by an `Opl3Device` before further warnings are
+/// counted but discarded.
+pub const MAX_REGISTER_WARNINGS: usize = 1024;
+
+/// The kind of problem detected with a register write.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegisterWarningKind {
+    /// The register address has no function on the YMF262.
+    Unused,
+    /// The register has a function, but the value sets bits that are unused or reserved.
+    /// The `u8` contains the offending bits.
+    ReservedBits(u8),
+    /// The register is in the secondary register file, but OPL3 mode is disabled (register
+    /// 0x105 bit 0), so the write would have no effect on real hardware.
+    Opl3ModeDisabled,
+    /// One of channels 6 to 8 is keyed on as a melodic channel while rhythm mode is enabled, or
+    /// rhythm mode is enabled while one of them is keyed on. The channels' operators belong to
+    /// the drums in rhythm mode, so the note sounds as a stuck or distorted drum. See
+    /// `registers::Drum`.
+    RhythmChannelKeyOn,
+}
+
+/// A record of a questionable register write made while strict mode was enabled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegisterWarning {
+    /// The register address written, in the range 0x000..=0x1FF. Addresses 0x100 and above are
+    /// in the secondary register file.
+    pub register: u16,
+    /// The value written.
+    pub value: u8,
+    /// The kind of problem detected.
+    pub kind: RegisterWarningKind,
+}
+
+/// Check a write to the specified register, returning the problem detected, if any.
+///
+/// # Arguments
+///
+/// * `register` - The register address, in the range 0x000..=0x1FF.
+/// * `value`    - The value being written.
+pub fn check_register_write(register: u16, value: u8) -> Option<RegisterWarningKind> {
+    let secondary = register & 0x100 != 0;
+    let reg = (register & 0xFF) as u8;
+
+    let valid_mask: u8 = match reg {
+        // Test register. Only the waveform select enable bit is meaningful, and only on the
+        // primary register file.
+        0x01 if !secondary => 0x20,
+        // Timer registers exist only on the primary register file.
+        0x02 | 0x03 if !secondary => 0xFF,
+        0x04 if !secondary => 0xE3,
+        // 4-op connection select.
+        0x04 => 0x3F,
+        // OPL3 mode. Bit 1 is the OPL4 NEW2 bit, which the YMF262 ignores. See
+        // `registers::ModeFlags`.
+        0x05 if secondary => 0x01,
+        // CSM and note select.
+        0x08 if !secondary => 0xC0,
+        0x20..=0x35 | 0x40..=0x55 | 0x60..=0x75 | 0x80..=0x95 if is_operator(reg) => 0xFF,
+        0xE0..=0xF5 if is_operator(reg) => 0x07,
+        0xA0..=0xA8 | 0xC0..=0xC8 => 0xFF,
+        // Key-on, block and F-number high bits. The top two bits are unused.
+        0xB0..=0xB8 => 0x3F,
+        0xBD if !secondary => 0xFF,
+        _ => return Some(RegisterWarningKind::Unused),
+    };
+
+    match value & !valid_mask {
+        0 => None,
+        reserved => Some(RegisterWarningKind::ReservedBits(reserved)),
+    }
+}
+
+/// Check whether a write to the specified register requires OPL3 mode, returning
+/// `RegisterWarningKind::Opl3ModeDisabled` if it does and OPL3 mode is disabled.
+///
+/// The secondary register file is only accessible in OPL3 mode, with the exception of register
+/// 0x105, which enables it.
+///
+/// # Arguments
+///
+/// * `register`     - The register address, in the range 0x000..=0x1FF.
+/// * `opl3_enabled` - Whether OPL3 mode is enabled.
+pub fn check_opl3_mode(register: u16, opl3_enabled: bool) -> Option<RegisterWarningKind> {
+    if register & 0x100 != 0 && register != 0x105 && !opl3_enabled {
+        Some(RegisterWarningKind::Opl3ModeDisabled)
+    } else {
+        None
+    }
+}
+
+/// Check whether a write keys channels 6 to 8 on as melodic channels while rhythm mode is enabled,
+/// returning `RegisterWarningKind::RhythmChannelKeyOn` if it does. Writes to the channels' other
+/// registers are not flagged: their operator registers and frequencies set up the drums.
+///
+/// # Arguments
+///
+/// * `register` - The register address, in the range 0x000..=0x1FF.
+/// * `value`    - The value being written.
+/// * `primary`  - The primary register file, as it was before the write.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::validate::{check_rhythm_mode, RegisterWarningKind};
+///
+/// let mut primary = [0u8; 256];
+/// primary[0xBD] = 0x20;
+/// // Keying channel 7 on steals the hi-hat and snare drum.
+/// assert_eq!(check_rhythm_mode(0xB7, 0x31, &primary), Some(RegisterWarningKind::RhythmChannelKeyOn));
+/// // Setting its frequency without the key-on bit tunes the drums.
+/// assert_eq!(check_rhythm_mode(0xB7, 0x11, &primary), None);
+/// ```
+pub fn check_rhythm_mode(
+    register: u16,
+    value: u8,
+    primary: &[u8; 256],
+) -> Option<RegisterWarningKind> {
+    let rhythm_enabled = primary[0xBD] & 0x20 != 0;
+    let conflict = match register {
+        0xB6..=0xB8 => rhythm_enabled && value & 0x20 != 0,
+        0xBD => {
+            !rhythm_enabled
+                && value & 0x20 != 0
+                && primary[0xB6..=0xB8].iter().any(|&reg| reg & 0x20 != 0)
+        }
+        _ => false,
+    };
+    conflict.then_some(RegisterWarningKind::RhythmChannelKeyOn)
+}
+
+/// Return `true` if the low 5 bits of an operator register address select an operator.
+/// Each operator register group has gaps at offsets 0x06, 0x07, 0x0E and 0x0F.
+fn is_operator(reg: u8) -> bool {
+    let offset = reg & 0x1F;
+    offset < 0x16 && (offset & 0x07) < 0x06
+}
@@ -0,0 +1,185 @@
+//! Conversion between event logs and VGM files.
+//!
+//! Writes to the YM3812 (OPL2), YM3526 (OPL), Y8950 (MSX-Audio) and YMF262 (OPL3) are read from
+//! VGM files. Writes to other chips, and to the second chip of dual-chip files, are skipped. To
+//! play those files, use `vgm_player::VgmPlayer`. Event logs are written as VGM 1.51 files
+//! targeting either the YM3812 or the YMF262.
+
+use crate::event_log::{EventLog, OplEvent, DEFAULT_TICK_RATE};
+use crate::pacing::{YM3812_CLOCK, YMF262_CLOCK};
+use crate::OplError;
+
+/// The sample rate that all VGM wait commands are expressed in.
+pub const VGM_SAMPLE_RATE: u32 = 44100;
+
+const VGM_MAGIC: &[u8; 4] = b"Vgm ";
+const VGM_VERSION: u32 = 0x151;
+const VGM_HEADER_SIZE: usize = 0x80;
+
+/// Return the little-endian u32 at `offset`, or 0 if it lies outside of `data`.
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> u32 {
+    match data.get(offset..offset + 4) {
+        Some(b) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        None => 0,
+    }
+}
+
+/// Return the number of operand bytes that follow a VGM command, or `None` for commands that are
+/// not defined by the VGM specification. Data blocks (0x67) are handled separately.
+pub(crate) fn command_operands(command: u8) -> Option<usize> {
+    match command {
+        0x30..=0x3F | 0x4F | 0x50 | 0x94 => Some(1),
+        0x40..=0x4E | 0x51..=0x5F | 0x61 | 0xA0..=0xBF => Some(2),
+        0x62 | 0x63 | 0x70..=0x8F => Some(0),
+        0x64 | 0xC0..=0xDF => Some(3),
+        0x90 | 0x91 | 0x95 | 0xE0..=0xFF => Some(4),
+        0x92 => Some(5),
+        0x93 => Some(10),
+        0x68 => Some(11),
+        _ => None,
+    }
+}
+
+impl EventLog {
+    /// Convert a VGM file into an event log. The event log uses the default tick rate of 49716
+    /// ticks per second. Compressed (VGZ) files must be decompressed first.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The contents of an uncompressed VGM file.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the converted `EventLog` or an `OplError` on failure.
+    pub fn from_vgm(data: &[u8]) -> Result<EventLog, OplError> {
+        const ERR: OplError = OplError::InvalidFormat("VGM");
+        if data.len() < 0x40 || &data[0..4] != VGM_MAGIC {
+            return Err(ERR);
+        }
+        let version = read_u32(data, 0x08);
+        let data_offset = match read_u32(data, 0x34) {
+            offset if version >= 0x150 && offset != 0 => 0x34 + offset as usize,
+            _ => 0x40,
+        };
+
+        let mut log = EventLog::new(DEFAULT_TICK_RATE);
+        let mut samples: u64 = 0;
+        let mut i = data_offset;
+        while let Some(&command) = data.get(i) {
+            if command == 0x66 {
+                break;
+            }
+            if command == 0x67 {
+                // Data block: 0x67 0x66 tt ss ss ss ss, followed by the data.
+                i += 7 + read_u32(data, i + 3) as usize;
+                continue;
+            }
+            let len = command_operands(command).ok_or(ERR)?;
+            let operands = data.get(i + 1..i + 1 + len).ok_or(ERR)?;
+            match command {
+                // YM3812, YM3526 and Y8950 writes, and YMF262 port 0 and port 1 writes.
+                0x5A..=0x5C | 0x5E | 0x5F => {
+                    let bank = if command == 0x5F { 0x100 } else { 0 };
+                    // Times are rounded up, so that converting back to samples is lossless.
+                    log.push(OplEvent {
+                        time: (samples * DEFAULT_TICK_RATE as u64).div_ceil(VGM_SAMPLE_RATE as u64),
+                        register: bank | operands[0] as u16,
+                        value: operands[1],
+                    });
+                }
+                0x61 => samples += u16::from_le_bytes([operands[0], operands[1]]) as u64,
+                0x62 => samples += 735,
+                0x63 => samples += 882,
+                0x70..=0x7F => samples += (command & 0x0F) as u64 + 1,
+                0x80..=0x8F => samples += (command & 0x0F) as u64,
+                _ => {}
+            }
+            i += 1 + len;
+        }
+        Ok(log)
+    }
+
+    /// Convert the event log into a VGM 1.51 file. If any event writes to the secondary register
+    /// file, the file targets a YMF262; otherwise it targets a YM3812. Event times are rounded
+    /// down to the 44100Hz resolution of the VGM format.
+    ///
+    /// # Returns
+    ///
+    /// The contents of the VGM file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::event_log::{EventLog, OplEvent};
+    ///
+    /// let mut log = EventLog::new(49716);
+    /// log.push(OplEvent { time: 0, register: 0x105, value: 0x01 });
+    /// log.push(OplEvent { time: 49716, register: 0xB0, value: 0x31 });
+    ///
+    /// let vgm = log.to_vgm();
+    /// let events: Vec<OplEvent> = EventLog::from_vgm(&vgm).unwrap().iter().collect();
+    /// assert_eq!(events.len(), 2);
+    /// assert_eq!(events[1].time, 49716);
+    /// ```
+    pub fn to_vgm(&self) -> Vec<u8> {
+        let opl3 = self.iter().any(|event| event.register & 0x100 != 0);
+
+        let mut commands: Vec<u8> = Vec::with_capacity(self.len() * 3 + 1);
+        let mut last_sample = 0u64;
+        for event in self.iter() {
+            let sample = event.time * VGM_SAMPLE_RATE as u64 / self.tick_rate() as u64;
+            push_wait(&mut commands, sample - last_sample);
+            last_sample = sample;
+            let command = match (opl3, event.register & 0x100 != 0) {
+                (false, _) => 0x5A,
+                (true, false) => 0x5E,
+                (true, true) => 0x5F,
+            };
+            commands.extend_from_slice(&[command, event.register as u8, event.value]);
+        }
+        commands.push(0x66);
+
+        let mut vgm = vec![0u8; VGM_HEADER_SIZE];
+        let mut put = |offset: usize, value: u32| {
+            vgm[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put(0x00, u32::from_le_bytes(*VGM_MAGIC));
+        put(0x04, (VGM_HEADER_SIZE + commands.len() - 4) as u32);
+        put(0x08, VGM_VERSION);
+        put(0x18, last_sample.min(u32::MAX as u64) as u32);
+        put(0x34, (VGM_HEADER_SIZE - 0x34) as u32);
+        if opl3 {
+            put(0x5C, YMF262_CLOCK);
+        } else {
+            put(0x50, YM3812_CLOCK);
+        }
+        vgm.extend_from_slice(&commands);
+        vgm
+    }
+}
+
+/// Append the shortest sequence of VGM wait commands totalling `samples`.
+pub(crate) fn push_wait(commands: &mut Vec<u8>, mut samples: u64) {
+    while samples > 0 {
+        match samples {
+            1..=16 => {
+                commands.push(0x70 + (samples - 1) as u8);
+                samples = 0;
+            }
+            735 => {
+                commands.push(0x62);
+                samples = 0;
+            }
+            882 => {
+                commands.push(0x63);
+                samples = 0;
+            }
+            _ => {
+                let wait = samples.min(u16::MAX as u64);
+                commands.push(0x61);
+                commands.extend_from_slice(&(wait as u16).to_le_bytes());
+                samples -= wait;
+            }
+        }
+    }
+}
@@ -0,0 +1,725 @@
+//! Playback of VGM files directly, including files for two chips at once.
+//!
+//! `EventLog::from_vgm` converts the writes of a single OPL chip into an event log. `VgmPlayer`
+//! instead plays a VGM file as it stands, with one emulated chip for every chip declared in the
+//! header. This covers dual-chip files, where bit 30 of a clock field declares a second chip and
+//! the second chip's writes use their own commands, and, with the `opll` feature, YM2413 files.
+//!
+//! YM3812, YM3526 and Y8950 chips are emulated by an `Opl3Chip` in OPL2 mode. The ADPCM section of
+//! the Y8950 is not emulated.
+//!
+//! The chips are mixed with a pan position each. By default, the two chips of a dual OPL2 file
+//! are panned hard left and right, as on the Sound Blaster Pro, and every other chip is centered.
+//!
+//! # Example
+//!
+//! ```
+//! use opl3_rs::event_log::{EventLog, OplEvent};
+//! use opl3_rs::vgm_player::VgmPlayer;
+//!
+//! let mut log = EventLog::new(49716);
+//! log.push(OplEvent { time: 0, register: 0xB0, value: 0x31 });
+//! log.push(OplEvent { time: 49716, register: 0xB0, value: 0x11 });
+//!
+//! let mut player = VgmPlayer::new(&log.to_vgm(), 44100).unwrap();
+//! let mut buffer = [0i16; 4096 * 2];
+//! while !player.is_finished() {
+//!     player.generate_stream(&mut buffer).unwrap();
+//! }
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::opl_core::OplCore;
+use crate::sample::Dither;
+use crate::vgm::{command_operands, read_u32, VGM_SAMPLE_RATE};
+use crate::{Opl3Chip, OplError};
+
+const ERR: OplError = OplError::InvalidFormat("VGM");
+
+/// Bit 30 of a clock field declares a second chip of the same type.
+const DUAL_CHIP_BIT: u32 = 0x4000_0000;
+
+/// The chip clocks a VGM header may declare, in Hz. Real chips run at 3.58MHz or 14.32MHz; far
+/// faster clocks would make rendering crawl, and are only found in corrupt files.
+const CLOCK_RANGE: std::ops::RangeInclusive<u32> = 100_000..=50_000_000;
+
+/// The number of frames `render_song` renders at a time.
+const SONG_BLOCK_FRAMES: usize = 4096;
+
+/// The `VgmChipType` enum lists the chips that `VgmPlayer` can play.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VgmChipType {
+    /// The YM2413 (OPLL). Only played with the `opll` feature.
+    Ym2413,
+    /// The YM3812 (OPL2).
+    Ym3812,
+    /// The YM3526 (OPL).
+    Ym3526,
+    /// The Y8950 (MSX-Audio).
+    Y8950,
+    /// The YMF262 (OPL3).
+    Ymf262,
+}
+
+impl VgmChipType {
+    /// The offset of the chip's clock field in the VGM header, and the first VGM version with it.
+    fn clock_field(&self) -> (usize, u32) {
+        match self {
+            VgmChipType::Ym2413 => (0x10, 0x100),
+            VgmChipType::Ym3812 => (0x50, 0x151),
+            VgmChipType::Ym3526 => (0x54, 0x151),
+            VgmChipType::Y8950 => (0x58, 0x151),
+            VgmChipType::Ymf262 => (0x5C, 0x151),
+        }
+    }
+
+    /// Decode a write command, returning the chip it addresses, whether it addresses the second
+    /// chip and the register bank it writes to.
+    fn from_command(command: u8) -> Option<(VgmChipType, bool, u16)> {
+        let (chip, bank) = match command & 0x0F {
+            0x01 => (VgmChipType::Ym2413, 0),
+            0x0A => (VgmChipType::Ym3812, 0),
+            0x0B => (VgmChipType::Ym3526, 0),
+            0x0C => (VgmChipType::Y8950, 0),
+            0x0E => (VgmChipType::Ymf262, 0),
+            0x0F => (VgmChipType::Ymf262, 0x100),
+            _ => return None,
+        };
+        match command & 0xF0 {
+            0x50 => Some((chip, false, bank)),
+            0xA0 => Some((chip, true, bank)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VgmChipType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            VgmChipType::Ym2413 => "YM2413",
+            VgmChipType::Ym3812 => "YM3812",
+            VgmChipType::Ym3526 => "YM3526",
+            VgmChipType::Y8950 => "Y8950",
+            VgmChipType::Ymf262 => "YMF262",
+        })
+    }
+}
+
+struct VgmChip {
+    chip_type: VgmChipType,
+    second: bool,
+    core: Box<dyn OplCore + Send>,
+    pan: f32,
+}
+
+/// The `VgmPlayer` struct plays a VGM file on emulated chips.
+pub struct VgmPlayer {
+    data: Vec<u8>,
+    position: usize,
+    loop_offset: Option<usize>,
+    loop_samples: Option<u64>,
+    total_samples: u64,
+    loop_length: u64,
+    looping: bool,
+    dither: Option<Dither>,
+    finished: bool,
+    sample_rate: u32,
+    vgm_samples: u64,
+    frames: u64,
+    chips: Vec<VgmChip>,
+    scratch: Vec<i16>,
+    mix: Vec<f32>,
+}
+
+impl VgmPlayer {
+    /// Create a new `VgmPlayer` for a VGM file. Compressed (VGZ) files must be decompressed first.
+    ///
+    /// # Arguments
+    ///
+    /// * `data`        - The contents of an uncompressed VGM file.
+    /// * `sample_rate` - The sample rate to generate audio at.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the new `VgmPlayer`, or an `OplError` if the file is not a VGM
+    /// file, declares no chip that can be played or a chip clock outside 100kHz to 50MHz, or the
+    /// sample rate is outside `Opl3Chip::MIN_SAMPLE_RATE` to `Opl3Chip::MAX_SAMPLE_RATE`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::event_log::EventLog;
+    /// use opl3_rs::vgm_player::VgmPlayer;
+    /// use opl3_rs::OplError;
+    ///
+    /// let mut vgm = EventLog::new(1000).to_vgm();
+    /// assert!(VgmPlayer::new(&vgm, 44100).is_ok());
+    ///
+    /// // A corrupt YM3812 clock is rejected.
+    /// vgm[0x50..0x54].copy_from_slice(&0x3200_0000u32.to_le_bytes());
+    /// assert!(matches!(
+    ///     VgmPlayer::new(&vgm, 44100),
+    ///     Err(OplError::InvalidFormat("VGM clock"))
+    /// ));
+    /// ```
+    pub fn new(data: &[u8], sample_rate: u32) -> Result<Self, OplError> {
+        Opl3Chip::check_sample_rate(sample_rate)?;
+        if data.len() < 0x40 || &data[0..4] != b"Vgm " {
+            return Err(ERR);
+        }
+        let version = read_u32(data, 0x08);
+        let data_offset = match read_u32(data, 0x34) {
+            offset if version >= 0x150 && offset != 0 => 0x34 + offset as usize,
+            _ => 0x40,
+        };
+        let loop_offset = match read_u32(data, 0x1C) {
+            0 => None,
+            offset => Some(0x1C + offset as usize),
+        };
+
+        let mut chips = Vec::new();
+        for chip_type in [
+            VgmChipType::Ym2413,
+            VgmChipType::Ym3812,
+            VgmChipType::Ym3526,
+            VgmChipType::Y8950,
+            VgmChipType::Ymf262,
+        ] {
+            let (offset, since) = chip_type.clock_field();
+            let clock = read_u32(data, offset);
+            if version < since || offset + 4 > data_offset || clock & !DUAL_CHIP_BIT == 0 {
+                continue;
+            }
+            let dual = clock & DUAL_CHIP_BIT != 0;
+            if !CLOCK_RANGE.contains(&(clock & 0x3FFF_FFFF)) {
+                return Err(OplError::InvalidFormat("VGM clock"));
+            }
+            for second in [false, true].into_iter().take(1 + dual as usize) {
+                let Some(core) = new_core(chip_type, clock & 0x3FFF_FFFF, sample_rate) else {
+                    continue;
+                };
+                let pan = match (dual, chip_type) {
+                    (true, VgmChipType::Ym3812 | VgmChipType::Ym3526 | VgmChipType::Y8950) => {
+                        if second {
+                            1.0
+                        } else {
+                            -1.0
+                        }
+                    }
+                    _ => 0.0,
+                };
+                chips.push(VgmChip {
+                    chip_type,
+                    second,
+                    core,
+                    pan,
+                });
+            }
+        }
+        if chips.is_empty() {
+            return Err(OplError::InvalidFormat("VGM chip"));
+        }
+
+        Ok(VgmPlayer {
+            data: data.to_vec(),
+            position: data_offset,
+            loop_offset,
+            loop_samples: None,
+            total_samples: read_u32(data, 0x18) as u64,
+            loop_length: read_u32(data, 0x20) as u64,
+            looping: false,
+            dither: None,
+            finished: false,
+            sample_rate,
+            vgm_samples: 0,
+            frames: 0,
+            chips,
+            scratch: Vec::new(),
+            mix: Vec::new(),
+        })
+    }
+
+    /// Return the chips being played, in the order used by `set_pan`. Each entry is the type of
+    /// the chip and whether it is the second chip of its type.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use opl3_rs::vgm_player::{VgmChipType, VgmPlayer};
+    ///
+    /// // A dual OPL2 file that plays a note on the second chip only.
+    /// let mut vgm = vec![0u8; 0x80];
+    /// vgm[0..4].copy_from_slice(b"Vgm ");
+    /// vgm[0x08..0x0C].copy_from_slice(&0x151u32.to_le_bytes());
+    /// vgm[0x34..0x38].copy_from_slice(&0x4Cu32.to_le_bytes());
+    /// vgm[0x50..0x54].copy_from_slice(&(3_579_545u32 | 0x4000_0000).to_le_bytes());
+    /// for (reg, value) in [(0x63, 0xF0), (0xA0, 0x98), (0xB0, 0x31)] {
+    ///     vgm.extend_from_slice(&[0xAA, reg, value]);
+    /// }
+    /// vgm.extend_from_slice(&[0x61, 0x44, 0xAC, 0x66]);
+    ///
+    /// let mut player = VgmPlayer::new(&vgm, 44100).unwrap();
+    /// assert_eq!(
+    ///     player.chips(),
+    ///     vec![(VgmChipType::Ym3812, false), (VgmChipType::Ym3812, true)]
+    /// );
+    ///
+    /// // The second chip is panned hard right.
+    /// let mut buffer = vec![0i16; 4410 * 2];
+    /// player.generate_stream(&mut buffer).unwrap();
+    /// assert!(buffer.iter().step_by(2).all(|&left| left.abs() < 4));
+    /// assert!(buffer.iter().skip(1).step_by(2).any(|&right| right != 0));
+    /// ```
+    pub fn chips(&self) -> Vec<(VgmChipType, bool)> {
+        self.chips
+            .iter()
+            .map(|chip| (chip.chip_type, chip.second))
+            .collect()
+    }
+
+    /// Set the pan position of one of the chips.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip` - The index of the chip, as returned by `chips`.
+    /// * `pan`  - The pan position, from -1.0 for hard left to 1.0 for hard right.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either `()` on success or an `OplError` if there is no such chip.
+    pub fn set_pan(&mut self, chip: usize, pan: f32) -> Result<(), OplError> {
+        let chip = self
+            .chips
+            .get_mut(chip)
+            .ok_or(OplError::RegisterOutOfRange)?;
+        chip.pan = pan.clamp(-1.0, 1.0);
+        Ok(())
+    }
+
+    /// Enable or disable looping. When enabled, playback jumps back to the loop point of the file
+    /// at its end, if it has one, and never finishes.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    /// Enable or disable TPDF dither when the chips are mixed back to 16 bits. The mix only falls
+    /// between 16-bit steps when a chip is partly panned with `set_pan`, but once enabled, dither
+    /// is added to every sample. Dithering is disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to dither the mixed output.
+    pub fn set_dither(&mut self, enabled: bool) {
+        self.dither = enabled.then(Dither::new);
+    }
+
+    /// Return the frame at which the looped part of the song starts, from the header, or `None`
+    /// if the file has no loop point.
+    pub fn loop_start_frame(&self) -> Option<u64> {
+        match self.loop_offset {
+            Some(_) if self.loop_length > 0 => {
+                let start = self.total_samples.saturating_sub(self.loop_length);
+                Some(self.to_frames(start))
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the length of the song in frames, from the header, with its looped part played a
+    /// number of times. Returns `None` if the header gives no length.
+    ///
+    /// # Arguments
+    ///
+    /// * `loops` - The number of times the looped part is played, if the file has a loop point.
+    ///             Values below 1 count as 1.
+    pub fn length_frames(&self, loops: u32) -> Option<u64> {
+        match self.loop_start_frame() {
+            Some(start) => Some(start + self.to_frames(self.loop_length) * loops.max(1) as u64),
+            None if self.total_samples > 0 => Some(self.to_frames(self.total_samples)),
+            None => None,
+        }
+    }
+
+    /// Return the number of frames generated so far.
+    pub fn position_frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Convert a number of samples at the VGM rate of 44100Hz to output frames.
+    fn to_frames(&self, vgm_samples: u64) -> u64 {
+        vgm_samples * self.sample_rate as u64 / VGM_SAMPLE_RATE as u64
+    }
+
+    /// Return `true` once the end of the file has been reached and every sample before it has
+    /// been generated.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Generate a stream of 2 channel, interleaved audio samples, with every chip mixed together.
+    /// Once the end of the file is reached, the chips keep running so that released notes decay.
+    ///
+    /// One frame is generated for every two samples in the buffer. If the buffer has an odd
+    /// length, its last sample is left unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - A mutable reference to a slice of i16 that will be filled with audio samples.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing either the number of frames generated on success, or an `OplError` if
+    /// the file contains an undefined command.
+    pub fn generate_stream(&mut self, buffer: &mut [i16]) -> Result<usize, OplError> {
+        let frames = buffer.len() / 2;
+        self.mix.clear();
+        self.mix.resize(frames * 2, 0.0);
+        let mut done = 0;
+        while done < frames {
+            let due = self.run_commands()?;
+            let block = due.map_or(frames - done, |due| {
+                ((due - self.frames.min(due)) as usize).min(frames - done)
+            });
+            if block == 0 {
+                continue;
+            }
+            self.render(done, block)?;
+            done += block;
+            self.frames += block as u64;
+        }
+
+        for (sample, &mixed) in buffer.iter_mut().zip(&self.mix) {
+            *sample = match &mut self.dither {
+                Some(dither) => dither.quantize(mixed / 32768.0),
+                None => mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16,
+            };
+        }
+        Ok(frames)
+    }
+
+    /// Process commands until the next wait that extends past the current frame, returning the
+    /// output frame at which the wait ends, or `None` at the end of the file.
+    fn run_commands(&mut self) -> Result<Option<u64>, OplError> {
+        loop {
+            let due = self.vgm_samples * self.sample_rate as u64 / VGM_SAMPLE_RATE as u64;
+            if due > self.frames {
+                return Ok(Some(due));
+            }
+            let Some(&command) = self.data.get(self.position).filter(|_| !self.finished) else {
+                self.finished = true;
+                return Ok(None);
+            };
+            if command == 0x66 {
+                // A loop without any waits in it would never finish, so it ends playback.
+                match self.loop_offset.filter(|_| self.looping) {
+                    Some(offset) if self.loop_samples != Some(self.vgm_samples) => {
+                        self.position = offset;
+                        self.loop_samples = Some(self.vgm_samples);
+                    }
+                    _ => self.finished = true,
+                }
+                continue;
+            }
+            if command == 0x67 {
+                // Data block: 0x67 0x66 tt ss ss ss ss, followed by the data.
+                self.position += 7 + read_u32(&self.data, self.position + 3) as usize;
+                continue;
+            }
+            let len = command_operands(command).ok_or(ERR)?;
+            let start = self.position + 1;
+            let operands = self.data.get(start..start + len).ok_or(ERR)?;
+            match command {
+                0x61 => self.vgm_samples += u16::from_le_bytes([operands[0], operands[1]]) as u64,
+                0x62 => self.vgm_samples += 735,
+                0x63 => self.vgm_samples += 882,
+                0x70..=0x7F => self.vgm_samples += (command & 0x0F) as u64 + 1,
+                0x80..=0x8F => self.vgm_samples += (command & 0x0F) as u64,
+                _ => {
+                    if let Some((chip_type, second, bank)) = VgmChipType::from_command(command) {
+                        let (register, value) = (bank | operands[0] as u16, operands[1]);
+                        let target = self
+                            .chips
+                            .iter_mut()
+                            .find(|chip| chip.chip_type == chip_type && chip.second == second);
+                        if let Some(chip) = target {
+                            chip.core.write_register(register, value);
+                        }
+                    }
+                }
+            }
+            self.position = start + len;
+        }
+    }
+
+    /// Render a block of frames from every chip into the mix buffer.
+    fn render(&mut self, offset: usize, frames: usize) -> Result<(), OplError> {
+        self.scratch.resize(frames * 2, 0);
+        let mix = &mut self.mix[offset * 2..(offset + frames) * 2];
+        for chip in self.chips.iter_mut() {
+            chip.core.generate_stream(&mut self.scratch)?;
+            let gains = [(1.0 - chip.pan).min(1.0), (1.0 + chip.pan).min(1.0)];
+            for (out, frame) in mix.chunks_exact_mut(2).zip(self.scratch.chunks_exact(2)) {
+                out[0] += frame[0] as f32 * gains[0];
+                out[1] += frame[1] as f32 * gains[1];
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create the emulated chip for a chip declared in a VGM header.
+fn new_core(
+    chip_type: VgmChipType,
+    clock: u32,
+    sample_rate: u32,
+) -> Option<Box<dyn OplCore + Send>> {
+    match chip_type {
+        #[cfg(feature = "opll")]
+        VgmChipType::Ym2413 => Some(Box::new(crate::opll::OpllChip::new(sample_rate))),
+        #[cfg(not(feature = "opll"))]
+        VgmChipType::Ym2413 => None,
+        VgmChipType::Ymf262 => {
+            let mut chip = Opl3Chip::new(sample_rate);
+            chip.set_master_clock(clock);
+            Some(Box::new(chip))
+        }
+        _ => {
+            // OPL2-family chips run on a clock a quarter of the OPL3's for the same sample rate.
+            let mut chip = Opl3Chip::new(sample_rate);
+            chip.set_master_clock(clock.saturating_mul(4));
+            Some(Box::new(chip))
+        }
+    }
+}
+
+/// A one line summary of the file and playback: the chips, the position and length, and the loop
+/// point.
+///
+/// # Example
+///
+/// ```
+/// use opl3_rs::event_log::{EventLog, OplEvent};
+/// use opl3_rs::vgm_player::VgmPlayer;
+///
+/// let mut log = EventLog::new(1000);
+/// log.push(OplEvent { time: 2500, register: 0xB0, value: 0x31 });
+///
+/// let mut player = VgmPlayer::new(&log.to_vgm(), 44100).unwrap();
+/// let mut buffer = [0i16; 44100 * 2];
+/// player.generate_stream(&mut buffer).unwrap();
+/// assert_eq!(player.to_string(), "VGM, YM3812, 0:01.00 / 0:02.50, no loop");
+/// ```
+impl fmt::Display for VgmPlayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn time(f: &mut fmt::Formatter<'_>, samples: u64, rate: u32) -> fmt::Result {
+            let centis = samples * 100 / rate.max(1) as u64;
+            write!(
+                f,
+                "{}:{:02}.{:02}",
+                centis / 6000,
+                centis / 100 % 60,
+                centis % 100
+            )
+        }
+
+        f.write_str("VGM, ")?;
+        let mut chips = self.chips.iter().filter(|chip| !chip.second).peekable();
+        while let Some(chip) = chips.next() {
+            write!(f, "{}", chip.chip_type)?;
+            if self
+                .chips
+                .iter()
+                .any(|other| other.chip_type == chip.chip_type && other.second)
+            {
+                f.write_str(" x2")?;
+            }
+            if chips.peek().is_some() {
+                f.write_str(" + ")?;
+            }
+        }
+        f.write_str(", ")?;
+        time(f, self.frames, self.sample_rate)?;
+        f.write_str(" / ")?;
+        time(f, self.total_samples, VGM_SAMPLE_RATE)?;
+        match self.loop_offset {
+            Some(_) if self.loop_length > 0 => {
+                f.write_str(", loop from ")?;
+                let start = self.total_samples.saturating_sub(self.loop_length);
+                time(f, start, VGM_SAMPLE_RATE)?;
+                f.write_str(if self.looping {
+                    ", looping"
+                } else {
+                    ", not looping"
+                })?;
+            }
+            _ => f.write_str(", no loop")?,
+        }
+        if self.finished {
+            f.write_str(", finished")?;
+        }
+        Ok(())
+    }
+}
+
+/// The `SongRenderOptions` struct configures `render_song`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SongRenderOptions {
+    /// The number of times the looped part of a song with a loop point is played. 1 plays the
+    /// song through once. Songs without a loop point are always played once.
+    pub loops: u32,
+    /// The length of the fade out played after the last loop, in milliseconds. 0 ends the
+    /// render exactly at the end of the last loop, for frontends that loop it gaplessly.
+    pub fade_ms: u32,
+    /// The most audio rendered after the end of a song without a loop point, in milliseconds,
+    /// so that released notes can decay. Silence at the end of it is trimmed.
+    pub tail_ms: u32,
+    /// The largest sample magnitude that counts as silence when trimming the tail.
+    pub silence_threshold: i16,
+}
+
+impl Default for SongRenderOptions {
+    fn default() -> Self {
+        SongRenderOptions {
+            loops: 2,
+            fade_ms: 8000,
+            tail_ms: 5000,
+            silence_threshold: 2,
+        }
+    }
+}
+
+/// The `RenderedSong` struct is a whole song rendered by `render_song`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenderedSong {
+    /// The rendered audio, as 2 channel, interleaved samples.
+    pub samples: Vec<i16>,
+    /// The frame at which the looped part of the song starts, or `None` if the song has no loop
+    /// point. The loop ends at the end of the song's last loop, before any fade out.
+    pub loop_start_sample: Option<u64>,
+    /// The length of the rendered audio.
+    pub duration: Duration,
+}
+
+/// Render a whole song to memory, from the start of a newly created player. A song with a loop
+/// point is played with its looped part repeated `options.loops` times, then faded out. A song
+/// without one is played to its end, followed by a tail in which released notes decay, with
+/// the silence at the end of the tail trimmed off.
+///
+/// # Arguments
+///
+/// * `player`  - The player to render, which should not have been played yet. Its looping
+///               setting is left as it was.
+/// * `options` - How many loops to play, and how to end the song.
+///
+/// # Returns
+///
+/// A Result containing either the rendered song on success or an `OplError` if the file
+/// contains an undefined command.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use opl3_rs::event_log::{EventLog, OplEvent};
+/// use opl3_rs::vgm_player::{render_song, SongRenderOptions, VgmPlayer};
+///
+/// // One second of a note, keyed off halfway through.
+/// let mut log = EventLog::new(44100);
+/// for (time, register, value) in [(0, 0x63, 0xF0), (0, 0xA0, 0x98), (0, 0xB0, 0x31)] {
+///     log.push(OplEvent { time, register, value });
+/// }
+/// log.push(OplEvent { time: 22050, register: 0xB0, value: 0x11 });
+/// log.push(OplEvent { time: 44100, register: 0xBD, value: 0x00 });
+///
+/// // Loop the whole song, from the start of its commands.
+/// let mut vgm = log.to_vgm();
+/// let data_offset = 0x34 + u32::from_le_bytes(vgm[0x34..0x38].try_into().unwrap());
+/// vgm[0x1C..0x20].copy_from_slice(&(data_offset - 0x1C).to_le_bytes());
+/// vgm[0x20..0x24].copy_from_slice(&44100u32.to_le_bytes());
+///
+/// let mut player = VgmPlayer::new(&vgm, 44100).unwrap();
+/// let options = SongRenderOptions { loops: 2, fade_ms: 500, ..Default::default() };
+/// let song = render_song(&mut player, &options).unwrap();
+/// assert_eq!(song.loop_start_sample, Some(0));
+/// assert_eq!(song.duration, Duration::from_millis(2500));
+/// assert_eq!(song.samples.len(), 110250 * 2);
+/// ```
+pub fn render_song(
+    player: &mut VgmPlayer,
+    options: &SongRenderOptions,
+) -> Result<RenderedSong, OplError> {
+    let looping = player.looping;
+    let mut samples = Vec::new();
+    let result = render_song_into(player, options, &mut samples);
+    player.set_looping(looping);
+    let loop_start_sample = result?;
+
+    let frames = samples.len() as u64 / 2;
+    Ok(RenderedSong {
+        samples,
+        loop_start_sample,
+        duration: Duration::from_nanos(frames * 1_000_000_000 / player.sample_rate as u64),
+    })
+}
+
+/// Render a song for `render_song`, returning the frame at which its loop starts.
+fn render_song_into(
+    player: &mut VgmPlayer,
+    options: &SongRenderOptions,
+    samples: &mut Vec<i16>,
+) -> Result<Option<u64>, OplError> {
+    let sample_rate = player.sample_rate as u64;
+    let ms_to_frames = |ms: u32| ms as u64 * sample_rate / 1000;
+    let mut buffer = vec![0i16; SONG_BLOCK_FRAMES * 2];
+    let mut render = |player: &mut VgmPlayer, samples: &mut Vec<i16>, frames: u64| {
+        let block = frames.min(SONG_BLOCK_FRAMES as u64) as usize;
+        player.generate_stream(&mut buffer[..block * 2])?;
+        samples.extend_from_slice(&buffer[..block * 2]);
+        Ok::<u64, OplError>(block as u64)
+    };
+
+    let (Some(loop_start), Some(end)) = (
+        player.loop_start_frame(),
+        player.length_frames(options.loops),
+    ) else {
+        player.set_looping(false);
+        while !player.is_finished() {
+            render(player, samples, SONG_BLOCK_FRAMES as u64)?;
+        }
+        let end = samples.len();
+        let tail = ms_to_frames(options.tail_ms);
+        let mut rendered = 0;
+        while rendered < tail {
+            rendered += render(player, samples, tail - rendered)?;
+        }
+        let threshold = options.silence_threshold.unsigned_abs();
+        let last = samples[end..]
+            .iter()
+            .rposition(|sample| sample.unsigned_abs() > threshold)
+            .map_or(end, |i| end + i + 1);
+        samples.truncate(last.next_multiple_of(2));
+        return Ok(None);
+    };
+
+    player.set_looping(true);
+    while player.frames < end {
+        render(player, samples, end - player.frames)?;
+    }
+    let fade = ms_to_frames(options.fade_ms);
+    let mut faded = 0;
+    while faded < fade {
+        let start = samples.len();
+        let block = render(player, samples, fade - faded)?;
+        for (i, frame) in samples[start..].chunks_exact_mut(2).enumerate() {
+            let gain = 1.0 - (faded + i as u64) as f32 / fade as f32;
+            for sample in frame {
+                *sample = (*sample as f32 * gain) as i16;
+            }
+        }
+        faded += block;
+    }
+    Ok(Some(loop_start))
+}